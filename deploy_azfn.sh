@@ -4,6 +4,6 @@ cp ./target/x86_64-unknown-linux-musl/release/azfunc_sharepoint_uploader .
 
 # Pack zip files
 rm -rf deployment.zip
-zip -r deployment.zip azfunc_sharepoint_uploader host.json HttpTriggerCopyBlob2SPO/
+zip -r deployment.zip azfunc_sharepoint_uploader host.json HttpTriggerCopyBlob2SPO/ HttpTriggerBatchCopy2SPO/ EventGridCopyBlob2SPO/ QueueTriggerCopyBlob2SPO/ TimerTriggerSyncJobs/
 
 # Deployment
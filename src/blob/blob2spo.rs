@@ -1,14 +1,18 @@
-use std::error::Error;
-use std::sync::Arc;
+use std::time::Duration;
 
-use azure_identity::DefaultAzureCredential;
-use azure_storage::StorageCredentials;
-use azure_storage_blobs::prelude::ClientBuilder;
+use azure_storage_blobs::prelude::RehydratePriority;
 use futures::StreamExt;
-use log::debug;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
 use spinner::SpinnerHandle;
 
-use crate::spo::spo_engine::{SPOEngine, SPOError};
+use crate::blob::blob_consistency::{SourceConsistency, SourceGuard};
+use crate::blob::blob_post_action::PostCopyAction;
+use crate::blob::blob_source::{BlobSource, BlobVersionSelector};
+use crate::blob::blob_tier::{check_blob_online, wait_blob_online};
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::spo::spo_engine::{SPOEngine, SPOError, SPOErrorKind};
 
 pub const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024; // 64MB
 
@@ -25,27 +29,129 @@ pub enum ProcessStatus {
 pub type ShowStatusFn =
     fn(status: ProcessStatus, spinner: &SpinnerHandle, message: &String, chunks_size: &u64);
 
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    /// Share point column (internal name) which receives the copied blob version id or snapshot
+    pub version_field: Option<String>,
+    /// Start rehydration of an archived source blob with this priority
+    pub rehydrate_priority: Option<RehydratePriority>,
+    /// Wait for a rehydrating source blob, polling every n seconds, then copy it
+    pub rehydrate_wait_secs: Option<u64>,
+    /// Protection of the source blob against concurrent writes
+    pub consistency: SourceConsistency,
+    /// Compare the uploaded file length with the blob size
+    pub verify: bool,
+    /// Run on the source blob after the upload (and verification) succeeded
+    pub post_action: Option<PostCopyAction>,
+    /// File name in Share Point, the blob name when not set
+    pub target_name: Option<String>,
+    /// Share point columns (internal names) set on the uploaded file
+    pub metadata: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CopyFailure {
+    pub container: String,
+    pub blob_name: String,
+    pub error: SPOError,
+}
+
+//
+//  Outcome of a multi blob copy
+//
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CopySummary {
+    pub copied: Vec<CopyResult>,
+    pub failed: Vec<CopyFailure>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CopyResult {
+    pub container: String,
+    pub blob_name: String,
+    pub version_id: Option<String>,
+    pub snapshot: Option<String>,
+    pub size: u64,
+    /// Server relative url of the uploaded file
+    pub spo_file_url: String,
+    pub verified: bool,
+    /// What was done with the source blob after the copy
+    pub post_action: Option<String>,
+}
+
 //
 //  Read file from azure blob storage and upload chunk file to share point online
 //
+#[allow(clippy::too_many_arguments)]
 pub async fn do_copy_file_to_spo(
     tenant_id: &String,
     client_id: &String,
-    client_secret: &String,
+    client_secret: &Secret,
     share_point_domain: &String,
     share_point_site: &String,
     share_point_pah: &String,
-    account: &String,
-    container: &String,
-    blob_name: &String,
+    source: &BlobSource,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
     callback: Option<ShowStatusFn>,
     spinner: Option<&SpinnerHandle>,
-) -> Result<(), SPOError> {
-    let credential = Arc::new(DefaultAzureCredential::default());
-    let storage_credentials = StorageCredentials::token_credential(credential);
+) -> Result<CopyResult, SPOError> {
+    if source.container.is_empty() || source.blob_name.is_empty() {
+        return Err(SPOError::new(&String::from(
+            "container and blob name are required",
+        )));
+    }
+    if options.post_action.is_some() && source.version.is_some() {
+        return Err(SPOError::new(&String::from(
+            "Post-copy actions apply to the current blob, not a version or snapshot",
+        )));
+    }
+    if let Some(post_action) = &options.post_action {
+        post_action.validate(source)?;
+    }
+    let http_client = http_config.build_client()?;
+    let blob_client = source.blob_client(&http_client)?;
+    let blob_name = &source.blob_name;
+    let file_name = options.target_name.as_ref().unwrap_or(blob_name);
+    let version = source.resolve_version(&http_client).await?;
+    let mut copy_result = CopyResult {
+        container: source.container.to_owned(),
+        blob_name: blob_name.to_owned(),
+        ..Default::default()
+    };
+    match &version {
+        Some(BlobVersionSelector::VersionId(v)) => copy_result.version_id = Some(v.to_owned()),
+        Some(BlobVersionSelector::Snapshot(s)) => copy_result.snapshot = Some(s.to_owned()),
+        _ => {}
+    }
+    let versioning = version.as_ref().and_then(|v| v.to_blob_versioning());
 
-    let blob_client =
-        ClientBuilder::new(account, storage_credentials).blob_client(container, blob_name);
+    //
+    //  Preflight, archived blobs can not be read until rehydrated
+    //
+    let encryption = &source.encryption;
+    let properties = match check_blob_online(
+        &blob_client,
+        &versioning,
+        encryption,
+        &options.rehydrate_priority,
+    )
+    .await
+    {
+        Err(e) if e.kind() == SPOErrorKind::BlobRehydrating => match options.rehydrate_wait_secs {
+            Some(secs) => {
+                wait_blob_online(
+                    &blob_client,
+                    &versioning,
+                    encryption,
+                    Duration::from_secs(secs),
+                )
+                .await?
+            }
+            None => return Err(e),
+        },
+        r => r?,
+    };
 
     let mut result: Vec<u8> = vec![];
     // The stream is composed of individual calls to the get blob endpoint
@@ -53,8 +159,13 @@ pub async fn do_copy_file_to_spo(
     let mut offset: u64 = 0;
     let mut has_first_chunk = false;
 
-    let mut spo_engine =
-        SPOEngine::new(&tenant_id, &client_id, &client_secret, &share_point_domain);
+    let mut spo_engine = SPOEngine::new(
+        tenant_id,
+        client_id,
+        client_secret,
+        share_point_domain,
+        &http_client,
+    );
 
     if let Some(callback) = callback {
         callback(
@@ -67,129 +178,150 @@ pub async fn do_copy_file_to_spo(
     //
     //  Read file from azure blob storage and upload chunk file to share point online
     //
-    let mut stream = blob_client.get().into_stream();
-    while let Some(value) = stream.next().await {
-        let mut body = value
-            .map_err(|e| SPOError::new(&format!("Error: {:?}", e)))?
-            .data;
-        // For each response, we stream the body instead of collecting it all
-        // into one large allocation.
-        while let Some(value) = body.next().await {
-            let value = value.map_err(|e| SPOError::new(&format!("Error: {:?}", e)))?;
+    let mut get_blob = blob_client.get();
+    if let Some(versioning) = versioning {
+        get_blob = get_blob.blob_versioning(versioning);
+    }
+    if let Some(cpk_info) = encryption.cpk_info() {
+        get_blob = get_blob.encryption_key(cpk_info);
+    }
+    let mut guard = SourceGuard::acquire(
+        &blob_client,
+        options.consistency,
+        version.is_some(),
+        &properties,
+    )
+    .await?;
+    get_blob = guard.apply(get_blob);
+    let mut stream = get_blob.into_stream();
+    let transfer: Result<(), SPOError> = async {
+        while let Some(value) = stream.next().await {
+            let response = value.map_err(|e| guard.read_error(e))?;
+            guard.check_etag(response.blob.properties.etag.as_ref())?;
+            guard.check_lease()?;
+            if copy_result.version_id.is_none() && copy_result.snapshot.is_none() {
+                // head of a versioned blob
+                copy_result.version_id = response.blob.version_id.clone();
+            }
+            let mut body = response.data;
+            // For each response, we stream the body instead of collecting it all
+            // into one large allocation.
+            while let Some(value) = body.next().await {
+                let value = value.map_err(|e| SPOError::new(&format!("Error: {:?}", e)))?;
+                copy_result.size += value.len() as u64;
 
-            //debug!("Value len : {:?}", value.len());
-            chunk_buffer_size = chunk_buffer_size + value.len() as u64;
-            //
-            //  Check chunk buffer size
-            //
-            if chunk_buffer_size < MAX_CHUNK_SIZE as u64 {
-                result.extend(&value);
-                //spinner.update(format!("Downloading... {} bytes", chunk_buffer_size));
-                if let Some(callback) = callback {
-                    callback(
-                        ProcessStatus::Downloading,
-                        spinner.unwrap(),
-                        &String::from("Downloading"),
-                        &chunk_buffer_size,
-                    );
-                }
-            } else {
-                debug!("Next Chunk");
-                //Download completed
-                if let Some(callback) = callback {
-                    callback(
-                        ProcessStatus::DownloadComplete,
-                        spinner.unwrap(),
-                        &String::from("Download Complete"),
-                        &chunk_buffer_size,
-                    );
-                }
-                //upload for previous chunk
-                if !has_first_chunk {
-                    debug!("Upload First Chunk");
-                    //spinner.update(format!("Downloaded... {} bytes", chunk_buffer_size));
-                    //callback(ProcessStatus::Start, spinner, &String::from("Upload Start"));
+                //debug!("Value len : {:?}", value.len());
+                chunk_buffer_size += value.len() as u64;
+                //
+                //  Check chunk buffer size
+                //
+                if chunk_buffer_size < MAX_CHUNK_SIZE as u64 {
+                    result.extend(&value);
+                    //spinner.update(format!("Downloading... {} bytes", chunk_buffer_size));
                     if let Some(callback) = callback {
                         callback(
-                            ProcessStatus::StartUpload,
+                            ProcessStatus::Downloading,
                             spinner.unwrap(),
-                            &String::from("Upload Start"),
+                            &String::from("Downloading"),
                             &chunk_buffer_size,
                         );
                     }
-                    let r = spo_engine
-                        .upload_start(
-                            share_point_site,
-                            share_point_pah,
-                            blob_name,
-                            result.as_slice(),
-                        )
-                        .await;
-                    match r {
-                        Ok(_) => {
-                            debug!("Upload Chunk Success");
-                            if let Some(callback) = callback {
-                                callback(
-                                    ProcessStatus::UploadComplete,
-                                    spinner.unwrap(),
-                                    &String::from("Upload Complete[StartUpload]"),
-                                    &chunk_buffer_size,
-                                );
-                            }
-                            //spinner.update(format!("Updated {} bytes", chunk_buffer_size));
-                            //setup flag and resetup
-                            has_first_chunk = true;
-                            offset = offset + result.len() as u64;
-                            chunk_buffer_size = value.len() as u64; //reset
-                            result = vec![];
-                            result.extend(&value);
-                        }
-                        Err(e) => {
-                            return Err(e);
-                        }
-                    }
                 } else {
-                    //has first chunk already
-
+                    debug!("Next Chunk");
+                    //Download completed
                     if let Some(callback) = callback {
                         callback(
-                            ProcessStatus::ContinueUpload,
+                            ProcessStatus::DownloadComplete,
                             spinner.unwrap(),
-                            &String::from("Upload Continue"),
+                            &String::from("Download Complete"),
                             &chunk_buffer_size,
                         );
                     }
-
-                    let r = spo_engine.upload_continue(result.as_slice(), &offset).await;
-                    match r {
-                        Ok(_) => {
-                            //debug!("continue upload end point url: {:?}", end_point_url);
-                            debug!("Upload Chunk Success");
-                            if let Some(callback) = callback {
-                                callback(
-                                    ProcessStatus::UploadComplete,
-                                    spinner.unwrap(),
-                                    &String::from("Upload Complete[ContinueUpload]"),
-                                    &chunk_buffer_size,
-                                );
+                    //upload for previous chunk
+                    if !has_first_chunk {
+                        debug!("Upload First Chunk");
+                        //spinner.update(format!("Downloaded... {} bytes", chunk_buffer_size));
+                        //callback(ProcessStatus::Start, spinner, &String::from("Upload Start"));
+                        if let Some(callback) = callback {
+                            callback(
+                                ProcessStatus::StartUpload,
+                                spinner.unwrap(),
+                                &String::from("Upload Start"),
+                                &chunk_buffer_size,
+                            );
+                        }
+                        let r = spo_engine
+                            .upload_start(
+                                share_point_site,
+                                share_point_pah,
+                                file_name,
+                                result.as_slice(),
+                            )
+                            .await;
+                        match r {
+                            Ok(_) => {
+                                debug!("Upload Chunk Success");
+                                if let Some(callback) = callback {
+                                    callback(
+                                        ProcessStatus::UploadComplete,
+                                        spinner.unwrap(),
+                                        &String::from("Upload Complete[StartUpload]"),
+                                        &chunk_buffer_size,
+                                    );
+                                }
+                                //spinner.update(format!("Updated {} bytes", chunk_buffer_size));
+                                //setup flag and resetup
+                                has_first_chunk = true;
+                                offset += result.len() as u64;
+                                chunk_buffer_size = value.len() as u64; //reset
+                                result = vec![];
+                                result.extend(&value);
                             }
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    } else {
+                        //has first chunk already
 
-                            offset = offset + result.len() as u64;
-                            chunk_buffer_size = value.len() as u64; //reset
-                            result = vec![];
-                            result.extend(&value);
+                        if let Some(callback) = callback {
+                            callback(
+                                ProcessStatus::ContinueUpload,
+                                spinner.unwrap(),
+                                &String::from("Upload Continue"),
+                                &chunk_buffer_size,
+                            );
                         }
-                        Err(e) => {
-                            return Err(e);
+
+                        let r = spo_engine.upload_continue(result.as_slice(), &offset).await;
+                        match r {
+                            Ok(_) => {
+                                //debug!("continue upload end point url: {:?}", end_point_url);
+                                debug!("Upload Chunk Success");
+                                if let Some(callback) = callback {
+                                    callback(
+                                        ProcessStatus::UploadComplete,
+                                        spinner.unwrap(),
+                                        &String::from("Upload Complete[ContinueUpload]"),
+                                        &chunk_buffer_size,
+                                    );
+                                }
+
+                                offset += result.len() as u64;
+                                chunk_buffer_size = value.len() as u64; //reset
+                                result = vec![];
+                                result.extend(&value);
+                            }
+                            Err(e) => {
+                                return Err(e);
+                            }
                         }
                     }
                 }
             }
         }
-    }
-    if result.len() > 0 {
         if !has_first_chunk {
-            //simple upload
+            //simple upload, also for an empty blob
             debug!("Upload First Chunk");
             if let Some(callback) = callback {
                 callback(
@@ -201,9 +333,9 @@ pub async fn do_copy_file_to_spo(
             }
             let r = spo_engine
                 .upload_one_time(
-                    &String::from("MVP"),
-                    &String::from("/sites/MVP/Shared Documents"),
-                    &String::from(blob_name),
+                    share_point_site,
+                    share_point_pah,
+                    file_name,
                     result.as_slice(),
                 )
                 .await;
@@ -260,6 +392,136 @@ pub async fn do_copy_file_to_spo(
                 }
             }
         }
+        Ok(())
+    }
+    .await;
+    guard.release().await;
+    if let Err(e) = transfer {
+        // don't leave a half uploaded file behind
+        if has_first_chunk {
+            if let Err(cancel_error) = spo_engine.upload_cancel().await {
+                error!("Cancel upload error: {}", cancel_error);
+            }
+        }
+        return Err(e);
+    }
+    copy_result.spo_file_url = format!("{}/{}", share_point_pah, file_name);
+
+    let mut fields = options.metadata.clone();
+    if let Some(version_field) = &options.version_field {
+        let version = copy_result
+            .version_id
+            .clone()
+            .or(copy_result.snapshot.clone());
+        if let Some(version) = version {
+            fields.push((version_field.to_owned(), version));
+        }
+    }
+    if !fields.is_empty() {
+        spo_engine.set_file_metadata(&fields).await?;
+    }
+
+    if options.verify {
+        let spo_file = spo_engine.get_file().await?;
+        if spo_file.length != copy_result.size.to_string() {
+            return Err(SPOError::new(&format!(
+                "Verify {} failed: {} bytes uploaded, blob has {} bytes",
+                copy_result.spo_file_url, spo_file.length, copy_result.size
+            )));
+        }
+        copy_result.verified = true;
+    }
+    if let Some(post_action) = &options.post_action {
+        copy_result.post_action = Some(
+            post_action
+                .execute(source, &http_client, &guard.etag())
+                .await?,
+        );
+    }
+    Ok(copy_result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::blob::blob_source::fixture::{serve_blobs, BlobStandIn};
+    use crate::common::http_client::fixture::serve_https;
+    use crate::spo::spo_engine::fixture::{route, SharePointStandIn};
+
+    use super::*;
+
+    async fn copy(
+        blob_name: &str,
+        content: &[u8],
+        options: &CopyOptions,
+    ) -> (Result<CopyResult, SPOError>, Arc<SharePointStandIn>) {
+        let blobs = BlobStandIn::with_blob("reports", blob_name, content);
+        let source = serve_blobs(blobs, "reports", blob_name).await;
+        let share_point = Arc::new(SharePointStandIn::default());
+        let http_config = serve_https(route(share_point.clone())).await;
+        let result = do_copy_file_to_spo(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+            &String::from("contoso"),
+            &String::from("Finance"),
+            &String::from("/sites/Finance/Shared Documents"),
+            &source,
+            &http_config,
+            options,
+            None,
+            None,
+        )
+        .await;
+        (result, share_point)
+    }
+
+    #[tokio::test]
+    async fn copy_small_blob() {
+        let options = CopyOptions {
+            verify: true,
+            ..Default::default()
+        };
+        let (result, share_point) = copy("q1.csv", b"a,b\n1,2\n", &options).await;
+        let result = result.unwrap();
+        assert_eq!(result.size, 8);
+        assert!(result.verified);
+        assert_eq!(
+            result.spo_file_url,
+            "/sites/Finance/Shared Documents/q1.csv"
+        );
+        assert_eq!(
+            share_point.file("/sites/Finance/Shared Documents/q1.csv"),
+            Some(b"a,b\n1,2\n".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_empty_blob() {
+        let options = CopyOptions {
+            verify: true,
+            target_name: Some(String::from("empty.txt")),
+            metadata: vec![(String::from("Department"), String::from("Finance"))],
+            ..Default::default()
+        };
+        let (result, share_point) = copy("empty.bin", b"", &options).await;
+        let result = result.unwrap();
+        assert_eq!(result.size, 0);
+        assert!(result.verified);
+        assert_eq!(
+            result.spo_file_url,
+            "/sites/Finance/Shared Documents/empty.txt"
+        );
+        assert_eq!(
+            share_point.file("/sites/Finance/Shared Documents/empty.txt"),
+            Some(vec![])
+        );
+        assert!(share_point
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|r| r.ends_with("empty.txt')/ListItemAllFields/ValidateUpdateListItem()")));
     }
-    Ok(())
 }
@@ -0,0 +1,895 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Instant;
+
+use azure_storage_blobs::prelude::ContainerClient;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use spinner::SpinnerHandle;
+use time::OffsetDateTime;
+
+use crate::blob::blob2spo::{CopyOptions, CopySummary, ShowStatusFn};
+use crate::blob::blob_filter::BlobFilter;
+use crate::blob::blob_source::BlobSource;
+use crate::blob::blob_sync::{copy_blob_below_folder, folder_prefix, SPO_RECONNECT_SECS};
+use crate::common::avro::read_avro_container;
+use crate::common::datetime::parse_datetime;
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::common::state_file::{load_state_file, save_state_file};
+use crate::spo::spo_engine::{SPOEngine, SPOError};
+
+pub const CHANGE_FEED_CONTAINER: &str = "$blobchangefeed";
+pub const DEFAULT_CHANGE_FEED_CURSOR_FILE: &str = "spo_change_feed_cursor.json";
+const SEGMENTS_META: &str = "meta/segments.json";
+const SEGMENTS_PREFIX: &str = "idx/segments/";
+// A failing blob is retried on the next runs, it is given up after this many attempts
+const MAX_CHANGE_FEED_ATTEMPTS: u32 = 3;
+const BLOB_CREATED: &str = "BlobCreated";
+
+//
+//  Container and optional blob name prefix of the blobs to copy, container[/prefix]
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeFeedFilter {
+    pub container: String,
+    pub prefix: String,
+}
+
+impl FromStr for ChangeFeedFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/').unwrap_or((s, "")) {
+            ("", _) => Err(format!("expect container[/prefix], got {}", s)),
+            (container, prefix) => Ok(ChangeFeedFilter {
+                container: container.to_string(),
+                prefix: prefix.to_string(),
+            }),
+        }
+    }
+}
+
+impl ChangeFeedFilter {
+    //
+    //  Path below the Share Point folder when the blob matches
+    //
+    pub fn relative_path(&self, container: &str, blob_name: &str) -> Option<String> {
+        if container != self.container {
+            return None;
+        }
+        blob_name
+            .strip_prefix(&folder_prefix(&self.prefix))
+            .filter(|p| !p.is_empty())
+            .map(|p| p.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShardCursor {
+    /// Last read chunk file of the shard
+    pub chunk: String,
+    /// Number of events already read from the chunk
+    pub events: usize,
+}
+
+//
+//  Position in the change feed, the segment (meta.json path) being read and the position of each of its shards
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChangeFeedCursor {
+    pub account: String,
+    pub segment: Option<String>,
+    pub shards: BTreeMap<String, ShardCursor>,
+    /// Blobs of the read events whose copy failed, retried on the next run
+    #[serde(default)]
+    pub failed: Vec<FailedBlob>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FailedBlob {
+    pub container: String,
+    pub blob_name: String,
+    pub relative_path: String,
+    pub attempts: u32,
+}
+
+impl ChangeFeedCursor {
+    pub fn new(account: &String) -> ChangeFeedCursor {
+        ChangeFeedCursor {
+            account: account.to_owned(),
+            ..Default::default()
+        }
+    }
+    //
+    //  A missing cursor file starts at the beginning of the change feed (or the start time)
+    //
+    pub fn load(path: &Path, account: &String) -> Result<ChangeFeedCursor, SPOError> {
+        match load_state_file::<ChangeFeedCursor>(path)? {
+            Some(cursor) if cursor.account == *account => Ok(cursor),
+            Some(cursor) => Err(SPOError::new(&format!(
+                "Cursor {} belongs to account {}",
+                path.display(),
+                cursor.account
+            ))),
+            None => Ok(ChangeFeedCursor::new(account)),
+        }
+    }
+    pub fn save(&self, path: &Path) -> Result<(), SPOError> {
+        save_state_file(path, self)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChangeFeedEvent {
+    pub id: String,
+    pub event_type: String,
+    pub event_time: String,
+    pub container: String,
+    pub blob_name: String,
+    pub content_length: Option<u64>,
+    pub content_type: Option<String>,
+    pub etag: Option<String>,
+}
+
+impl ChangeFeedEvent {
+    //
+    //  Subject is /blobServices/default/containers/[container]/blobs/[blob name]
+    //
+    fn from_record(record: &Value) -> Option<ChangeFeedEvent> {
+        let subject = record["subject"].as_str()?;
+        let (container, blob_name) = subject
+            .strip_prefix("/blobServices/default/containers/")?
+            .split_once("/blobs/")?;
+        Some(ChangeFeedEvent {
+            id: record["id"].as_str().unwrap_or_default().to_string(),
+            event_type: record["eventType"].as_str().unwrap_or_default().to_string(),
+            event_time: record["eventTime"].as_str().unwrap_or_default().to_string(),
+            container: container.to_string(),
+            blob_name: blob_name.to_string(),
+            content_length: record["data"]["contentLength"].as_u64(),
+            content_type: record["data"]["contentType"]
+                .as_str()
+                .map(|c| c.to_string()),
+            etag: record["data"]["etag"].as_str().map(|e| e.to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SegmentsMeta {
+    last_consumable: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SegmentMeta {
+    /// $blobchangefeed/log/00/2019/02/22/1810/
+    chunk_file_paths: Vec<String>,
+}
+
+//
+//  Reads the change feed of the account, one chunk file (batch) at a time from the cursor
+//
+pub struct ChangeFeedReader {
+    container_client: ContainerClient,
+    cursor: ChangeFeedCursor,
+    segments: VecDeque<String>,
+    /// (shard, chunk file) of the segment being read, in reading order
+    chunks: Option<VecDeque<(String, String)>>,
+}
+
+impl ChangeFeedReader {
+    //
+    //  Consumable segments from the cursor segment, or from the segment of start_time without cursor
+    //
+    pub async fn open(
+        source: &BlobSource,
+        http_client: &Client,
+        cursor: ChangeFeedCursor,
+        start_time: &Option<OffsetDateTime>,
+    ) -> Result<ChangeFeedReader, SPOError> {
+        let mut change_feed = source.clone();
+        change_feed.container = CHANGE_FEED_CONTAINER.to_string();
+        let container_client = change_feed.container_client(http_client)?;
+
+        let segments_meta: SegmentsMeta =
+            read_json(&container_client, &String::from(SEGMENTS_META)).await?;
+        let last_consumable = parse_datetime(&segments_meta.last_consumable)?;
+        let mut segments: Vec<String> = list_blob_names(&container_client, SEGMENTS_PREFIX)
+            .await?
+            .into_iter()
+            .filter(|p| p.ends_with("/meta.json"))
+            .filter(|p| segment_time(p).is_some_and(|t| t <= last_consumable))
+            .collect();
+        segments.sort();
+        let segments = segments
+            .into_iter()
+            .filter(|p| match (&cursor.segment, start_time) {
+                (Some(segment), _) => p >= segment,
+                // the segment containing the start time, and the later ones
+                (None, Some(start)) => segment_time(p).is_some_and(|t| t + HOUR > *start),
+                (None, None) => true,
+            })
+            .collect();
+        debug!(
+            "Change feed consumable until {}, segments {:?}",
+            segments_meta.last_consumable, segments
+        );
+        Ok(ChangeFeedReader {
+            container_client,
+            cursor,
+            segments,
+            chunks: None,
+        })
+    }
+    //
+    //  Position after the last returned batch
+    //
+    pub fn cursor(&self) -> &ChangeFeedCursor {
+        &self.cursor
+    }
+    //
+    //  Unread events of the next chunk file, None at the end of the consumable change feed
+    //
+    pub async fn next_batch(&mut self) -> Result<Option<Vec<ChangeFeedEvent>>, SPOError> {
+        loop {
+            if self.chunks.is_none() {
+                match self.segments.pop_front() {
+                    Some(segment) => self.chunks = Some(self.open_segment(&segment).await?),
+                    None => return Ok(None),
+                }
+            }
+            let (shard, chunk) = match self.chunks.as_mut().and_then(|c| c.pop_front()) {
+                Some(c) => c,
+                None => {
+                    self.chunks = None;
+                    continue;
+                }
+            };
+            let read = match self.cursor.shards.get(&shard) {
+                Some(shard_cursor) if shard_cursor.chunk == chunk => shard_cursor.events,
+                _ => 0,
+            };
+            let content = get_blob_content(&self.container_client, &chunk).await?;
+            let records = read_avro_container(&content)?;
+            let total = records.len();
+            debug!("Chunk {} has {} events, {} read", chunk, total, read);
+            let events: Vec<ChangeFeedEvent> = records
+                .iter()
+                .skip(read)
+                .filter_map(|r| {
+                    let event = ChangeFeedEvent::from_record(r);
+                    if event.is_none() {
+                        warn!("Skip change feed record {}", r);
+                    }
+                    event
+                })
+                .collect();
+            self.cursor.shards.insert(
+                shard,
+                ShardCursor {
+                    chunk,
+                    events: total,
+                },
+            );
+            if total > read {
+                return Ok(Some(events));
+            }
+        }
+    }
+    //
+    //  Chunk files of every shard of the segment, after the cursor position
+    //
+    async fn open_segment(
+        &mut self,
+        segment: &String,
+    ) -> Result<VecDeque<(String, String)>, SPOError> {
+        if self.cursor.segment.as_ref() != Some(segment) {
+            self.cursor.segment = Some(segment.to_owned());
+            self.cursor.shards.clear();
+        }
+        let meta: SegmentMeta = read_json(&self.container_client, segment).await?;
+        let mut chunks: VecDeque<(String, String)> = VecDeque::new();
+        for shard_path in meta.chunk_file_paths {
+            let shard = shard_path
+                .trim_start_matches(CHANGE_FEED_CONTAINER)
+                .trim_start_matches('/')
+                .to_string();
+            let mut shard_chunks = list_blob_names(&self.container_client, &shard).await?;
+            shard_chunks.sort();
+            for chunk in shard_chunks {
+                let read_before = self
+                    .cursor
+                    .shards
+                    .get(&shard)
+                    .is_some_and(|c| chunk < c.chunk);
+                if !read_before {
+                    chunks.push_back((shard.to_owned(), chunk));
+                }
+            }
+        }
+        debug!("Segment {} has {} chunks to read", segment, chunks.len());
+        Ok(chunks)
+    }
+}
+
+const HOUR: time::Duration = time::Duration::hours(1);
+
+//
+//  idx/segments/2019/02/22/1800/meta.json is the segment of 2019-02-22T18:00:00Z
+//
+fn segment_time(path: &str) -> Option<OffsetDateTime> {
+    let parts: Vec<&str> = path.strip_prefix(SEGMENTS_PREFIX)?.split('/').collect();
+    match parts.as_slice() {
+        [year, month, day, hour_minute, _] if hour_minute.len() == 4 => parse_datetime(&format!(
+            "{}-{}-{}T{}:{}:00Z",
+            year,
+            month,
+            day,
+            &hour_minute[..2],
+            &hour_minute[2..]
+        ))
+        .ok(),
+        _ => None,
+    }
+}
+
+async fn list_blob_names(
+    container_client: &ContainerClient,
+    prefix: &str,
+) -> Result<Vec<String>, SPOError> {
+    let mut stream = container_client
+        .list_blobs()
+        .prefix(prefix.to_string())
+        .into_stream();
+    let mut names: Vec<String> = vec![];
+    while let Some(page) = stream.next().await {
+        let page = page.map_err(|e| SPOError::new(&format!("List change feed error: {}", e)))?;
+        names.extend(page.blobs.blobs().map(|b| b.name.to_owned()));
+    }
+    Ok(names)
+}
+
+async fn get_blob_content(
+    container_client: &ContainerClient,
+    blob_name: &String,
+) -> Result<Vec<u8>, SPOError> {
+    container_client
+        .blob_client(blob_name)
+        .get_content()
+        .await
+        .map_err(|e| SPOError::new(&format!("Read change feed {} error: {}", blob_name, e)))
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(
+    container_client: &ContainerClient,
+    blob_name: &String,
+) -> Result<T, SPOError> {
+    let content = get_blob_content(container_client, blob_name).await?;
+    serde_json::from_slice(&content)
+        .map_err(|e| SPOError::new(&format!("Invalid change feed {}: {}", blob_name, e)))
+}
+
+//
+//  Blobs created by the events and selected by the filters, keyed by container and blob name
+//  with the path below the Share Point folder. A blob created several times appears once
+//
+fn created_blobs(
+    events: &[ChangeFeedEvent],
+    filters: &[ChangeFeedFilter],
+    filter: &BlobFilter,
+) -> BTreeMap<(String, String), String> {
+    let mut blobs: BTreeMap<(String, String), String> = BTreeMap::new();
+    for event in events.iter().filter(|e| e.event_type == BLOB_CREATED) {
+        let relative_path = filters
+            .iter()
+            .find_map(|f| f.relative_path(&event.container, &event.blob_name));
+        let relative_path = match relative_path {
+            Some(p) => p,
+            None => continue,
+        };
+        // the event carries the blob size and type, its time is the last modification
+        let excluded = filter.excluded(
+            &relative_path,
+            event.content_length.unwrap_or_default(),
+            &parse_datetime(&event.event_time).unwrap_or(OffsetDateTime::UNIX_EPOCH),
+            event.content_type.as_deref().unwrap_or_default(),
+        );
+        match excluded {
+            Some(reason) => debug!(
+                "Blob {}/{} left out: {}",
+                event.container, event.blob_name, reason
+            ),
+            None => {
+                blobs.insert(
+                    (event.container.to_owned(), event.blob_name.to_owned()),
+                    relative_path,
+                );
+            }
+        }
+    }
+    blobs
+}
+
+//
+//  Copy the blobs created in the filtered containers / prefixes since the cursor,
+//  the failed copies of the previous run first. The cursor file is written after each batch
+//  with the failed copies, a blob is retried until MAX_CHANGE_FEED_ATTEMPTS
+//
+#[allow(clippy::too_many_arguments)]
+pub async fn do_copy_change_feed_to_spo(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    share_point_domain: &String,
+    share_point_site: &String,
+    share_point_path: &String,
+    source: &BlobSource,
+    filters: &[ChangeFeedFilter],
+    filter: &BlobFilter,
+    cursor_file: &Path,
+    start_time: &Option<OffsetDateTime>,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+    callback: Option<ShowStatusFn>,
+    spinner: Option<&SpinnerHandle>,
+) -> Result<CopySummary, SPOError> {
+    if filters.is_empty() {
+        return Err(SPOError::new(&String::from(
+            "at least one container filter is required",
+        )));
+    }
+    let http_client = http_config.build_client()?;
+    let mut cursor = ChangeFeedCursor::load(cursor_file, &source.account)?;
+    let mut failed: BTreeMap<(String, String), FailedBlob> = std::mem::take(&mut cursor.failed)
+        .into_iter()
+        .map(|f| ((f.container.to_owned(), f.blob_name.to_owned()), f))
+        .collect();
+    let mut reader = ChangeFeedReader::open(source, &http_client, cursor, start_time).await?;
+
+    let mut spo_engine = SPOEngine::new(
+        tenant_id,
+        client_id,
+        client_secret,
+        share_point_domain,
+        &http_client,
+    );
+    // Connected on the first blob to copy, and again when the token gets old
+    let mut connected: Option<Instant> = None;
+    let mut spo_folders: BTreeSet<String> = BTreeSet::new();
+    let mut summary = CopySummary::default();
+    let mut retries: Option<BTreeMap<(String, String), String>> = Some(
+        failed
+            .iter()
+            .map(|(key, f)| (key.to_owned(), f.relative_path.to_owned()))
+            .collect(),
+    );
+    loop {
+        let blobs = match retries.take() {
+            Some(blobs) => blobs,
+            None => match reader.next_batch().await? {
+                Some(events) => {
+                    let blobs = created_blobs(&events, filters, filter);
+                    debug!(
+                        "Batch of {} events, {} blobs to copy",
+                        events.len(),
+                        blobs.len()
+                    );
+                    blobs
+                }
+                None => break,
+            },
+        };
+        for ((container, blob_name), relative_path) in blobs {
+            if connected.is_none_or(|c| c.elapsed().as_secs() >= SPO_RECONNECT_SECS) {
+                spo_engine.connect(share_point_site).await?;
+                connected = Some(Instant::now());
+            }
+            let mut blob_source = source.clone();
+            blob_source.container = container.to_owned();
+            blob_source.set_blob_name(&blob_name);
+            let res = copy_blob_below_folder(
+                &spo_engine,
+                &mut spo_folders,
+                tenant_id,
+                client_id,
+                client_secret,
+                share_point_domain,
+                share_point_site,
+                share_point_path,
+                &blob_source,
+                &relative_path,
+                http_config,
+                options,
+                callback,
+                spinner,
+            )
+            .await;
+            let key = (container, blob_name);
+            match res {
+                Ok(r) => {
+                    info!("Copied {}/{}", key.0, key.1);
+                    failed.remove(&key);
+                    summary.copied.push(r);
+                }
+                Err(failure) => {
+                    let attempts = failed.get(&key).map(|f| f.attempts).unwrap_or_default() + 1;
+                    if attempts >= MAX_CHANGE_FEED_ATTEMPTS {
+                        error!(
+                            "Copy {}/{} failed {} times, skipped : {}",
+                            key.0, key.1, attempts, failure.error
+                        );
+                        failed.remove(&key);
+                    } else {
+                        failed.insert(
+                            key.clone(),
+                            FailedBlob {
+                                container: key.0,
+                                blob_name: key.1,
+                                relative_path,
+                                attempts,
+                            },
+                        );
+                    }
+                    summary.failed.push(failure);
+                }
+            }
+        }
+        let mut cursor = reader.cursor().clone();
+        cursor.failed = failed.values().cloned().collect();
+        cursor.save(cursor_file)?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use warp::http::Response;
+    use warp::Filter;
+
+    use crate::blob::blob_source::fixture::{serve_blobs, BlobStandIn};
+    use crate::blob::blob_source::BlobCredential;
+    use crate::common::avro::fixture::{container, long, string};
+    use crate::common::http_client::fixture::serve_https;
+    use crate::spo::spo_engine::fixture::{route, SharePointStandIn};
+
+    use super::*;
+
+    const EVENT_SCHEMA: &str = r#"{"type":"record","name":"BlobChangeEvent","namespace":"com.microsoft.azure.storage.blobchangefeed","fields":[
+        {"name":"schemaVersion","type":"int"},
+        {"name":"topic","type":"string"},
+        {"name":"subject","type":"string"},
+        {"name":"eventType","type":{"type":"enum","name":"BlobChangeEventType","symbols":["UnspecifiedEventType","BlobCreated","BlobDeleted"]}},
+        {"name":"eventTime","type":"string"},
+        {"name":"id","type":"string"},
+        {"name":"data","type":{"type":"record","name":"BlobChangeEventData","fields":[
+            {"name":"api","type":"string"},
+            {"name":"etag","type":"string"},
+            {"name":"contentLength","type":["null","long"]}
+        ]}}
+    ]}"#;
+
+    fn event(id: &str, event_type: i64, container: &str, blob_name: &str) -> Vec<u8> {
+        [
+            long(3),
+            string("/subscriptions/x/resourceGroups/y/providers/Microsoft.Storage/storageAccounts/account"),
+            string(&format!(
+                "/blobServices/default/containers/{}/blobs/{}",
+                container, blob_name
+            )),
+            long(event_type),
+            string("2024-01-01T10:00:00.0000000Z"),
+            string(id),
+            string("PutBlob"),
+            string("0x8D"),
+            long(1),
+            long(10),
+        ]
+        .concat()
+    }
+
+    //
+    //  $blobchangefeed stand-in, serves list blobs and get blob from the files
+    //
+    async fn serve_change_feed(files: HashMap<String, Vec<u8>>) -> String {
+        let files = Arc::new(files);
+        let route = warp::path::full()
+            .and(warp::query::<HashMap<String, String>>())
+            .map(move |path: warp::path::FullPath, query: HashMap<String, String>| {
+                let name = path
+                    .as_str()
+                    .trim_start_matches("/account/")
+                    .trim_start_matches(CHANGE_FEED_CONTAINER)
+                    .trim_start_matches("%24blobchangefeed")
+                    .trim_start_matches('/')
+                    .to_string();
+                let response = Response::builder()
+                    .header("x-ms-request-id", "00000000-0000-0000-0000-000000000000")
+                    .header("date", "Mon, 01 Jan 2024 10:00:00 GMT");
+                if query.get("comp").map(|c| c.as_str()) == Some("list") {
+                    let prefix = query.get("prefix").cloned().unwrap_or_default();
+                    let mut names: Vec<&String> =
+                        files.keys().filter(|n| n.starts_with(&prefix)).collect();
+                    names.sort();
+                    let blobs: String = names
+                        .iter()
+                        .map(|n| {
+                            format!(
+                                "<Blob><Name>{}</Name><Properties>\
+                                <Creation-Time>Mon, 01 Jan 2024 10:00:00 GMT</Creation-Time>\
+                                <Last-Modified>Mon, 01 Jan 2024 10:00:00 GMT</Last-Modified>\
+                                <Etag>0x1</Etag><Content-Length>{}</Content-Length>\
+                                <Content-Type>application/octet-stream</Content-Type>\
+                                <BlobType>AppendBlob</BlobType></Properties></Blob>",
+                                n,
+                                files[*n].len()
+                            )
+                        })
+                        .collect();
+                    return response
+                        .header("content-type", "application/xml")
+                        .body(format!(
+                            "<?xml version=\"1.0\" encoding=\"utf-8\"?><EnumerationResults><Blobs>{}</Blobs><NextMarker /></EnumerationResults>",
+                            blobs
+                        ).into_bytes())
+                        .unwrap();
+                }
+                match files.get(&name) {
+                    Some(content) => response
+                        .header("content-length", content.len())
+                        .header("last-modified", "Mon, 01 Jan 2024 10:00:00 GMT")
+                        .header("x-ms-creation-time", "Mon, 01 Jan 2024 10:00:00 GMT")
+                        .header("etag", "0x1")
+                        .header("x-ms-blob-type", "AppendBlob")
+                        .header("x-ms-server-encrypted", "true")
+                        .body(content.clone())
+                        .unwrap(),
+                    None => response.status(404).body(vec![]).unwrap(),
+                }
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral((Ipv4Addr::LOCALHOST, 0));
+        tokio::spawn(server);
+        format!("http://{}/account", addr)
+    }
+
+    fn fixture_files(last_consumable: &str) -> HashMap<String, Vec<u8>> {
+        let segment_meta = |hour: &str| {
+            json!({
+                "version": 0,
+                "begin": format!("2024-01-01T{}:00:00.000Z", hour),
+                "intervalSecs": 3600,
+                "status": "Finalized",
+                "chunkFilePaths": [format!("$blobchangefeed/log/00/2024/01/01/{}00/", hour)]
+            })
+            .to_string()
+            .into_bytes()
+        };
+        HashMap::from([
+            (
+                String::from("meta/segments.json"),
+                json!({ "version": 0, "lastConsumable": last_consumable })
+                    .to_string()
+                    .into_bytes(),
+            ),
+            (
+                String::from("idx/segments/2024/01/01/1000/meta.json"),
+                segment_meta("10"),
+            ),
+            (
+                String::from("idx/segments/2024/01/01/1100/meta.json"),
+                segment_meta("11"),
+            ),
+            (
+                String::from("log/00/2024/01/01/1000/00000.avro"),
+                container(
+                    EVENT_SCHEMA,
+                    &[
+                        event("1", 1, "inbox", "scans/a.pdf"),
+                        event("2", 2, "inbox", "scans/old.pdf"),
+                    ],
+                ),
+            ),
+            (
+                String::from("log/00/2024/01/01/1000/00001.avro"),
+                container(EVENT_SCHEMA, &[event("3", 1, "other", "b.pdf")]),
+            ),
+            (
+                String::from("log/00/2024/01/01/1100/00000.avro"),
+                container(EVENT_SCHEMA, &[event("4", 1, "inbox", "scans/2024/c.pdf")]),
+            ),
+        ])
+    }
+
+    fn change_feed_source(endpoint: &str) -> BlobSource {
+        BlobSource::new(&String::from("account"), &String::new(), &String::new()).set_credential(
+            BlobCredential::Sas {
+                endpoint: endpoint.to_string(),
+                token: Secret::new(&String::from("sv=2022-11-02&sig=fixture")),
+            },
+        )
+    }
+
+    async fn read_all(reader: &mut ChangeFeedReader) -> Vec<String> {
+        let mut ids = vec![];
+        while let Some(events) = reader.next_batch().await.unwrap() {
+            ids.extend(events.into_iter().map(|e| e.id));
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn read_change_feed_from_cursor() {
+        let endpoint = serve_change_feed(fixture_files("2024-01-01T10:00:00.000Z")).await;
+        let source = change_feed_source(&endpoint);
+        let client = Client::new();
+
+        // only the first segment is consumable
+        let cursor = ChangeFeedCursor::new(&source.account);
+        let mut reader = ChangeFeedReader::open(&source, &client, cursor, &None)
+            .await
+            .unwrap();
+        assert_eq!(read_all(&mut reader).await, vec!["1", "2", "3"]);
+        let cursor = reader.cursor().clone();
+        assert_eq!(
+            cursor.segment,
+            Some(String::from("idx/segments/2024/01/01/1000/meta.json"))
+        );
+
+        // nothing new from the cursor
+        let mut reader = ChangeFeedReader::open(&source, &client, cursor.clone(), &None)
+            .await
+            .unwrap();
+        assert!(read_all(&mut reader).await.is_empty());
+
+        // the next segment became consumable
+        let endpoint = serve_change_feed(fixture_files("2024-01-01T11:00:00.000Z")).await;
+        let source = change_feed_source(&endpoint);
+        let mut reader = ChangeFeedReader::open(&source, &client, cursor, &None)
+            .await
+            .unwrap();
+        let events = reader.next_batch().await.unwrap().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].container, "inbox");
+        assert_eq!(events[0].blob_name, "scans/2024/c.pdf");
+        assert_eq!(events[0].event_type, BLOB_CREATED);
+        assert_eq!(events[0].content_length, Some(10));
+        assert!(reader.next_batch().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn read_change_feed_from_start_time() {
+        let endpoint = serve_change_feed(fixture_files("2024-01-01T11:00:00.000Z")).await;
+        let source = change_feed_source(&endpoint);
+        let start_time = Some(parse_datetime("2024-01-01T11:30:00Z").unwrap());
+        let mut reader = ChangeFeedReader::open(
+            &source,
+            &Client::new(),
+            ChangeFeedCursor::new(&source.account),
+            &start_time,
+        )
+        .await
+        .unwrap();
+        assert_eq!(read_all(&mut reader).await, vec!["4"]);
+    }
+
+    #[test]
+    fn change_feed_filter() {
+        let filter: ChangeFeedFilter = "inbox/scans/".parse().unwrap();
+        assert_eq!(
+            filter.relative_path("inbox", "scans/2024/c.pdf"),
+            Some(String::from("2024/c.pdf"))
+        );
+        assert_eq!(filter.relative_path("inbox", "other/c.pdf"), None);
+        assert_eq!(filter.relative_path("other", "scans/c.pdf"), None);
+        let filter: ChangeFeedFilter = "inbox/scans".parse().unwrap();
+        assert_eq!(
+            filter.relative_path("inbox", "scans/c.pdf"),
+            Some(String::from("c.pdf"))
+        );
+        assert_eq!(filter.relative_path("inbox", "scans-old/c.pdf"), None);
+        let filter: ChangeFeedFilter = "inbox".parse().unwrap();
+        assert_eq!(
+            filter.relative_path("inbox", "a.pdf"),
+            Some(String::from("a.pdf"))
+        );
+        assert!("/scans".parse::<ChangeFeedFilter>().is_err());
+    }
+
+    async fn copy_change_feed(
+        source: &BlobSource,
+        http_config: &HttpConfig,
+        cursor_file: &Path,
+    ) -> CopySummary {
+        do_copy_change_feed_to_spo(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+            &String::from("contoso"),
+            &String::from("Finance"),
+            &String::from("/sites/Finance/Shared Documents"),
+            source,
+            &["inbox/scans".parse().unwrap()],
+            &BlobFilter::default(),
+            cursor_file,
+            &None,
+            http_config,
+            &CopyOptions::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_copies_are_retried_from_the_cursor() {
+        // scans/a.pdf is missing, its copy fails
+        let stand_in = Arc::new(BlobStandIn::default());
+        for (name, content) in fixture_files("2024-01-01T10:00:00.000Z") {
+            stand_in.insert(CHANGE_FEED_CONTAINER, &name, &content);
+        }
+        let source = serve_blobs(stand_in.clone(), "", "").await;
+        let share_point = Arc::new(SharePointStandIn::default());
+        let http_config = serve_https(route(share_point.clone())).await;
+        let cursor_file = std::env::temp_dir().join(format!(
+            "spo_change_feed_cursor_{}.json",
+            std::process::id()
+        ));
+        let failed_blob = |attempts: u32| FailedBlob {
+            container: String::from("inbox"),
+            blob_name: String::from("scans/a.pdf"),
+            relative_path: String::from("a.pdf"),
+            attempts,
+        };
+
+        let summary = copy_change_feed(&source, &http_config, &cursor_file).await;
+        assert!(summary.copied.is_empty());
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].blob_name, "scans/a.pdf");
+        let cursor = ChangeFeedCursor::load(&cursor_file, &source.account).unwrap();
+        assert!(cursor.segment.is_some());
+        assert_eq!(cursor.failed, vec![failed_blob(1)]);
+
+        // the cursor moved on, only the failed blob is tried again
+        let summary = copy_change_feed(&source, &http_config, &cursor_file).await;
+        assert_eq!(summary.failed.len(), 1);
+        let cursor = ChangeFeedCursor::load(&cursor_file, &source.account).unwrap();
+        assert_eq!(cursor.failed, vec![failed_blob(2)]);
+
+        stand_in.insert("inbox", "scans/a.pdf", b"a");
+        let summary = copy_change_feed(&source, &http_config, &cursor_file).await;
+        assert_eq!(summary.copied.len(), 1);
+        assert!(summary.failed.is_empty());
+        assert_eq!(
+            share_point.file("/sites/Finance/Shared Documents/a.pdf"),
+            Some(b"a".to_vec())
+        );
+        let cursor = ChangeFeedCursor::load(&cursor_file, &source.account).unwrap();
+        assert!(cursor.failed.is_empty());
+
+        // a blob failing MAX_CHANGE_FEED_ATTEMPTS times is given up
+        let mut cursor = cursor;
+        cursor.failed = vec![FailedBlob {
+            blob_name: String::from("scans/gone.pdf"),
+            relative_path: String::from("gone.pdf"),
+            ..failed_blob(MAX_CHANGE_FEED_ATTEMPTS - 1)
+        }];
+        cursor.save(&cursor_file).unwrap();
+        let summary = copy_change_feed(&source, &http_config, &cursor_file).await;
+        assert_eq!(summary.failed.len(), 1);
+        let cursor = ChangeFeedCursor::load(&cursor_file, &source.account).unwrap();
+        assert!(cursor.failed.is_empty());
+        std::fs::remove_file(&cursor_file).unwrap();
+    }
+}
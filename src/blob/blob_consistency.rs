@@ -0,0 +1,315 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use azure_core::prelude::{IfMatchCondition, LeaseDuration};
+use azure_storage_blobs::blob::operations::GetBlobBuilder;
+use azure_storage_blobs::blob::BlobProperties;
+use azure_storage_blobs::prelude::{BlobClient, BlobLeaseClient};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::spo::spo_engine::{SPOError, SPOErrorKind};
+
+pub const LEASE_DURATION_SECS: u8 = 60;
+pub const LEASE_RENEW_SECS: u64 = 30;
+
+//
+//  How the source blob is protected against concurrent writes while it is copied
+//
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceConsistency {
+    /// Pin the ETag, every range read is sent with If-Match
+    #[default]
+    ETag,
+    /// Hold a renewable lease during the copy, in addition to the ETag
+    Lease,
+    None,
+}
+
+impl FromStr for SourceConsistency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "etag" => Ok(SourceConsistency::ETag),
+            "lease" => Ok(SourceConsistency::Lease),
+            "none" => Ok(SourceConsistency::None),
+            _ => Err(format!("expect etag, lease or none, got {}", s)),
+        }
+    }
+}
+
+//
+//  ETag and lease of the source blob for the duration of a copy.
+//  The lease is renewed by a background task, so a slow upload between two range reads
+//  does not let it expire
+//
+pub struct SourceGuard {
+    blob_name: String,
+    consistency: SourceConsistency,
+    etag: Option<String>,
+    lease: Option<(BlobLeaseClient, JoinHandle<()>)>,
+    renew_error: Arc<Mutex<Option<String>>>,
+}
+
+impl SourceGuard {
+    //
+    //  Versions and snapshots are immutable, properties are None when the preflight was skipped
+    //
+    pub async fn acquire(
+        blob_client: &BlobClient,
+        consistency: SourceConsistency,
+        immutable: bool,
+        properties: &Option<BlobProperties>,
+    ) -> Result<SourceGuard, SPOError> {
+        SourceGuard::acquire_with_renewal(
+            blob_client,
+            consistency,
+            immutable,
+            properties,
+            Duration::from_secs(LEASE_RENEW_SECS),
+        )
+        .await
+    }
+    pub(crate) async fn acquire_with_renewal(
+        blob_client: &BlobClient,
+        consistency: SourceConsistency,
+        immutable: bool,
+        properties: &Option<BlobProperties>,
+        renew_interval: Duration,
+    ) -> Result<SourceGuard, SPOError> {
+        let consistency = if immutable {
+            SourceConsistency::None
+        } else {
+            consistency
+        };
+        let mut guard = SourceGuard {
+            blob_name: blob_client.blob_name().to_string(),
+            consistency,
+            etag: None,
+            lease: None,
+            renew_error: Arc::new(Mutex::new(None)),
+        };
+        if consistency == SourceConsistency::None {
+            return Ok(guard);
+        }
+        guard.etag = properties.as_ref().map(|p| p.etag.to_string());
+        if consistency == SourceConsistency::Lease {
+            let lease = blob_client
+                .acquire_lease(LeaseDuration::Seconds(LEASE_DURATION_SECS))
+                .await
+                .map_err(|e| SPOError::new(&format!("Acquire blob lease error: {}", e)))?;
+            debug!("Acquired lease on blob {}", guard.blob_name);
+            let lease_client = blob_client.blob_lease_client(lease.lease_id);
+            let renewal = renew_lease(
+                lease_client.clone(),
+                guard.blob_name.clone(),
+                renew_interval,
+                guard.renew_error.clone(),
+            );
+            guard.lease = Some((lease_client, tokio::spawn(renewal)));
+        }
+        Ok(guard)
+    }
+    //
+    //  ETag of the copied blob state, None when not guarded
+    //
+    pub fn etag(&self) -> Option<String> {
+        self.etag.clone()
+    }
+    pub fn apply(&self, mut get_blob: GetBlobBuilder) -> GetBlobBuilder {
+        if let Some(etag) = &self.etag {
+            get_blob = get_blob.if_match(IfMatchCondition::Match(etag.to_owned()));
+        }
+        if let Some((lease_client, _)) = &self.lease {
+            get_blob = get_blob.lease_id(lease_client.lease_id());
+        }
+        get_blob
+    }
+    //
+    //  Every range response must come from the same blob state as the first one
+    //
+    pub fn check_etag(&mut self, etag: &str) -> Result<(), SPOError> {
+        if self.consistency == SourceConsistency::None {
+            return Ok(());
+        }
+        match &self.etag {
+            Some(expected) if expected != etag => Err(self.source_changed()),
+            Some(_) => Ok(()),
+            None => {
+                self.etag = Some(etag.to_string());
+                Ok(())
+            }
+        }
+    }
+    //
+    //  Map a read error, a failed If-Match means the blob was overwritten
+    //
+    pub fn read_error(&self, e: azure_core::Error) -> SPOError {
+        if let Err(renew_error) = self.check_lease() {
+            return renew_error;
+        }
+        match e.as_http_error().map(|h| h.status() as u16) {
+            Some(412) => self.source_changed(),
+            _ => SPOError::new(&format!("Error: {:?}", e)),
+        }
+    }
+    //
+    //  Fails once the lease could not be renewed, the blob is no longer protected
+    //
+    pub fn check_lease(&self) -> Result<(), SPOError> {
+        match self.renew_error.lock().unwrap().as_ref() {
+            Some(e) => Err(SPOError::new(e)),
+            None => Ok(()),
+        }
+    }
+    pub async fn release(&mut self) {
+        if let Some((lease_client, renewal)) = self.lease.take() {
+            renewal.abort();
+            if let Err(e) = lease_client.release().await {
+                error!("Release lease on blob {} error: {}", self.blob_name, e);
+            }
+        }
+    }
+    fn source_changed(&self) -> SPOError {
+        SPOError::with_kind(
+            SPOErrorKind::SourceChanged,
+            &format!("Source blob {} changed during copy", self.blob_name),
+        )
+    }
+}
+
+impl Drop for SourceGuard {
+    //
+    //  Without release (ex. the copy was cancelled) the renewal stops and the lease expires
+    //
+    fn drop(&mut self) {
+        if let Some((_, renewal)) = &self.lease {
+            renewal.abort();
+        }
+    }
+}
+
+async fn renew_lease(
+    lease_client: BlobLeaseClient,
+    blob_name: String,
+    renew_interval: Duration,
+    renew_error: Arc<Mutex<Option<String>>>,
+) {
+    loop {
+        tokio::time::sleep(renew_interval).await;
+        match lease_client.renew().await {
+            Ok(_) => debug!("Renewed lease on blob {}", blob_name),
+            Err(e) => {
+                error!("Renew lease on blob {} error: {}", blob_name, e);
+                *renew_error.lock().unwrap() = Some(format!("Renew blob lease error: {}", e));
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use azure_storage_blobs::prelude::ClientBuilder;
+    use futures::StreamExt;
+
+    use crate::blob::blob_source::fixture::{serve_blobs, BlobStandIn};
+
+    use super::*;
+
+    //
+    //  Lease guarded blob of 3 range reads, the lease expires after 300 ms without renewal
+    //
+    async fn leased_blob() -> (Arc<BlobStandIn>, BlobClient, SourceGuard) {
+        let stand_in = BlobStandIn::with_blob("reports", "q1.csv", &[7u8; 30]);
+        *stand_in.lease_life.lock().unwrap() = Some(Duration::from_millis(300));
+        let source = serve_blobs(stand_in.clone(), "reports", "q1.csv").await;
+        let blob_client = source.blob_client(&reqwest::Client::new()).unwrap();
+        let guard = SourceGuard::acquire_with_renewal(
+            &blob_client,
+            SourceConsistency::Lease,
+            false,
+            &None,
+            Duration::from_millis(100),
+        )
+        .await
+        .unwrap();
+        (stand_in, blob_client, guard)
+    }
+
+    #[tokio::test]
+    async fn lease_outlives_a_slow_upload() {
+        let (stand_in, blob_client, mut guard) = leased_blob().await;
+        let mut stream = guard
+            .apply(blob_client.get().chunk_size(10u64))
+            .into_stream();
+        let mut size = 0;
+        while let Some(response) = stream.next().await {
+            let response = response.map_err(|e| guard.read_error(e)).unwrap();
+            guard.check_lease().unwrap();
+            size += response.data.collect().await.unwrap().len();
+            // the upload of the chunk takes longer than the lease life
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        assert_eq!(size, 30);
+        guard.release().await;
+        assert!(stand_in.leases.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lost_lease_is_not_source_changed() {
+        let (stand_in, blob_client, mut guard) = leased_blob().await;
+        // broken and taken by another client
+        stand_in.leases.lock().unwrap().insert(
+            String::from("reports/q1.csv"),
+            (
+                String::from("other"),
+                std::time::Instant::now() + Duration::from_secs(60),
+            ),
+        );
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let error = guard.check_lease().unwrap_err();
+        assert!(error.to_string().contains("Renew blob lease error"));
+
+        let mut stream = guard.apply(blob_client.get()).into_stream();
+        let error = guard.read_error(stream.next().await.unwrap().unwrap_err());
+        assert_eq!(error.kind(), SPOErrorKind::General);
+        assert!(error.to_string().contains("Renew blob lease error"));
+        guard.release().await;
+    }
+
+    #[tokio::test]
+    async fn etag_change_is_source_changed() {
+        let blob_client = ClientBuilder::emulator().blob_client("container", "file.txt");
+        let mut guard = SourceGuard::acquire(&blob_client, SourceConsistency::ETag, false, &None)
+            .await
+            .unwrap();
+
+        assert!(guard.check_etag("\"0x1\"").is_ok());
+        assert!(guard.check_etag("\"0x1\"").is_ok());
+        let e = guard.check_etag("\"0x2\"").unwrap_err();
+        assert_eq!(e.kind(), SPOErrorKind::SourceChanged);
+    }
+
+    #[tokio::test]
+    async fn immutable_source_is_not_guarded() {
+        let blob_client = ClientBuilder::emulator().blob_client("container", "file.txt");
+        let mut guard = SourceGuard::acquire(&blob_client, SourceConsistency::Lease, true, &None)
+            .await
+            .unwrap();
+
+        assert!(guard.check_etag("\"0x1\"").is_ok());
+        assert!(guard.check_etag("\"0x2\"").is_ok());
+    }
+
+    #[test]
+    fn parse_consistency() {
+        assert_eq!("ETag".parse(), Ok(SourceConsistency::ETag));
+        assert_eq!("lease".parse(), Ok(SourceConsistency::Lease));
+        assert!("other".parse::<SourceConsistency>().is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use azure_core::headers::{HeaderName, Headers};
+use azure_core::{Context, CustomHeaders};
+use azure_storage_blobs::blob::BlobProperties;
+use azure_storage_blobs::prelude::CPKInfo;
+
+use crate::common::secret::Secret;
+use crate::spo::spo_engine::SPOError;
+
+//
+//  Encryption settings of the source blob.
+//  The customer-provided key is sent on every read, the encryption scope is checked
+//  against the blob and used for blobs written back to the account.
+//
+#[derive(Debug, Clone, Default)]
+pub struct BlobEncryption {
+    /// Base64 AES-256 key
+    pub encryption_key: Option<Secret>,
+    /// Base64 SHA256 of the key
+    pub encryption_key_sha256: Option<String>,
+    pub encryption_scope: Option<String>,
+}
+
+impl BlobEncryption {
+    pub fn new(
+        encryption_key: Option<Secret>,
+        encryption_key_sha256: Option<String>,
+        encryption_scope: Option<String>,
+    ) -> Result<BlobEncryption, SPOError> {
+        if encryption_key.is_some() != encryption_key_sha256.is_some() {
+            return Err(SPOError::new(&String::from(
+                "Customer-provided key requires both the key and its SHA256",
+            )));
+        }
+        if encryption_key.is_some() && encryption_scope.is_some() {
+            return Err(SPOError::new(&String::from(
+                "Only one of customer-provided key or encryption scope can be used",
+            )));
+        }
+        Ok(BlobEncryption {
+            encryption_key,
+            encryption_key_sha256,
+            encryption_scope,
+        })
+    }
+    //
+    //  Key headers for the request, never keep the result around (its Debug shows the key)
+    //
+    pub fn cpk_info(&self) -> Option<CPKInfo> {
+        match (&self.encryption_key, &self.encryption_key_sha256) {
+            (Some(key), Some(key_sha256)) => {
+                Some((key.expose().to_owned(), key_sha256.to_owned()).into())
+            }
+            _ => None,
+        }
+    }
+    //
+    //  Key or scope headers as request context, for operations without an encryption option
+    //  in the sdk (copy blob). Empty without encryption
+    //
+    pub fn request_context(&self) -> Context {
+        let mut headers = Headers::new();
+        if let (Some(key), Some(key_sha256)) = (&self.encryption_key, &self.encryption_key_sha256) {
+            headers.insert(
+                HeaderName::from_static("x-ms-encryption-key"),
+                key.expose().to_owned(),
+            );
+            headers.insert(
+                HeaderName::from_static("x-ms-encryption-key-sha256"),
+                key_sha256.to_owned(),
+            );
+            headers.insert(
+                HeaderName::from_static("x-ms-encryption-algorithm"),
+                "AES256",
+            );
+        }
+        if let Some(scope) = &self.encryption_scope {
+            headers.insert(
+                HeaderName::from_static("x-ms-encryption-scope"),
+                scope.to_owned(),
+            );
+        }
+        let mut context = Context::new();
+        context.insert(CustomHeaders::from(headers));
+        context
+    }
+    //
+    //  Fail fast when the blob needs a key we don't have, or is encrypted differently
+    //
+    pub fn check_properties(
+        &self,
+        blob_name: &str,
+        properties: &BlobProperties,
+    ) -> Result<(), SPOError> {
+        match (
+            &properties.customer_provided_key_sha256,
+            &self.encryption_key_sha256,
+        ) {
+            (Some(_), None) => {
+                return Err(SPOError::new(&format!(
+                    "Blob {} is encrypted with a customer-provided key, the key is required",
+                    blob_name
+                )))
+            }
+            (Some(expected), Some(actual)) if expected != actual => {
+                return Err(SPOError::new(&format!(
+                    "Blob {} is encrypted with another customer-provided key",
+                    blob_name
+                )))
+            }
+            _ => {}
+        }
+        if let (Some(expected), Some(actual)) =
+            (&self.encryption_scope, &properties.encryption_scope)
+        {
+            if expected != actual {
+                return Err(SPOError::new(&format!(
+                    "Blob {} is encrypted with scope {}, expected {}",
+                    blob_name, actual, expected
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    #[test]
+    fn encryption_debug_does_not_leak_key() {
+        let encryption = BlobEncryption::new(
+            Some(Secret::new(&KEY.to_string())),
+            Some(String::from("sha256")),
+            None,
+        )
+        .unwrap();
+        assert!(!format!("{:#?}", encryption).contains(KEY));
+        assert!(encryption.cpk_info().is_some());
+    }
+
+    #[test]
+    fn encryption_requires_key_and_sha256() {
+        assert!(BlobEncryption::new(Some(Secret::new(&KEY.to_string())), None, None).is_err());
+        assert!(BlobEncryption::new(
+            Some(Secret::new(&KEY.to_string())),
+            Some(String::from("sha256")),
+            Some(String::from("scope"))
+        )
+        .is_err());
+        assert!(BlobEncryption::new(None, None, Some(String::from("scope")))
+            .unwrap()
+            .cpk_info()
+            .is_none());
+    }
+}
@@ -0,0 +1,272 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::blob::blob_source::BlobSource;
+use crate::common::datetime::{format_datetime, parse_datetime};
+use crate::spo::spo_engine::SPOError;
+
+//
+//  Which blobs of a listing are copied, as given in a request, a job or the command line.
+//  Patterns match the blob name below the prefix, * also matches /
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlobFilterSpec {
+    /// Copy only the blobs matching one of the patterns, all blobs when empty
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Never copy the blobs matching one of the patterns
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// RFC 3339
+    #[serde(default)]
+    pub modified_after: Option<String>,
+    /// RFC 3339
+    #[serde(default)]
+    pub modified_before: Option<String>,
+    /// ex. application/pdf or image/*
+    #[serde(default)]
+    pub content_types: Vec<String>,
+}
+
+impl BlobFilterSpec {
+    pub fn to_blob_filter(&self) -> Result<BlobFilter, SPOError> {
+        let modified_after = match &self.modified_after {
+            Some(t) => Some(parse_datetime(t)?),
+            None => None,
+        };
+        let modified_before = match &self.modified_before {
+            Some(t) => Some(parse_datetime(t)?),
+            None => None,
+        };
+        if let (Some(min), Some(max)) = (self.min_size, self.max_size) {
+            if min > max {
+                return Err(SPOError::new(&format!(
+                    "min size {} is above max size {}",
+                    min, max
+                )));
+            }
+        }
+        Ok(BlobFilter {
+            include: build_glob_set(&self.include)?,
+            exclude: build_glob_set(&self.exclude)?,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            modified_after,
+            modified_before,
+            content_types: self
+                .content_types
+                .iter()
+                .map(|c| c.trim().to_lowercase())
+                .collect(),
+        })
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<Option<GlobSet>, SPOError> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(
+            Glob::new(pattern)
+                .map_err(|e| SPOError::new(&format!("Invalid pattern {}: {}", pattern, e)))?,
+        );
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|e| SPOError::new(&format!("Invalid patterns: {}", e)))
+}
+
+//
+//  Compiled filter applied while listing the blobs, the default keeps every blob
+//
+#[derive(Debug, Clone, Default)]
+pub struct BlobFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<OffsetDateTime>,
+    modified_before: Option<OffsetDateTime>,
+    content_types: Vec<String>,
+}
+
+impl BlobFilter {
+    //
+    //  Size, time or content type filters, a listing without properties must read them
+    //
+    pub fn needs_properties(&self) -> bool {
+        self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.modified_after.is_some()
+            || self.modified_before.is_some()
+            || !self.content_types.is_empty()
+    }
+
+    //
+    //  Why the blob is left out, None when it is copied
+    //
+    pub fn excluded(
+        &self,
+        path: &str,
+        size: u64,
+        last_modified: &OffsetDateTime,
+        content_type: &str,
+    ) -> Option<String> {
+        if let Some(reason) = self.excluded_path(path) {
+            return Some(reason);
+        }
+        if self.min_size.is_some_and(|min| size < min) {
+            return Some(format!(
+                "smaller than {} bytes",
+                self.min_size.unwrap_or_default()
+            ));
+        }
+        if self.max_size.is_some_and(|max| size > max) {
+            return Some(format!(
+                "larger than {} bytes",
+                self.max_size.unwrap_or_default()
+            ));
+        }
+        if let Some(after) = self.modified_after.filter(|t| last_modified <= t) {
+            return Some(format!("modified before {}", format_datetime(&after)));
+        }
+        if let Some(before) = self.modified_before.filter(|t| last_modified >= t) {
+            return Some(format!("modified after {}", format_datetime(&before)));
+        }
+        if !self.content_types.is_empty() && !self.matches_content_type(content_type) {
+            return Some(format!("content type {}", content_type));
+        }
+        None
+    }
+
+    //
+    //  Include / exclude patterns only
+    //
+    pub fn excluded_path(&self, path: &str) -> Option<String> {
+        if self.include.as_ref().is_some_and(|g| !g.is_match(path)) {
+            return Some(String::from("not included"));
+        }
+        if self.exclude.as_ref().is_some_and(|g| g.is_match(path)) {
+            return Some(String::from("excluded"));
+        }
+        None
+    }
+
+    // text/plain; charset=utf-8 matches text/plain and text/*
+    fn matches_content_type(&self, content_type: &str) -> bool {
+        let content_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        self.content_types
+            .iter()
+            .any(|c| match c.strip_suffix("/*") {
+                Some(main_type) => content_type
+                    .split_once('/')
+                    .is_some_and(|(t, _)| t == main_type),
+                None => *c == content_type,
+            })
+    }
+
+    //
+    //  Check a blob found without its properties (ex. by tags), they are read only when needed
+    //
+    pub async fn excluded_blob(
+        &self,
+        source: &BlobSource,
+        http_client: &Client,
+        path: &str,
+    ) -> Result<Option<String>, SPOError> {
+        if let Some(reason) = self.excluded_path(path) {
+            return Ok(Some(reason));
+        }
+        if !self.needs_properties() {
+            return Ok(None);
+        }
+        let blob = source
+            .blob_client(http_client)?
+            .get_properties()
+            .await
+            .map_err(|e| SPOError::new(&format!("Get blob properties error: {}", e)))?
+            .blob;
+        Ok(self.excluded(
+            path,
+            blob.properties.content_length,
+            &blob.properties.last_modified,
+            &blob.properties.content_type,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_by_pattern_and_attributes() {
+        let filter = BlobFilterSpec {
+            include: vec![String::from("*.pdf"), String::from("scans/**")],
+            exclude: vec![String::from("**/draft-*")],
+            min_size: Some(10),
+            max_size: Some(1000),
+            modified_after: Some(String::from("2024-01-01T00:00:00Z")),
+            modified_before: None,
+            content_types: vec![String::from("application/pdf"), String::from("image/*")],
+        }
+        .to_blob_filter()
+        .unwrap();
+        let time = parse_datetime("2024-03-01T10:00:00Z").unwrap();
+
+        assert_eq!(
+            filter.excluded("2024/a.pdf", 100, &time, "application/pdf"),
+            None
+        );
+        assert_eq!(
+            filter.excluded("scans/a.png", 100, &time, "image/png; q=1"),
+            None
+        );
+        assert_eq!(
+            filter.excluded("a.docx", 100, &time, "application/pdf"),
+            Some(String::from("not included"))
+        );
+        assert_eq!(
+            filter.excluded("2024/draft-a.pdf", 100, &time, "application/pdf"),
+            Some(String::from("excluded"))
+        );
+        assert!(filter
+            .excluded("a.pdf", 5, &time, "application/pdf")
+            .is_some());
+        assert!(filter
+            .excluded("a.pdf", 5000, &time, "application/pdf")
+            .is_some());
+        assert!(filter
+            .excluded(
+                "a.pdf",
+                100,
+                &parse_datetime("2023-12-31T10:00:00Z").unwrap(),
+                "application/pdf"
+            )
+            .is_some());
+        assert!(filter.excluded("a.pdf", 100, &time, "text/plain").is_some());
+
+        assert!(!BlobFilter::default().needs_properties());
+        assert_eq!(BlobFilter::default().excluded("a.txt", 0, &time, ""), None);
+        assert!(BlobFilterSpec {
+            include: vec![String::from("a[")],
+            ..Default::default()
+        }
+        .to_blob_filter()
+        .is_err());
+    }
+}
@@ -0,0 +1,596 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::blob::blob2spo::{CopyOptions, MAX_CHUNK_SIZE};
+use crate::blob::blob_source::BlobSource;
+use crate::blob::blob_sync::{
+    folder_prefix, list_source_blobs, list_spo_files, sync_action, SyncAction, SyncOptions,
+    SyncState,
+};
+use crate::blob::blob_tier::check_blob_online;
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::spo::spo_endpoint::SPOEndpoint;
+use crate::spo::spo_engine::{SPOEngine, SPOError, SPOErrorKind};
+use crate::spo::spo_model::SPOFile;
+use crate::spo::spo_path::join_path;
+
+// the upload session id is generated at upload time
+const UPLOAD_ID_PLACEHOLDER: &str = "{upload-id}";
+
+//
+//  What a run would do with a blob
+//
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanDecision {
+    /// New file in Share Point
+    Upload,
+    /// Replaces the existing Share Point file
+    Overwrite,
+    Skip,
+    /// Share Point file recycled (sync --delete)
+    Delete,
+    /// The upload would fail, ex. the Share Point folder is missing
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub container: String,
+    pub blob_name: String,
+    pub size: u64,
+    /// Upload requests, one request below the chunk size
+    pub chunks: u64,
+    /// First upload request, Files/add or StartUpload
+    pub upload_url: String,
+    pub spo_file_url: String,
+    pub decision: PlanDecision,
+    pub reason: String,
+}
+
+//
+//  Outcome of a dry run, nothing was uploaded
+//
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CopyPlan {
+    pub web_url: String,
+    pub folder: String,
+    pub folder_exists: bool,
+    /// A missing folder is created by the run (sync), else nothing is uploaded to it
+    #[serde(default)]
+    pub create_folder: bool,
+    pub entries: Vec<PlanEntry>,
+}
+
+impl CopyPlan {
+    pub fn to_table(&self) -> String {
+        let mut lines = vec![
+            format!("Site   : {}", self.web_url),
+            format!(
+                "Folder : {} ({})",
+                self.folder,
+                match (self.folder_exists, self.create_folder) {
+                    (true, _) => "exists",
+                    (false, true) => "missing, would be created",
+                    (false, false) => "missing",
+                }
+            ),
+            format!(
+                "{:<10} {:>14} {:>6}  {:<48} {}",
+                "DECISION", "SIZE", "CHUNKS", "SHARE POINT FILE", "REASON"
+            ),
+        ];
+        for e in &self.entries {
+            lines.push(format!(
+                "{:<10} {:>14} {:>6}  {:<48} {}",
+                format!("{:?}", e.decision).to_lowercase(),
+                e.size,
+                e.chunks,
+                e.spo_file_url,
+                e.reason
+            ));
+            if matches!(e.decision, PlanDecision::Upload | PlanDecision::Overwrite) {
+                lines.push(format!("{:>34}{}", "", e.upload_url));
+            }
+        }
+        let count = |d: PlanDecision| self.entries.iter().filter(|e| e.decision == d).count();
+        lines.push(format!(
+            "{} upload, {} overwrite, {} skip, {} delete, {} fail, {} bytes",
+            count(PlanDecision::Upload),
+            count(PlanDecision::Overwrite),
+            count(PlanDecision::Skip),
+            count(PlanDecision::Delete),
+            count(PlanDecision::Fail),
+            self.entries
+                .iter()
+                .filter(|e| matches!(e.decision, PlanDecision::Upload | PlanDecision::Overwrite))
+                .map(|e| e.size)
+                .sum::<u64>()
+        ));
+        lines.join("\n")
+    }
+}
+
+//
+//  Upload requests of a file, the last chunk goes with FinishUpload
+//
+pub fn chunk_count(size: u64) -> u64 {
+    size / MAX_CHUNK_SIZE as u64 + 1
+}
+
+//
+//  A copy doesn't upload to a missing folder, only its sub folders are created
+//
+fn check_folder(folder_exists: bool, decision: PlanDecision, reason: &str) -> (PlanDecision, &str) {
+    match decision {
+        PlanDecision::Upload | PlanDecision::Overwrite if !folder_exists => {
+            (PlanDecision::Fail, "folder missing")
+        }
+        _ => (decision, reason),
+    }
+}
+
+fn plan_entry(
+    end_point: &SPOEndpoint,
+    container: &String,
+    blob_name: &String,
+    size: u64,
+    decision: PlanDecision,
+    reason: &str,
+) -> PlanEntry {
+    let chunks = chunk_count(size);
+    let upload_url = match chunks {
+        1 => end_point.to_file_one_time_upload_endpoint(),
+        _ => end_point
+            .clone()
+            .set_uuid(&String::from(UPLOAD_ID_PLACEHOLDER))
+            .to_file_start_upload_endpoint(),
+    };
+    PlanEntry {
+        container: container.to_owned(),
+        blob_name: blob_name.to_owned(),
+        size,
+        chunks,
+        upload_url,
+        spo_file_url: end_point.to_server_relative_file_url(),
+        decision,
+        reason: reason.to_string(),
+    }
+}
+
+//
+//  Authenticate and check the site is reachable, then check the destination folder
+//
+async fn connect_destination(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    share_point_domain: &String,
+    share_point_site: &String,
+    share_point_path: &String,
+    http_config: &HttpConfig,
+) -> Result<(SPOEngine, CopyPlan), SPOError> {
+    let http_client = http_config.build_client()?;
+    let mut spo_engine = SPOEngine::new(
+        tenant_id,
+        client_id,
+        client_secret,
+        share_point_domain,
+        &http_client,
+    );
+    spo_engine.connect(share_point_site).await?;
+    let plan = CopyPlan {
+        web_url: spo_engine.web_url(),
+        folder: share_point_path.to_owned(),
+        folder_exists: spo_engine.get_folder(share_point_path).await.is_ok(),
+        create_folder: false,
+        entries: vec![],
+    };
+    Ok((spo_engine, plan))
+}
+
+//
+//  Dry run of do_copy_file_to_spo: blob size, destination and overwrite decision
+//
+#[allow(clippy::too_many_arguments)]
+pub async fn plan_copy_file_to_spo(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    share_point_domain: &String,
+    share_point_site: &String,
+    share_point_path: &String,
+    source: &BlobSource,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+) -> Result<CopyPlan, SPOError> {
+    let http_client = http_config.build_client()?;
+    let blob_client = source.blob_client(&http_client)?;
+    let versioning = source
+        .resolve_version(&http_client)
+        .await?
+        .and_then(|v| v.to_blob_versioning());
+    let properties = check_blob_online(&blob_client, &versioning, &source.encryption, &None).await;
+
+    let (spo_engine, mut plan) = connect_destination(
+        tenant_id,
+        client_id,
+        client_secret,
+        share_point_domain,
+        share_point_site,
+        share_point_path,
+        http_config,
+    )
+    .await?;
+    let file_name = options.target_name.as_ref().unwrap_or(&source.blob_name);
+    let file_exists = plan.folder_exists
+        && spo_engine
+            .get_file_at(share_point_path, file_name)
+            .await
+            .is_ok();
+    let end_point = SPOEndpoint::new(share_point_domain)
+        .set_site(share_point_site)
+        .set_web_url(&plan.web_url)
+        .set_path(share_point_path)
+        .set_file_name(file_name);
+    let (decision, reason) = match file_exists {
+        true => (PlanDecision::Overwrite, "file exists"),
+        false => (PlanDecision::Upload, ""),
+    };
+    let (size, decision, reason) = match properties {
+        Ok(Some(p)) => (p.content_length, decision, reason),
+        // customer-provided key, the size is known at download
+        Ok(None) => (0, decision, "size unknown (encrypted)"),
+        Err(e) if e.kind() == SPOErrorKind::BlobArchived => {
+            (0, PlanDecision::Skip, "blob archived")
+        }
+        Err(e) => return Err(e),
+    };
+    let (decision, reason) = check_folder(plan.folder_exists, decision, reason);
+    plan.entries.push(plan_entry(
+        &end_point,
+        &source.container,
+        &source.blob_name,
+        size,
+        decision,
+        reason,
+    ));
+    Ok(plan)
+}
+
+//
+//  Dry run of do_sync_prefix_to_spo, the sync state is read but not written
+//
+#[allow(clippy::too_many_arguments)]
+pub async fn plan_sync_prefix_to_spo(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    share_point_domain: &String,
+    share_point_site: &String,
+    share_point_path: &String,
+    source: &BlobSource,
+    http_config: &HttpConfig,
+    sync_options: &SyncOptions,
+) -> Result<CopyPlan, SPOError> {
+    if source.container.is_empty() {
+        return Err(SPOError::new(&String::from("container is required")));
+    }
+    let http_client = http_config.build_client()?;
+    let state = SyncState::load(
+        &sync_options.state_file,
+        &format!(
+            "{}/{}/{}",
+            source.account, source.container, sync_options.prefix
+        ),
+        &format!(
+            "{}/{}/{}",
+            share_point_domain, share_point_site, share_point_path
+        ),
+    )?;
+    let (blobs, excluded) = list_source_blobs(
+        source,
+        &http_client,
+        &sync_options.prefix,
+        &sync_options.filter,
+    )
+    .await?;
+    let (spo_engine, mut plan) = connect_destination(
+        tenant_id,
+        client_id,
+        client_secret,
+        share_point_domain,
+        share_point_site,
+        share_point_path,
+        http_config,
+    )
+    .await?;
+    // the sync creates a missing folder, it has no files
+    plan.create_folder = true;
+    let mut files: BTreeMap<String, SPOFile> = BTreeMap::new();
+    if plan.folder_exists {
+        let mut folders: BTreeSet<String> = BTreeSet::new();
+        list_spo_files(&spo_engine, share_point_path, &mut files, &mut folders).await?;
+    }
+
+    for blob in &blobs {
+        let spo_file = files.get(&blob.relative_path);
+        let (decision, reason) =
+            match sync_action(blob, state.files.get(&blob.relative_path), spo_file) {
+                SyncAction::Skip => (PlanDecision::Skip, "unchanged"),
+                SyncAction::Adopt => (PlanDecision::Skip, "same file in Share Point"),
+                SyncAction::Add => (PlanDecision::Upload, ""),
+                SyncAction::Update => (PlanDecision::Overwrite, "blob changed"),
+            };
+        let (relative_folder, file_name) = blob
+            .relative_path
+            .rsplit_once('/')
+            .unwrap_or(("", &blob.relative_path));
+        let end_point = SPOEndpoint::new(share_point_domain)
+            .set_site(share_point_site)
+            .set_web_url(&plan.web_url)
+            .set_path(&join_path(share_point_path, relative_folder))
+            .set_file_name(&file_name.to_string());
+        plan.entries.push(plan_entry(
+            &end_point,
+            &source.container,
+            &blob.name,
+            blob.size,
+            decision,
+            reason,
+        ));
+    }
+
+    for (blob, reason) in &excluded {
+        let (relative_folder, file_name) = blob
+            .relative_path
+            .rsplit_once('/')
+            .unwrap_or(("", &blob.relative_path));
+        let end_point = SPOEndpoint::new(share_point_domain)
+            .set_site(share_point_site)
+            .set_web_url(&plan.web_url)
+            .set_path(&join_path(share_point_path, relative_folder))
+            .set_file_name(&file_name.to_string());
+        plan.entries.push(plan_entry(
+            &end_point,
+            &source.container,
+            &blob.name,
+            blob.size,
+            PlanDecision::Skip,
+            &format!("filter: {}", reason),
+        ));
+    }
+
+    if sync_options.delete {
+        // the files of excluded blobs are kept
+        let blob_paths: BTreeSet<&String> = blobs
+            .iter()
+            .chain(excluded.iter().map(|(b, _)| b))
+            .map(|b| &b.relative_path)
+            .collect();
+        for relative_path in state.files.keys().filter(|p| !blob_paths.contains(p)) {
+            if !files.contains_key(relative_path) {
+                continue;
+            }
+            let (relative_folder, file_name) = relative_path
+                .rsplit_once('/')
+                .unwrap_or(("", relative_path));
+            let end_point = SPOEndpoint::new(share_point_domain)
+                .set_site(share_point_site)
+                .set_web_url(&plan.web_url)
+                .set_path(&join_path(share_point_path, relative_folder))
+                .set_file_name(&file_name.to_string());
+            let mut entry = plan_entry(
+                &end_point,
+                &source.container,
+                &format!("{}{}", folder_prefix(&sync_options.prefix), relative_path),
+                0,
+                PlanDecision::Delete,
+                "blob deleted",
+            );
+            entry.upload_url = end_point.to_file_recycle_endpoint();
+            plan.entries.push(entry);
+        }
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::blob::blob_filter::BlobFilter;
+    use crate::blob::blob_source::fixture::{serve_blobs, BlobStandIn};
+    use crate::blob::blob_sync::SyncStateEntry;
+    use crate::common::http_client::fixture::serve_https;
+    use crate::spo::spo_engine::fixture::{route, SharePointStandIn};
+
+    async fn plan_sync(
+        source: &BlobSource,
+        http_config: &HttpConfig,
+        share_point_path: &str,
+        state_file: &PathBuf,
+    ) -> CopyPlan {
+        plan_sync_prefix_to_spo(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+            &String::from("contoso"),
+            &String::from("Finance"),
+            &share_point_path.to_string(),
+            source,
+            http_config,
+            &SyncOptions {
+                prefix: String::from("reports"),
+                delete: true,
+                state_file: state_file.to_owned(),
+                filter: BlobFilter::default(),
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    fn decisions(plan: &CopyPlan) -> Vec<(&str, PlanDecision, &str)> {
+        plan.entries
+            .iter()
+            .map(|e| (e.blob_name.as_str(), e.decision, e.reason.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn plan_entry_urls_and_chunks() {
+        let end_point = SPOEndpoint::new(&String::from("contoso"))
+            .set_site(&String::from("MVP"))
+            .set_path(&String::from("/sites/MVP/Shared Documents"))
+            .set_file_name(&String::from("a.pdf"));
+        let entry = plan_entry(
+            &end_point,
+            &String::from("reports"),
+            &String::from("a.pdf"),
+            10,
+            PlanDecision::Upload,
+            "",
+        );
+        assert_eq!(entry.chunks, 1);
+        assert_eq!(
+            entry.upload_url,
+            end_point.to_file_one_time_upload_endpoint()
+        );
+        assert_eq!(entry.spo_file_url, "/sites/MVP/Shared Documents/a.pdf");
+
+        let size = MAX_CHUNK_SIZE as u64 * 2 + 1;
+        let entry = plan_entry(
+            &end_point,
+            &String::from("reports"),
+            &String::from("a.pdf"),
+            size,
+            PlanDecision::Overwrite,
+            "file exists",
+        );
+        assert_eq!(entry.chunks, 3);
+        assert!(entry
+            .upload_url
+            .ends_with("/StartUpload(uploadId=guid'{upload-id}')"));
+
+        let plan = CopyPlan {
+            web_url: end_point.to_spo_web_url(),
+            folder: String::from("/sites/MVP/Shared Documents"),
+            folder_exists: true,
+            create_folder: false,
+            entries: vec![entry],
+        };
+        assert!(plan.to_table().ends_with(&format!(
+            "0 upload, 1 overwrite, 0 skip, 0 delete, 0 fail, {} bytes",
+            size
+        )));
+    }
+
+    #[tokio::test]
+    async fn sync_plan_decisions() {
+        let stand_in = BlobStandIn::with_blob("c", "reports/a.pdf", b"a");
+        stand_in.insert("c", "reports/b.pdf", b"b");
+        stand_in.insert("c", "reports/new.pdf", b"new");
+        let source = serve_blobs(stand_in, "c", "").await;
+        let share_point = Arc::new(SharePointStandIn::default());
+        for name in ["a.pdf", "b.pdf", "gone.pdf"] {
+            share_point.files.lock().unwrap().insert(
+                format!("/sites/Finance/Shared Documents/{}", name),
+                name.as_bytes()[..1].to_vec(),
+            );
+        }
+        let http_config = serve_https(route(share_point.clone())).await;
+        let state_file =
+            std::env::temp_dir().join(format!("spo_plan_state_{}.json", std::process::id()));
+        let mut state = SyncState::new(
+            &format!("{}/c/reports", source.account),
+            &String::from("contoso/Finance//sites/Finance/Shared Documents"),
+        );
+        let synced = |etag: &str| SyncStateEntry {
+            etag: etag.to_string(),
+            size: 1,
+            spo_time_last_modified: Some(String::from("2024-01-01T10:00:00Z")),
+        };
+        state.files.insert(String::from("a.pdf"), synced("0x1"));
+        // the blob was overwritten since the last sync
+        state.files.insert(String::from("b.pdf"), synced("0x0"));
+        // the blob was deleted since the last sync
+        state.files.insert(String::from("gone.pdf"), synced("0x1"));
+        state.save(&state_file).unwrap();
+
+        let plan = plan_sync(
+            &source,
+            &http_config,
+            "/sites/Finance/Shared Documents",
+            &state_file,
+        )
+        .await;
+        assert!(plan.folder_exists);
+        assert_eq!(
+            decisions(&plan),
+            vec![
+                ("reports/a.pdf", PlanDecision::Skip, "unchanged"),
+                ("reports/b.pdf", PlanDecision::Overwrite, "blob changed"),
+                ("reports/new.pdf", PlanDecision::Upload, ""),
+                ("reports/gone.pdf", PlanDecision::Delete, "blob deleted"),
+            ]
+        );
+        // a dry run changes nothing
+        assert_eq!(
+            share_point.file("/sites/Finance/Shared Documents/gone.pdf"),
+            Some(b"g".to_vec())
+        );
+        assert!(share_point
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|r| r.starts_with("GET ") || r.ends_with("/_api/ContextInfo")));
+
+        // the sync creates a missing folder and uploads every blob to it
+        let plan = plan_sync(&source, &http_config, "/sites/Finance/Missing", &state_file).await;
+        assert!(!plan.folder_exists);
+        assert_eq!(
+            decisions(&plan),
+            vec![
+                ("reports/a.pdf", PlanDecision::Upload, ""),
+                ("reports/b.pdf", PlanDecision::Upload, ""),
+                ("reports/new.pdf", PlanDecision::Upload, ""),
+            ]
+        );
+        let table = plan.to_table();
+        assert!(table.contains("/sites/Finance/Missing (missing, would be created)"));
+        assert!(table.ends_with("3 upload, 0 overwrite, 0 skip, 0 delete, 0 fail, 5 bytes"));
+        std::fs::remove_file(&state_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn copy_plan_fails_in_a_missing_folder() {
+        let source = serve_blobs(BlobStandIn::with_blob("c", "a.pdf", b"a"), "c", "a.pdf").await;
+        let share_point = Arc::new(SharePointStandIn::default());
+        let http_config = serve_https(route(share_point)).await;
+        let plan = plan_copy_file_to_spo(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+            &String::from("contoso"),
+            &String::from("Finance"),
+            &String::from("/sites/Finance/Missing"),
+            &source,
+            &http_config,
+            &CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(!plan.folder_exists);
+        assert_eq!(
+            decisions(&plan),
+            vec![("a.pdf", PlanDecision::Fail, "folder missing")]
+        );
+        assert!(plan.to_table().contains("/sites/Finance/Missing (missing)"));
+    }
+}
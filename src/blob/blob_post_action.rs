@@ -0,0 +1,362 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use azure_core::prelude::{IfMatchCondition, IfSourceMatchCondition};
+use azure_storage_blobs::blob::CopyStatus;
+use azure_storage_blobs::prelude::{AccessTier, BlobClient};
+use log::{debug, info};
+use reqwest::Client;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::blob::blob_source::{BlobCredential, BlobSource};
+use crate::spo::spo_engine::SPOError;
+
+const COPY_POLL_SECS: u64 = 2;
+
+//
+//  What to do with the source blob once it is copied (and verified)
+//
+#[derive(Debug, Clone, PartialEq)]
+pub enum PostCopyAction {
+    Delete,
+    /// Copy to container/prefix + blob name, then delete the source
+    Move {
+        container: String,
+        prefix: String,
+    },
+    SetTier(AccessTier),
+    SetMetadata {
+        name: String,
+        value: String,
+    },
+}
+
+impl FromStr for PostCopyAction {
+    type Err = String;
+
+    //
+    //  delete | move:<container>[/<prefix>] | tier:<Hot|Cool|Archive> | metadata:<name>=<value>
+    //
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (action, argument) = s.split_once(':').unwrap_or((s, ""));
+        match (action.to_lowercase().as_str(), argument) {
+            ("delete", "") => Ok(PostCopyAction::Delete),
+            ("move", target) if !target.is_empty() => {
+                let (container, prefix) = target.split_once('/').unwrap_or((target, ""));
+                Ok(PostCopyAction::Move {
+                    container: container.to_string(),
+                    prefix: prefix.to_string(),
+                })
+            }
+            ("tier", tier) => tier
+                .parse::<AccessTier>()
+                .map(PostCopyAction::SetTier)
+                .map_err(|_| format!("expect tier:Hot, tier:Cool or tier:Archive, got {}", s)),
+            ("metadata", marker) => match marker.split_once('=') {
+                Some((name, value)) if !name.is_empty() => Ok(PostCopyAction::SetMetadata {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                }),
+                _ => Err(format!("expect metadata:<name>=<value>, got {}", s)),
+            },
+            _ => Err(format!(
+                "expect delete, move:<container>[/<prefix>], tier:<tier> or metadata:<name>=<value>, got {}",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for PostCopyAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PostCopyAction::Delete => write!(f, "delete"),
+            PostCopyAction::Move { container, prefix } if prefix.is_empty() => {
+                write!(f, "move:{}", container)
+            }
+            PostCopyAction::Move { container, prefix } => {
+                write!(f, "move:{}/{}", container, prefix)
+            }
+            PostCopyAction::SetTier(tier) => write!(f, "tier:{}", tier),
+            PostCopyAction::SetMetadata { name, value } => write!(f, "metadata:{}={}", name, value),
+        }
+    }
+}
+
+impl Serialize for PostCopyAction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PostCopyAction {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl PostCopyAction {
+    //
+    //  A move writes to another container with the credential of the source,
+    //  a SAS must be an account SAS to reach it (a blob or container SAS is scoped to the source)
+    //
+    //
+    //  The source blob is gone after the action
+    //
+    pub fn removes_source(&self) -> bool {
+        matches!(self, PostCopyAction::Delete | PostCopyAction::Move { .. })
+    }
+    pub fn validate(&self, source: &BlobSource) -> Result<(), SPOError> {
+        if let (PostCopyAction::Move { .. }, BlobCredential::Sas { token, .. }) =
+            (self, &source.credential)
+        {
+            let account_sas = token
+                .expose()
+                .trim_start_matches('?')
+                .split('&')
+                .any(|p| p.starts_with("srt="));
+            if !account_sas {
+                return Err(SPOError::new(&String::from(
+                    "Move needs an account SAS (or another credential of the account), the SAS of the source blob can't write the target",
+                )));
+            }
+        }
+        Ok(())
+    }
+    //
+    //  Run the action on the source blob, etag is the copied state of the blob.
+    //  Returns a description of what was done.
+    //
+    pub async fn execute(
+        &self,
+        source: &BlobSource,
+        http_client: &Client,
+        etag: &Option<String>,
+    ) -> Result<String, SPOError> {
+        self.validate(source)?;
+        let blob_client = source.blob_client(http_client)?;
+        match self {
+            PostCopyAction::Delete => {
+                delete_blob(&blob_client, etag).await?;
+                Ok(format!("deleted {}/{}", source.container, source.blob_name))
+            }
+            PostCopyAction::Move { container, prefix } => {
+                let mut target = source.clone();
+                target.container = container.to_owned();
+                target.blob_name = format!("{}{}", prefix, source.blob_name);
+                copy_blob(source, &blob_client, &target, http_client, etag).await?;
+                delete_blob(&blob_client, etag).await?;
+                Ok(format!(
+                    "moved to {}/{}",
+                    target.container, target.blob_name
+                ))
+            }
+            PostCopyAction::SetTier(tier) => {
+                blob_client
+                    .set_blob_tier(*tier)
+                    .await
+                    .map_err(|e| SPOError::new(&format!("Set blob tier error: {}", e)))?;
+                Ok(format!("tier set to {}", tier))
+            }
+            PostCopyAction::SetMetadata { name, value } => {
+                let mut metadata = blob_client
+                    .get_metadata()
+                    .await
+                    .map_err(|e| SPOError::new(&format!("Get blob metadata error: {}", e)))?
+                    .metadata;
+                metadata.insert(name.to_owned(), value.to_owned());
+                let mut set_metadata = blob_client.set_metadata().metadata(metadata);
+                if let Some(etag) = etag {
+                    set_metadata = set_metadata.if_match(IfMatchCondition::Match(etag.to_owned()));
+                }
+                set_metadata
+                    .await
+                    .map_err(|e| SPOError::new(&format!("Set blob metadata error: {}", e)))?;
+                Ok(format!("metadata {}={}", name, value))
+            }
+        }
+    }
+}
+
+async fn delete_blob(blob_client: &BlobClient, etag: &Option<String>) -> Result<(), SPOError> {
+    let mut delete = blob_client.delete();
+    if let Some(etag) = etag {
+        delete = delete.if_match(IfMatchCondition::Match(etag.to_owned()));
+    }
+    delete
+        .await
+        .map_err(|e| SPOError::new(&format!("Delete blob error: {}", e)))?;
+    Ok(())
+}
+
+//
+//  Server side copy inside the account with the encryption of the source, waits until the copy is done
+//
+async fn copy_blob(
+    source: &BlobSource,
+    blob_client: &BlobClient,
+    target: &BlobSource,
+    http_client: &Client,
+    etag: &Option<String>,
+) -> Result<(), SPOError> {
+    let mut source_url = blob_client
+        .url()
+        .map_err(|e| SPOError::new(&format!("Invalid blob url: {}", e)))?;
+    if let BlobCredential::Sas { token, .. } = &source.credential {
+        source_url.set_query(Some(token.expose()));
+    }
+    let target_client = target.blob_client(http_client)?;
+    // the target is encrypted like the source, the key is also needed to read the copy status
+    let mut copy = target_client
+        .copy(source_url)
+        .context(source.encryption.request_context());
+    if let Some(etag) = etag {
+        copy = copy.if_source_match(IfSourceMatchCondition::Match(etag.to_owned()));
+    }
+    let mut status = copy
+        .await
+        .map_err(|e| SPOError::new(&format!("Copy blob error: {}", e)))?
+        .copy_status;
+    while status == CopyStatus::Pending {
+        debug!("Copy to {} pending", target.blob_name);
+        tokio::time::sleep(Duration::from_secs(COPY_POLL_SECS)).await;
+        status = target_client
+            .get_properties()
+            .context(source.encryption.request_context())
+            .await
+            .map_err(|e| SPOError::new(&format!("Get blob properties error: {}", e)))?
+            .blob
+            .properties
+            .copy_status
+            .unwrap_or(CopyStatus::Success);
+    }
+    if status != CopyStatus::Success {
+        return Err(SPOError::new(&format!(
+            "Copy blob to {}/{} {}",
+            target.container, target.blob_name, status
+        )));
+    }
+    info!("Copied blob to {}/{}", target.container, target.blob_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::blob_encryption::BlobEncryption;
+    use crate::blob::blob_source::fixture::{serve_blobs, BlobStandIn};
+    use crate::common::secret::Secret;
+
+    use super::*;
+
+    fn sas_source(token: &str) -> BlobSource {
+        BlobSource::new(
+            &String::from("account"),
+            &String::from("reports"),
+            &String::from("q1.csv"),
+        )
+        .set_credential(BlobCredential::Sas {
+            endpoint: String::from("https://account.blob.core.windows.net"),
+            token: Secret::new(&token.to_string()),
+        })
+    }
+
+    #[test]
+    fn move_needs_an_account_sas() {
+        let move_action: PostCopyAction = "move:archive".parse().unwrap();
+        let error = move_action
+            .validate(&sas_source("sv=2022-11-02&sr=b&sp=rd&sig=x"))
+            .unwrap_err();
+        assert!(error.to_string().contains("account SAS"));
+        assert!(move_action
+            .validate(&sas_source("sv=2022-11-02&sr=c&sp=rwd&sig=x"))
+            .is_err());
+        assert!(move_action
+            .validate(&sas_source("sv=2022-11-02&ss=b&srt=co&sp=rwd&sig=x"))
+            .is_ok());
+        assert!(PostCopyAction::Delete
+            .validate(&sas_source("sv=2022-11-02&sr=b&sp=rd&sig=x"))
+            .is_ok());
+        let mut source = sas_source("");
+        source.credential = BlobCredential::DefaultAzureCredential;
+        assert!(move_action.validate(&source).is_ok());
+    }
+
+    #[tokio::test]
+    async fn move_keeps_the_customer_provided_key() {
+        let stand_in = BlobStandIn::with_blob("reports", "q1.csv", b"a,b\n");
+        let mut source = serve_blobs(stand_in.clone(), "reports", "q1.csv").await;
+        if let BlobCredential::Sas { token, .. } = &mut source.credential {
+            *token = Secret::new(&String::from("sv=2022-11-02&ss=b&srt=co&sig=fixture"));
+        }
+        source.encryption = BlobEncryption::new(
+            Some(Secret::new(&String::from("a2V5"))),
+            Some(String::from("c2hh")),
+            None,
+        )
+        .unwrap();
+        let action: PostCopyAction = "move:archive/2024/".parse().unwrap();
+        let done = action
+            .execute(&source, &Client::new(), &Some(String::from("0x1")))
+            .await
+            .unwrap();
+        assert_eq!(done, "moved to archive/2024/q1.csv");
+        let blobs = stand_in.blobs.lock().unwrap();
+        assert_eq!(
+            blobs.keys().collect::<Vec<_>>(),
+            vec!["archive/2024/q1.csv"]
+        );
+
+        let request_headers = stand_in.request_headers.lock().unwrap();
+        let copy = request_headers
+            .iter()
+            .find(|h| h.contains_key("x-ms-copy-source"))
+            .unwrap();
+        assert_eq!(copy["x-ms-encryption-key"], "a2V5");
+        assert_eq!(copy["x-ms-encryption-key-sha256"], "c2hh");
+        assert_eq!(copy["x-ms-encryption-algorithm"], "AES256");
+        assert_eq!(copy["x-ms-source-if-match"], "0x1");
+    }
+
+    #[test]
+    fn parse_post_copy_action() {
+        assert_eq!("delete".parse(), Ok(PostCopyAction::Delete));
+        assert_eq!(
+            "move:archive/2024/".parse(),
+            Ok(PostCopyAction::Move {
+                container: String::from("archive"),
+                prefix: String::from("2024/"),
+            })
+        );
+        assert_eq!(
+            "tier:Cool".parse(),
+            Ok(PostCopyAction::SetTier(AccessTier::Cool))
+        );
+        assert_eq!(
+            "metadata:spo_copied=true".parse(),
+            Ok(PostCopyAction::SetMetadata {
+                name: String::from("spo_copied"),
+                value: String::from("true"),
+            })
+        );
+        assert!("move".parse::<PostCopyAction>().is_err());
+        assert!("tier:Frozen".parse::<PostCopyAction>().is_err());
+    }
+
+    #[test]
+    fn post_copy_action_round_trip() {
+        for action in [
+            "delete",
+            "move:archive",
+            "move:archive/done/",
+            "tier:Archive",
+        ] {
+            assert_eq!(
+                action.parse::<PostCopyAction>().unwrap().to_string(),
+                action
+            );
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+
+use futures::StreamExt;
+use log::debug;
+use reqwest::Client;
+use spinner::SpinnerHandle;
+use time::OffsetDateTime;
+
+use crate::blob::blob2spo::{
+    do_copy_file_to_spo, CopyFailure, CopyOptions, CopySummary, ShowStatusFn,
+};
+use crate::blob::blob_filter::BlobFilter;
+use crate::blob::blob_post_action::PostCopyAction;
+use crate::blob::blob_source::BlobSource;
+use crate::common::datetime::format_datetime;
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::spo::spo_engine::SPOError;
+
+//
+//  Find blobs by index tags ex. status='approved' AND dept='finance'.
+//  The query runs across the account, or inside source.container when it is not empty.
+//  Every found blob is returned as a source with the same credential.
+//
+pub async fn find_blobs_by_tags(
+    source: &BlobSource,
+    http_client: &Client,
+    tag_query: &String,
+) -> Result<Vec<BlobSource>, SPOError> {
+    let expression = if source.container.is_empty() {
+        tag_query.to_owned()
+    } else {
+        format!("@container='{}' AND ({})", source.container, tag_query)
+    };
+    debug!("Find blobs by tags : {}", expression);
+
+    let service_client = source.blob_service_client(http_client)?;
+    let mut stream = service_client.find_blobs_by_tags(expression).into_stream();
+    let mut found: Vec<BlobSource> = vec![];
+    while let Some(page) = stream.next().await {
+        let page = page.map_err(|e| SPOError::new(&format!("Find blobs by tags error: {}", e)))?;
+        for blob in page.blobs {
+            let mut blob_source = source.clone();
+            blob_source.container = blob.container_name;
+            blob_source.blob_name = blob.name;
+            found.push(blob_source);
+        }
+    }
+    debug!("Found {} blobs", found.len());
+    Ok(found)
+}
+
+//
+//  Stamp tag_name=<now> on the blob, keeping the existing tags
+//
+pub async fn stamp_blob_tag(
+    source: &BlobSource,
+    http_client: &Client,
+    tag_name: &String,
+) -> Result<(), SPOError> {
+    let blob_client = source.blob_client(http_client)?;
+    let tags = blob_client
+        .get_tags()
+        .await
+        .map_err(|e| SPOError::new(&format!("Get blob tags error: {}", e)))?
+        .tags;
+    let mut tags: HashMap<String, String> = tags.into();
+    tags.insert(
+        tag_name.to_owned(),
+        format_datetime(&OffsetDateTime::now_utc()),
+    );
+    blob_client
+        .set_tags(tags)
+        .await
+        .map_err(|e| SPOError::new(&format!("Set blob tags error: {}", e)))?;
+    Ok(())
+}
+
+//
+//  The tag is stamped after the post action, a deleted or moved source blob can't be stamped
+//
+pub fn check_stamp_tag(
+    stamp_tag: &Option<String>,
+    post_action: &Option<PostCopyAction>,
+) -> Result<(), SPOError> {
+    match (stamp_tag, post_action) {
+        (Some(_), Some(action)) if action.removes_source() => Err(SPOError::new(&String::from(
+            "A stamp tag is not allowed with the delete or move post action, the source blob is gone",
+        ))),
+        _ => Ok(()),
+    }
+}
+
+//
+//  Copy every blob matching the tag query and the filter, optionally stamping a tag on each copied blob.
+//  Blobs with the same name in different containers are failed, not copied over each other
+//
+#[allow(clippy::too_many_arguments)]
+pub async fn do_copy_tagged_blobs_to_spo(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    share_point_domain: &String,
+    share_point_site: &String,
+    share_point_path: &String,
+    source: &BlobSource,
+    tag_query: &String,
+    stamp_tag: &Option<String>,
+    filter: &BlobFilter,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+    callback: Option<ShowStatusFn>,
+    spinner: Option<&SpinnerHandle>,
+) -> Result<CopySummary, SPOError> {
+    if !source.blob_name.is_empty() || source.version.is_some() {
+        return Err(SPOError::new(&String::from(
+            "A tag query selects the blobs, blob name and version are not allowed with it",
+        )));
+    }
+    if options.target_name.is_some() {
+        return Err(SPOError::new(&String::from(
+            "A target name is not allowed with a tag query, every blob keeps its name",
+        )));
+    }
+    check_stamp_tag(stamp_tag, &options.post_action)?;
+    let http_client = http_config.build_client()?;
+    let blobs = find_blobs_by_tags(source, &http_client, tag_query).await?;
+
+    let mut summary = CopySummary::default();
+    let mut selected: Vec<BlobSource> = vec![];
+    for blob in blobs {
+        match filter
+            .excluded_blob(&blob, &http_client, &blob.blob_name)
+            .await
+        {
+            Ok(Some(reason)) => {
+                debug!(
+                    "Blob {}/{} left out: {}",
+                    blob.container, blob.blob_name, reason
+                );
+            }
+            Ok(None) => selected.push(blob),
+            Err(e) => summary.failed.push(CopyFailure {
+                container: blob.container.to_owned(),
+                blob_name: blob.blob_name.to_owned(),
+                error: e,
+            }),
+        }
+    }
+    //
+    //  Blobs of different containers with the same name would overwrite each other in Share Point
+    //
+    let mut containers: HashMap<String, Vec<String>> = HashMap::new();
+    for blob in &selected {
+        containers
+            .entry(blob.blob_name.to_owned())
+            .or_default()
+            .push(blob.container.to_owned());
+    }
+    for blob in selected {
+        let res = match &containers[&blob.blob_name] {
+            c if c.len() > 1 => Err(SPOError::new(&format!(
+                "Blob name {} is found in containers {}, the copies would overwrite each other",
+                blob.blob_name,
+                c.join(", ")
+            ))),
+            _ => {
+                do_copy_file_to_spo(
+                    tenant_id,
+                    client_id,
+                    client_secret,
+                    share_point_domain,
+                    share_point_site,
+                    share_point_path,
+                    &blob,
+                    http_config,
+                    options,
+                    callback,
+                    spinner,
+                )
+                .await
+            }
+        };
+        let res = match (res, stamp_tag) {
+            (Ok(r), Some(tag_name)) => stamp_blob_tag(&blob, &http_client, tag_name)
+                .await
+                .map(|_| r),
+            (res, _) => res,
+        };
+        match res {
+            Ok(r) => summary.copied.push(r),
+            Err(e) => summary.failed.push(CopyFailure {
+                container: blob.container.to_owned(),
+                blob_name: blob.blob_name.to_owned(),
+                error: e,
+            }),
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::blob::blob_source::fixture::{serve_blobs, BlobStandIn};
+    use crate::blob::blob_source::BlobVersionSelector;
+    use crate::common::http_client::fixture::serve_https;
+    use crate::spo::spo_engine::fixture::{route, SharePointStandIn};
+
+    use super::*;
+
+    async fn copy_tagged(
+        source: &BlobSource,
+        http_config: &HttpConfig,
+        options: &CopyOptions,
+    ) -> Result<CopySummary, SPOError> {
+        do_copy_tagged_blobs_to_spo(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+            &String::from("contoso"),
+            &String::from("Finance"),
+            &String::from("/sites/Finance/Shared Documents"),
+            source,
+            &String::from("status='approved'"),
+            &None,
+            &BlobFilter::default(),
+            http_config,
+            options,
+            None,
+            None,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn tag_query_selects_the_blobs() {
+        let source = BlobSource::new(
+            &String::from("account"),
+            &String::from("reports"),
+            &String::from("q1.csv"),
+        );
+        let error = copy_tagged(&source, &HttpConfig::default(), &CopyOptions::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("blob name and version"));
+
+        let mut source = source;
+        source.blob_name = String::new();
+        source.version = Some(BlobVersionSelector::Snapshot(String::from(
+            "2024-01-01T10:00:00.0000000Z",
+        )));
+        assert!(
+            copy_tagged(&source, &HttpConfig::default(), &CopyOptions::default())
+                .await
+                .is_err()
+        );
+
+        source.version = None;
+        let options = CopyOptions {
+            target_name: Some(String::from("report.csv")),
+            ..Default::default()
+        };
+        let error = copy_tagged(&source, &HttpConfig::default(), &options)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("target name"));
+    }
+
+    #[test]
+    fn stamp_tag_needs_the_source_blob() {
+        let stamp_tag = Some(String::from("spo_copied"));
+        for action in ["delete", "move:archive/done"] {
+            let error = check_stamp_tag(&stamp_tag, &Some(action.parse().unwrap())).unwrap_err();
+            assert!(error.to_string().contains("stamp tag"));
+        }
+        assert!(check_stamp_tag(&stamp_tag, &Some("tier:Cool".parse().unwrap())).is_ok());
+        assert!(check_stamp_tag(&stamp_tag, &None).is_ok());
+        assert!(check_stamp_tag(&None, &Some(PostCopyAction::Delete)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn same_blob_name_in_two_containers_is_failed() {
+        let blobs = BlobStandIn::with_blob("north", "q1.csv", b"north");
+        blobs.insert("south", "q1.csv", b"south");
+        blobs.insert("south", "q2.csv", b"q2");
+        let source = serve_blobs(blobs.clone(), "", "").await;
+        let share_point = Arc::new(SharePointStandIn::default());
+        let http_config = serve_https(route(share_point.clone())).await;
+
+        let summary = copy_tagged(&source, &http_config, &CopyOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(summary.copied.len(), 1);
+        assert_eq!(summary.copied[0].blob_name, "q2.csv");
+        let mut failed: Vec<String> = summary
+            .failed
+            .iter()
+            .map(|f| format!("{}/{}", f.container, f.blob_name))
+            .collect();
+        failed.sort();
+        assert_eq!(failed, vec!["north/q1.csv", "south/q1.csv"]);
+        assert!(summary.failed[0]
+            .error
+            .to_string()
+            .contains("containers north, south"));
+        assert_eq!(
+            share_point.file("/sites/Finance/Shared Documents/q2.csv"),
+            Some(b"q2".to_vec())
+        );
+        assert_eq!(
+            share_point.file("/sites/Finance/Shared Documents/q1.csv"),
+            None
+        );
+    }
+}
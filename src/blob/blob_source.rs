@@ -0,0 +1,932 @@
+use std::sync::Arc;
+
+use azure_core::ClientOptions;
+use azure_storage::{CloudLocation, ConnectionString, StorageCredentials};
+use azure_storage_blobs::prelude::{
+    BlobClient, BlobServiceClient, BlobVersioning, ClientBuilder, ContainerClient, Snapshot,
+    VersionId,
+};
+use futures::StreamExt;
+use log::debug;
+use percent_encoding::percent_decode_str;
+use reqwest::Client;
+use time::OffsetDateTime;
+use url::Url;
+
+use crate::blob::blob_encryption::BlobEncryption;
+use crate::blob::blob_tier::ArchiveStatusPolicy;
+use crate::common::cloud::cloud;
+use crate::common::datetime::{format_datetime, parse_datetime};
+use crate::common::http_client::{
+    new_azure_credential, new_client_secret_credential, new_transport_options,
+};
+use crate::common::secret::Secret;
+use crate::spo::spo_engine::SPOError;
+
+//
+//  How to authenticate against the source Storage Account
+//
+#[derive(Debug, Clone)]
+pub enum BlobCredential {
+    /// Ambient identity (environment, managed identity, Azure CLI)
+    DefaultAzureCredential,
+    /// SAS token taken from a full blob SAS url, endpoint is the account url
+    Sas {
+        endpoint: String,
+        token: Secret,
+    },
+    AccountKey(Secret),
+    /// Includes UseDevelopmentStorage=true for Azurite
+    ConnectionString(Secret),
+    /// Application registration of a request, instead of the AZURE_* variables of the process
+    ClientSecret {
+        tenant_id: String,
+        client_id: String,
+        client_secret: Secret,
+    },
+}
+
+//
+//  Which state of the blob to copy, head when not set
+//
+#[derive(Debug, Clone)]
+pub enum BlobVersionSelector {
+    VersionId(String),
+    Snapshot(String),
+    /// The version which was current at this time
+    AsOf(OffsetDateTime),
+}
+
+impl BlobVersionSelector {
+    //
+    //  At most one of version id, snapshot or as-of time (RFC 3339)
+    //
+    pub fn from_options(
+        version_id: &Option<String>,
+        snapshot: &Option<String>,
+        as_of: &Option<String>,
+    ) -> Result<Option<BlobVersionSelector>, SPOError> {
+        match (version_id, snapshot, as_of) {
+            (None, None, None) => Ok(None),
+            (Some(v), None, None) => Ok(Some(BlobVersionSelector::VersionId(v.to_owned()))),
+            (None, Some(s), None) => Ok(Some(BlobVersionSelector::Snapshot(s.to_owned()))),
+            (None, None, Some(t)) => Ok(Some(BlobVersionSelector::AsOf(parse_datetime(t)?))),
+            _ => Err(SPOError::new(&String::from(
+                "Only one of version id, snapshot or as-of time can be used",
+            ))),
+        }
+    }
+    pub fn to_blob_versioning(&self) -> Option<BlobVersioning> {
+        match self {
+            BlobVersionSelector::VersionId(v) => Some(VersionId::new(v.to_owned()).into()),
+            BlobVersionSelector::Snapshot(s) => Some(Snapshot::new(s.to_owned()).into()),
+            BlobVersionSelector::AsOf(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobSource {
+    pub account: String,
+    pub container: String,
+    pub blob_name: String,
+    pub credential: BlobCredential,
+    pub version: Option<BlobVersionSelector>,
+    pub encryption: BlobEncryption,
+}
+
+impl BlobSource {
+    pub fn new(account: &String, container: &String, blob_name: &String) -> BlobSource {
+        BlobSource {
+            account: account.to_owned(),
+            container: container.to_owned(),
+            blob_name: blob_name.to_owned(),
+            credential: BlobCredential::DefaultAzureCredential,
+            version: None,
+            encryption: BlobEncryption::default(),
+        }
+    }
+    //
+    //  https://[account].blob.core.windows.net/[container]/[blob]?[sas]
+    //  http://127.0.0.1:10000/[account]/[container]/[blob]?[sas] (Azurite, path style)
+    //
+    pub fn from_sas_url(sas_url: &Secret) -> Result<BlobSource, SPOError> {
+        let url = Url::parse(sas_url.expose())
+            .map_err(|e| SPOError::new(&format!("Invalid blob SAS url: {}", e)))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| SPOError::new(&String::from("Invalid blob SAS url: missing host")))?;
+        let token = url.query().filter(|q| !q.is_empty()).ok_or_else(|| {
+            SPOError::new(&String::from("Invalid blob SAS url: missing SAS token"))
+        })?;
+        let mut segments: Vec<String> = url
+            .path_segments()
+            .map(|s| {
+                s.map(|p| percent_decode_str(p).decode_utf8_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let path_style = url.domain().is_none() || host == "localhost";
+        let (account, endpoint) = if path_style {
+            if segments.is_empty() {
+                return Err(SPOError::new(&String::from(
+                    "Invalid blob SAS url: missing account",
+                )));
+            }
+            let account = segments.remove(0);
+            let endpoint = format!("{}/{}", origin(&url), account);
+            (account, endpoint)
+        } else {
+            let account = host.split('.').next().unwrap_or_default().to_string();
+            (account, origin(&url))
+        };
+        if segments.len() < 2 {
+            return Err(SPOError::new(&String::from(
+                "Invalid blob SAS url: expect [container]/[blob]",
+            )));
+        }
+        let container = segments.remove(0);
+        let blob_name = segments.join("/");
+        Ok(BlobSource {
+            account,
+            container,
+            blob_name,
+            credential: BlobCredential::Sas {
+                endpoint,
+                token: Secret::new(&token.to_string()),
+            },
+            version: None,
+            encryption: BlobEncryption::default(),
+        })
+    }
+    pub fn set_credential(&mut self, credential: BlobCredential) -> BlobSource {
+        self.credential = credential;
+        self.to_owned()
+    }
+    //
+    //  Read with the application instead of the default identity, a SAS, key or connection string is kept
+    //
+    #[allow(clippy::ptr_arg)]
+    pub fn set_application(
+        &mut self,
+        tenant_id: &String,
+        client_id: &String,
+        client_secret: &Secret,
+    ) -> BlobSource {
+        if let BlobCredential::DefaultAzureCredential = self.credential {
+            self.credential = BlobCredential::ClientSecret {
+                tenant_id: tenant_id.to_owned(),
+                client_id: client_id.to_owned(),
+                client_secret: client_secret.clone(),
+            };
+        }
+        self.to_owned()
+    }
+    pub fn set_blob_name(&mut self, blob_name: &String) -> BlobSource {
+        self.blob_name = blob_name.to_owned();
+        self.to_owned()
+    }
+    pub fn set_version(&mut self, version: Option<BlobVersionSelector>) -> BlobSource {
+        self.version = version;
+        self.to_owned()
+    }
+    pub fn set_encryption(&mut self, encryption: BlobEncryption) -> BlobSource {
+        self.encryption = encryption;
+        self.to_owned()
+    }
+    //
+    //  Resolve the version selector to a version id or snapshot,
+    //  "as of" picks the latest version id which is not after the given time
+    //
+    pub async fn resolve_version(
+        &self,
+        http_client: &Client,
+    ) -> Result<Option<BlobVersionSelector>, SPOError> {
+        match &self.version {
+            Some(BlobVersionSelector::AsOf(as_of)) => {
+                let container_client = self.container_client(http_client)?;
+                let mut stream = container_client
+                    .list_blobs()
+                    .prefix(self.blob_name.clone())
+                    .include_versions(true)
+                    .into_stream();
+                let mut selected: Option<(OffsetDateTime, String)> = None;
+                while let Some(page) = stream.next().await {
+                    let page =
+                        page.map_err(|e| SPOError::new(&format!("List versions error: {}", e)))?;
+                    for blob in page.blobs.blobs() {
+                        if blob.name != self.blob_name {
+                            continue;
+                        }
+                        let version_id = match &blob.version_id {
+                            Some(v) => v,
+                            None => continue,
+                        };
+                        let version_time = parse_datetime(version_id)?;
+                        if version_time > *as_of {
+                            continue;
+                        }
+                        if selected.as_ref().is_none_or(|(t, _)| version_time > *t) {
+                            selected = Some((version_time, version_id.to_owned()));
+                        }
+                    }
+                }
+                match selected {
+                    Some((_, version_id)) => {
+                        debug!("Version at {} is {}", format_datetime(as_of), version_id);
+                        Ok(Some(BlobVersionSelector::VersionId(version_id)))
+                    }
+                    None => Err(SPOError::new(&format!(
+                        "No version of {} exists at {}",
+                        self.blob_name,
+                        format_datetime(as_of)
+                    ))),
+                }
+            }
+            other => Ok(other.clone()),
+        }
+    }
+    pub fn blob_client(&self, http_client: &Client) -> Result<BlobClient, SPOError> {
+        Ok(self
+            .client_builder(http_client)?
+            .blob_client(&self.container, &self.blob_name))
+    }
+    pub fn container_client(&self, http_client: &Client) -> Result<ContainerClient, SPOError> {
+        Ok(self
+            .client_builder(http_client)?
+            .container_client(&self.container))
+    }
+    pub fn blob_service_client(&self, http_client: &Client) -> Result<BlobServiceClient, SPOError> {
+        Ok(self.client_builder(http_client)?.blob_service_client())
+    }
+    fn client_builder(&self, http_client: &Client) -> Result<ClientBuilder, SPOError> {
+        let builder = match &self.credential {
+            BlobCredential::DefaultAzureCredential => ClientBuilder::with_location(
+                cloud().storage_location(&self.account),
+                StorageCredentials::token_credential(new_azure_credential(http_client)),
+            ),
+            BlobCredential::Sas { endpoint, token } => ClientBuilder::with_location(
+                CloudLocation::Custom {
+                    uri: endpoint.to_owned(),
+                },
+                StorageCredentials::sas_token(token.expose())
+                    .map_err(|e| SPOError::new(&format!("Invalid SAS token: {}", e)))?,
+            ),
+            BlobCredential::AccountKey(key) => ClientBuilder::with_location(
+                cloud().storage_location(&self.account),
+                StorageCredentials::access_key(&self.account, key.expose()),
+            ),
+            BlobCredential::ConnectionString(connection_string) => {
+                connection_string_client_builder(connection_string)?
+            }
+            BlobCredential::ClientSecret {
+                tenant_id,
+                client_id,
+                client_secret,
+            } => ClientBuilder::with_location(
+                cloud().storage_location(&self.account),
+                StorageCredentials::token_credential(new_client_secret_credential(
+                    http_client,
+                    tenant_id,
+                    client_id,
+                    client_secret,
+                )),
+            ),
+        };
+        let mut options = ClientOptions::new(new_transport_options(http_client));
+        options
+            .per_call_policies_mut()
+            .push(Arc::new(ArchiveStatusPolicy));
+        Ok(builder.client_options(options))
+    }
+}
+
+//
+//  Build the blob source from CLI arguments or function request fields,
+//  credentials are already resolved from Key Vault
+//
+pub fn resolve_blob_source(
+    account: &Option<String>,
+    container: &Option<String>,
+    blob_name: &Option<String>,
+    sas_url: Option<Secret>,
+    account_key: Option<Secret>,
+    connection_string: Option<Secret>,
+) -> Result<BlobSource, SPOError> {
+    if let Some(sas_url) = sas_url {
+        return BlobSource::from_sas_url(&sas_url);
+    }
+    // container and blob name may be left empty for multi blob sources (ex. tag query)
+    let container = container.clone().unwrap_or_default();
+    let blob_name = blob_name.clone().unwrap_or_default();
+    if let Some(connection_string) = connection_string {
+        let account = match account {
+            Some(a) => a.to_owned(),
+            None => ConnectionString::new(connection_string.expose())
+                .map_err(|e| SPOError::new(&format!("Invalid connection string: {}", e)))?
+                .account_name
+                .unwrap_or_default()
+                .to_string(),
+        };
+        return Ok(BlobSource::new(&account, &container, &blob_name)
+            .set_credential(BlobCredential::ConnectionString(connection_string)));
+    }
+    let account = account
+        .clone()
+        .ok_or_else(|| SPOError::new(&String::from("storage account is required")))?;
+    let mut source = BlobSource::new(&account, &container, &blob_name);
+    if let Some(account_key) = account_key {
+        source.set_credential(BlobCredential::AccountKey(account_key));
+    }
+    Ok(source)
+}
+
+fn connection_string_client_builder(connection_string: &Secret) -> Result<ClientBuilder, SPOError> {
+    let cs = ConnectionString::new(connection_string.expose())
+        .map_err(|e| SPOError::new(&format!("Invalid connection string: {}", e)))?;
+    if cs.use_development_storage == Some(true) {
+        return Ok(ClientBuilder::emulator());
+    }
+    let credentials = cs
+        .storage_credentials()
+        .map_err(|e| SPOError::new(&format!("Invalid connection string: {}", e)))?;
+    if let Some(blob_endpoint) = cs.blob_endpoint {
+        return Ok(ClientBuilder::with_location(
+            CloudLocation::Custom {
+                uri: blob_endpoint.trim_end_matches('/').to_string(),
+            },
+            credentials,
+        ));
+    }
+    let account = cs.account_name.ok_or_else(|| {
+        SPOError::new(&String::from(
+            "Invalid connection string: missing AccountName",
+        ))
+    })?;
+    match cs.endpoint_suffix {
+        Some(suffix) => {
+            let protocol = match cs.default_endpoints_protocol {
+                Some(azure_storage::EndpointProtocol::Http) => "http",
+                _ => "https",
+            };
+            Ok(ClientBuilder::with_location(
+                CloudLocation::Custom {
+                    uri: format!("{}://{}.blob.{}", protocol, account, suffix),
+                },
+                credentials,
+            ))
+        }
+        None => Ok(ClientBuilder::with_location(
+            cloud().storage_location(account),
+            credentials,
+        )),
+    }
+}
+
+fn origin(url: &Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}://{}:{}", url.scheme(), url.host_str().unwrap(), port),
+        None => format!("{}://{}", url.scheme(), url.host_str().unwrap()),
+    }
+}
+
+//
+//  Blob service stand-in for tests, answers get properties and get blob of the blobs
+//  (container/blob name) behind a SAS endpoint
+//
+#[cfg(test)]
+pub(crate) mod fixture {
+    use std::collections::{BTreeMap, HashMap};
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use warp::http::{HeaderMap, Method, Response};
+    use warp::Filter;
+
+    use super::{percent_decode_str, BlobCredential, BlobSource};
+    use crate::common::secret::Secret;
+
+    #[derive(Default)]
+    pub struct BlobStandIn {
+        pub blobs: Mutex<BTreeMap<String, Vec<u8>>>,
+        /// access tier and archive status of the blobs, Hot when not set
+        pub tiers: Mutex<HashMap<String, (String, Option<String>)>>,
+        /// A lease expires when not renewed within, 60 secs when not set
+        pub lease_life: Mutex<Option<Duration>>,
+        /// lease id and expiry of the leased blobs
+        pub leases: Mutex<HashMap<String, (String, Instant)>>,
+        /// method, path and comp of every request
+        pub requests: Mutex<Vec<String>>,
+        /// headers of every request
+        pub request_headers: Mutex<Vec<HeaderMap>>,
+    }
+
+    impl BlobStandIn {
+        pub fn with_blob(container: &str, blob_name: &str, content: &[u8]) -> Arc<BlobStandIn> {
+            let stand_in = BlobStandIn::default();
+            stand_in
+                .blobs
+                .lock()
+                .unwrap()
+                .insert(format!("{}/{}", container, blob_name), content.to_vec());
+            Arc::new(stand_in)
+        }
+        pub fn insert(&self, container: &str, blob_name: &str, content: &[u8]) {
+            self.blobs
+                .lock()
+                .unwrap()
+                .insert(format!("{}/{}", container, blob_name), content.to_vec());
+        }
+        pub fn set_tier(
+            &self,
+            container: &str,
+            blob_name: &str,
+            tier: &str,
+            archive_status: Option<&str>,
+        ) {
+            self.tiers.lock().unwrap().insert(
+                format!("{}/{}", container, blob_name),
+                (tier.to_string(), archive_status.map(|a| a.to_string())),
+            );
+        }
+        pub fn respond(
+            &self,
+            method: &Method,
+            path: &str,
+            query: &HashMap<String, String>,
+            headers: &HeaderMap,
+        ) -> Response<Vec<u8>> {
+            let comp = query.get("comp").map(|c| c.as_str());
+            self.requests.lock().unwrap().push(match comp {
+                Some(comp) => format!("{} {}?comp={}", method, path, comp),
+                None => format!("{} {}", method, path),
+            });
+            let response = Response::builder()
+                .header("x-ms-request-id", "00000000-0000-0000-0000-000000000000")
+                .header("date", "Mon, 01 Jan 2024 10:00:00 GMT")
+                .header("x-ms-version", "2022-11-02")
+                .header("server", "Windows-Azure-Blob/1.0");
+            // find blobs by tags, every blob matches
+            if comp == Some("blobs") {
+                let blobs: String = self
+                    .blobs
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .filter_map(|k| k.split_once('/'))
+                    .map(|(container, name)| {
+                        format!(
+                            "<Blob><Name>{}</Name><ContainerName>{}</ContainerName><TagValue>x</TagValue></Blob>",
+                            name, container
+                        )
+                    })
+                    .collect();
+                return response
+                    .header("content-type", "application/xml")
+                    .body(format!(
+                        "<?xml version=\"1.0\" encoding=\"utf-8\"?><EnumerationResults><Where>{}</Where><Blobs>{}</Blobs><NextMarker /></EnumerationResults>",
+                        query.get("where").cloned().unwrap_or_default(),
+                        blobs
+                    ).into_bytes())
+                    .unwrap();
+            }
+            // list the blobs of a container below the prefix
+            if comp == Some("list") {
+                let container = format!("{}/", path.trim_start_matches("/account/"));
+                let prefix = query.get("prefix").cloned().unwrap_or_default();
+                let blobs: String = self
+                    .blobs
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter_map(|(k, v)| Some((k.strip_prefix(&container)?, v)))
+                    .filter(|(name, _)| name.starts_with(&prefix))
+                    .map(|(name, content)| {
+                        format!(
+                            "<Blob><Name>{}</Name><Properties>\
+                            <Creation-Time>Mon, 01 Jan 2024 10:00:00 GMT</Creation-Time>\
+                            <Last-Modified>Mon, 01 Jan 2024 10:00:00 GMT</Last-Modified>\
+                            <Etag>0x1</Etag><Content-Length>{}</Content-Length>\
+                            <Content-Type>application/octet-stream</Content-Type>\
+                            <BlobType>BlockBlob</BlobType></Properties></Blob>",
+                            name,
+                            content.len()
+                        )
+                    })
+                    .collect();
+                return response
+                    .header("content-type", "application/xml")
+                    .body(format!(
+                        "<?xml version=\"1.0\" encoding=\"utf-8\"?><EnumerationResults><Blobs>{}</Blobs><NextMarker /></EnumerationResults>",
+                        blobs
+                    ).into_bytes())
+                    .unwrap();
+            }
+            self.request_headers.lock().unwrap().push(headers.clone());
+            let name = path.trim_start_matches("/account/");
+            // server side copy inside the account, done at once
+            let copy_source = headers
+                .get("x-ms-copy-source")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| url::Url::parse(v).ok());
+            if let (&Method::PUT, Some(copy_source)) = (method, copy_source) {
+                let source_name = copy_source.path().trim_start_matches("/account/");
+                let mut blobs = self.blobs.lock().unwrap();
+                let Some(content) = blobs.get(source_name).cloned() else {
+                    return response.status(404).body(vec![]).unwrap();
+                };
+                blobs.insert(name.to_string(), content);
+                return response
+                    .status(202)
+                    .header("last-modified", "Mon, 01 Jan 2024 10:00:00 GMT")
+                    .header("etag", "0x2")
+                    .header("x-ms-copy-id", "00000000-0000-0000-0000-000000000001")
+                    .header("x-ms-copy-status", "success")
+                    .body(vec![])
+                    .unwrap();
+            }
+            if method == Method::DELETE {
+                let status = match self.blobs.lock().unwrap().remove(name) {
+                    Some(_) => 202,
+                    None => 404,
+                };
+                return response
+                    .status(status)
+                    .header("x-ms-delete-type-permanent", "false")
+                    .body(vec![])
+                    .unwrap();
+            }
+            let Some(content) = self.blobs.lock().unwrap().get(name).cloned() else {
+                return response.status(404).body(vec![]).unwrap();
+            };
+            let (tier, archive_status) = self
+                .tiers
+                .lock()
+                .unwrap()
+                .get(name)
+                .cloned()
+                .unwrap_or((String::from("Hot"), None));
+            if comp == Some("tier") {
+                // a pending rehydration can't be changed
+                let status = match archive_status {
+                    Some(_) => 409,
+                    None => 202,
+                };
+                return response.status(status).body(vec![]).unwrap();
+            }
+            let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+            let response = response
+                .header("last-modified", "Mon, 01 Jan 2024 10:00:00 GMT")
+                .header("etag", "0x1");
+            let mut leases = self.leases.lock().unwrap();
+            let active_lease = leases
+                .get(name)
+                .filter(|(_, expiry)| *expiry > Instant::now())
+                .map(|(id, _)| id.to_owned());
+            let expiry = Instant::now()
+                + self
+                    .lease_life
+                    .lock()
+                    .unwrap()
+                    .unwrap_or(Duration::from_secs(60));
+            if comp == Some("lease") {
+                let lease_id = header("x-ms-lease-id").map(|l| l.to_string());
+                return match (header("x-ms-lease-action"), active_lease) {
+                    (Some("acquire"), None) => {
+                        let lease_id = uuid::Uuid::new_v4().to_string();
+                        leases.insert(name.to_string(), (lease_id.clone(), expiry));
+                        response
+                            .status(201)
+                            .header("x-ms-lease-id", lease_id)
+                            .body(vec![])
+                            .unwrap()
+                    }
+                    // an expired lease is renewed as long as nobody else took the blob
+                    (Some("renew"), _)
+                        if leases.get(name).map(|(id, _)| id.to_owned()) == lease_id =>
+                    {
+                        leases.insert(name.to_string(), (lease_id.clone().unwrap(), expiry));
+                        response
+                            .header("x-ms-lease-id", lease_id.unwrap())
+                            .body(vec![])
+                            .unwrap()
+                    }
+                    (Some("release"), _)
+                        if leases.get(name).map(|(id, _)| id.to_owned()) == lease_id =>
+                    {
+                        leases.remove(name);
+                        response.body(vec![]).unwrap()
+                    }
+                    _ => response.status(409).body(vec![]).unwrap(),
+                };
+            }
+            if let Some(lease_id) = header("x-ms-lease-id") {
+                if active_lease.as_deref() != Some(lease_id) {
+                    return response
+                        .status(412)
+                        .header("x-ms-error-code", "LeaseNotPresentWithBlobOperation")
+                        .body(vec![])
+                        .unwrap();
+                }
+            }
+            drop(leases);
+            let response = match archive_status {
+                Some(archive_status) => response.header("x-ms-archive-status", archive_status),
+                None => response,
+            };
+            // range reads x-ms-range: bytes=start-end
+            let range = header("x-ms-range")
+                .and_then(|r| r.strip_prefix("bytes="))
+                .and_then(|r| r.split_once('-'))
+                .and_then(|(start, end)| {
+                    Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+                });
+            let (response, content) = match range {
+                Some((start, end)) if *method == Method::GET && start < content.len() => {
+                    let end = end.min(content.len() - 1);
+                    (
+                        response.status(206).header(
+                            "content-range",
+                            format!("bytes {}-{}/{}", start, end, content.len()),
+                        ),
+                        content[start..=end].to_vec(),
+                    )
+                }
+                _ => (response, content),
+            };
+            let response = response
+                .header("content-length", content.len())
+                .header("content-type", "application/octet-stream")
+                .header("x-ms-creation-time", "Mon, 01 Jan 2024 10:00:00 GMT")
+                .header("x-ms-blob-type", "BlockBlob")
+                .header("x-ms-access-tier", tier)
+                .header("x-ms-server-encrypted", "true");
+            match *method {
+                Method::HEAD => response.body(vec![]).unwrap(),
+                _ => response.body(content).unwrap(),
+            }
+        }
+    }
+
+    //
+    //  Serve the stand-in, the source addresses the blob with a SAS of the endpoint
+    //
+    pub async fn serve_blobs(
+        stand_in: Arc<BlobStandIn>,
+        container: &str,
+        blob_name: &str,
+    ) -> BlobSource {
+        let route = warp::method()
+            .and(warp::path::full())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::headers_cloned())
+            .map(
+                move |method: Method,
+                      path: warp::path::FullPath,
+                      query: HashMap<String, String>,
+                      headers: HeaderMap| {
+                    let path = percent_decode_str(path.as_str()).decode_utf8_lossy();
+                    stand_in.respond(&method, &path, &query, &headers)
+                },
+            );
+        let (addr, server) = warp::serve(route).bind_ephemeral((Ipv4Addr::LOCALHOST, 0));
+        tokio::spawn(server);
+        BlobSource::new(
+            &String::from("account"),
+            &container.to_string(),
+            &blob_name.to_string(),
+        )
+        .set_credential(BlobCredential::Sas {
+            endpoint: format!("http://{}/account", addr),
+            token: Secret::new(&String::from("sv=2022-11-02&sig=fixture")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::Filter;
+
+    use super::*;
+    use crate::common::http_client::fixture::serve_https;
+
+    fn blob_url(source: &BlobSource) -> String {
+        source
+            .blob_client(&Client::new())
+            .unwrap()
+            .url()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn source_from_sas_url() {
+        let source = BlobSource::from_sas_url(&Secret::from(String::from(
+            "https://acct.blob.core.windows.net/inbox/2024/Q1%20report%23final.pdf?sv=2022-11-02&sig=abc%3D",
+        )))
+        .unwrap();
+        assert_eq!(source.account, "acct");
+        assert_eq!(source.container, "inbox");
+        assert_eq!(source.blob_name, "2024/Q1 report#final.pdf");
+        match &source.credential {
+            BlobCredential::Sas { endpoint, token } => {
+                assert_eq!(endpoint, "https://acct.blob.core.windows.net");
+                assert_eq!(token.expose(), "sv=2022-11-02&sig=abc%3D");
+            }
+            other => panic!("unexpected credential {:?}", other),
+        }
+
+        // Azurite and IP endpoints put the account in the path
+        let source = BlobSource::from_sas_url(&Secret::from(String::from(
+            "http://127.0.0.1:10000/devstoreaccount1/inbox/a/b.txt?sig=x",
+        )))
+        .unwrap();
+        assert_eq!(source.account, "devstoreaccount1");
+        assert_eq!(source.container, "inbox");
+        assert_eq!(source.blob_name, "a/b.txt");
+        assert!(matches!(
+            &source.credential,
+            BlobCredential::Sas { endpoint, .. } if endpoint == "http://127.0.0.1:10000/devstoreaccount1"
+        ));
+
+        for invalid in [
+            "https://acct.blob.core.windows.net/inbox/file.txt",
+            "https://acct.blob.core.windows.net/inbox/file.txt?",
+            "https://acct.blob.core.windows.net/inbox?sig=x",
+            "http://localhost:10000/?sig=x",
+            "not a url",
+        ] {
+            assert!(
+                BlobSource::from_sas_url(&Secret::from(String::from(invalid))).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn source_from_connection_string() {
+        let container = Some(String::from("inbox"));
+        let blob_name = Some(String::from("file.txt"));
+        let key = "a2V5";
+
+        let source = resolve_blob_source(
+            &None,
+            &container,
+            &blob_name,
+            None,
+            None,
+            Some(Secret::from(format!(
+                "DefaultEndpointsProtocol=https;AccountName=acct;AccountKey={};EndpointSuffix=core.example",
+                key
+            ))),
+        )
+        .unwrap();
+        assert_eq!(source.account, "acct");
+        assert_eq!(
+            blob_url(&source),
+            "https://acct.blob.core.example/inbox/file.txt"
+        );
+
+        let source = resolve_blob_source(
+            &None,
+            &container,
+            &blob_name,
+            None,
+            None,
+            Some(Secret::from(format!(
+                "AccountName=acct;AccountKey={};BlobEndpoint=https://blobs.example.com/",
+                key
+            ))),
+        )
+        .unwrap();
+        assert_eq!(
+            blob_url(&source),
+            "https://blobs.example.com/inbox/file.txt"
+        );
+
+        let source = resolve_blob_source(
+            &None,
+            &container,
+            &blob_name,
+            None,
+            None,
+            Some(Secret::from(String::from("UseDevelopmentStorage=true"))),
+        )
+        .unwrap();
+        assert!(blob_url(&source).starts_with("http://127.0.0.1:10000/devstoreaccount1/inbox/"));
+
+        assert!(resolve_blob_source(
+            &None,
+            &container,
+            &blob_name,
+            None,
+            None,
+            Some(Secret::from(String::from("not a connection string"))),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn source_needs_account_without_url_or_connection_string() {
+        assert!(resolve_blob_source(&None, &None, &None, None, None, None).is_err());
+        let source = resolve_blob_source(
+            &Some(String::from("acct")),
+            &Some(String::from("inbox")),
+            &Some(String::from("file.txt")),
+            None,
+            Some(Secret::from(String::from("a2V5"))),
+            None,
+        )
+        .unwrap();
+        assert!(matches!(source.credential, BlobCredential::AccountKey(_)));
+    }
+
+    #[tokio::test]
+    async fn client_secret_of_the_request_reads_the_blob() {
+        let blobs = fixture::BlobStandIn::with_blob("c", "b.txt", b"hello");
+        let requests: Arc<std::sync::Mutex<Vec<String>>> = Arc::default();
+        let route = {
+            let requests = requests.clone();
+            warp::method()
+                .and(warp::path::full())
+                .and(warp::query::<std::collections::HashMap<String, String>>())
+                .and(warp::header::headers_cloned())
+                .map(
+                    move |method: warp::http::Method,
+                          path: warp::path::FullPath,
+                          query: std::collections::HashMap<String, String>,
+                          headers: warp::http::HeaderMap| {
+                        let authorization = headers
+                            .get("authorization")
+                            .and_then(|v| v.to_str().ok())
+                            .unwrap_or_default();
+                        requests
+                            .lock()
+                            .unwrap()
+                            .push(format!("{} {}", path.as_str(), authorization));
+                        if path.as_str().ends_with("/oauth2/v2.0/token") {
+                            return warp::http::Response::builder()
+                                .header("content-type", "application/json")
+                                .body(
+                                    br#"{"token_type":"Bearer","expires_in":3600,"access_token":"blob-token"}"#
+                                        .to_vec(),
+                                )
+                                .unwrap();
+                        }
+                        // the stand-in expects the account in the path
+                        blobs.respond(
+                            &method,
+                            &format!("/account{}", path.as_str()),
+                            &query,
+                            &headers,
+                        )
+                    },
+                )
+                .boxed()
+        };
+        let http_config = serve_https(route).await;
+        let source = BlobSource::new(
+            &String::from("account"),
+            &String::from("c"),
+            &String::from("b.txt"),
+        )
+        .set_application(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+        );
+        assert!(matches!(
+            source.credential,
+            BlobCredential::ClientSecret { .. }
+        ));
+        // a SAS is not replaced
+        let sas = BlobSource::from_sas_url(&Secret::new(&String::from(
+            "https://account.blob.core.windows.net/c/b.txt?sv=2022-11-02&sr=b&sp=r&sig=x",
+        )))
+        .unwrap()
+        .set_application(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+        );
+        assert!(matches!(sas.credential, BlobCredential::Sas { .. }));
+
+        let content = source
+            .blob_client(&http_config.build_client().unwrap())
+            .unwrap()
+            .get_content()
+            .await
+            .unwrap();
+        assert_eq!(content, b"hello");
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                String::from("/tenant/oauth2/v2.0/token "),
+                String::from("/c/b.txt Bearer blob-token"),
+            ]
+        );
+    }
+}
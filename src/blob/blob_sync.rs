@@ -0,0 +1,626 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use futures::StreamExt;
+use log::{debug, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use spinner::SpinnerHandle;
+use time::OffsetDateTime;
+
+use crate::blob::blob2spo::{
+    do_copy_file_to_spo, CopyFailure, CopyOptions, CopyResult, ShowStatusFn,
+};
+use crate::blob::blob_filter::BlobFilter;
+use crate::blob::blob_source::BlobSource;
+use crate::common::datetime::parse_datetime;
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::common::state_file::{load_state_file, save_state_file};
+use crate::spo::spo_engine::{SPOEngine, SPOError};
+use crate::spo::spo_model::SPOFile;
+use crate::spo::spo_path::{ensure_spo_folders, join_path};
+
+pub const DEFAULT_SYNC_STATE_FILE: &str = "spo_sync_state.json";
+// Share Point access tokens are valid for about one hour
+pub(crate) const SPO_RECONNECT_SECS: u64 = 30 * 60;
+// Hidden folder with the list forms at the root of a document library
+const SPO_FORMS_FOLDER: &str = "Forms";
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Blob name prefix, the rest of the blob name is the path below the Share Point folder
+    pub prefix: String,
+    /// Recycle Share Point files whose source blob disappeared, only files uploaded by a previous sync
+    pub delete: bool,
+    pub state_file: PathBuf,
+    /// Blobs left out of the sync, their Share Point files are never deleted
+    pub filter: BlobFilter,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncStateEntry {
+    pub etag: String,
+    pub size: u64,
+    /// Share Point TimeLastModified after the upload, detects files changed in Share Point
+    pub spo_time_last_modified: Option<String>,
+}
+
+//
+//  What the previous runs synced, keyed by the path relative to the prefix / folder
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncState {
+    pub source: String,
+    pub destination: String,
+    pub files: BTreeMap<String, SyncStateEntry>,
+}
+
+impl SyncState {
+    pub fn new(source: &String, destination: &String) -> SyncState {
+        SyncState {
+            source: source.to_owned(),
+            destination: destination.to_owned(),
+            files: BTreeMap::new(),
+        }
+    }
+    //
+    //  A missing sync state file, or the state of another source / destination, starts from scratch
+    //
+    pub fn load(path: &Path, source: &String, destination: &String) -> Result<SyncState, SPOError> {
+        match load_state_file::<SyncState>(path)? {
+            Some(state) if state.source == *source && state.destination == *destination => {
+                Ok(state)
+            }
+            Some(state) => {
+                warn!(
+                    "Sync state {} belongs to {} -> {}, starting a new state",
+                    path.display(),
+                    state.source,
+                    state.destination
+                );
+                Ok(SyncState::new(source, destination))
+            }
+            None => Ok(SyncState::new(source, destination)),
+        }
+    }
+    pub fn save(&self, path: &Path) -> Result<(), SPOError> {
+        save_state_file(path, self)
+    }
+}
+
+//
+//  Outcome of a sync, paths are relative to the prefix / folder
+//
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncSummary {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub deleted: Vec<String>,
+    pub skipped: usize,
+    /// Blobs left out by the filter
+    pub excluded: usize,
+    pub failed: Vec<CopyFailure>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SourceBlob {
+    pub name: String,
+    /// Blob name without the prefix, path below the Share Point folder
+    pub relative_path: String,
+    pub etag: String,
+    pub size: u64,
+    pub last_modified: OffsetDateTime,
+    pub content_type: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SyncAction {
+    Skip,
+    /// Same file already in Share Point without state (ex. lost state file), record it
+    Adopt,
+    Add,
+    Update,
+}
+
+pub(crate) fn sync_action(
+    blob: &SourceBlob,
+    state: Option<&SyncStateEntry>,
+    spo_file: Option<&SPOFile>,
+) -> SyncAction {
+    let spo_file = match spo_file {
+        Some(f) => f,
+        None => return SyncAction::Add,
+    };
+    let same_size = spo_file.length == blob.size.to_string();
+    match state {
+        Some(entry)
+            if entry.etag == blob.etag
+                && same_size
+                && entry.spo_time_last_modified == spo_file.time_last_modified =>
+        {
+            SyncAction::Skip
+        }
+        Some(_) => SyncAction::Update,
+        None if same_size && spo_file_is_newer(spo_file, &blob.last_modified) => SyncAction::Adopt,
+        None => SyncAction::Update,
+    }
+}
+
+fn spo_file_is_newer(spo_file: &SPOFile, last_modified: &OffsetDateTime) -> bool {
+    spo_file
+        .time_last_modified
+        .as_ref()
+        .and_then(|t| parse_datetime(t).ok())
+        .is_some_and(|t| t >= *last_modified)
+}
+
+//
+//  Prefix of the blob names below a folder, ends with / so reports doesn't match reports-old/x
+//
+pub(crate) fn folder_prefix(prefix: &str) -> String {
+    match prefix.trim_end_matches('/') {
+        "" => String::new(),
+        folder => format!("{}/", folder),
+    }
+}
+
+//
+//  Blobs below the prefix, folder placeholders (names ending with /) are skipped.
+//  The blobs left out by the filter are returned apart with the reason
+//
+pub(crate) async fn list_source_blobs(
+    source: &BlobSource,
+    http_client: &Client,
+    prefix: &String,
+    filter: &BlobFilter,
+) -> Result<(Vec<SourceBlob>, Vec<(SourceBlob, String)>), SPOError> {
+    let folder = folder_prefix(prefix);
+    let container_client = source.container_client(http_client)?;
+    let mut stream = container_client
+        .list_blobs()
+        .prefix(folder.to_owned())
+        .into_stream();
+    let mut blobs: Vec<SourceBlob> = vec![];
+    let mut excluded: Vec<(SourceBlob, String)> = vec![];
+    while let Some(page) = stream.next().await {
+        let page = page.map_err(|e| SPOError::new(&format!("List blobs error: {}", e)))?;
+        for blob in page.blobs.blobs() {
+            let relative_path = &blob.name[folder.len()..];
+            if relative_path.is_empty() || relative_path.ends_with('/') {
+                continue;
+            }
+            let source_blob = SourceBlob {
+                name: blob.name.to_owned(),
+                relative_path: relative_path.to_string(),
+                etag: blob.properties.etag.to_string(),
+                size: blob.properties.content_length,
+                last_modified: blob.properties.last_modified,
+                content_type: blob.properties.content_type.to_owned(),
+            };
+            match filter.excluded(
+                relative_path,
+                source_blob.size,
+                &source_blob.last_modified,
+                &source_blob.content_type,
+            ) {
+                Some(reason) => excluded.push((source_blob, reason)),
+                None => blobs.push(source_blob),
+            }
+        }
+    }
+    debug!(
+        "Found {} blobs below {}, {} excluded",
+        blobs.len(),
+        prefix,
+        excluded.len()
+    );
+    Ok((blobs, excluded))
+}
+
+//
+//  Files and folders below the Share Point folder, keyed by relative path
+//
+pub(crate) async fn list_spo_files(
+    spo_engine: &SPOEngine,
+    share_point_path: &String,
+    files: &mut BTreeMap<String, SPOFile>,
+    folders: &mut BTreeSet<String>,
+) -> Result<(), SPOError> {
+    let mut pending = vec![String::new()];
+    while let Some(relative_folder) = pending.pop() {
+        let folder = spo_engine
+            .get_folder(&join_path(share_point_path, &relative_folder))
+            .await?;
+        for file in folder.files.map(|r| r.results).unwrap_or_default() {
+            if let Some(name) = file.name.clone() {
+                files.insert(join_path(&relative_folder, &name), file);
+            }
+        }
+        for sub_folder in folder.folders.map(|r| r.results).unwrap_or_default() {
+            let name = sub_folder.name.unwrap_or_default();
+            if name.is_empty() || (relative_folder.is_empty() && name == SPO_FORMS_FOLDER) {
+                continue;
+            }
+            let relative_path = join_path(&relative_folder, &name);
+            folders.insert(relative_path.clone());
+            pending.push(relative_path);
+        }
+    }
+    debug!(
+        "Found {} files in {} folders below {}",
+        files.len(),
+        folders.len(),
+        share_point_path
+    );
+    Ok(())
+}
+
+//
+//  Copy a blob to its path below the Share Point folder, the missing folders are created first.
+//  The spo engine must be connected to the site, the folders already created are kept in spo_folders
+//
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn copy_blob_below_folder(
+    spo_engine: &SPOEngine,
+    spo_folders: &mut BTreeSet<String>,
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    share_point_domain: &String,
+    share_point_site: &String,
+    share_point_path: &String,
+    source: &BlobSource,
+    relative_path: &str,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+    callback: Option<ShowStatusFn>,
+    spinner: Option<&SpinnerHandle>,
+) -> Result<CopyResult, CopyFailure> {
+    let (relative_folder, file_name) = relative_path
+        .rsplit_once('/')
+        .unwrap_or(("", relative_path));
+    let copy_options = CopyOptions {
+        target_name: Some(file_name.to_string()),
+        ..options.clone()
+    };
+    let res = async {
+        ensure_spo_folders(spo_engine, share_point_path, relative_folder, spo_folders).await?;
+        do_copy_file_to_spo(
+            tenant_id,
+            client_id,
+            client_secret,
+            share_point_domain,
+            share_point_site,
+            &join_path(share_point_path, relative_folder),
+            source,
+            http_config,
+            &copy_options,
+            callback,
+            spinner,
+        )
+        .await
+    }
+    .await;
+    res.map_err(|e| CopyFailure {
+        container: source.container.to_owned(),
+        blob_name: source.blob_name.to_owned(),
+        error: e,
+    })
+}
+
+//
+//  Upload new and changed blobs below the prefix to the Share Point folder,
+//  blobs are compared by name, size and last modified with the files and the state of the previous run
+//
+#[allow(clippy::too_many_arguments)]
+pub async fn do_sync_prefix_to_spo(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    share_point_domain: &String,
+    share_point_site: &String,
+    share_point_path: &String,
+    source: &BlobSource,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+    sync_options: &SyncOptions,
+    callback: Option<ShowStatusFn>,
+    spinner: Option<&SpinnerHandle>,
+) -> Result<SyncSummary, SPOError> {
+    if source.container.is_empty() {
+        return Err(SPOError::new(&String::from("container is required")));
+    }
+    let http_client = http_config.build_client()?;
+    let source_key = format!(
+        "{}/{}/{}",
+        source.account, source.container, sync_options.prefix
+    );
+    let destination_key = format!(
+        "{}/{}/{}",
+        share_point_domain, share_point_site, share_point_path
+    );
+    let mut state = SyncState::load(&sync_options.state_file, &source_key, &destination_key)?;
+
+    let (blobs, excluded) = list_source_blobs(
+        source,
+        &http_client,
+        &sync_options.prefix,
+        &sync_options.filter,
+    )
+    .await?;
+
+    let mut spo_engine = SPOEngine::new(
+        tenant_id,
+        client_id,
+        client_secret,
+        share_point_domain,
+        &http_client,
+    );
+    spo_engine.connect(share_point_site).await?;
+    let mut connected = Instant::now();
+    spo_engine.ensure_folder(share_point_path).await?;
+    let mut spo_files: BTreeMap<String, SPOFile> = BTreeMap::new();
+    let mut spo_folders: BTreeSet<String> = BTreeSet::new();
+    list_spo_files(
+        &spo_engine,
+        share_point_path,
+        &mut spo_files,
+        &mut spo_folders,
+    )
+    .await?;
+
+    let mut summary = SyncSummary {
+        excluded: excluded.len(),
+        ..Default::default()
+    };
+    for blob in &blobs {
+        let spo_file = spo_files.get(&blob.relative_path);
+        let action = sync_action(blob, state.files.get(&blob.relative_path), spo_file);
+        debug!("Sync {} : {:?}", blob.relative_path, action);
+        match action {
+            SyncAction::Skip => {
+                summary.skipped += 1;
+                continue;
+            }
+            SyncAction::Adopt => {
+                state.files.insert(
+                    blob.relative_path.to_owned(),
+                    SyncStateEntry {
+                        etag: blob.etag.to_owned(),
+                        size: blob.size,
+                        spo_time_last_modified: spo_file.and_then(|f| f.time_last_modified.clone()),
+                    },
+                );
+                summary.skipped += 1;
+                continue;
+            }
+            SyncAction::Add | SyncAction::Update => {}
+        }
+        if connected.elapsed().as_secs() >= SPO_RECONNECT_SECS {
+            spo_engine.connect(share_point_site).await?;
+            connected = Instant::now();
+        }
+        let mut blob_source = source.clone();
+        blob_source.set_blob_name(&blob.name);
+        let res = copy_blob_below_folder(
+            &spo_engine,
+            &mut spo_folders,
+            tenant_id,
+            client_id,
+            client_secret,
+            share_point_domain,
+            share_point_site,
+            share_point_path,
+            &blob_source,
+            &blob.relative_path,
+            http_config,
+            options,
+            callback,
+            spinner,
+        )
+        .await;
+        // the state keeps the Share Point time to notice later changes of the file
+        let res = match res {
+            Ok(copy_result) => {
+                let (relative_folder, file_name) = blob
+                    .relative_path
+                    .rsplit_once('/')
+                    .unwrap_or(("", &blob.relative_path));
+                spo_engine
+                    .get_file_at(
+                        &join_path(share_point_path, relative_folder),
+                        &file_name.to_string(),
+                    )
+                    .await
+                    .map(|spo_file| SyncStateEntry {
+                        etag: blob.etag.to_owned(),
+                        size: copy_result.size,
+                        spo_time_last_modified: spo_file.time_last_modified,
+                    })
+                    .map_err(|e| CopyFailure {
+                        container: source.container.to_owned(),
+                        blob_name: blob.name.to_owned(),
+                        error: e,
+                    })
+            }
+            Err(failure) => Err(failure),
+        };
+        match res {
+            Ok(entry) => {
+                info!("Synced {} ({:?})", blob.relative_path, action);
+                state.files.insert(blob.relative_path.to_owned(), entry);
+                state.save(&sync_options.state_file)?;
+                if action == SyncAction::Add {
+                    summary.added.push(blob.relative_path.to_owned());
+                } else {
+                    summary.updated.push(blob.relative_path.to_owned());
+                }
+            }
+            Err(failure) => summary.failed.push(failure),
+        }
+    }
+
+    if sync_options.delete {
+        let blob_paths: BTreeSet<&String> = blobs
+            .iter()
+            .chain(excluded.iter().map(|(b, _)| b))
+            .map(|b| &b.relative_path)
+            .collect();
+        let removed: Vec<String> = state
+            .files
+            .keys()
+            .filter(|p| !blob_paths.contains(p))
+            .cloned()
+            .collect();
+        if !removed.is_empty() && connected.elapsed().as_secs() >= SPO_RECONNECT_SECS {
+            spo_engine.connect(share_point_site).await?;
+        }
+        for relative_path in removed {
+            if spo_files.contains_key(&relative_path) {
+                let (relative_folder, file_name) = relative_path
+                    .rsplit_once('/')
+                    .unwrap_or(("", &relative_path));
+                let res = spo_engine
+                    .recycle_file(
+                        &join_path(share_point_path, relative_folder),
+                        &file_name.to_string(),
+                    )
+                    .await;
+                if let Err(e) = res {
+                    summary.failed.push(CopyFailure {
+                        container: source.container.to_owned(),
+                        blob_name: format!(
+                            "{}{}",
+                            folder_prefix(&sync_options.prefix),
+                            relative_path
+                        ),
+                        error: e,
+                    });
+                    continue;
+                }
+                info!("Recycled {}", relative_path);
+                summary.deleted.push(relative_path.to_owned());
+            }
+            state.files.remove(&relative_path);
+        }
+    }
+    state.save(&sync_options.state_file)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::blob_source::fixture::{serve_blobs, BlobStandIn};
+
+    fn blob(etag: &str, size: u64) -> SourceBlob {
+        SourceBlob {
+            name: String::from("reports/a.pdf"),
+            relative_path: String::from("a.pdf"),
+            etag: etag.to_string(),
+            size,
+            last_modified: parse_datetime("2024-01-01T10:00:00Z").unwrap(),
+            content_type: String::from("application/pdf"),
+        }
+    }
+
+    fn spo_file(length: u64, time_last_modified: &str) -> SPOFile {
+        SPOFile {
+            name: Some(String::from("a.pdf")),
+            length: length.to_string(),
+            time_last_modified: Some(time_last_modified.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sync_action_compares_state_and_spo_file() {
+        let entry = SyncStateEntry {
+            etag: String::from("0x1"),
+            size: 10,
+            spo_time_last_modified: Some(String::from("2024-01-01T10:05:00Z")),
+        };
+        let synced = spo_file(10, "2024-01-01T10:05:00Z");
+
+        assert_eq!(sync_action(&blob("0x1", 10), None, None), SyncAction::Add);
+        assert_eq!(
+            sync_action(&blob("0x1", 10), Some(&entry), Some(&synced)),
+            SyncAction::Skip
+        );
+        // blob overwritten
+        assert_eq!(
+            sync_action(&blob("0x2", 10), Some(&entry), Some(&synced)),
+            SyncAction::Update
+        );
+        // file changed in Share Point
+        assert_eq!(
+            sync_action(
+                &blob("0x1", 10),
+                Some(&entry),
+                Some(&spo_file(10, "2024-02-01T00:00:00Z"))
+            ),
+            SyncAction::Update
+        );
+        // no state, same size and newer than the blob
+        assert_eq!(
+            sync_action(&blob("0x1", 10), None, Some(&synced)),
+            SyncAction::Adopt
+        );
+        assert_eq!(
+            sync_action(
+                &blob("0x1", 10),
+                None,
+                Some(&spo_file(10, "2023-12-31T00:00:00Z"))
+            ),
+            SyncAction::Update
+        );
+    }
+
+    #[test]
+    fn sync_state_of_other_destination_is_reset() {
+        let path = std::env::temp_dir().join(format!("spo_sync_state_{}.json", std::process::id()));
+        let source = String::from("account/container/reports/");
+        let mut state = SyncState::new(&source, &String::from("domain/site/Shared Documents"));
+        state
+            .files
+            .insert(String::from("a.pdf"), SyncStateEntry::default());
+        state.save(&path).unwrap();
+
+        let loaded = SyncState::load(
+            &path,
+            &source,
+            &String::from("domain/site/Shared Documents"),
+        )
+        .unwrap();
+        assert_eq!(loaded, state);
+        let loaded = SyncState::load(&path, &source, &String::from("domain/site/Archive")).unwrap();
+        assert!(loaded.files.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn prefix_matches_whole_folders() {
+        let stand_in = BlobStandIn::with_blob("c", "reports/a.pdf", b"a");
+        stand_in.insert("c", "reports/2024/b.pdf", b"b");
+        stand_in.insert("c", "reports-old/x.pdf", b"x");
+        let source = serve_blobs(stand_in, "c", "").await;
+        let http_client = HttpConfig::default().build_client().unwrap();
+
+        for prefix in ["reports", "reports/"] {
+            let (blobs, _) = list_source_blobs(
+                &source,
+                &http_client,
+                &prefix.to_string(),
+                &BlobFilter::default(),
+            )
+            .await
+            .unwrap();
+            let paths: Vec<&str> = blobs.iter().map(|b| b.relative_path.as_str()).collect();
+            assert_eq!(paths, vec!["2024/b.pdf", "a.pdf"]);
+        }
+        assert_eq!(folder_prefix(""), "");
+        assert_eq!(folder_prefix("/"), "");
+    }
+}
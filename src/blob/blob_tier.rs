@@ -0,0 +1,281 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use azure_core::headers::HeaderName;
+use azure_core::{Context, Policy, PolicyResult, Request};
+use azure_storage_blobs::blob::BlobProperties;
+use azure_storage_blobs::prelude::{AccessTier, BlobClient, BlobVersioning, RehydratePriority};
+use log::{debug, info};
+
+use crate::blob::blob_encryption::BlobEncryption;
+use crate::spo::spo_engine::{SPOError, SPOErrorKind};
+
+pub const DEFAULT_REHYDRATE_POLL_SECS: u64 = 600;
+// Standard priority rehydration may take up to 15 hours
+pub const MAX_REHYDRATE_WAIT_SECS: u64 = 16 * 60 * 60;
+
+const ARCHIVE_STATUS: HeaderName = HeaderName::from_static("x-ms-archive-status");
+
+//
+//  x-ms-archive-status of a response (rehydrate-pending-to-hot while rehydrating), the sdk does
+//  not read it. Put the slot in the context of the request to receive it
+//
+#[derive(Debug, Default)]
+pub struct ArchiveStatus(Mutex<Option<String>>);
+
+#[derive(Debug)]
+pub struct ArchiveStatusPolicy;
+
+#[async_trait]
+impl Policy for ArchiveStatusPolicy {
+    async fn send(
+        &self,
+        ctx: &Context,
+        request: &mut Request,
+        next: &[Arc<dyn Policy>],
+    ) -> PolicyResult {
+        let response = next[0].send(ctx, request, &next[1..]).await?;
+        if let Some(slot) = ctx.get::<Arc<ArchiveStatus>>() {
+            *slot.0.lock().unwrap() = response.headers().get_optional_string(&ARCHIVE_STATUS);
+        }
+        Ok(response)
+    }
+}
+
+//
+//  Fail fast when the blob is offline (Archive tier) instead of failing inside the download stream.
+//  A pending rehydration (x-ms-archive-status) returns BlobRehydrating, otherwise with a
+//  rehydrate priority, rehydration to Hot is started and BlobRehydrating is returned.
+//  The blob encryption is checked at the same time.
+//  Returns the blob properties, None when they can't be read without the customer-provided key.
+//
+pub async fn check_blob_online(
+    blob_client: &BlobClient,
+    versioning: &Option<BlobVersioning>,
+    encryption: &BlobEncryption,
+    rehydrate_priority: &Option<RehydratePriority>,
+) -> Result<Option<BlobProperties>, SPOError> {
+    let archive_status = Arc::new(ArchiveStatus::default());
+    let mut context = Context::new();
+    context.insert(archive_status.clone());
+    let mut get_properties = blob_client.get_properties().context(context);
+    if let Some(versioning) = versioning {
+        get_properties = get_properties.blob_versioning(versioning.to_owned());
+    }
+    let properties = match get_properties.await {
+        Ok(p) => p,
+        // the sdk can't send key headers with get properties, the download does
+        Err(e)
+            if encryption.encryption_key.is_some()
+                && e.as_http_error()
+                    .and_then(|h| h.error_code())
+                    .is_some_and(|c| c == "BlobUsesCustomerSpecifiedEncryption") =>
+        {
+            debug!("Skip preflight of blob {}: {}", blob_client.blob_name(), e);
+            return Ok(None);
+        }
+        Err(e) => return Err(SPOError::new(&format!("Get blob properties error: {}", e))),
+    };
+    encryption.check_properties(blob_client.blob_name(), &properties.blob.properties)?;
+    if properties.blob.properties.access_tier != Some(AccessTier::Archive) {
+        return Ok(Some(properties.blob.properties));
+    }
+    let blob_name = blob_client.blob_name();
+    let archive_status = archive_status.0.lock().unwrap().clone();
+    if let Some(status) = archive_status.filter(|s| s.starts_with("rehydrate-pending-to-")) {
+        return Err(SPOError::with_kind(
+            SPOErrorKind::BlobRehydrating,
+            &format!("Blob {} is being rehydrated ({})", blob_name, status),
+        ));
+    }
+    let priority = match rehydrate_priority {
+        Some(p) => p.to_owned(),
+        None => {
+            return Err(SPOError::with_kind(
+                SPOErrorKind::BlobArchived,
+                &format!("Blob {} is archived, rehydrate it before copy", blob_name),
+            ))
+        }
+    };
+    let mut set_blob_tier = blob_client
+        .set_blob_tier(AccessTier::Hot)
+        .rehydrate_priority(priority);
+    if let Some(versioning) = versioning {
+        set_blob_tier = set_blob_tier.blob_versioning(versioning.to_owned());
+    }
+    match set_blob_tier.await {
+        Ok(_) => {
+            info!(
+                "Rehydration of blob {} started with {} priority",
+                blob_name, priority
+            );
+            Err(SPOError::with_kind(
+                SPOErrorKind::BlobRehydrating,
+                &format!(
+                    "Blob {} is being rehydrated with {} priority",
+                    blob_name, priority
+                ),
+            ))
+        }
+        // Conflict: a rehydration is already pending
+        Err(e) if e.as_http_error().is_some_and(|h| h.status() as u16 == 409) => {
+            debug!("Rehydration of blob {} already pending: {}", blob_name, e);
+            Err(SPOError::with_kind(
+                SPOErrorKind::BlobRehydrating,
+                &format!("Blob {} is already being rehydrated", blob_name),
+            ))
+        }
+        Err(e) => Err(SPOError::new(&format!("Rehydrate blob error: {}", e))),
+    }
+}
+
+//
+//  Poll the blob properties until the pending rehydration is done, a blob which is archived
+//  without rehydration fails at once
+//
+pub async fn wait_blob_online(
+    blob_client: &BlobClient,
+    versioning: &Option<BlobVersioning>,
+    encryption: &BlobEncryption,
+    poll_interval: Duration,
+) -> Result<Option<BlobProperties>, SPOError> {
+    let started = Instant::now();
+    loop {
+        match check_blob_online(blob_client, versioning, encryption, &None).await {
+            Ok(properties) => return Ok(properties),
+            Err(e) if e.kind() == SPOErrorKind::BlobRehydrating => {
+                if started.elapsed().as_secs() > MAX_REHYDRATE_WAIT_SECS {
+                    return Err(SPOError::with_kind(
+                        SPOErrorKind::BlobRehydrating,
+                        &format!(
+                            "Blob {} is still being rehydrated after {} secs",
+                            blob_client.blob_name(),
+                            started.elapsed().as_secs()
+                        ),
+                    ));
+                }
+                info!(
+                    "Waiting for rehydration of blob {} ({} secs elapsed)",
+                    blob_client.blob_name(),
+                    started.elapsed().as_secs()
+                );
+                tokio::time::sleep(poll_interval).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::blob::blob_source::fixture::{serve_blobs, BlobStandIn};
+
+    use super::*;
+
+    async fn check(
+        stand_in: &Arc<BlobStandIn>,
+        rehydrate_priority: Option<RehydratePriority>,
+    ) -> Result<Option<BlobProperties>, SPOError> {
+        let source = serve_blobs(stand_in.clone(), "reports", "q1.csv").await;
+        let blob_client = source.blob_client(&reqwest::Client::new()).unwrap();
+        check_blob_online(
+            &blob_client,
+            &None,
+            &BlobEncryption::default(),
+            &rehydrate_priority,
+        )
+        .await
+    }
+
+    fn tier_requests(stand_in: &BlobStandIn) -> usize {
+        stand_in
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.ends_with("?comp=tier"))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn online_and_archived_blobs() {
+        let stand_in = BlobStandIn::with_blob("reports", "q1.csv", b"a,b\n");
+        let properties = check(&stand_in, None).await.unwrap().unwrap();
+        assert_eq!(properties.access_tier, Some(AccessTier::Hot));
+
+        stand_in.set_tier("reports", "q1.csv", "Archive", None);
+        let error = check(&stand_in, None).await.unwrap_err();
+        assert_eq!(error.kind(), SPOErrorKind::BlobArchived);
+        assert_eq!(tier_requests(&stand_in), 0);
+
+        let error = check(&stand_in, Some(RehydratePriority::High))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), SPOErrorKind::BlobRehydrating);
+        assert!(error.to_string().contains("rehydrated with High priority"));
+        assert_eq!(tier_requests(&stand_in), 1);
+    }
+
+    #[tokio::test]
+    async fn pending_rehydration_is_not_restarted() {
+        let stand_in = BlobStandIn::with_blob("reports", "q1.csv", b"a,b\n");
+        stand_in.set_tier(
+            "reports",
+            "q1.csv",
+            "Archive",
+            Some("rehydrate-pending-to-hot"),
+        );
+        let error = check(&stand_in, None).await.unwrap_err();
+        assert_eq!(error.kind(), SPOErrorKind::BlobRehydrating);
+        assert!(error.to_string().contains("rehydrate-pending-to-hot"));
+
+        let error = check(&stand_in, Some(RehydratePriority::Standard))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), SPOErrorKind::BlobRehydrating);
+        assert_eq!(tier_requests(&stand_in), 0);
+    }
+
+    #[tokio::test]
+    async fn wait_for_pending_rehydration() {
+        let stand_in = BlobStandIn::with_blob("reports", "q1.csv", b"a,b\n");
+        stand_in.set_tier(
+            "reports",
+            "q1.csv",
+            "Archive",
+            Some("rehydrate-pending-to-cool"),
+        );
+        let source = serve_blobs(stand_in.clone(), "reports", "q1.csv").await;
+        let blob_client = source.blob_client(&reqwest::Client::new()).unwrap();
+        let rehydrated = stand_in.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            rehydrated.set_tier("reports", "q1.csv", "Cool", None);
+        });
+        let properties = wait_blob_online(
+            &blob_client,
+            &None,
+            &BlobEncryption::default(),
+            Duration::from_millis(20),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(properties.access_tier, Some(AccessTier::Cool));
+
+        // archived without rehydration, nothing to wait for
+        stand_in.set_tier("reports", "q1.csv", "Archive", None);
+        let error = wait_blob_online(
+            &blob_client,
+            &None,
+            &BlobEncryption::default(),
+            Duration::from_millis(20),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), SPOErrorKind::BlobArchived);
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use spinner::SpinnerHandle;
+use time::OffsetDateTime;
+
+use crate::blob::blob2spo::{CopyOptions, CopySummary, ShowStatusFn};
+use crate::blob::blob_filter::BlobFilter;
+use crate::blob::blob_source::BlobSource;
+use crate::blob::blob_sync::{
+    copy_blob_below_folder, list_source_blobs, SourceBlob, SPO_RECONNECT_SECS,
+};
+use crate::common::datetime::{format_datetime, parse_datetime};
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::common::state_file::{load_state_file, save_state_file};
+use crate::spo::spo_engine::{SPOEngine, SPOError};
+
+pub const DEFAULT_WATCH_CHECKPOINT_FILE: &str = "spo_watch_checkpoint.json";
+pub const DEFAULT_WATCH_POLL_SECS: u64 = 60;
+pub const DEFAULT_WATCH_SETTLE_SECS: u64 = 30;
+// A failing blob holds the checkpoint for this many polls, then it is skipped
+const MAX_WATCH_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    /// Blob name prefix, the rest of the blob name is the path below the Share Point folder
+    pub prefix: String,
+    pub poll_interval: Duration,
+    /// Blobs modified less than this ago are left for the next poll (still being written)
+    pub settle: Duration,
+    pub checkpoint_file: PathBuf,
+    /// Without checkpoint, only blobs modified after this time are copied
+    pub since: Option<OffsetDateTime>,
+    /// Blobs never copied
+    pub filter: BlobFilter,
+}
+
+//
+//  Last modified time of the newest copied blob, with the blobs copied at exactly that time
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WatchCheckpoint {
+    pub source: String,
+    pub destination: String,
+    /// RFC 3339
+    pub last_modified: Option<String>,
+    /// Blob name and ETag of the blobs copied at last_modified
+    pub copied_at_last_modified: BTreeMap<String, String>,
+    /// Blob name and number of failed attempts of the blob holding the checkpoint
+    pub failed_attempts: BTreeMap<String, u32>,
+}
+
+impl WatchCheckpoint {
+    pub fn new(source: &String, destination: &String) -> WatchCheckpoint {
+        WatchCheckpoint {
+            source: source.to_owned(),
+            destination: destination.to_owned(),
+            ..Default::default()
+        }
+    }
+    //
+    //  A missing checkpoint file, or the checkpoint of another source / destination, starts from scratch
+    //
+    pub fn load(
+        path: &Path,
+        source: &String,
+        destination: &String,
+    ) -> Result<WatchCheckpoint, SPOError> {
+        match load_state_file::<WatchCheckpoint>(path)? {
+            Some(checkpoint)
+                if checkpoint.source == *source && checkpoint.destination == *destination =>
+            {
+                Ok(checkpoint)
+            }
+            Some(checkpoint) => {
+                warn!(
+                    "Checkpoint {} belongs to {} -> {}, starting a new checkpoint",
+                    path.display(),
+                    checkpoint.source,
+                    checkpoint.destination
+                );
+                Ok(WatchCheckpoint::new(source, destination))
+            }
+            None => Ok(WatchCheckpoint::new(source, destination)),
+        }
+    }
+    pub fn save(&self, path: &Path) -> Result<(), SPOError> {
+        save_state_file(path, self)
+    }
+    //
+    //  Move the checkpoint to the blob, copied or given up
+    //
+    fn advance(&mut self, blob: &SourceBlob) {
+        let last_modified = format_datetime(&blob.last_modified);
+        if self.last_modified.as_ref() != Some(&last_modified) {
+            self.last_modified = Some(last_modified);
+            self.copied_at_last_modified.clear();
+        }
+        self.copied_at_last_modified
+            .insert(blob.name.to_owned(), blob.etag.to_owned());
+        self.failed_attempts.remove(&blob.name);
+    }
+}
+
+//
+//  Blobs created or modified after the checkpoint which are settled, oldest first
+//
+fn new_blobs(
+    blobs: Vec<SourceBlob>,
+    checkpoint: &WatchCheckpoint,
+    since: &Option<OffsetDateTime>,
+    settled_before: &OffsetDateTime,
+) -> Result<Vec<SourceBlob>, SPOError> {
+    let last_modified = match &checkpoint.last_modified {
+        Some(t) => Some(parse_datetime(t)?),
+        None => since.to_owned(),
+    };
+    let mut found: Vec<SourceBlob> = blobs
+        .into_iter()
+        .filter(|b| b.last_modified <= *settled_before)
+        .filter(|b| match &last_modified {
+            Some(t) if b.last_modified == *t => {
+                checkpoint.copied_at_last_modified.get(&b.name) != Some(&b.etag)
+            }
+            Some(t) => b.last_modified > *t,
+            None => true,
+        })
+        .collect();
+    found.sort_by(|a, b| {
+        a.last_modified
+            .cmp(&b.last_modified)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(found)
+}
+
+//
+//  One poll of the container, copies the new blobs and moves the checkpoint after each of them
+//
+#[allow(clippy::too_many_arguments)]
+pub async fn do_watch_poll(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    share_point_domain: &String,
+    share_point_site: &String,
+    share_point_path: &String,
+    source: &BlobSource,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+    watch_options: &WatchOptions,
+    callback: Option<ShowStatusFn>,
+    spinner: Option<&SpinnerHandle>,
+) -> Result<CopySummary, SPOError> {
+    let http_client = http_config.build_client()?;
+    let source_key = format!(
+        "{}/{}/{}",
+        source.account, source.container, watch_options.prefix
+    );
+    let destination_key = format!(
+        "{}/{}/{}",
+        share_point_domain, share_point_site, share_point_path
+    );
+    let mut checkpoint = WatchCheckpoint::load(
+        &watch_options.checkpoint_file,
+        &source_key,
+        &destination_key,
+    )?;
+
+    let settled_before = OffsetDateTime::now_utc() - watch_options.settle;
+    let (blobs, _) = list_source_blobs(
+        source,
+        &http_client,
+        &watch_options.prefix,
+        &watch_options.filter,
+    )
+    .await?;
+    let blobs = new_blobs(blobs, &checkpoint, &watch_options.since, &settled_before)?;
+    let mut summary = CopySummary::default();
+    if blobs.is_empty() {
+        return Ok(summary);
+    }
+    debug!("Found {} new blobs", blobs.len());
+
+    let mut spo_engine = SPOEngine::new(
+        tenant_id,
+        client_id,
+        client_secret,
+        share_point_domain,
+        &http_client,
+    );
+    spo_engine.connect(share_point_site).await?;
+    let connected = Instant::now();
+    let mut spo_folders: BTreeSet<String> = BTreeSet::new();
+    for blob in &blobs {
+        if connected.elapsed().as_secs() >= SPO_RECONNECT_SECS {
+            // the next poll continues from the checkpoint with a new token
+            break;
+        }
+        let mut blob_source = source.clone();
+        blob_source.set_blob_name(&blob.name);
+        let res = copy_blob_below_folder(
+            &spo_engine,
+            &mut spo_folders,
+            tenant_id,
+            client_id,
+            client_secret,
+            share_point_domain,
+            share_point_site,
+            share_point_path,
+            &blob_source,
+            &blob.relative_path,
+            http_config,
+            options,
+            callback,
+            spinner,
+        )
+        .await;
+        match res {
+            Ok(r) => {
+                info!("Copied {}", blob.name);
+                checkpoint.advance(blob);
+                checkpoint.save(&watch_options.checkpoint_file)?;
+                summary.copied.push(r);
+            }
+            Err(failure) => {
+                let attempts = checkpoint
+                    .failed_attempts
+                    .entry(blob.name.to_owned())
+                    .or_default();
+                *attempts += 1;
+                let give_up = *attempts >= MAX_WATCH_ATTEMPTS;
+                if give_up {
+                    error!(
+                        "Copy {} failed {} times, skipped : {}",
+                        blob.name, MAX_WATCH_ATTEMPTS, failure.error
+                    );
+                    checkpoint.advance(blob);
+                }
+                checkpoint.save(&watch_options.checkpoint_file)?;
+                summary.failed.push(failure);
+                if !give_up {
+                    // keep the order, the blob is retried on the next poll
+                    break;
+                }
+            }
+        }
+    }
+    Ok(summary)
+}
+
+//
+//  Poll the container until the process is stopped, the checkpoint file makes restarts resume
+//
+#[allow(clippy::too_many_arguments)]
+pub async fn do_watch_prefix_to_spo(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    share_point_domain: &String,
+    share_point_site: &String,
+    share_point_path: &String,
+    source: &BlobSource,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+    watch_options: &WatchOptions,
+    callback: Option<ShowStatusFn>,
+    spinner: Option<&SpinnerHandle>,
+) -> Result<(), SPOError> {
+    if source.container.is_empty() {
+        return Err(SPOError::new(&String::from("container is required")));
+    }
+    loop {
+        let res = do_watch_poll(
+            tenant_id,
+            client_id,
+            client_secret,
+            share_point_domain,
+            share_point_site,
+            share_point_path,
+            source,
+            http_config,
+            options,
+            watch_options,
+            callback,
+            spinner,
+        )
+        .await;
+        match res {
+            Ok(summary) if summary.copied.is_empty() && summary.failed.is_empty() => {
+                debug!("No new blobs");
+            }
+            Ok(summary) => {
+                for f in &summary.failed {
+                    error!(
+                        "Copy file to SPO error : {}/{} {}",
+                        f.container, f.blob_name, f.error
+                    );
+                }
+                info!(
+                    "Copied {} blobs, {} failed",
+                    summary.copied.len(),
+                    summary.failed.len()
+                );
+            }
+            // listing or checkpoint errors, try again on the next poll
+            Err(e) => error!("Watch poll error : {}", e),
+        }
+        tokio::time::sleep(watch_options.poll_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(name: &str, etag: &str, last_modified: &str) -> SourceBlob {
+        SourceBlob {
+            name: name.to_string(),
+            relative_path: name.to_string(),
+            etag: etag.to_string(),
+            size: 1,
+            last_modified: parse_datetime(last_modified).unwrap(),
+            content_type: String::from("text/plain"),
+        }
+    }
+
+    #[test]
+    fn new_blobs_after_checkpoint_and_settled() {
+        let mut checkpoint = WatchCheckpoint::default();
+        checkpoint.advance(&blob("b.txt", "0x1", "2024-01-01T10:00:00Z"));
+        let settled_before = parse_datetime("2024-01-01T12:00:00Z").unwrap();
+        let blobs = vec![
+            blob("a.txt", "0x1", "2024-01-01T09:00:00Z"),
+            // copied at the checkpoint time
+            blob("b.txt", "0x1", "2024-01-01T10:00:00Z"),
+            // same time, not copied yet
+            blob("c.txt", "0x1", "2024-01-01T10:00:00Z"),
+            blob("e.txt", "0x1", "2024-01-01T11:30:00Z"),
+            blob("d.txt", "0x1", "2024-01-01T11:00:00Z"),
+            // still being written
+            blob("f.txt", "0x1", "2024-01-01T12:00:01Z"),
+        ];
+
+        let found = new_blobs(blobs, &checkpoint, &None, &settled_before).unwrap();
+        let names: Vec<&str> = found.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, vec!["c.txt", "d.txt", "e.txt"]);
+    }
+
+    #[test]
+    fn new_blobs_without_checkpoint_since() {
+        let settled_before = parse_datetime("2024-01-01T12:00:00Z").unwrap();
+        let blobs = vec![
+            blob("a.txt", "0x1", "2024-01-01T09:00:00Z"),
+            blob("b.txt", "0x1", "2024-01-01T10:00:00Z"),
+        ];
+        let since = Some(parse_datetime("2024-01-01T09:30:00Z").unwrap());
+
+        let found = new_blobs(
+            blobs.clone(),
+            &WatchCheckpoint::default(),
+            &since,
+            &settled_before,
+        )
+        .unwrap();
+        assert_eq!(found.len(), 1);
+        let found = new_blobs(blobs, &WatchCheckpoint::default(), &None, &settled_before).unwrap();
+        assert_eq!(found.len(), 2);
+    }
+}
@@ -1 +1,12 @@
 pub mod blob2spo;
+pub mod blob_change_feed;
+pub mod blob_consistency;
+pub mod blob_encryption;
+pub mod blob_filter;
+pub mod blob_plan;
+pub mod blob_post_action;
+pub mod blob_query;
+pub mod blob_source;
+pub mod blob_sync;
+pub mod blob_tier;
+pub mod blob_watch;
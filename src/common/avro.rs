@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+
+use serde_json::{Map, Number, Value};
+
+use crate::spo::spo_engine::SPOError;
+
+const MAGIC: &[u8] = b"Obj\x01";
+const SYNC_MARKER_SIZE: usize = 16;
+
+//
+//  Minimal Avro object container reader (null codec), as written by the blob change feed.
+//  Records are returned as JSON values, enums as strings and bytes / fixed as byte arrays.
+//
+pub fn read_avro_container(data: &[u8]) -> Result<Vec<Value>, SPOError> {
+    let mut reader = AvroReader { data, position: 0 };
+    if reader.read_bytes(MAGIC.len())? != MAGIC {
+        return Err(avro_error("not an Avro object container"));
+    }
+    let metadata = reader.read_value(&Schema::Map(Box::new(Schema::Bytes)), &HashMap::new())?;
+    let metadata_bytes = |key: &str| -> Option<Vec<u8>> {
+        metadata.get(key).and_then(|v| v.as_array()).map(|a| {
+            a.iter()
+                .filter_map(|b| b.as_u64().map(|b| b as u8))
+                .collect()
+        })
+    };
+    let codec = metadata_bytes("avro.codec").unwrap_or_else(|| b"null".to_vec());
+    if codec != b"null" {
+        return Err(avro_error(&format!(
+            "codec {} is not supported",
+            String::from_utf8_lossy(&codec)
+        )));
+    }
+    let schema_json: Value = metadata_bytes("avro.schema")
+        .ok_or_else(|| avro_error("missing avro.schema"))
+        .and_then(|s| serde_json::from_slice(&s).map_err(|e| avro_error(&e.to_string())))?;
+    let mut named: HashMap<String, Schema> = HashMap::new();
+    let schema = Schema::parse(&schema_json, &mut named)?;
+    let sync_marker = reader.read_bytes(SYNC_MARKER_SIZE)?.to_vec();
+
+    let mut records: Vec<Value> = vec![];
+    while reader.position < data.len() {
+        let count = reader.read_long()?;
+        let _size = reader.read_long()?;
+        for _ in 0..count {
+            records.push(reader.read_value(&schema, &named)?);
+        }
+        if reader.read_bytes(SYNC_MARKER_SIZE)? != sync_marker.as_slice() {
+            return Err(avro_error("invalid sync marker"));
+        }
+    }
+    Ok(records)
+}
+
+fn avro_error(message: &str) -> SPOError {
+    SPOError::new(&format!("Avro error: {}", message))
+}
+
+#[derive(Debug, Clone)]
+enum Schema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record(Vec<(String, Schema)>),
+    Enum(Vec<String>),
+    Array(Box<Schema>),
+    Map(Box<Schema>),
+    Union(Vec<Schema>),
+    Fixed(usize),
+    /// Named type defined earlier in the schema
+    Reference(String),
+}
+
+impl Schema {
+    fn parse(json: &Value, named: &mut HashMap<String, Schema>) -> Result<Schema, SPOError> {
+        match json {
+            Value::String(name) => Schema::parse_name(name, named),
+            Value::Array(branches) => Ok(Schema::Union(
+                branches
+                    .iter()
+                    .map(|b| Schema::parse(b, named))
+                    .collect::<Result<_, _>>()?,
+            )),
+            Value::Object(object) => {
+                let type_name = object
+                    .get("type")
+                    .ok_or_else(|| avro_error("schema without type"))?;
+                let schema = match type_name.as_str() {
+                    Some("record") | Some("error") => {
+                        let fields = object
+                            .get("fields")
+                            .and_then(|f| f.as_array())
+                            .ok_or_else(|| avro_error("record without fields"))?;
+                        let mut record_fields = vec![];
+                        for field in fields {
+                            let name = field["name"].as_str().unwrap_or_default().to_string();
+                            record_fields.push((name, Schema::parse(&field["type"], named)?));
+                        }
+                        Schema::Record(record_fields)
+                    }
+                    Some("enum") => Schema::Enum(
+                        object
+                            .get("symbols")
+                            .and_then(|s| s.as_array())
+                            .map(|s| {
+                                s.iter()
+                                    .map(|v| v.as_str().unwrap_or_default().to_string())
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                    ),
+                    Some("array") => Schema::Array(Box::new(Schema::parse(
+                        object.get("items").unwrap_or(&Value::Null),
+                        named,
+                    )?)),
+                    Some("map") => Schema::Map(Box::new(Schema::parse(
+                        object.get("values").unwrap_or(&Value::Null),
+                        named,
+                    )?)),
+                    Some("fixed") => Schema::Fixed(
+                        object.get("size").and_then(|s| s.as_u64()).unwrap_or(0) as usize,
+                    ),
+                    // primitive with attributes ex. logical types
+                    _ => Schema::parse(type_name, named)?,
+                };
+                if let Some(name) = object.get("name").and_then(|n| n.as_str()) {
+                    named.insert(name.to_string(), schema.clone());
+                    let short_name = name.rsplit('.').next().unwrap_or(name);
+                    named.insert(short_name.to_string(), schema.clone());
+                    if let Some(namespace) = object.get("namespace").and_then(|n| n.as_str()) {
+                        named.insert(format!("{}.{}", namespace, short_name), schema.clone());
+                    }
+                }
+                Ok(schema)
+            }
+            _ => Err(avro_error(&format!("invalid schema {}", json))),
+        }
+    }
+    fn parse_name(name: &str, named: &HashMap<String, Schema>) -> Result<Schema, SPOError> {
+        Ok(match name {
+            "null" => Schema::Null,
+            "boolean" => Schema::Boolean,
+            "int" => Schema::Int,
+            "long" => Schema::Long,
+            "float" => Schema::Float,
+            "double" => Schema::Double,
+            "bytes" => Schema::Bytes,
+            "string" => Schema::String,
+            _ if named.contains_key(name) => Schema::Reference(name.to_string()),
+            _ => return Err(avro_error(&format!("unknown type {}", name))),
+        })
+    }
+}
+
+struct AvroReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> AvroReader<'a> {
+    fn read_bytes(&mut self, size: usize) -> Result<&'a [u8], SPOError> {
+        let end = self
+            .position
+            .checked_add(size)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| avro_error("unexpected end of data"))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+    //
+    //  Variable length zig-zag encoding, int and long
+    //
+    fn read_long(&mut self) -> Result<i64, SPOError> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_bytes(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            if shift > 63 {
+                return Err(avro_error("invalid long"));
+            }
+        }
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+    fn read_length(&mut self) -> Result<usize, SPOError> {
+        usize::try_from(self.read_long()?).map_err(|_| avro_error("negative length"))
+    }
+    //
+    //  Array and map blocks, a negative count is followed by the block size in bytes
+    //
+    fn read_block_count(&mut self) -> Result<usize, SPOError> {
+        let count = self.read_long()?;
+        if count < 0 {
+            self.read_long()?;
+        }
+        Ok(count.unsigned_abs() as usize)
+    }
+    fn read_value(
+        &mut self,
+        schema: &Schema,
+        named: &HashMap<String, Schema>,
+    ) -> Result<Value, SPOError> {
+        Ok(match schema {
+            Schema::Null => Value::Null,
+            Schema::Boolean => Value::Bool(self.read_bytes(1)?[0] != 0),
+            Schema::Int | Schema::Long => Value::Number(self.read_long()?.into()),
+            Schema::Float => {
+                let bytes: [u8; 4] = self.read_bytes(4)?.try_into().unwrap();
+                float_value(f32::from_le_bytes(bytes) as f64)
+            }
+            Schema::Double => {
+                let bytes: [u8; 8] = self.read_bytes(8)?.try_into().unwrap();
+                float_value(f64::from_le_bytes(bytes))
+            }
+            Schema::Bytes => {
+                let size = self.read_length()?;
+                bytes_value(self.read_bytes(size)?)
+            }
+            Schema::String => {
+                let size = self.read_length()?;
+                Value::String(String::from_utf8_lossy(self.read_bytes(size)?).to_string())
+            }
+            Schema::Record(fields) => {
+                let mut object = Map::new();
+                for (name, field_schema) in fields {
+                    object.insert(name.to_owned(), self.read_value(field_schema, named)?);
+                }
+                Value::Object(object)
+            }
+            Schema::Enum(symbols) => {
+                let index = self.read_length()?;
+                Value::String(
+                    symbols
+                        .get(index)
+                        .ok_or_else(|| avro_error("invalid enum index"))?
+                        .to_owned(),
+                )
+            }
+            Schema::Array(items) => {
+                let mut values = vec![];
+                loop {
+                    let count = self.read_block_count()?;
+                    if count == 0 {
+                        break;
+                    }
+                    for _ in 0..count {
+                        values.push(self.read_value(items, named)?);
+                    }
+                }
+                Value::Array(values)
+            }
+            Schema::Map(values) => {
+                let mut object = Map::new();
+                loop {
+                    let count = self.read_block_count()?;
+                    if count == 0 {
+                        break;
+                    }
+                    for _ in 0..count {
+                        let size = self.read_length()?;
+                        let key = String::from_utf8_lossy(self.read_bytes(size)?).to_string();
+                        object.insert(key, self.read_value(values, named)?);
+                    }
+                }
+                Value::Object(object)
+            }
+            Schema::Union(branches) => {
+                let index = self.read_length()?;
+                let branch = branches
+                    .get(index)
+                    .ok_or_else(|| avro_error("invalid union index"))?;
+                self.read_value(branch, named)?
+            }
+            Schema::Fixed(size) => bytes_value(self.read_bytes(*size)?),
+            Schema::Reference(name) => {
+                let schema = named
+                    .get(name)
+                    .ok_or_else(|| avro_error(&format!("unknown type {}", name)))?;
+                self.read_value(schema, named)?
+            }
+        })
+    }
+}
+
+fn float_value(value: f64) -> Value {
+    Number::from_f64(value)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+fn bytes_value(bytes: &[u8]) -> Value {
+    Value::Array(bytes.iter().map(|b| Value::from(*b)).collect())
+}
+
+//
+//  Writer for test fixtures
+//
+#[cfg(test)]
+pub(crate) mod fixture {
+    pub fn long(value: i64) -> Vec<u8> {
+        let mut n = ((value << 1) ^ (value >> 63)) as u64;
+        let mut bytes = vec![];
+        loop {
+            if n & !0x7f == 0 {
+                bytes.push(n as u8);
+                return bytes;
+            }
+            bytes.push((n & 0x7f | 0x80) as u8);
+            n >>= 7;
+        }
+    }
+    pub fn string(value: &str) -> Vec<u8> {
+        let mut bytes = long(value.len() as i64);
+        bytes.extend(value.as_bytes());
+        bytes
+    }
+    //
+    //  Object container with one block of already encoded records
+    //
+    pub fn container(schema: &str, records: &[Vec<u8>]) -> Vec<u8> {
+        let sync_marker = [7u8; 16];
+        let mut bytes = super::MAGIC.to_vec();
+        bytes.extend(long(2));
+        bytes.extend(string("avro.schema"));
+        bytes.extend(string(schema));
+        bytes.extend(string("avro.codec"));
+        bytes.extend(string("null"));
+        bytes.extend(long(0));
+        bytes.extend(sync_marker);
+        let block: Vec<u8> = records.concat();
+        bytes.extend(long(records.len() as i64));
+        bytes.extend(long(block.len() as i64));
+        bytes.extend(block);
+        bytes.extend(sync_marker);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::fixture::*;
+    use super::*;
+
+    #[test]
+    fn read_records_with_union_enum_and_map() {
+        let schema = r#"{"type":"record","name":"Event","fields":[
+            {"name":"id","type":"long"},
+            {"name":"kind","type":{"type":"enum","name":"Kind","symbols":["Created","Deleted"]}},
+            {"name":"url","type":["null","string"]},
+            {"name":"tags","type":{"type":"map","values":"string"}},
+            {"name":"previous","type":["null","Kind"]}
+        ]}"#;
+        let record = [
+            long(-42),
+            long(1),
+            long(1),
+            string("https://a/b"),
+            long(1),
+            string("k"),
+            string("v"),
+            long(0),
+            long(0),
+        ]
+        .concat();
+        let records = read_avro_container(&container(schema, &[record])).unwrap();
+        assert_eq!(
+            records,
+            vec![json!({
+                "id": -42,
+                "kind": "Deleted",
+                "url": "https://a/b",
+                "tags": {"k": "v"},
+                "previous": null
+            })]
+        );
+        assert!(read_avro_container(b"not avro").is_err());
+    }
+}
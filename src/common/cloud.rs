@@ -0,0 +1,406 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use azure_storage::CloudLocation;
+use log::error;
+
+use crate::spo::spo_engine::SPOError;
+
+pub const CLOUD_KEY: &str = "SPO_CLOUD";
+pub const CLOUD_SHARE_POINT_SUFFIX_KEY: &str = "SPO_CLOUD_SHARE_POINT_SUFFIX";
+pub const CLOUD_SHARE_POINT_PRINCIPAL_KEY: &str = "SPO_CLOUD_SHARE_POINT_PRINCIPAL";
+pub const CLOUD_ACS_HOST_KEY: &str = "SPO_CLOUD_ACS_HOST";
+pub const CLOUD_AUTHORITY_HOST_KEY: &str = "SPO_CLOUD_AUTHORITY_HOST";
+pub const CLOUD_GRAPH_HOST_KEY: &str = "SPO_CLOUD_GRAPH_HOST";
+pub const CLOUD_STORAGE_SUFFIX_KEY: &str = "SPO_CLOUD_STORAGE_SUFFIX";
+pub const CLOUD_KEY_VAULT_SUFFIX_KEY: &str = "SPO_CLOUD_KEY_VAULT_SUFFIX";
+
+// Share Point Online application principal, the same in every cloud
+const SHARE_POINT_PRINCIPAL: &str = "00000003-0000-0ff1-ce00-000000000000";
+
+//
+//  Endpoints of the process, set once at start up from --cloud or SPO_CLOUD
+//
+static CLOUD: OnceLock<CloudEndpoints> = OnceLock::new();
+
+//
+//  Microsoft 365 / Azure cloud of the tenant
+//
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CloudEnvironment {
+    #[default]
+    Public,
+    /// GCC, Microsoft 365 and Azure in the commercial cloud
+    USGov,
+    /// GCC High
+    USGovHigh,
+    DoD,
+    /// Operated by 21Vianet
+    China,
+    /// Public endpoints replaced by the SPO_CLOUD_* settings
+    Custom,
+}
+
+impl FromStr for CloudEnvironment {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "public" | "" => Ok(CloudEnvironment::Public),
+            "usgov" | "gcc" => Ok(CloudEnvironment::USGov),
+            "usgovhigh" | "gcchigh" => Ok(CloudEnvironment::USGovHigh),
+            "dod" => Ok(CloudEnvironment::DoD),
+            "china" => Ok(CloudEnvironment::China),
+            "custom" => Ok(CloudEnvironment::Custom),
+            other => Err(format!(
+                "unknown cloud {}, expect public, usgov, usgovhigh, dod, china or custom",
+                other
+            )),
+        }
+    }
+}
+
+impl CloudEnvironment {
+    pub fn endpoints(&self) -> CloudEndpoints {
+        let (
+            share_point_suffix,
+            acs_host,
+            authority_host,
+            graph_host,
+            storage_suffix,
+            key_vault_suffix,
+        ) = match self {
+            CloudEnvironment::Public | CloudEnvironment::USGov | CloudEnvironment::Custom => (
+                "sharepoint.com",
+                "accounts.accesscontrol.windows.net",
+                "login.microsoftonline.com",
+                "graph.microsoft.com",
+                "core.windows.net",
+                "vault.azure.net",
+            ),
+            CloudEnvironment::USGovHigh => (
+                "sharepoint.us",
+                "login.microsoftonline.us",
+                "login.microsoftonline.us",
+                "graph.microsoft.us",
+                "core.usgovcloudapi.net",
+                "vault.usgovcloudapi.net",
+            ),
+            CloudEnvironment::DoD => (
+                "sharepoint-mil.us",
+                "login.microsoftonline.us",
+                "login.microsoftonline.us",
+                "dod-graph.microsoft.us",
+                "core.usgovcloudapi.net",
+                "vault.usgovcloudapi.net",
+            ),
+            CloudEnvironment::China => (
+                "sharepoint.cn",
+                "accounts.accesscontrol.chinacloudapi.cn",
+                "login.chinacloudapi.cn",
+                "microsoftgraph.chinacloudapi.cn",
+                "core.chinacloudapi.cn",
+                "vault.azure.cn",
+            ),
+        };
+        CloudEndpoints {
+            environment: *self,
+            share_point_suffix: share_point_suffix.to_string(),
+            share_point_principal: SHARE_POINT_PRINCIPAL.to_string(),
+            acs_host: acs_host.to_string(),
+            authority_host: authority_host.to_string(),
+            graph_host: graph_host.to_string(),
+            storage_suffix: storage_suffix.to_string(),
+            key_vault_suffix: key_vault_suffix.to_string(),
+        }
+    }
+}
+
+//
+//  Host names of a cloud, each can be overridden by its SPO_CLOUD_* setting
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloudEndpoints {
+    pub environment: CloudEnvironment,
+    /// [share_point_domain].[share_point_suffix] ex. sharepoint.us, or a custom domain
+    pub share_point_suffix: String,
+    pub share_point_principal: String,
+    /// Share Point app-only token host (Azure ACS)
+    pub acs_host: String,
+    /// Azure AD host of the Storage Account, Key Vault and Graph identity
+    pub authority_host: String,
+    pub graph_host: String,
+    /// [account].blob.[storage_suffix]
+    pub storage_suffix: String,
+    /// [vault].[key_vault_suffix]
+    pub key_vault_suffix: String,
+}
+
+impl Default for CloudEndpoints {
+    fn default() -> Self {
+        CloudEnvironment::Public.endpoints()
+    }
+}
+
+impl CloudEndpoints {
+    //
+    //  SPO_CLOUD with the SPO_CLOUD_* overrides, public when not set
+    //
+    pub fn from_env() -> Result<CloudEndpoints, SPOError> {
+        let environment = match std::env::var(CLOUD_KEY) {
+            Ok(value) => value
+                .parse::<CloudEnvironment>()
+                .map_err(|e| SPOError::new(&format!("Invalid {}: {}", CLOUD_KEY, e)))?,
+            Err(_) => CloudEnvironment::Public,
+        };
+        CloudEndpoints::from_settings(environment, |key| {
+            std::env::var(key).ok().filter(|v| !v.trim().is_empty())
+        })
+    }
+
+    //
+    //  Endpoints of the environment with the overrides found by lookup,
+    //  a custom cloud requires at least its Share Point suffix
+    //
+    pub fn from_settings(
+        environment: CloudEnvironment,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<CloudEndpoints, SPOError> {
+        let mut endpoints = environment.endpoints();
+        let overrides = [
+            (
+                CLOUD_SHARE_POINT_SUFFIX_KEY,
+                &mut endpoints.share_point_suffix,
+            ),
+            (
+                CLOUD_SHARE_POINT_PRINCIPAL_KEY,
+                &mut endpoints.share_point_principal,
+            ),
+            (CLOUD_ACS_HOST_KEY, &mut endpoints.acs_host),
+            (CLOUD_AUTHORITY_HOST_KEY, &mut endpoints.authority_host),
+            (CLOUD_GRAPH_HOST_KEY, &mut endpoints.graph_host),
+            (CLOUD_STORAGE_SUFFIX_KEY, &mut endpoints.storage_suffix),
+            (CLOUD_KEY_VAULT_SUFFIX_KEY, &mut endpoints.key_vault_suffix),
+        ];
+        let mut share_point_suffix_set = false;
+        for (key, value) in overrides {
+            if let Some(v) = lookup(key) {
+                share_point_suffix_set |= key == CLOUD_SHARE_POINT_SUFFIX_KEY;
+                *value = v.trim().trim_start_matches('.').to_string();
+            }
+        }
+        if environment == CloudEnvironment::Custom && !share_point_suffix_set {
+            return Err(SPOError::new(&format!(
+                "{} is required for the custom cloud",
+                CLOUD_SHARE_POINT_SUFFIX_KEY
+            )));
+        }
+        Ok(endpoints)
+    }
+
+    pub fn share_point_host(&self, share_point_domain: &str) -> String {
+        format!("{}.{}", share_point_domain, self.share_point_suffix)
+    }
+
+    //
+    //  Share point domain of a host, None for a host of another cloud
+    //
+    pub fn share_point_domain<'a>(&self, host: &'a str) -> Option<&'a str> {
+        host.strip_suffix(&self.share_point_suffix)
+            .and_then(|d| d.strip_suffix('.'))
+            .filter(|d| !d.is_empty() && !d.contains('.'))
+    }
+
+    pub fn acs_token_url(&self, tenant_id: &str) -> String {
+        format!("https://{}/{}/tokens/OAuth/2", self.acs_host, tenant_id)
+    }
+
+    //
+    //  Resource of the app-only token, [principal]/[share point host]@[tenant]
+    //
+    pub fn share_point_resource(&self, share_point_domain: &str, tenant_id: &str) -> String {
+        format!(
+            "{}/{}@{}",
+            self.share_point_principal,
+            self.share_point_host(share_point_domain),
+            tenant_id
+        )
+    }
+
+    pub fn authority_url(&self) -> String {
+        format!("https://{}", self.authority_host)
+    }
+
+    pub fn graph_url(&self) -> String {
+        format!("https://{}/v1.0", self.graph_host)
+    }
+
+    //
+    //  Client credentials token endpoint and scope of Microsoft Graph
+    //
+    pub fn graph_token_url(&self, tenant_id: &str) -> String {
+        format!(
+            "https://{}/{}/oauth2/v2.0/token",
+            self.authority_host, tenant_id
+        )
+    }
+
+    pub fn graph_scope(&self) -> String {
+        format!("https://{}/.default", self.graph_host)
+    }
+
+    pub fn storage_location(&self, account: &str) -> CloudLocation {
+        match self.storage_suffix.as_str() {
+            "core.windows.net" => CloudLocation::Public {
+                account: account.to_string(),
+            },
+            "core.chinacloudapi.cn" => CloudLocation::China {
+                account: account.to_string(),
+            },
+            suffix => CloudLocation::Custom {
+                uri: format!("https://{}.blob.{}", account, suffix),
+            },
+        }
+    }
+
+    pub fn key_vault_url(&self, vault: &str) -> String {
+        format!("https://{}.{}", vault, self.key_vault_suffix)
+    }
+
+    pub fn key_vault_resource(&self) -> String {
+        format!("https://{}", self.key_vault_suffix)
+    }
+}
+
+//
+//  Set the endpoints of the process before the first call, later calls are ignored
+//
+pub fn init_cloud(endpoints: CloudEndpoints) {
+    if CLOUD.set(endpoints).is_err() {
+        error!("Cloud endpoints are already set");
+    }
+}
+
+//
+//  Endpoints of the process, read from the environment when init_cloud was not called.
+//  Invalid cloud settings panic, the hosts of another cloud would receive the credentials
+//
+pub fn cloud() -> &'static CloudEndpoints {
+    CLOUD.get_or_init(|| CloudEndpoints::from_env().unwrap_or_else(|e| panic!("{}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints_of_clouds() {
+        let public = CloudEndpoints::default();
+        assert_eq!(
+            public.acs_token_url("t1"),
+            "https://accounts.accesscontrol.windows.net/t1/tokens/OAuth/2"
+        );
+        assert_eq!(
+            public.share_point_resource("contoso", "t1"),
+            "00000003-0000-0ff1-ce00-000000000000/contoso.sharepoint.com@t1"
+        );
+        assert!(matches!(
+            public.storage_location("blobs"),
+            CloudLocation::Public { .. }
+        ));
+
+        // GCC tenants use the commercial endpoints
+        let gcc = "GCC".parse::<CloudEnvironment>().unwrap().endpoints();
+        assert_eq!(gcc.environment, CloudEnvironment::USGov);
+        assert_eq!(
+            CloudEndpoints {
+                environment: CloudEnvironment::Public,
+                ..gcc.clone()
+            },
+            public
+        );
+        assert_eq!(gcc.share_point_host("contoso"), "contoso.sharepoint.com");
+        assert_eq!(
+            gcc.acs_token_url("t1"),
+            "https://accounts.accesscontrol.windows.net/t1/tokens/OAuth/2"
+        );
+        assert_eq!(gcc.authority_url(), "https://login.microsoftonline.com");
+        assert_eq!(gcc.graph_scope(), "https://graph.microsoft.com/.default");
+        assert!(matches!(
+            gcc.storage_location("blobs"),
+            CloudLocation::Public { .. }
+        ));
+
+        let high = "GCCHigh".parse::<CloudEnvironment>().unwrap().endpoints();
+        assert_eq!(high.environment, CloudEnvironment::USGovHigh);
+        assert_eq!(high.share_point_host("contoso"), "contoso.sharepoint.us");
+        assert_eq!(
+            high.acs_token_url("t1"),
+            "https://login.microsoftonline.us/t1/tokens/OAuth/2"
+        );
+        assert_eq!(high.authority_url(), "https://login.microsoftonline.us");
+        assert_eq!(high.graph_scope(), "https://graph.microsoft.us/.default");
+        assert!(matches!(
+            high.storage_location("blobs"),
+            CloudLocation::Custom { uri } if uri == "https://blobs.blob.core.usgovcloudapi.net"
+        ));
+        assert_eq!(
+            high.share_point_domain("contoso.sharepoint.us"),
+            Some("contoso")
+        );
+        assert_eq!(high.share_point_domain("contoso.sharepoint.com"), None);
+
+        let dod = "DoD".parse::<CloudEnvironment>().unwrap().endpoints();
+        assert_eq!(dod.environment, CloudEnvironment::DoD);
+        assert_eq!(dod.share_point_host("contoso"), "contoso.sharepoint-mil.us");
+        assert_eq!(
+            dod.share_point_domain("contoso.sharepoint-mil.us"),
+            Some("contoso")
+        );
+        assert_eq!(
+            dod.acs_token_url("t1"),
+            "https://login.microsoftonline.us/t1/tokens/OAuth/2"
+        );
+        assert_eq!(dod.graph_url(), "https://dod-graph.microsoft.us/v1.0");
+        assert!(matches!(
+            dod.storage_location("blobs"),
+            CloudLocation::Custom { uri } if uri == "https://blobs.blob.core.usgovcloudapi.net"
+        ));
+
+        let china = CloudEnvironment::China.endpoints();
+        assert_eq!(china.share_point_host("contoso"), "contoso.sharepoint.cn");
+        assert_eq!(china.key_vault_url("kv"), "https://kv.vault.azure.cn");
+        assert!(matches!(
+            china.storage_location("blobs"),
+            CloudLocation::China { .. }
+        ));
+
+        assert!("mars".parse::<CloudEnvironment>().is_err());
+        assert!("dps.mil".parse::<CloudEnvironment>().is_err());
+    }
+
+    #[test]
+    fn custom_cloud_overrides() {
+        let settings = |key: &str| match key {
+            CLOUD_SHARE_POINT_SUFFIX_KEY => Some(String::from(".sharepoint.example")),
+            CLOUD_STORAGE_SUFFIX_KEY => Some(String::from("core.example")),
+            _ => None,
+        };
+        let custom = CloudEndpoints::from_settings(CloudEnvironment::Custom, settings).unwrap();
+        assert_eq!(
+            custom.share_point_host("contoso"),
+            "contoso.sharepoint.example"
+        );
+        assert_eq!(custom.acs_host, "accounts.accesscontrol.windows.net");
+        assert!(matches!(
+            custom.storage_location("blobs"),
+            CloudLocation::Custom { uri } if uri == "https://blobs.blob.core.example"
+        ));
+
+        assert!(CloudEndpoints::from_settings(CloudEnvironment::Custom, |_| None).is_err());
+        // overrides also apply to a known cloud
+        let china = CloudEndpoints::from_settings(CloudEnvironment::China, settings).unwrap();
+        assert_eq!(china.share_point_suffix, "sharepoint.example");
+        assert_eq!(china.authority_host, "login.chinacloudapi.cn");
+    }
+}
@@ -0,0 +1,16 @@
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::spo::spo_engine::SPOError;
+
+//
+//  RFC 3339 timestamps are used for CLI options, request fields, blob version ids and state files
+//
+pub fn parse_datetime(value: &str) -> Result<OffsetDateTime, SPOError> {
+    OffsetDateTime::parse(value, &Rfc3339)
+        .map_err(|e| SPOError::new(&format!("Invalid RFC 3339 timestamp {}: {}", value, e)))
+}
+
+pub fn format_datetime(value: &OffsetDateTime) -> String {
+    value.format(&Rfc3339).unwrap_or_default()
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use azure_core::auth::TokenCredential;
+use log::debug;
+use oauth2::http::HeaderMap;
+use reqwest::Client;
+use serde::Deserialize;
+use url::Url;
+
+use crate::common::cloud::cloud;
+use crate::common::http_client::{new_azure_credential, new_managed_identity_credential};
+use crate::common::secret::Secret;
+use crate::spo::spo_engine::SPOError;
+
+pub const KEY_VAULT_ENDPOINT_KEY: &str = "SPO_KEY_VAULT_ENDPOINT";
+/// Vaults of the references found in function requests, comma separated vault names
+pub const KEY_VAULT_NAMES_KEY: &str = "SPO_KEY_VAULT_NAMES";
+/// Seconds a resolved secret is reused before it is read again, so rotated secrets are picked up
+pub const KEY_VAULT_CACHE_SECS_KEY: &str = "SPO_KEY_VAULT_CACHE_SECS";
+const DEFAULT_KEY_VAULT_CACHE_SECS: u64 = 300;
+
+const KEY_VAULT_API_VERSION: &str = "7.4";
+
+//
+//  Resolved secrets with the time they were read, keyed by the reference text
+//
+static SECRET_CACHE: OnceLock<Mutex<HashMap<String, (Secret, Instant)>>> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyVaultReference {
+    pub vault_name: String,
+    /// https://[vault_name].[key_vault_suffix] of the cloud
+    pub vault_url: String,
+    pub secret_name: String,
+    pub secret_version: Option<String>,
+}
+
+impl KeyVaultReference {
+    //
+    //  Accept @Microsoft.KeyVault(SecretUri=...), @Microsoft.KeyVault(VaultName=..;SecretName=..)
+    //  and kv://vault/secret[/version]. The vault must be a vault of the cloud, the token is sent to it
+    //
+    pub fn parse(value: &str) -> Option<KeyVaultReference> {
+        let value = value.trim();
+        if let Some(rest) = value.strip_prefix("kv://") {
+            let mut parts = rest.trim_end_matches('/').split('/');
+            let vault = parts.next().filter(|v| !v.is_empty())?;
+            let secret_name = parts.next().filter(|v| !v.is_empty())?;
+            return KeyVaultReference::new(vault, secret_name, parts.next());
+        }
+        let inner = value
+            .strip_prefix("@Microsoft.KeyVault(")?
+            .strip_suffix(')')?;
+        let mut settings: HashMap<String, String> = HashMap::new();
+        for setting in inner.split(';') {
+            if let Some((k, v)) = setting.split_once('=') {
+                settings.insert(k.trim().to_lowercase(), v.trim().to_string());
+            }
+        }
+        if let Some(secret_uri) = settings.get("secreturi") {
+            // https://{vault}.vault.azure.net/secrets/{name}/{version}
+            let url = Url::parse(secret_uri).ok()?;
+            if url.scheme() != "https" || url.port().is_some() {
+                return None;
+            }
+            let vault = url
+                .host_str()?
+                .strip_suffix(&cloud().key_vault_suffix)?
+                .strip_suffix('.')?;
+            let path = url.path().strip_prefix("/secrets/")?;
+            let mut parts = path.trim_end_matches('/').split('/');
+            let secret_name = parts.next().filter(|v| !v.is_empty())?;
+            return KeyVaultReference::new(vault, secret_name, parts.next());
+        }
+        KeyVaultReference::new(
+            settings.get("vaultname")?,
+            settings.get("secretname")?,
+            settings.get("secretversion").map(|v| v.as_str()),
+        )
+    }
+
+    //
+    //  Reference text of the three forms, resolved or rejected but never used as a plain value
+    //
+    pub fn is_reference(value: &str) -> bool {
+        let value = value.trim();
+        value.starts_with("kv://") || value.starts_with("@Microsoft.KeyVault(")
+    }
+
+    fn new(
+        vault: &str,
+        secret_name: &str,
+        secret_version: Option<&str>,
+    ) -> Option<KeyVaultReference> {
+        if !is_vault_name(vault) || secret_name.is_empty() {
+            return None;
+        }
+        Some(KeyVaultReference {
+            vault_name: vault.to_lowercase(),
+            vault_url: cloud().key_vault_url(&vault.to_lowercase()),
+            secret_name: secret_name.to_string(),
+            secret_version: secret_version
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string()),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyVaultSecretResponse {
+    value: Secret,
+}
+
+//
+//  Resolve Key Vault references with an Azure identity.
+//  Plain values are returned as they are.
+//
+#[derive(Clone)]
+pub struct KeyVaultResolver {
+    http_client: Client,
+    credential: Option<Arc<dyn TokenCredential>>,
+    endpoint: Option<String>,
+    cache_ttl: Duration,
+    /// Vaults the references may name, any vault of the cloud when None
+    allowed_vaults: Option<Vec<String>>,
+}
+
+impl KeyVaultResolver {
+    //
+    //  Ambient identity: AZURE_* variables, managed identity, Azure CLI
+    //
+    pub fn new(http_client: &Client) -> KeyVaultResolver {
+        KeyVaultResolver::with_credential(http_client, new_azure_credential(http_client))
+    }
+    //
+    //  Managed identity only, the AZURE_* variables are not looked at
+    //
+    pub fn with_managed_identity(http_client: &Client) -> KeyVaultResolver {
+        KeyVaultResolver::with_credential(http_client, new_managed_identity_credential(http_client))
+    }
+    fn with_credential(
+        http_client: &Client,
+        credential: Arc<dyn TokenCredential>,
+    ) -> KeyVaultResolver {
+        match std::env::var(KEY_VAULT_ENDPOINT_KEY) {
+            Ok(endpoint) if !endpoint.is_empty() => {
+                KeyVaultResolver::with_endpoint(http_client, &endpoint)
+            }
+            _ => KeyVaultResolver {
+                http_client: http_client.clone(),
+                credential: Some(credential),
+                endpoint: None,
+                cache_ttl: cache_ttl_from_env(),
+                allowed_vaults: None,
+            },
+        }
+    }
+    //
+    //  Send every lookup to a local (mock) vault without authentication
+    //
+    #[allow(clippy::ptr_arg)]
+    pub fn with_endpoint(http_client: &Client, endpoint: &String) -> KeyVaultResolver {
+        KeyVaultResolver {
+            http_client: http_client.clone(),
+            credential: None,
+            endpoint: Some(endpoint.trim_end_matches('/').to_string()),
+            cache_ttl: cache_ttl_from_env(),
+            allowed_vaults: None,
+        }
+    }
+    pub fn set_cache_ttl(&mut self, cache_ttl: Duration) -> KeyVaultResolver {
+        self.cache_ttl = cache_ttl;
+        self.to_owned()
+    }
+    //
+    //  Only resolve references to these vaults
+    //
+    pub fn set_allowed_vaults(&mut self, vaults: &[String]) -> KeyVaultResolver {
+        self.allowed_vaults = Some(vaults.iter().map(|v| v.to_lowercase()).collect());
+        self.to_owned()
+    }
+    pub async fn resolve(&self, value: &String) -> Result<Secret, SPOError> {
+        let reference = match KeyVaultReference::parse(value) {
+            Some(r) => r,
+            None if KeyVaultReference::is_reference(value) => {
+                return Err(SPOError::new(&format!(
+                    "Invalid Key Vault reference, expect a vault name or https://[vault].{}",
+                    cloud().key_vault_suffix
+                )))
+            }
+            None => return Ok(Secret::new(value)),
+        };
+        if let Some(allowed_vaults) = &self.allowed_vaults {
+            if !allowed_vaults.contains(&reference.vault_name) {
+                return Err(SPOError::new(&format!(
+                    "Key Vault {} is not listed in {}",
+                    reference.vault_name, KEY_VAULT_NAMES_KEY
+                )));
+            }
+        }
+        let cache = SECRET_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+        let cache_key = format!("{}|{}", self.endpoint.clone().unwrap_or_default(), value);
+        if let Some((secret, read_at)) = cache.lock().unwrap().get(&cache_key) {
+            if read_at.elapsed() < self.cache_ttl {
+                debug!("Key Vault secret {} from cache", reference.secret_name);
+                return Ok(secret.clone());
+            }
+        }
+        let secret = self.get_secret(&reference).await?;
+        cache
+            .lock()
+            .unwrap()
+            .insert(cache_key, (secret.clone(), Instant::now()));
+        Ok(secret)
+    }
+    //
+    //  Resolve an optional value, keeping None as None
+    //
+    pub async fn resolve_option(&self, value: &Option<String>) -> Result<Option<Secret>, SPOError> {
+        match value {
+            Some(v) => self.resolve(v).await.map(Some),
+            None => Ok(None),
+        }
+    }
+    async fn get_secret(&self, reference: &KeyVaultReference) -> Result<Secret, SPOError> {
+        let vault_url = self.endpoint.clone().unwrap_or(reference.vault_url.clone());
+        let url = match &reference.secret_version {
+            Some(version) => format!(
+                "{}/secrets/{}/{}?api-version={}",
+                vault_url, reference.secret_name, version, KEY_VAULT_API_VERSION
+            ),
+            None => format!(
+                "{}/secrets/{}?api-version={}",
+                vault_url, reference.secret_name, KEY_VAULT_API_VERSION
+            ),
+        };
+        debug!("Get Key Vault secret with url : {:?}", url);
+
+        let mut headers = HeaderMap::new();
+        if let Some(credential) = &self.credential {
+            let token = credential
+                .get_token(&cloud().key_vault_resource())
+                .await
+                .map_err(|e| SPOError::new(&format!("Key Vault token error: {}", e)))?;
+            headers.append(
+                "Authorization",
+                format!("Bearer {}", token.token.secret()).parse().unwrap(),
+            );
+        }
+        let res = self
+            .http_client
+            .get(url)
+            .headers(headers)
+            .send()
+            .await
+            .map_err(|e| SPOError::new(&format!("Key Vault request error: {}", e)))?;
+        if !res.status().is_success() {
+            return Err(SPOError::new(&format!(
+                "Key Vault secret {} error: {}",
+                reference.secret_name,
+                res.status()
+            )));
+        }
+        res.json::<KeyVaultSecretResponse>()
+            .await
+            .map(|r| r.value)
+            .map_err(|e| SPOError::new(&format!("Key Vault response error: {}", e)))
+    }
+}
+
+fn cache_ttl_from_env() -> Duration {
+    Duration::from_secs(
+        std::env::var(KEY_VAULT_CACHE_SECS_KEY)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_KEY_VAULT_CACHE_SECS),
+    )
+}
+
+//
+//  Vault names are 3 to 24 letters, digits and dashes
+//
+fn is_vault_name(vault: &str) -> bool {
+    (3..=24).contains(&vault.len()) && vault.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+//
+//  Vault names of the KEY_VAULT_NAMES_KEY setting
+//
+pub fn allowed_vaults_from_env() -> Vec<String> {
+    std::env::var(KEY_VAULT_NAMES_KEY)
+        .unwrap_or_default()
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde_json::json;
+    use warp::Filter;
+
+    use super::*;
+
+    #[test]
+    fn parse_secret_uri_reference() {
+        let r = KeyVaultReference::parse(
+            "@Microsoft.KeyVault(SecretUri=https://myvault.vault.azure.net/secrets/spo-secret/abc123)",
+        )
+        .unwrap();
+        assert_eq!(r.vault_url, "https://myvault.vault.azure.net");
+        assert_eq!(r.secret_name, "spo-secret");
+        assert_eq!(r.secret_version, Some("abc123".to_string()));
+    }
+
+    #[test]
+    fn parse_vault_name_reference() {
+        let r = KeyVaultReference::parse(
+            "@Microsoft.KeyVault(VaultName=myvault;SecretName=spo-secret)",
+        )
+        .unwrap();
+        assert_eq!(r.vault_url, "https://myvault.vault.azure.net");
+        assert_eq!(r.secret_name, "spo-secret");
+        assert_eq!(r.secret_version, None);
+    }
+
+    #[test]
+    fn parse_kv_scheme_reference() {
+        let r = KeyVaultReference::parse("kv://myvault/spo-secret").unwrap();
+        assert_eq!(r.vault_url, "https://myvault.vault.azure.net");
+        assert_eq!(r.secret_name, "spo-secret");
+        assert!(KeyVaultReference::parse("kv://myvault").is_none());
+        assert!(KeyVaultReference::parse("plain-secret").is_none());
+    }
+
+    #[test]
+    fn references_to_other_hosts_are_rejected() {
+        for value in [
+            "@Microsoft.KeyVault(SecretUri=https://attacker.example.com/secrets/spo-secret)",
+            "@Microsoft.KeyVault(SecretUri=http://myvault.vault.azure.net/secrets/spo-secret)",
+            "@Microsoft.KeyVault(SecretUri=https://myvault.vault.azure.net:8443/secrets/spo-secret)",
+            "@Microsoft.KeyVault(SecretUri=https://a.b.vault.azure.net/secrets/spo-secret)",
+            "@Microsoft.KeyVault(VaultName=https://attacker.example.com;SecretName=spo-secret)",
+            "kv://attacker.example.com/spo-secret",
+            "kv://http:/attacker/spo-secret",
+        ] {
+            assert!(KeyVaultReference::parse(value).is_none(), "{}", value);
+            assert!(KeyVaultReference::is_reference(value));
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_and_unlisted_vaults_are_not_resolved() {
+        static HITS: AtomicUsize = AtomicUsize::new(0);
+        let route = warp::path!("secrets" / String).map(|name: String| {
+            HITS.fetch_add(1, Ordering::SeqCst);
+            warp::reply::json(&json!({ "value": name }))
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral((Ipv4Addr::LOCALHOST, 0));
+        tokio::spawn(server);
+
+        let resolver = KeyVaultResolver::with_endpoint(&Client::new(), &format!("http://{}", addr))
+            .set_allowed_vaults(&[String::from("SPO-Vault")]);
+        assert!(resolver
+            .resolve(&String::from("kv://attacker.example.com/spo-secret"))
+            .await
+            .is_err());
+        assert!(resolver
+            .resolve(&String::from("kv://other-vault/spo-secret"))
+            .await
+            .is_err());
+        assert_eq!(HITS.load(Ordering::SeqCst), 0);
+        assert_eq!(
+            resolver
+                .resolve(&String::from("kv://spo-vault/listed"))
+                .await
+                .unwrap()
+                .expose(),
+            "listed"
+        );
+    }
+
+    #[tokio::test]
+    async fn resolve_from_mock_vault_with_cache() {
+        static HITS: AtomicUsize = AtomicUsize::new(0);
+        let route = warp::path!("secrets" / String).map(|name: String| {
+            HITS.fetch_add(1, Ordering::SeqCst);
+            warp::reply::json(&json!({ "value": format!("value-of-{}", name) }))
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral((Ipv4Addr::LOCALHOST, 0));
+        tokio::spawn(server);
+
+        let resolver = KeyVaultResolver::with_endpoint(&Client::new(), &format!("http://{}", addr));
+        let reference = String::from("kv://myvault/mock-secret");
+
+        let secret = resolver.resolve(&reference).await.unwrap();
+        assert_eq!(secret.expose(), "value-of-mock-secret");
+        let secret = resolver.resolve(&reference).await.unwrap();
+        assert_eq!(secret.expose(), "value-of-mock-secret");
+        assert_eq!(HITS.load(Ordering::SeqCst), 1);
+
+        let plain = resolver.resolve(&String::from("plain")).await.unwrap();
+        assert_eq!(plain.expose(), "plain");
+    }
+
+    #[tokio::test]
+    async fn expired_secret_is_read_again() {
+        static HITS: AtomicUsize = AtomicUsize::new(0);
+        let route = warp::path!("secrets" / String).map(|name: String| {
+            let hit = HITS.fetch_add(1, Ordering::SeqCst);
+            warp::reply::json(&json!({ "value": format!("{}-v{}", name, hit) }))
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral((Ipv4Addr::LOCALHOST, 0));
+        tokio::spawn(server);
+
+        let reference = String::from("kv://myvault/rotated");
+        let mut resolver =
+            KeyVaultResolver::with_endpoint(&Client::new(), &format!("http://{}", addr));
+        let resolver = resolver.set_cache_ttl(Duration::ZERO);
+        assert_eq!(
+            resolver.resolve(&reference).await.unwrap().expose(),
+            "rotated-v0"
+        );
+        assert_eq!(
+            resolver.resolve(&reference).await.unwrap().expose(),
+            "rotated-v1"
+        );
+    }
+}
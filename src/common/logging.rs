@@ -0,0 +1,25 @@
+use log::LevelFilter;
+
+//
+//  azure_core dumps every request at debug level, including the x-ms-encryption-key,
+//  Authorization headers and SAS query strings, so these modules never go below info
+//
+const SENSITIVE_MODULES: [&str; 2] = [
+    "azure_core::policies::transport",
+    "azure_core::http_client::reqwest",
+];
+
+//
+//  Same as pretty_env_logger::init() (RUST_LOG, default error), with sensitive modules capped
+//
+pub fn init_logger() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    match std::env::var("RUST_LOG") {
+        Ok(filters) => builder.parse_filters(&filters),
+        Err(_) => builder.filter_level(LevelFilter::Error),
+    };
+    for module in SENSITIVE_MODULES {
+        builder.filter_module(module, LevelFilter::Info);
+    }
+    builder.init();
+}
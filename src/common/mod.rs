@@ -0,0 +1,8 @@
+pub mod avro;
+pub mod cloud;
+pub mod datetime;
+pub mod http_client;
+pub mod key_vault;
+pub mod logging;
+pub mod secret;
+pub mod state_file;
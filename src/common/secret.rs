@@ -0,0 +1,93 @@
+use std::fmt::{Debug, Display, Formatter};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const REDACTED: &str = "***";
+
+//
+//  Credential or token value which is never printed, logged or serialized in clear text
+//
+#[derive(Default, Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: &String) -> Secret {
+        Secret(value.to_owned())
+    }
+    pub fn expose(&self) -> &String {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret({:?})", REDACTED)
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spo::spo_model::SPOTokenResponse;
+
+    const SECRET_VALUE: &str = "s3cr3t-v@lue";
+
+    #[test]
+    fn secret_is_redacted_in_debug_display_and_json() {
+        let secret = Secret::new(&SECRET_VALUE.to_string());
+
+        assert!(!format!("{:?}", secret).contains(SECRET_VALUE));
+        assert!(!format!("{:#?}", secret).contains(SECRET_VALUE));
+        assert!(!format!("{}", secret).contains(SECRET_VALUE));
+        assert!(!serde_json::to_string(&secret)
+            .unwrap()
+            .contains(SECRET_VALUE));
+        assert_eq!(secret.expose(), SECRET_VALUE);
+    }
+
+    #[test]
+    fn secret_deserializes_from_plain_string() {
+        let secret: Secret = serde_json::from_str(&format!("\"{}\"", SECRET_VALUE)).unwrap();
+        assert_eq!(secret.expose(), SECRET_VALUE);
+    }
+
+    #[test]
+    fn token_response_does_not_leak_access_token() {
+        let token: SPOTokenResponse = serde_json::from_value(serde_json::json!({
+            "token_type": "Bearer",
+            "expires_in": "3599",
+            "access_token": SECRET_VALUE,
+        }))
+        .unwrap();
+
+        assert_eq!(token.access_token.as_ref().unwrap().expose(), SECRET_VALUE);
+        assert!(!format!("{:#?}", token).contains(SECRET_VALUE));
+        assert!(!serde_json::to_string(&token)
+            .unwrap()
+            .contains(SECRET_VALUE));
+    }
+}
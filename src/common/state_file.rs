@@ -0,0 +1,32 @@
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::spo::spo_engine::SPOError;
+
+//
+//  JSON state kept between runs (sync state, checkpoints, cursors), None when the file doesn't exist yet
+//
+pub fn load_state_file<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, SPOError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| SPOError::new(&format!("Read state {} error: {}", path.display(), e)))?;
+    serde_json::from_str(&content)
+        .map(Some)
+        .map_err(|e| SPOError::new(&format!("Invalid state {}: {}", path.display(), e)))
+}
+
+//
+//  Write to a temporary file first, an interrupted run keeps the previous state
+//
+pub fn save_state_file<T: Serialize>(path: &Path, state: &T) -> Result<(), SPOError> {
+    let content = serde_json::to_string_pretty(state)
+        .map_err(|e| SPOError::new(&format!("Serialize state error: {}", e)))?;
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, content)
+        .and_then(|_| std::fs::rename(&temp_path, path))
+        .map_err(|e| SPOError::new(&format!("Write state {} error: {}", path.display(), e)))
+}
@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::spo::spo_engine::SPOError;
+
+pub const BLOB_CREATED_EVENT: &str = "Microsoft.Storage.BlobCreated";
+pub const SUBSCRIPTION_VALIDATION_EVENT: &str = "Microsoft.EventGrid.SubscriptionValidationEvent";
+
+//
+//  Blob of a Microsoft.Storage.BlobCreated event
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlobCreatedEvent {
+    pub id: String,
+    pub account: String,
+    pub container: String,
+    pub blob_name: String,
+    pub url: Option<String>,
+    pub content_length: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventGridPayload {
+    /// Subscription validation handshake (Event Grid schema), answer with the code
+    Validation { validation_code: String },
+    /// BlobCreated events of the delivery, other event types are left out
+    BlobCreated(Vec<BlobCreatedEvent>),
+}
+
+//
+//  Parse an Event Grid delivery, Event Grid schema (array of events)
+//  or CloudEvents 1.0 schema (single event or batch)
+//
+pub fn parse_event_grid_payload(body: &Value) -> Result<EventGridPayload, SPOError> {
+    let events: Vec<&Value> = match body {
+        Value::Array(events) => events.iter().collect(),
+        Value::Object(_) => vec![body],
+        _ => {
+            return Err(SPOError::new(&String::from(
+                "Invalid Event Grid payload: expect an event or an array of events",
+            )))
+        }
+    };
+    let mut blob_created: Vec<BlobCreatedEvent> = vec![];
+    for event in events {
+        // CloudEvents has type / source, Event Grid schema has eventType / topic
+        let (event_type, topic) = if event.get("specversion").is_some() {
+            (&event["type"], &event["source"])
+        } else {
+            (&event["eventType"], &event["topic"])
+        };
+        match event_type.as_str() {
+            Some(SUBSCRIPTION_VALIDATION_EVENT) => {
+                let validation_code =
+                    event["data"]["validationCode"].as_str().ok_or_else(|| {
+                        SPOError::new(&String::from(
+                            "Invalid subscription validation event: missing validationCode",
+                        ))
+                    })?;
+                return Ok(EventGridPayload::Validation {
+                    validation_code: validation_code.to_string(),
+                });
+            }
+            Some(BLOB_CREATED_EVENT) => blob_created.push(blob_created_event(
+                event,
+                topic.as_str().unwrap_or_default(),
+            )?),
+            other => log::debug!("Skip event type {:?}", other),
+        }
+    }
+    Ok(EventGridPayload::BlobCreated(blob_created))
+}
+
+//
+//  topic / source is /subscriptions/[id]/resourceGroups/[rg]/providers/Microsoft.Storage/storageAccounts/[account]
+//  subject is /blobServices/default/containers/[container]/blobs/[blob name]
+//
+fn blob_created_event(event: &Value, topic: &str) -> Result<BlobCreatedEvent, SPOError> {
+    let subject = event["subject"].as_str().unwrap_or_default();
+    let (container, blob_name) = subject
+        .strip_prefix("/blobServices/default/containers/")
+        .and_then(|s| s.split_once("/blobs/"))
+        .ok_or_else(|| SPOError::new(&format!("Invalid BlobCreated subject: {}", subject)))?;
+    let url = event["data"]["url"].as_str().map(|u| u.to_string());
+    let account = match topic.rsplit_once("/storageAccounts/") {
+        Some((_, account)) => account.to_string(),
+        // https://[account].blob.core.windows.net/...
+        None => url
+            .as_ref()
+            .and_then(|u| u.split("://").nth(1))
+            .and_then(|h| h.split('.').next())
+            .unwrap_or_default()
+            .to_string(),
+    };
+    Ok(BlobCreatedEvent {
+        id: event["id"].as_str().unwrap_or_default().to_string(),
+        account,
+        container: container.to_string(),
+        blob_name: blob_name.to_string(),
+        url,
+        content_length: event["data"]["contentLength"].as_u64(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const TOPIC: &str = "/subscriptions/s/resourceGroups/rg/providers/Microsoft.Storage/storageAccounts/contosoblobs";
+
+    #[test]
+    fn parse_subscription_validation() {
+        let body = json!([{
+            "id": "1",
+            "eventType": SUBSCRIPTION_VALIDATION_EVENT,
+            "subject": "",
+            "data": { "validationCode": "512d38b6-c7b8-40c8-89fe-f46f9e9622b6" },
+            "dataVersion": "1",
+            "eventTime": "2024-01-01T10:00:00Z"
+        }]);
+        assert_eq!(
+            parse_event_grid_payload(&body).unwrap(),
+            EventGridPayload::Validation {
+                validation_code: String::from("512d38b6-c7b8-40c8-89fe-f46f9e9622b6")
+            }
+        );
+    }
+
+    #[test]
+    fn parse_blob_created_event_grid_and_cloud_events() {
+        let expected = BlobCreatedEvent {
+            id: String::from("1"),
+            account: String::from("contosoblobs"),
+            container: String::from("inbox"),
+            blob_name: String::from("scans/a.pdf"),
+            url: Some(String::from(
+                "https://contosoblobs.blob.core.windows.net/inbox/scans/a.pdf",
+            )),
+            content_length: Some(10),
+        };
+        let data = json!({
+            "api": "PutBlob",
+            "url": "https://contosoblobs.blob.core.windows.net/inbox/scans/a.pdf",
+            "contentLength": 10
+        });
+        let event_grid = json!([
+            {
+                "id": "1",
+                "topic": TOPIC,
+                "subject": "/blobServices/default/containers/inbox/blobs/scans/a.pdf",
+                "eventType": BLOB_CREATED_EVENT,
+                "data": data
+            },
+            {
+                "id": "2",
+                "topic": TOPIC,
+                "subject": "/blobServices/default/containers/inbox/blobs/b.pdf",
+                "eventType": "Microsoft.Storage.BlobDeleted",
+                "data": {}
+            }
+        ]);
+        assert_eq!(
+            parse_event_grid_payload(&event_grid).unwrap(),
+            EventGridPayload::BlobCreated(vec![expected.clone()])
+        );
+
+        let cloud_event = json!({
+            "specversion": "1.0",
+            "id": "1",
+            "source": TOPIC,
+            "subject": "/blobServices/default/containers/inbox/blobs/scans/a.pdf",
+            "type": BLOB_CREATED_EVENT,
+            "data": data
+        });
+        assert_eq!(
+            parse_event_grid_payload(&cloud_event).unwrap(),
+            EventGridPayload::BlobCreated(vec![expected.clone()])
+        );
+        assert_eq!(
+            parse_event_grid_payload(&json!([cloud_event])).unwrap(),
+            EventGridPayload::BlobCreated(vec![expected])
+        );
+        assert!(parse_event_grid_payload(&json!("event")).is_err());
+    }
+}
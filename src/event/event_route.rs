@@ -0,0 +1,276 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::blob::blob2spo::{CopyFailure, CopyOptions, CopySummary};
+use crate::blob::blob_source::BlobSource;
+use crate::blob::blob_sync::{copy_blob_below_folder, folder_prefix};
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::event::event_grid::BlobCreatedEvent;
+use crate::route::route_rules::{RouteConfig, RouteDestination, RouteRule, RouteRules};
+use crate::spo::spo_engine::{SPOEngine, SPOError};
+use crate::spo::spo_path::join_path;
+
+/// Inline JSON array of routes
+pub const EVENT_ROUTES_KEY: &str = "SPO_EVENT_ROUTES";
+/// JSON file with the array of routes
+pub const EVENT_ROUTES_FILE_KEY: &str = "SPO_EVENT_ROUTES_FILE";
+
+//
+//  Share Point destination of the blobs created in a container (and below a prefix)
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EventRoute {
+    /// Any storage account when not set
+    #[serde(default)]
+    pub account: Option<String>,
+    pub container: String,
+    #[serde(default)]
+    pub prefix: String,
+    pub share_point_domain: String,
+    pub share_point_site: String,
+    pub share_point_path: String,
+}
+
+impl EventRoute {
+    //
+    //  Same destination as a routing rule, the path below the prefix is the path below the folder
+    //
+    pub fn to_route_rule(&self) -> RouteRule {
+        RouteRule {
+            account: self.account.as_ref().map(|a| regex::escape(a)),
+            container: regex::escape(&self.container),
+            blob: Some(format!(
+                "{}(?:(.*)/)?([^/]+)",
+                regex::escape(&folder_prefix(&self.prefix))
+            )),
+            share_point_domain: Some(self.share_point_domain.to_owned()),
+            site: self.share_point_site.to_owned(),
+            library: self.share_point_path.to_owned(),
+            folder: String::from("{1}"),
+            file_name: Some(String::from("{2}")),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn event_routes_to_rules(routes: &[EventRoute]) -> Result<RouteRules, SPOError> {
+    RouteRules::new(&RouteConfig {
+        share_point_domain: None,
+        rules: routes.iter().map(|r| r.to_route_rule()).collect(),
+    })
+}
+
+pub fn parse_event_routes(json: &str) -> Result<Vec<EventRoute>, SPOError> {
+    serde_json::from_str(json).map_err(|e| SPOError::new(&format!("Invalid event routes: {}", e)))
+}
+
+//
+//  Routes from SPO_EVENT_ROUTES or the file of SPO_EVENT_ROUTES_FILE
+//
+pub fn load_event_routes() -> Result<Vec<EventRoute>, SPOError> {
+    if let Ok(json) = std::env::var(EVENT_ROUTES_KEY) {
+        return parse_event_routes(&json);
+    }
+    match std::env::var(EVENT_ROUTES_FILE_KEY) {
+        Ok(path) => {
+            let json = std::fs::read_to_string(&path).map_err(|e| {
+                SPOError::new(&format!("Read event routes file {} error: {}", path, e))
+            })?;
+            parse_event_routes(&json)
+        }
+        Err(_) => Err(SPOError::new(&format!(
+            "No event routes, set {} or {}",
+            EVENT_ROUTES_KEY, EVENT_ROUTES_FILE_KEY
+        ))),
+    }
+}
+
+//
+//  Copy the created blobs to the Share Point folder of the first matching rule,
+//  blobs without a rule are skipped
+//
+pub async fn do_copy_blob_created_to_spo(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    rules: &RouteRules,
+    events: &[BlobCreatedEvent],
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+) -> Result<CopySummary, SPOError> {
+    let mut summary = CopySummary::default();
+    // Event Grid delivers at least once, duplicate events of a blob give one copy
+    let mut blobs: BTreeMap<(String, String, String), RouteDestination> = BTreeMap::new();
+    for event in events {
+        let key = (
+            event.account.to_owned(),
+            event.container.to_owned(),
+            event.blob_name.to_owned(),
+        );
+        match rules.resolve(&event.account, &event.container, &event.blob_name) {
+            Ok(Some(destination)) => {
+                blobs.insert(key, destination);
+            }
+            Ok(None) => info!(
+                "No route for blob {}/{}/{}, event {} skipped",
+                event.account, event.container, event.blob_name, event.id
+            ),
+            Err(e) => summary.failed.push(CopyFailure {
+                container: key.1,
+                blob_name: key.2,
+                error: e,
+            }),
+        }
+    }
+    debug!("{} events, {} blobs to copy", events.len(), blobs.len());
+
+    let http_client = http_config.build_client()?;
+    // one connection per site, the folders already created per library
+    let mut spo_engines: BTreeMap<(String, String), SPOEngine> = BTreeMap::new();
+    let mut spo_folders: BTreeMap<(String, String, String), BTreeSet<String>> = BTreeMap::new();
+    for ((account, container, blob_name), destination) in blobs {
+        let res = async {
+            let share_point_domain = destination.share_point_domain.clone().ok_or_else(|| {
+                SPOError::new(&format!(
+                    "Rule {}: share_point_domain is required",
+                    destination.rule
+                ))
+            })?;
+            let site = (
+                share_point_domain.to_owned(),
+                destination.share_point_site.to_owned(),
+            );
+            if !spo_engines.contains_key(&site) {
+                let mut spo_engine = SPOEngine::new(
+                    tenant_id,
+                    client_id,
+                    client_secret,
+                    &share_point_domain,
+                    &http_client,
+                );
+                spo_engine.connect(&destination.share_point_site).await?;
+                spo_engines.insert(site.clone(), spo_engine);
+            }
+            Ok::<(String, String), SPOError>(site)
+        }
+        .await;
+        let (share_point_domain, share_point_site) = match res {
+            Ok(site) => site,
+            Err(e) => {
+                summary.failed.push(CopyFailure {
+                    container,
+                    blob_name,
+                    error: e,
+                });
+                continue;
+            }
+        };
+        let copy_options = CopyOptions {
+            metadata: destination.metadata.clone().into_iter().collect(),
+            ..options.clone()
+        };
+        let res = copy_blob_below_folder(
+            &spo_engines[&(share_point_domain.to_owned(), share_point_site.to_owned())],
+            spo_folders
+                .entry((
+                    share_point_domain.to_owned(),
+                    share_point_site.to_owned(),
+                    destination.library.to_owned(),
+                ))
+                .or_default(),
+            tenant_id,
+            client_id,
+            client_secret,
+            &share_point_domain,
+            &share_point_site,
+            &destination.library,
+            &BlobSource::new(&account, &container, &blob_name),
+            &join_path(&destination.folder, &destination.file_name),
+            http_config,
+            &copy_options,
+            None,
+            None,
+        )
+        .await;
+        match res {
+            Ok(r) => summary.copied.push(r),
+            Err(failure) => summary.failed.push(failure),
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_routes_as_rules() {
+        let routes = parse_event_routes(
+            r#"[
+                {"account": "contosoblobs", "container": "inbox", "prefix": "invoices/",
+                 "share_point_domain": "contoso", "share_point_site": "Finance",
+                 "share_point_path": "/sites/Finance/Shared Documents/Invoices"},
+                {"container": "inbox",
+                 "share_point_domain": "contoso", "share_point_site": "MVP",
+                 "share_point_path": "/sites/MVP/Shared Documents"}
+            ]"#,
+        )
+        .unwrap();
+
+        let rules = event_routes_to_rules(&routes).unwrap();
+
+        let destination = rules
+            .resolve("contosoblobs", "inbox", "invoices/2024/a.pdf")
+            .unwrap()
+            .unwrap();
+        assert_eq!(destination.share_point_site, "Finance");
+        assert_eq!(
+            destination.share_point_path(),
+            "/sites/Finance/Shared Documents/Invoices/2024"
+        );
+        assert_eq!(destination.folder, "2024");
+        assert_eq!(destination.file_name, "a.pdf");
+
+        let destination = rules
+            .resolve("other", "inbox", "invoices/a.pdf")
+            .unwrap()
+            .unwrap();
+        assert_eq!(destination.share_point_site, "MVP");
+        assert_eq!(destination.folder, "invoices");
+        assert_eq!(destination.file_name, "a.pdf");
+
+        // the prefix is a folder, invoices-old isn't below invoices
+        let destination = rules
+            .resolve("contosoblobs", "inbox", "invoices-old/a.pdf")
+            .unwrap()
+            .unwrap();
+        assert_eq!(destination.share_point_site, "MVP");
+        let routes = parse_event_routes(
+            r#"[{"container": "inbox", "prefix": "invoices",
+                 "share_point_domain": "contoso", "share_point_site": "Finance",
+                 "share_point_path": "/sites/Finance/Shared Documents"}]"#,
+        )
+        .unwrap();
+        let rules = event_routes_to_rules(&routes).unwrap();
+        assert!(rules
+            .resolve("contosoblobs", "inbox", "invoices-old/a.pdf")
+            .unwrap()
+            .is_none());
+        let destination = rules
+            .resolve("contosoblobs", "inbox", "invoices/a.pdf")
+            .unwrap()
+            .unwrap();
+        assert_eq!(destination.folder, "");
+        assert_eq!(destination.file_name, "a.pdf");
+
+        assert!(rules
+            .resolve("contosoblobs", "outbox", "a.pdf")
+            .unwrap()
+            .is_none());
+        assert!(parse_event_routes(r#"[{"container": "inbox"}]"#).is_err());
+    }
+}
@@ -0,0 +1,2 @@
+pub mod event_grid;
+pub mod event_route;
@@ -0,0 +1,891 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::StreamExt;
+use log::{debug, error, info};
+use reqwest::Client;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::blob::blob2spo::{do_copy_file_to_spo, CopyOptions, CopyResult};
+use crate::blob::blob_source::BlobSource;
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::job::job_config::JobLocation;
+use crate::route::route_rules::{RouteDestination, RouteRules};
+use crate::spo::spo_engine::{SPOEngine, SPOError, SPOErrorKind};
+use crate::spo::spo_onedrive::{personal_site_path, resolve_onedrive};
+use crate::spo::spo_path::ensure_spo_folders;
+
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+/// Rows copied between two writes of the report of a running batch
+const REPORT_EVERY_ROWS: usize = 10;
+const MANIFEST_COLUMNS: [&str; 9] = [
+    "account",
+    "container",
+    "blob_name",
+    "share_point_domain",
+    "share_point_site",
+    "share_point_path",
+    "target_name",
+    "metadata",
+    "onedrive_user",
+];
+const REPORT_COLUMNS: [&str; 4] = ["status", "spo_file_url", "error_class", "error"];
+
+/// Library and relative folder of a routed row, created before its copy
+pub type RowFolder = Option<(String, String)>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ManifestFormat {
+    Csv,
+    Json,
+}
+
+impl ManifestFormat {
+    pub fn from_name(name: &str) -> Result<ManifestFormat, SPOError> {
+        match name.rsplit_once('.').map(|(_, e)| e.to_lowercase()) {
+            Some(e) if e == "csv" => Ok(ManifestFormat::Csv),
+            Some(e) if e == "json" => Ok(ManifestFormat::Json),
+            _ => Err(SPOError::new(&format!(
+                "Unknown manifest format of {}, expect .csv or .json",
+                name
+            ))),
+        }
+    }
+}
+
+//
+//  One copy of the manifest, account and domain default to the batch ones.
+//  Without site and path, the destination comes from the routing rules.
+//  With onedrive_user, the path is a folder below the Documents of the user
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ManifestRow {
+    #[serde(default)]
+    pub account: Option<String>,
+    pub container: String,
+    pub blob_name: String,
+    #[serde(default)]
+    pub share_point_domain: Option<String>,
+    #[serde(default)]
+    pub share_point_site: String,
+    #[serde(default)]
+    pub share_point_path: String,
+    /// File name in Share Point, the blob name when not set
+    #[serde(default)]
+    pub target_name: Option<String>,
+    /// Share point columns (internal names), an object in JSON or Field=Value;Field=Value in CSV
+    #[serde(default, deserialize_with = "deserialize_metadata")]
+    pub metadata: BTreeMap<String, String>,
+    /// User principal name of the OneDrive, on [share_point_domain]-my.sharepoint.com
+    #[serde(default)]
+    pub onedrive_user: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchRowStatus {
+    Copied,
+    Failed,
+    Invalid,
+    /// Not copied because other rows are invalid
+    Skipped,
+    /// Not copied yet, the batch is running
+    Pending,
+    /// Written to the copy requests queue of the function, the result goes to the results queue
+    Queued,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchRowResult {
+    #[serde(flatten)]
+    pub row: ManifestRow,
+    pub status: BatchRowStatus,
+    pub spo_file_url: Option<String>,
+    pub error_class: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BatchSummary {
+    pub copied: usize,
+    pub failed: usize,
+    pub invalid: usize,
+    pub rows: Vec<BatchRowResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MetadataValue {
+    Text(String),
+    Fields(BTreeMap<String, String>),
+}
+
+fn deserialize_metadata<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, String>, D::Error> {
+    match Option::<MetadataValue>::deserialize(deserializer)? {
+        Some(MetadataValue::Fields(fields)) => Ok(fields),
+        Some(MetadataValue::Text(text)) => parse_metadata(&text).map_err(serde::de::Error::custom),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+//
+//  Field=Value;Field=Value
+//
+fn parse_metadata(text: &str) -> Result<BTreeMap<String, String>, String> {
+    text.split(';')
+        .filter(|f| !f.trim().is_empty())
+        .map(|f| match f.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => {
+                Ok((name.trim().to_string(), value.to_string()))
+            }
+            _ => Err(format!("expect Field=Value, got {}", f)),
+        })
+        .collect()
+}
+
+fn format_metadata(metadata: &BTreeMap<String, String>) -> String {
+    metadata
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+//
+//  CSV with a header line (columns in any order) or JSON array of rows
+//
+pub fn parse_manifest(
+    content: &[u8],
+    format: ManifestFormat,
+) -> Result<Vec<ManifestRow>, SPOError> {
+    match format {
+        ManifestFormat::Json => serde_json::from_slice(content)
+            .map_err(|e| SPOError::new(&format!("Invalid JSON manifest: {}", e))),
+        ManifestFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(content);
+            let headers = reader
+                .headers()
+                .map_err(|e| SPOError::new(&format!("Invalid CSV manifest header: {}", e)))?
+                .clone();
+            let mut rows = vec![];
+            for (i, record) in reader.records().enumerate() {
+                let record =
+                    record.map_err(|e| SPOError::new(&format!("Invalid CSV manifest: {}", e)))?;
+                // empty cells are missing values
+                let fields: serde_json::Map<String, Value> = headers
+                    .iter()
+                    .zip(record.iter())
+                    .filter(|(_, v)| !v.is_empty())
+                    .map(|(h, v)| (h.trim().to_string(), Value::String(v.to_string())))
+                    .collect();
+                rows.push(serde_json::from_value(Value::Object(fields)).map_err(|e| {
+                    SPOError::new(&format!("Invalid manifest row {}: {}", i + 1, e))
+                })?);
+            }
+            Ok(rows)
+        }
+    }
+}
+
+//
+//  Same format as the manifest, with the result columns
+//
+pub fn format_report(rows: &[BatchRowResult], format: ManifestFormat) -> Result<Vec<u8>, SPOError> {
+    match format {
+        ManifestFormat::Json => serde_json::to_vec_pretty(rows)
+            .map_err(|e| SPOError::new(&format!("Serialize report error: {}", e))),
+        ManifestFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            let write_error = |e: csv::Error| SPOError::new(&format!("Write report error: {}", e));
+            writer
+                .write_record(MANIFEST_COLUMNS.iter().chain(REPORT_COLUMNS.iter()))
+                .map_err(write_error)?;
+            for r in rows {
+                let status = serde_json::to_value(r.status)
+                    .ok()
+                    .and_then(|v| v.as_str().map(|s| s.to_string()))
+                    .unwrap_or_default();
+                writer
+                    .write_record([
+                        r.row.account.clone().unwrap_or_default(),
+                        r.row.container.to_owned(),
+                        r.row.blob_name.to_owned(),
+                        r.row.share_point_domain.clone().unwrap_or_default(),
+                        r.row.share_point_site.to_owned(),
+                        r.row.share_point_path.to_owned(),
+                        r.row.target_name.clone().unwrap_or_default(),
+                        format_metadata(&r.row.metadata),
+                        r.row.onedrive_user.clone().unwrap_or_default(),
+                        status,
+                        r.spo_file_url.clone().unwrap_or_default(),
+                        r.error_class.clone().unwrap_or_default(),
+                        r.error.clone().unwrap_or_default(),
+                    ])
+                    .map_err(write_error)?;
+            }
+            writer
+                .into_inner()
+                .map_err(|e| SPOError::new(&format!("Write report error: {}", e)))
+        }
+    }
+}
+
+//
+//  Errors of a row, before anything is copied
+//
+pub fn validate_row(
+    row: &ManifestRow,
+    default_account: &str,
+    default_domain: &Option<String>,
+) -> Vec<String> {
+    let mut errors = vec![];
+    let mut required = vec![("container", &row.container), ("blob_name", &row.blob_name)];
+    match &row.onedrive_user {
+        Some(onedrive_user) if !row.share_point_site.trim().is_empty() => errors.push(format!(
+            "share_point_site {} is not allowed with onedrive_user {}",
+            row.share_point_site, onedrive_user
+        )),
+        Some(_) => {}
+        None => {
+            required.push(("share_point_site", &row.share_point_site));
+            required.push(("share_point_path", &row.share_point_path));
+        }
+    }
+    for (name, value) in required {
+        if value.trim().is_empty() {
+            errors.push(format!("{} is required", name));
+        }
+    }
+    if row.account.is_none() && default_account.is_empty() {
+        errors.push(String::from(
+            "account is required, no storage account given",
+        ));
+    }
+    if row.share_point_domain.is_none() && default_domain.is_none() {
+        errors.push(String::from(
+            "share_point_domain is required, no Share Point domain given",
+        ));
+    }
+    if row.blob_name.ends_with('/') {
+        errors.push(String::from("blob_name is a folder"));
+    }
+    if let Some(target_name) = &row.target_name {
+        if target_name.is_empty() || target_name.contains('/') {
+            errors.push(format!("invalid target_name {}", target_name));
+        }
+    }
+    errors
+}
+
+//
+//  Fill the destination of a row without site and path from the first matching rule,
+//  the columns of the row override the rule metadata
+//
+pub fn route_row(
+    row: &mut ManifestRow,
+    default_account: &str,
+    rules: &RouteRules,
+) -> Result<Option<RouteDestination>, SPOError> {
+    if rules.is_empty()
+        || row.onedrive_user.is_some()
+        || !row.share_point_site.trim().is_empty()
+        || !row.share_point_path.trim().is_empty()
+    {
+        return Ok(None);
+    }
+    let account = row.account.as_deref().unwrap_or(default_account);
+    let destination = match rules.resolve(account, &row.container, &row.blob_name)? {
+        Some(d) => d,
+        None => return Ok(None),
+    };
+    row.share_point_domain = row
+        .share_point_domain
+        .clone()
+        .or(destination.share_point_domain.clone());
+    row.share_point_site = destination.share_point_site.to_owned();
+    row.share_point_path = destination.share_point_path();
+    if row.target_name.is_none() {
+        row.target_name = Some(destination.file_name.to_owned());
+    }
+    for (field, value) in &destination.metadata {
+        row.metadata
+            .entry(field.to_owned())
+            .or_insert(value.to_owned());
+    }
+    Ok(Some(destination))
+}
+
+//
+//  Error class of a failed copy in the report
+//
+pub fn error_class(e: &SPOError) -> String {
+    match e.kind() {
+        SPOErrorKind::General if e.spo_error().is_some() => String::from("SharePoint"),
+        kind => format!("{:?}", kind),
+    }
+}
+
+//
+//  Route and validate every row: the folder of each row and its errors
+//
+pub fn check_manifest_rows(
+    rows: &mut [ManifestRow],
+    default_account: &str,
+    default_domain: &Option<String>,
+    rules: &RouteRules,
+) -> (Vec<RowFolder>, Vec<Vec<String>>) {
+    let mut destinations: BTreeSet<(String, String, String)> = BTreeSet::new();
+    let mut row_errors: Vec<Vec<String>> = vec![];
+    let mut row_folders: Vec<RowFolder> = vec![];
+    for row in rows.iter_mut() {
+        let mut errors = vec![];
+        match route_row(row, default_account, rules) {
+            Ok(Some(destination)) if !destination.folder.is_empty() => {
+                row_folders.push(Some((destination.library, destination.folder)))
+            }
+            Ok(_) => row_folders.push(None),
+            Err(e) => {
+                errors.push(e.to_string());
+                row_folders.push(None);
+            }
+        }
+        let row = &*row;
+        errors.extend(validate_row(row, default_account, default_domain));
+        let file_name = row.target_name.clone().unwrap_or_else(|| {
+            row.blob_name
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        });
+        let site = match &row.onedrive_user {
+            Some(onedrive_user) => personal_site_path(onedrive_user),
+            None => row.share_point_site.to_owned(),
+        };
+        if !destinations.insert((
+            site,
+            row.share_point_path.trim_end_matches('/').to_owned(),
+            file_name.to_owned(),
+        )) {
+            errors.push(format!("duplicate destination {}", file_name));
+        }
+        row_errors.push(errors);
+    }
+    (row_folders, row_errors)
+}
+
+//
+//  Report of the checked rows before any copy, the valid rows have the given status
+//
+pub fn checked_rows_report(
+    rows: &[ManifestRow],
+    row_errors: &[Vec<String>],
+    status: BatchRowStatus,
+) -> Vec<BatchRowResult> {
+    rows.iter()
+        .zip(row_errors)
+        .map(|(row, errors)| BatchRowResult {
+            row: row.clone(),
+            status: match errors.is_empty() {
+                true => status,
+                false => BatchRowStatus::Invalid,
+            },
+            spo_file_url: None,
+            error_class: (!errors.is_empty()).then(|| String::from("Validation")),
+            error: (!errors.is_empty()).then(|| errors.join(", ")),
+        })
+        .collect()
+}
+
+//
+//  Copy one checked row, its folder is created first. The source credential applies to
+//  the source account, the rows of other accounts are read with the default identity
+//
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_manifest_row(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    default_domain: &Option<String>,
+    source: &BlobSource,
+    row: &ManifestRow,
+    folder: &RowFolder,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+) -> Result<CopyResult, SPOError> {
+    let account = row.account.clone().unwrap_or(source.account.to_owned());
+    let mut blob_source = match account == source.account {
+        true => source.clone(),
+        false => BlobSource::new(&account, &String::new(), &String::new()),
+    };
+    blob_source.container = row.container.to_owned();
+    blob_source.set_blob_name(&row.blob_name);
+    let share_point_domain = row
+        .share_point_domain
+        .clone()
+        .or(default_domain.clone())
+        .unwrap_or_default();
+    let copy_options = CopyOptions {
+        target_name: row.target_name.clone(),
+        metadata: row.metadata.clone().into_iter().collect(),
+        ..options.clone()
+    };
+    let (share_point_domain, share_point_site, share_point_path) = match &row.onedrive_user {
+        Some(onedrive_user) => {
+            let onedrive = resolve_onedrive(
+                tenant_id,
+                client_id,
+                client_secret,
+                &share_point_domain,
+                onedrive_user,
+                &row.share_point_path,
+                &http_config.build_client()?,
+            )
+            .await?;
+            (
+                onedrive.share_point_domain,
+                onedrive.share_point_site,
+                onedrive.share_point_path,
+            )
+        }
+        None => (
+            share_point_domain,
+            row.share_point_site.to_owned(),
+            row.share_point_path.to_owned(),
+        ),
+    };
+    if let Some((library, relative_folder)) = folder {
+        let http_client = http_config.build_client()?;
+        let mut spo_engine = SPOEngine::new(
+            tenant_id,
+            client_id,
+            client_secret,
+            &share_point_domain,
+            &http_client,
+        );
+        spo_engine.connect(&share_point_site).await?;
+        ensure_spo_folders(&spo_engine, library, relative_folder, &mut BTreeSet::new()).await?;
+    }
+    do_copy_file_to_spo(
+        tenant_id,
+        client_id,
+        client_secret,
+        &share_point_domain,
+        &share_point_site,
+        &share_point_path,
+        &blob_source,
+        http_config,
+        &copy_options,
+        None,
+        None,
+    )
+    .await
+}
+
+//
+//  Route and validate every row, then copy with at most `concurrency` copies at once.
+//  Nothing is copied when a row is invalid, the report tells which.
+//  The result of each copied row is also sent to progress with the row index
+//
+#[allow(clippy::too_many_arguments)]
+pub async fn do_copy_manifest_to_spo(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    default_domain: &Option<String>,
+    source: &BlobSource,
+    mut rows: Vec<ManifestRow>,
+    rules: &RouteRules,
+    concurrency: usize,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+    progress: Option<UnboundedSender<(usize, BatchRowResult)>>,
+) -> BatchSummary {
+    let mut summary = BatchSummary::default();
+    let (row_folders, row_errors) =
+        check_manifest_rows(&mut rows, &source.account, default_domain, rules);
+    summary.invalid = row_errors.iter().filter(|e| !e.is_empty()).count();
+    if summary.invalid > 0 {
+        error!("{} invalid manifest rows, nothing copied", summary.invalid);
+        summary.rows = checked_rows_report(&rows, &row_errors, BatchRowStatus::Skipped);
+        return summary;
+    }
+
+    info!("Copy {} manifest rows, {} at once", rows.len(), concurrency);
+    let progress = progress.as_ref();
+    summary.rows = futures::stream::iter(rows.into_iter().zip(row_folders).enumerate())
+        .map(|(index, (row, folder))| async move {
+            let res = copy_manifest_row(
+                tenant_id,
+                client_id,
+                client_secret,
+                default_domain,
+                source,
+                &row,
+                &folder,
+                http_config,
+                options,
+            )
+            .await;
+            let result = match res {
+                Ok(r) => {
+                    debug!(
+                        "Copied {}/{} to {}",
+                        row.container, row.blob_name, r.spo_file_url
+                    );
+                    BatchRowResult {
+                        row,
+                        status: BatchRowStatus::Copied,
+                        spo_file_url: Some(r.spo_file_url),
+                        error_class: None,
+                        error: None,
+                    }
+                }
+                Err(e) => {
+                    error!("Copy {}/{} error : {}", row.container, row.blob_name, e);
+                    BatchRowResult {
+                        row,
+                        status: BatchRowStatus::Failed,
+                        spo_file_url: None,
+                        error_class: Some(error_class(&e)),
+                        error: Some(e.to_string()),
+                    }
+                }
+            };
+            if let Some(progress) = progress {
+                // the report writer may be gone after a write error
+                let _ = progress.unbounded_send((index, result.clone()));
+            }
+            result
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await;
+    summary.copied = summary
+        .rows
+        .iter()
+        .filter(|r| r.status == BatchRowStatus::Copied)
+        .count();
+    summary.failed = summary.rows.len() - summary.copied;
+    summary
+}
+
+//
+//  Rows of the manifest, in the format of its extension
+//
+pub async fn read_manifest(
+    manifest: &JobLocation,
+    http_client: &Client,
+) -> Result<(Vec<ManifestRow>, ManifestFormat), SPOError> {
+    let format = ManifestFormat::from_name(&manifest.name())?;
+    let content = manifest
+        .read(http_client)
+        .await?
+        .ok_or_else(|| SPOError::new(&format!("Manifest {} not found", manifest.name())))?;
+    Ok((parse_manifest(&content, format)?, format))
+}
+
+//
+//  Read the manifest, copy its rows (routed by the rules when given)
+//  and write the report (when given) in the manifest format. The report is written
+//  with every row pending first, then again every REPORT_EVERY_ROWS copied rows
+//
+#[allow(clippy::too_many_arguments)]
+pub async fn do_batch_copy_to_spo(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    default_domain: &Option<String>,
+    source: &BlobSource,
+    manifest: &JobLocation,
+    report: &Option<JobLocation>,
+    rules: &RouteRules,
+    concurrency: usize,
+    http_config: &HttpConfig,
+    options: &CopyOptions,
+) -> Result<BatchSummary, SPOError> {
+    let http_client = http_config.build_client()?;
+    let (rows, format) = read_manifest(manifest, &http_client).await?;
+    let report = match report {
+        Some(report) => report,
+        None => {
+            return Ok(do_copy_manifest_to_spo(
+                tenant_id,
+                client_id,
+                client_secret,
+                default_domain,
+                source,
+                rows,
+                rules,
+                concurrency,
+                http_config,
+                options,
+                None,
+            )
+            .await)
+        }
+    };
+    let mut report_rows: Vec<BatchRowResult> = rows
+        .iter()
+        .map(|row| BatchRowResult {
+            row: row.clone(),
+            status: BatchRowStatus::Pending,
+            spo_file_url: None,
+            error_class: None,
+            error: None,
+        })
+        .collect();
+    report
+        .write(&http_client, format_report(&report_rows, format)?)
+        .await?;
+    let (sender, mut receiver) = unbounded();
+    let copy = do_copy_manifest_to_spo(
+        tenant_id,
+        client_id,
+        client_secret,
+        default_domain,
+        source,
+        rows,
+        rules,
+        concurrency,
+        http_config,
+        options,
+        Some(sender),
+    );
+    let write = async {
+        let mut unwritten = 0;
+        while let Some((index, result)) = receiver.next().await {
+            report_rows[index] = result;
+            unwritten += 1;
+            if unwritten >= REPORT_EVERY_ROWS {
+                report
+                    .write(&http_client, format_report(&report_rows, format)?)
+                    .await?;
+                unwritten = 0;
+            }
+        }
+        Ok::<(), SPOError>(())
+    };
+    let (summary, written) = futures::join!(copy, write);
+    if let Err(e) = written {
+        error!("Write report error : {}", e);
+    }
+    report
+        .write(&http_client, format_report(&summary.rows, format)?)
+        .await?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::blob::blob_source::fixture::{serve_blobs, BlobStandIn};
+    use crate::common::http_client::fixture::serve_https;
+    use crate::route::route_rules::{RouteConfig, RouteRule};
+    use crate::spo::spo_engine::fixture::{route, SharePointStandIn};
+
+    use super::*;
+
+    const CSV_MANIFEST: &str = "\
+container,blob_name,share_point_site,share_point_path,target_name,metadata
+reports,2024/q1.pdf,Finance,/sites/Finance/Shared Documents,Q1 2024.pdf,Quarter=Q1;Year=2024
+reports,2024/q2.pdf,Finance,/sites/Finance/Shared Documents,,
+";
+
+    #[test]
+    fn manifest_csv_and_json_round_trip() {
+        let rows = parse_manifest(CSV_MANIFEST.as_bytes(), ManifestFormat::Csv).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].target_name, Some(String::from("Q1 2024.pdf")));
+        assert_eq!(rows[0].metadata["Quarter"], "Q1");
+        assert_eq!(rows[0].metadata["Year"], "2024");
+        assert_eq!(rows[1].target_name, None);
+        assert!(rows[1].metadata.is_empty());
+
+        let json = serde_json::to_vec(&rows).unwrap();
+        assert_eq!(parse_manifest(&json, ManifestFormat::Json).unwrap(), rows);
+
+        let results: Vec<BatchRowResult> = rows
+            .iter()
+            .map(|row| BatchRowResult {
+                row: row.clone(),
+                status: BatchRowStatus::Failed,
+                spo_file_url: None,
+                error_class: Some(String::from("BlobArchived")),
+                error: Some(String::from("archived")),
+            })
+            .collect();
+        let report = format_report(&results, ManifestFormat::Csv).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with(
+            "account,container,blob_name,share_point_domain,share_point_site,share_point_path,\
+             target_name,metadata,onedrive_user,status,spo_file_url,error_class,error\n"
+        ));
+        assert!(report.contains("Quarter=Q1;Year=2024,,failed,,BlobArchived,archived"));
+        // the report is a manifest too
+        assert_eq!(
+            parse_manifest(report.as_bytes(), ManifestFormat::Csv).unwrap(),
+            rows
+        );
+        let report = format_report(&results, ManifestFormat::Json).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Vec<BatchRowResult>>(&report).unwrap(),
+            results
+        );
+    }
+
+    #[test]
+    fn rows_without_destination_are_routed() {
+        let rules = RouteRules::new(&RouteConfig {
+            share_point_domain: Some(String::from("contoso")),
+            rules: vec![RouteRule {
+                container: String::from("reports"),
+                blob: Some(String::from(r"(\d{4})/.+")),
+                site: String::from("Finance"),
+                library: String::from("/sites/Finance/Shared Documents"),
+                folder: String::from("{1}"),
+                metadata: BTreeMap::from([
+                    (String::from("Year"), String::from("{1}")),
+                    (String::from("Quarter"), String::from("unknown")),
+                ]),
+                ..Default::default()
+            }],
+        })
+        .unwrap();
+        let mut rows = parse_manifest(
+            "container,blob_name,metadata\nreports,2024/q1.pdf,Quarter=Q1\n".as_bytes(),
+            ManifestFormat::Csv,
+        )
+        .unwrap();
+        let destination = route_row(&mut rows[0], "contosoblobs", &rules)
+            .unwrap()
+            .unwrap();
+        assert_eq!(destination.folder, "2024");
+        assert_eq!(rows[0].share_point_domain, Some(String::from("contoso")));
+        assert_eq!(rows[0].share_point_site, "Finance");
+        assert_eq!(
+            rows[0].share_point_path,
+            "/sites/Finance/Shared Documents/2024"
+        );
+        assert_eq!(rows[0].target_name, Some(String::from("q1.pdf")));
+        assert_eq!(rows[0].metadata["Year"], "2024");
+        assert_eq!(rows[0].metadata["Quarter"], "Q1");
+        assert!(validate_row(&rows[0], "contosoblobs", &None).is_empty());
+
+        // rows with a destination are kept
+        let mut row = rows[0].clone();
+        row.share_point_site = String::from("MVP");
+        assert!(route_row(&mut row, "contosoblobs", &rules)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn onedrive_rows_need_no_site() {
+        let rows = parse_manifest(
+            "container,blob_name,onedrive_user,share_point_path,share_point_site\n\
+             reports,john.pdf,John.Doe@contoso.com,Reports,\n\
+             reports,ann.pdf,ann@contoso.com,,MVP\n"
+                .as_bytes(),
+            ManifestFormat::Csv,
+        )
+        .unwrap();
+        assert!(validate_row(&rows[0], "contosoblobs", &Some(String::from("contoso"))).is_empty());
+        assert_eq!(
+            validate_row(&rows[1], "contosoblobs", &Some(String::from("contoso"))),
+            vec!["share_point_site MVP is not allowed with onedrive_user ann@contoso.com"]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_rows_copy_nothing() {
+        let mut rows = parse_manifest(CSV_MANIFEST.as_bytes(), ManifestFormat::Csv).unwrap();
+        rows[1].target_name = Some(String::from("Q1 2024.pdf"));
+        let summary = do_copy_manifest_to_spo(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+            &Some(String::from("contoso")),
+            &BlobSource::new(
+                &String::from("contosoblobs"),
+                &String::new(),
+                &String::new(),
+            ),
+            rows,
+            &RouteRules::default(),
+            DEFAULT_BATCH_CONCURRENCY,
+            &HttpConfig::default(),
+            &CopyOptions::default(),
+            None,
+        )
+        .await;
+        assert_eq!(summary.invalid, 1);
+        assert_eq!(summary.rows[0].status, BatchRowStatus::Skipped);
+        assert_eq!(summary.rows[1].status, BatchRowStatus::Invalid);
+        assert_eq!(
+            summary.rows[1].error,
+            Some(String::from("duplicate destination Q1 2024.pdf"))
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_writes_the_report_of_every_row() {
+        let blobs = Arc::new(BlobStandIn::default());
+        let mut manifest = String::from("container,blob_name,share_point_site,share_point_path\n");
+        for i in 0..REPORT_EVERY_ROWS + 2 {
+            // the last blob is missing
+            if i <= REPORT_EVERY_ROWS {
+                blobs.insert("reports", &format!("r{}.csv", i), b"a,b\n");
+            }
+            manifest.push_str(&format!(
+                "reports,r{}.csv,Finance,/sites/Finance/Shared Documents\n",
+                i
+            ));
+        }
+        let source = serve_blobs(blobs, "", "").await;
+        let share_point = Arc::new(SharePointStandIn::default());
+        let http_config = serve_https(route(share_point.clone())).await;
+        let dir = std::env::temp_dir().join(format!("spo_batch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("manifest.csv"), manifest).unwrap();
+        let report = JobLocation::File(dir.join("report.csv"));
+
+        let summary = do_batch_copy_to_spo(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+            &Some(String::from("contoso")),
+            &source,
+            &JobLocation::File(dir.join("manifest.csv")),
+            &Some(report.clone()),
+            &RouteRules::default(),
+            DEFAULT_BATCH_CONCURRENCY,
+            &http_config,
+            &CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.copied, REPORT_EVERY_ROWS + 1);
+        assert_eq!(summary.failed, 1);
+        let rows = parse_manifest(
+            &std::fs::read(dir.join("report.csv")).unwrap(),
+            ManifestFormat::Csv,
+        )
+        .unwrap();
+        assert_eq!(rows.len(), REPORT_EVERY_ROWS + 2);
+        let report = String::from_utf8(std::fs::read(dir.join("report.csv")).unwrap()).unwrap();
+        assert_eq!(report.matches(",copied,").count(), REPORT_EVERY_ROWS + 1);
+        assert_eq!(report.matches(",failed,").count(), 1);
+        assert!(!report.contains("pending"));
+        assert_eq!(
+            share_point.file("/sites/Finance/Shared Documents/r0.csv"),
+            Some(b"a,b\n".to_vec())
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+use azure_core::error::HttpError;
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::blob::blob_filter::BlobFilterSpec;
+use crate::blob::blob_source::BlobSource;
+use crate::common::secret::Secret;
+use crate::common::state_file::{load_state_file, save_state_file};
+use crate::job::job_schedule::parse_job_schedule;
+use crate::spo::spo_engine::SPOError;
+
+//
+//  What a job does with its container
+//
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum JobAction {
+    /// Copy a single blob
+    Copy { blob_name: String },
+    /// Sync the blobs below a prefix, see the sync command
+    Sync {
+        #[serde(default)]
+        prefix: String,
+        #[serde(default)]
+        delete: bool,
+        /// include, exclude, min_size, max_size, modified_after, modified_before, content_types
+        #[serde(flatten)]
+        filter: BlobFilterSpec,
+    },
+}
+
+//
+//  Scheduled copy or sync, the blobs are read with the default identity
+//
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobDefinition {
+    pub name: String,
+    /// sec min hour day-of-month month day-of-week (UTC) ex. 0 30 2 * * * for 02:30 every night
+    pub schedule: String,
+    pub account: String,
+    pub container: String,
+    #[serde(flatten)]
+    pub action: JobAction,
+    pub share_point_domain: String,
+    pub share_point_site: String,
+    pub share_point_path: String,
+    #[serde(default)]
+    pub version_field: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobConfig {
+    pub jobs: Vec<JobDefinition>,
+}
+
+impl JobConfig {
+    //
+    //  Job names identify the last run status and name the sync state file, they must be unique
+    //  and only use letters, digits, _ and -
+    //
+    pub fn validate(&self) -> Result<(), SPOError> {
+        let mut names: BTreeSet<&String> = BTreeSet::new();
+        for job in &self.jobs {
+            if job.name.is_empty()
+                || !job
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(SPOError::new(&format!(
+                    "Invalid job name {:?}, expect letters, digits, _ or -",
+                    job.name
+                )));
+            }
+            if !names.insert(&job.name) {
+                return Err(SPOError::new(&format!("Duplicate job name {}", job.name)));
+            }
+            parse_job_schedule(&job.schedule)
+                .map_err(|e| SPOError::new(&format!("Job {}: {}", job.name, e)))?;
+            if let JobAction::Sync { filter, .. } = &job.action {
+                filter
+                    .to_blob_filter()
+                    .map_err(|e| SPOError::new(&format!("Job {}: {}", job.name, e)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+//
+//  Local JSON file, or blob url with the default identity or a SAS token
+//
+#[derive(Debug, Clone)]
+pub enum JobLocation {
+    File(PathBuf),
+    Blob(Box<BlobSource>),
+}
+
+impl JobLocation {
+    pub fn parse(value: &String) -> Result<JobLocation, SPOError> {
+        if !value.starts_with("https://") && !value.starts_with("http://") {
+            return Ok(JobLocation::File(PathBuf::from(value)));
+        }
+        let url = Url::parse(value)
+            .map_err(|e| SPOError::new(&format!("Invalid blob url {}: {}", value, e)))?;
+        if url.query().is_some() {
+            return BlobSource::from_sas_url(&Secret::new(value))
+                .map(|source| JobLocation::Blob(Box::new(source)));
+        }
+        // https://[account].blob.core.windows.net/[container]/[blob]
+        let account = url.host_str().unwrap_or_default().split('.').next();
+        match (account, url.path().trim_start_matches('/').split_once('/')) {
+            (Some(account), Some((container, blob_name))) if !blob_name.is_empty() => {
+                Ok(JobLocation::Blob(Box::new(BlobSource::new(
+                    &account.to_string(),
+                    &container.to_string(),
+                    &blob_name.to_string(),
+                ))))
+            }
+            _ => Err(SPOError::new(&format!(
+                "Invalid blob url {}: expect https://[account].blob.core.windows.net/[container]/[blob]",
+                value
+            ))),
+        }
+    }
+
+    //
+    //  A blob without SAS token is read with the application instead of the default identity
+    //
+    #[allow(clippy::ptr_arg)]
+    pub fn set_application(
+        &mut self,
+        tenant_id: &String,
+        client_id: &String,
+        client_secret: &Secret,
+    ) -> JobLocation {
+        if let JobLocation::Blob(source) = self {
+            source.set_application(tenant_id, client_id, client_secret);
+        }
+        self.to_owned()
+    }
+
+    //
+    //  Blob url only, for callers which must not reach the local files
+    //
+    pub fn parse_blob_url(value: &String) -> Result<JobLocation, SPOError> {
+        match JobLocation::parse(value)? {
+            JobLocation::File(_) => Err(SPOError::new(&format!(
+                "{} is not a blob url, expect https://[account].blob.core.windows.net/[container]/[blob]",
+                value
+            ))),
+            location => Ok(location),
+        }
+    }
+
+    //
+    //  File path or blob name, its extension tells the format
+    //
+    pub fn name(&self) -> String {
+        match self {
+            JobLocation::File(path) => path.to_string_lossy().to_string(),
+            JobLocation::Blob(source) => source.blob_name.to_owned(),
+        }
+    }
+
+    //
+    //  Raw content, None when the file or blob doesn't exist yet
+    //
+    pub async fn read(&self, http_client: &Client) -> Result<Option<Vec<u8>>, SPOError> {
+        match self {
+            JobLocation::File(path) if !path.exists() => Ok(None),
+            JobLocation::File(path) => std::fs::read(path)
+                .map(Some)
+                .map_err(|e| SPOError::new(&format!("Read {} error: {}", path.display(), e))),
+            JobLocation::Blob(source) => {
+                match source.blob_client(http_client)?.get_content().await {
+                    Ok(content) => Ok(Some(content)),
+                    Err(e) if e.as_http_error().is_some_and(is_not_found) => Ok(None),
+                    Err(e) => Err(SPOError::new(&format!(
+                        "Read blob {}/{} error: {}",
+                        source.container, source.blob_name, e
+                    ))),
+                }
+            }
+        }
+    }
+
+    pub async fn write(&self, http_client: &Client, content: Vec<u8>) -> Result<(), SPOError> {
+        match self {
+            JobLocation::File(path) => std::fs::write(path, content)
+                .map_err(|e| SPOError::new(&format!("Write {} error: {}", path.display(), e))),
+            JobLocation::Blob(source) => source
+                .blob_client(http_client)?
+                .put_block_blob(content)
+                .await
+                .map(|_| ())
+                .map_err(|e| {
+                    SPOError::new(&format!(
+                        "Write blob {}/{} error: {}",
+                        source.container, source.blob_name, e
+                    ))
+                }),
+        }
+    }
+
+    //
+    //  JSON content, None when the file or blob doesn't exist yet
+    //
+    pub async fn load<T: DeserializeOwned>(
+        &self,
+        http_client: &Client,
+    ) -> Result<Option<T>, SPOError> {
+        if let JobLocation::File(path) = self {
+            return load_state_file(path);
+        }
+        match self.read(http_client).await? {
+            Some(content) => serde_json::from_slice(&content)
+                .map(Some)
+                .map_err(|e| SPOError::new(&format!("Invalid JSON in {}: {}", self.name(), e))),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn save<T: Serialize>(
+        &self,
+        http_client: &Client,
+        value: &T,
+    ) -> Result<(), SPOError> {
+        if let JobLocation::File(path) = self {
+            return save_state_file(path, value);
+        }
+        let content = serde_json::to_vec_pretty(value)
+            .map_err(|e| SPOError::new(&format!("Serialize error: {}", e)))?;
+        self.write(http_client, content).await
+    }
+}
+
+fn is_not_found(e: &HttpError) -> bool {
+    e.status() as u16 == 404
+}
+
+//
+//  Job definitions, a missing config is an error
+//
+pub async fn load_job_config(
+    location: &JobLocation,
+    http_client: &Client,
+) -> Result<JobConfig, SPOError> {
+    let config: JobConfig = location
+        .load(http_client)
+        .await?
+        .ok_or_else(|| SPOError::new(&format!("Job config {:?} not found", location)))?;
+    config.validate()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parse_job_config() {
+        let config: JobConfig = serde_json::from_value(json!({
+            "jobs": [
+                {
+                    "name": "nightly-reports",
+                    "schedule": "0 30 2 * * *",
+                    "type": "sync",
+                    "account": "contosoblobs",
+                    "container": "reports",
+                    "prefix": "published/",
+                    "include": ["*.pdf"],
+                    "share_point_domain": "contoso",
+                    "share_point_site": "MVP",
+                    "share_point_path": "/sites/MVP/Shared Documents/Reports"
+                },
+                {
+                    "name": "price-list",
+                    "schedule": "0 0 6 * * Mon-Fri",
+                    "type": "copy",
+                    "account": "contosoblobs",
+                    "container": "sales",
+                    "blob_name": "prices.xlsx",
+                    "share_point_domain": "contoso",
+                    "share_point_site": "MVP",
+                    "share_point_path": "/sites/MVP/Shared Documents"
+                }
+            ]
+        }))
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.jobs[0].action,
+            JobAction::Sync {
+                prefix: String::from("published/"),
+                delete: false,
+                filter: BlobFilterSpec {
+                    include: vec![String::from("*.pdf")],
+                    ..Default::default()
+                }
+            }
+        );
+        assert_eq!(
+            config.jobs[1].action,
+            JobAction::Copy {
+                blob_name: String::from("prices.xlsx")
+            }
+        );
+
+        let mut invalid = config.clone();
+        invalid.jobs[1].name = String::from("nightly-reports");
+        assert!(invalid.validate().is_err());
+        let mut invalid = config.clone();
+        invalid.jobs[1].schedule = String::from("every night");
+        assert!(invalid.validate().is_err());
+        for name in ["", "../nightly", "nightly reports", "nightly/reports"] {
+            let mut invalid = config.clone();
+            invalid.jobs[1].name = name.to_string();
+            assert!(invalid.validate().is_err(), "{:?}", name);
+        }
+
+        match JobLocation::parse(&String::from(
+            "https://contosoblobs.blob.core.windows.net/config/jobs.json",
+        ))
+        .unwrap()
+        {
+            JobLocation::Blob(source) => {
+                assert_eq!(source.account, "contosoblobs");
+                assert_eq!(source.container, "config");
+                assert_eq!(source.blob_name, "jobs.json");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            JobLocation::parse(&String::from("jobs.json")).unwrap(),
+            JobLocation::File(_)
+        ));
+        assert!(JobLocation::parse_blob_url(&String::from("/etc/passwd")).is_err());
+        assert!(JobLocation::parse_blob_url(&String::from(
+            "https://contosoblobs.blob.core.windows.net/config/jobs.json"
+        ))
+        .is_ok());
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::blob::blob2spo::{do_copy_file_to_spo, CopyOptions};
+use crate::blob::blob_source::BlobSource;
+use crate::blob::blob_sync::{do_sync_prefix_to_spo, SyncOptions};
+use crate::common::datetime::{format_datetime, parse_datetime};
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::job::job_config::{load_job_config, JobAction, JobConfig, JobDefinition, JobLocation};
+use crate::spo::spo_engine::SPOError;
+
+pub const DEFAULT_JOB_STATUS_FILE: &str = "spo_job_status.json";
+/// The daemon reloads the job config at least this often
+const MAX_SCHEDULE_SLEEP_SECS: u64 = 300;
+
+//
+//  Outcome of the last run of a job
+//
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub last_run: String,
+    pub succeeded: bool,
+    pub duration_secs: u64,
+    /// Copy result or sync summary
+    pub summary: Option<Value>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobStatusFile {
+    /// Jobs without run are due at their first occurrence after this time
+    pub last_check: Option<String>,
+    pub jobs: BTreeMap<String, JobStatus>,
+}
+
+pub fn parse_job_schedule(expression: &str) -> Result<Schedule, SPOError> {
+    Schedule::from_str(expression).map_err(|e| {
+        SPOError::new(&format!(
+            "Invalid schedule {} (sec min hour day month weekday): {}",
+            expression, e
+        ))
+    })
+}
+
+//
+//  First occurrence of the schedule after the time
+//
+pub fn next_occurrence(
+    expression: &str,
+    after: &OffsetDateTime,
+) -> Result<Option<OffsetDateTime>, SPOError> {
+    let after = DateTime::<Utc>::from_timestamp(after.unix_timestamp(), 0).unwrap_or_default();
+    Ok(parse_job_schedule(expression)?
+        .after(&after)
+        .next()
+        .and_then(|t| OffsetDateTime::from_unix_timestamp(t.timestamp()).ok()))
+}
+
+//
+//  Jobs with an occurrence since their last run (or the last check) up to now,
+//  missed occurrences run once
+//
+pub fn due_jobs<'a>(
+    config: &'a JobConfig,
+    status: &JobStatusFile,
+    now: &OffsetDateTime,
+) -> Result<Vec<&'a JobDefinition>, SPOError> {
+    let last_check = match &status.last_check {
+        Some(t) => parse_datetime(t)?,
+        None => return Ok(vec![]),
+    };
+    let mut due = vec![];
+    for job in &config.jobs {
+        let since = match status.jobs.get(&job.name) {
+            Some(s) => parse_datetime(&s.last_run)?.max(last_check),
+            None => last_check,
+        };
+        if next_occurrence(&job.schedule, &since)?.is_some_and(|t| t <= *now) {
+            due.push(job);
+        }
+    }
+    Ok(due)
+}
+
+//
+//  Copy or sync of a job, the reply is the copy result or the sync summary
+//
+pub async fn run_job(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    job: &JobDefinition,
+    state_dir: &Path,
+    http_config: &HttpConfig,
+) -> Result<Value, SPOError> {
+    let options = CopyOptions {
+        version_field: job.version_field.clone(),
+        ..Default::default()
+    };
+    match &job.action {
+        JobAction::Copy { blob_name } => {
+            let source = BlobSource::new(&job.account, &job.container, blob_name);
+            do_copy_file_to_spo(
+                tenant_id,
+                client_id,
+                client_secret,
+                &job.share_point_domain,
+                &job.share_point_site,
+                &job.share_point_path,
+                &source,
+                http_config,
+                &options,
+                None,
+                None,
+            )
+            .await
+            .map(|r| serde_json::json!(r))
+        }
+        JobAction::Sync {
+            prefix,
+            delete,
+            filter,
+        } => {
+            let source = BlobSource::new(&job.account, &job.container, &String::new());
+            let sync_options = SyncOptions {
+                prefix: prefix.to_owned(),
+                delete: *delete,
+                state_file: state_dir.join(format!("spo_sync_{}.json", job.name)),
+                filter: filter.to_blob_filter()?,
+            };
+            let summary = do_sync_prefix_to_spo(
+                tenant_id,
+                client_id,
+                client_secret,
+                &job.share_point_domain,
+                &job.share_point_site,
+                &job.share_point_path,
+                &source,
+                http_config,
+                &options,
+                &sync_options,
+                None,
+                None,
+            )
+            .await?;
+            // failed blobs are retried by the next run, the job is not successful
+            match summary.failed.first() {
+                Some(f) => Err(SPOError::new(&format!(
+                    "{} blobs failed, first {}: {}",
+                    summary.failed.len(),
+                    f.blob_name,
+                    f.error
+                ))),
+                None => Ok(serde_json::json!(summary)),
+            }
+        }
+    }
+}
+
+//
+//  Run the due jobs one after the other and record their status, the names of the jobs run are returned
+//
+pub async fn run_due_jobs(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    config: &JobConfig,
+    status: &mut JobStatusFile,
+    state_dir: &Path,
+    http_config: &HttpConfig,
+) -> Result<Vec<String>, SPOError> {
+    let now = OffsetDateTime::now_utc();
+    let due = due_jobs(config, status, &now)?;
+    let mut names = vec![];
+    for job in due {
+        info!("Run job {}", job.name);
+        let start = OffsetDateTime::now_utc();
+        let res = run_job(
+            tenant_id,
+            client_id,
+            client_secret,
+            job,
+            state_dir,
+            http_config,
+        )
+        .await;
+        let duration_secs = (OffsetDateTime::now_utc() - start).whole_seconds().max(0) as u64;
+        let job_status = match res {
+            Ok(summary) => JobStatus {
+                last_run: format_datetime(&start),
+                succeeded: true,
+                duration_secs,
+                summary: Some(summary),
+                error: None,
+            },
+            Err(e) => {
+                error!("Job {} error : {}", job.name, e);
+                JobStatus {
+                    last_run: format_datetime(&start),
+                    succeeded: false,
+                    duration_secs,
+                    summary: None,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+        status.jobs.insert(job.name.to_owned(), job_status);
+        names.push(job.name.to_owned());
+    }
+    status.last_check = Some(format_datetime(&now));
+    Ok(names)
+}
+
+//
+//  One scheduler tick: load the config and the status, run the due jobs, save the status.
+//  The names of the jobs run are returned with the config and the status
+//
+pub async fn do_run_scheduled_jobs(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    config_location: &JobLocation,
+    status_location: &JobLocation,
+    state_dir: &Path,
+    http_config: &HttpConfig,
+) -> Result<(JobConfig, JobStatusFile, Vec<String>), SPOError> {
+    let http_client = http_config.build_client()?;
+    let config = load_job_config(config_location, &http_client).await?;
+    let mut status: JobStatusFile = status_location
+        .load(&http_client)
+        .await?
+        .unwrap_or_default();
+    let names = run_due_jobs(
+        tenant_id,
+        client_id,
+        client_secret,
+        &config,
+        &mut status,
+        state_dir,
+        http_config,
+    )
+    .await?;
+    debug!("Jobs run: {:?}", names);
+    status_location.save(&http_client, &status).await?;
+    Ok((config, status, names))
+}
+
+//
+//  Scheduler daemon, sleeps until the next occurrence of any job
+//
+pub async fn do_schedule_jobs(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    config_location: &JobLocation,
+    status_location: &JobLocation,
+    state_dir: &Path,
+    http_config: &HttpConfig,
+) -> Result<(), SPOError> {
+    loop {
+        let res = do_run_scheduled_jobs(
+            tenant_id,
+            client_id,
+            client_secret,
+            config_location,
+            status_location,
+            state_dir,
+            http_config,
+        )
+        .await;
+        let config = match res {
+            Ok((config, _, _)) => Some(config),
+            // config or status errors, try again on the next check
+            Err(e) => {
+                error!("Scheduled jobs error : {}", e);
+                None
+            }
+        };
+        let now = OffsetDateTime::now_utc();
+        let sleep_secs = schedule_sleep_secs(config.as_ref(), &now);
+        debug!("Sleep {} secs", sleep_secs);
+        tokio::time::sleep(Duration::from_secs(sleep_secs)).await;
+    }
+}
+
+//
+//  Seconds until the next occurrence of the jobs, at most MAX_SCHEDULE_SLEEP_SECS
+//  so a changed config is picked up
+//
+fn schedule_sleep_secs(config: Option<&JobConfig>, now: &OffsetDateTime) -> u64 {
+    let next = config
+        .iter()
+        .flat_map(|c| c.jobs.iter())
+        .filter_map(|job| next_occurrence(&job.schedule, now).ok().flatten())
+        .min();
+    debug!("Next job at {:?}", next.map(|t| format_datetime(&t)));
+    next.map(|t| (t - *now).whole_seconds().max(0) as u64 + 1)
+        .unwrap_or(MAX_SCHEDULE_SLEEP_SECS)
+        .min(MAX_SCHEDULE_SLEEP_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(name: &str, schedule: &str) -> JobDefinition {
+        JobDefinition {
+            name: name.to_string(),
+            schedule: schedule.to_string(),
+            account: String::from("contosoblobs"),
+            container: String::from("reports"),
+            action: JobAction::Sync {
+                prefix: String::new(),
+                delete: false,
+                filter: Default::default(),
+            },
+            share_point_domain: String::from("contoso"),
+            share_point_site: String::from("MVP"),
+            share_point_path: String::from("/sites/MVP/Shared Documents"),
+            version_field: None,
+        }
+    }
+
+    #[test]
+    fn jobs_due_since_last_run() {
+        let config = JobConfig {
+            jobs: vec![job("nightly", "0 30 2 * * *"), job("hourly", "0 0 * * * *")],
+        };
+        let now = parse_datetime("2024-03-02T03:10:00Z").unwrap();
+        let mut status = JobStatusFile::default();
+        // first check only records the time
+        assert!(due_jobs(&config, &status, &now).unwrap().is_empty());
+
+        status.last_check = Some(String::from("2024-03-02T02:50:00Z"));
+        let due = due_jobs(&config, &status, &now).unwrap();
+        assert_eq!(
+            due.iter().map(|j| j.name.as_str()).collect::<Vec<_>>(),
+            vec!["hourly"]
+        );
+
+        status.last_check = Some(String::from("2024-03-01T23:00:00Z"));
+        status.jobs.insert(
+            String::from("hourly"),
+            JobStatus {
+                last_run: String::from("2024-03-02T03:00:00Z"),
+                ..Default::default()
+            },
+        );
+        let due = due_jobs(&config, &status, &now).unwrap();
+        assert_eq!(
+            due.iter().map(|j| j.name.as_str()).collect::<Vec<_>>(),
+            vec!["nightly"]
+        );
+        assert_eq!(
+            next_occurrence("0 30 2 * * *", &now).unwrap(),
+            Some(parse_datetime("2024-03-03T02:30:00Z").unwrap())
+        );
+    }
+
+    #[test]
+    fn sleep_until_the_next_job() {
+        let now = parse_datetime("2024-03-02T03:10:00Z").unwrap();
+        let config = JobConfig {
+            jobs: vec![job("nightly", "0 30 2 * * *"), job("often", "0 12 * * * *")],
+        };
+        assert_eq!(schedule_sleep_secs(Some(&config), &now), 121);
+        let config = JobConfig {
+            jobs: vec![job("nightly", "0 30 2 * * *")],
+        };
+        assert_eq!(
+            schedule_sleep_secs(Some(&config), &now),
+            MAX_SCHEDULE_SLEEP_SECS
+        );
+        // a failed run is tried again after the longest sleep
+        assert_eq!(schedule_sleep_secs(None, &now), MAX_SCHEDULE_SLEEP_SECS);
+    }
+}
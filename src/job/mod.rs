@@ -0,0 +1,3 @@
+pub mod job_batch;
+pub mod job_config;
+pub mod job_schedule;
@@ -0,0 +1,6 @@
+pub mod blob;
+pub mod common;
+pub mod event;
+pub mod job;
+pub mod route;
+pub mod spo;
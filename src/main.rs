@@ -1,16 +1,52 @@
 use std::error::Error;
-use std::time::SystemTime;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
-use clap::Parser;
+use azure_storage_blobs::prelude::RehydratePriority;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use console::Style;
-use futures::StreamExt;
 use log::{error, info};
 use spinner::{SpinnerBuilder, SpinnerHandle};
 
-use crate::blob::blob2spo::{do_copy_file_to_spo, ProcessStatus};
-
-mod blob;
-mod spo;
+use sharepoint_uploader::blob::blob2spo::{do_copy_file_to_spo, CopyOptions, ProcessStatus};
+use sharepoint_uploader::blob::blob_change_feed::{
+    do_copy_change_feed_to_spo, ChangeFeedFilter, DEFAULT_CHANGE_FEED_CURSOR_FILE,
+};
+use sharepoint_uploader::blob::blob_consistency::SourceConsistency;
+use sharepoint_uploader::blob::blob_encryption::BlobEncryption;
+use sharepoint_uploader::blob::blob_filter::{BlobFilter, BlobFilterSpec};
+use sharepoint_uploader::blob::blob_plan::{
+    plan_copy_file_to_spo, plan_sync_prefix_to_spo, CopyPlan,
+};
+use sharepoint_uploader::blob::blob_post_action::PostCopyAction;
+use sharepoint_uploader::blob::blob_query::{check_stamp_tag, do_copy_tagged_blobs_to_spo};
+use sharepoint_uploader::blob::blob_source::{
+    resolve_blob_source, BlobSource, BlobVersionSelector,
+};
+use sharepoint_uploader::blob::blob_sync::{
+    do_sync_prefix_to_spo, SyncOptions, DEFAULT_SYNC_STATE_FILE,
+};
+use sharepoint_uploader::blob::blob_tier::DEFAULT_REHYDRATE_POLL_SECS;
+use sharepoint_uploader::blob::blob_watch::{
+    do_watch_prefix_to_spo, WatchOptions, DEFAULT_WATCH_CHECKPOINT_FILE, DEFAULT_WATCH_POLL_SECS,
+    DEFAULT_WATCH_SETTLE_SECS,
+};
+use sharepoint_uploader::common::cloud::{init_cloud, CloudEndpoints, CloudEnvironment};
+use sharepoint_uploader::common::datetime::parse_datetime;
+use sharepoint_uploader::common::http_client::HttpConfig;
+use sharepoint_uploader::common::key_vault::KeyVaultResolver;
+use sharepoint_uploader::common::logging::init_logger;
+use sharepoint_uploader::common::secret::Secret;
+use sharepoint_uploader::job::job_batch::{do_batch_copy_to_spo, DEFAULT_BATCH_CONCURRENCY};
+use sharepoint_uploader::job::job_config::JobLocation;
+use sharepoint_uploader::job::job_schedule::{
+    do_run_scheduled_jobs, do_schedule_jobs, DEFAULT_JOB_STATUS_FILE,
+};
+use sharepoint_uploader::route::route_rules::{load_env_route_rules, load_route_rules, RouteRules};
+use sharepoint_uploader::spo::spo_engine::SPOError;
+use sharepoint_uploader::spo::spo_onedrive::resolve_onedrive;
+use sharepoint_uploader::spo::spo_teams::{resolve_channel_folder, DEFAULT_CHANNEL};
+use sharepoint_uploader::spo::spo_url::resolve_destination_url;
 
 fn show_status(
     status: ProcessStatus,
@@ -23,7 +59,7 @@ fn show_status(
     match status {
         ProcessStatus::StartDownload => {
             //info!("Start download blob file [{}]", cyan.apply_to(message));
-            spinner.message(message.clone().into());
+            spinner.message(message.clone());
             //spinner.update("".to_string());
         }
         ProcessStatus::Downloading => {
@@ -33,96 +69,1055 @@ fn show_status(
         }
         ProcessStatus::DownloadComplete => {
             //info!("Download complete [{}]", cyan.apply_to(message));
-            spinner.message(message.clone().into());
+            spinner.message(message.clone());
         }
         ProcessStatus::StartUpload => {
             //info!("Start upload file to share point online [{}]", cyan.apply_to(message));
-            spinner.message(message.clone().into());
+            spinner.message(message.clone());
         }
         ProcessStatus::ContinueUpload => {
             //info!("Continue upload file to share point online [{}]", cyan.apply_to(message));
-            spinner.message(message.clone().into());
+            spinner.message(message.clone());
         }
         ProcessStatus::FinishUpload => {
             //info!("Finish upload file to share point online [{}]", cyan.apply_to(message));
-            spinner.message(message.clone().into());
+            spinner.message(message.clone());
         }
         ProcessStatus::UploadComplete => {
             //info!("Upload done [{}]", cyan.apply_to(message));
             //let message = format!("{} with {} bytes", message, chunks_size);
-            spinner.message(message.clone().into());
+            spinner.message(message.clone());
         }
     }
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// Storage account for copy file to share point online
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Copy one blob (or the blobs matching --tag-query) when no command is given
+    #[command(flatten)]
+    copy: CopyArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Upload new and changed blobs below a prefix to a Share Point folder
+    Sync(SyncArgs),
+    /// Poll a container and upload new or modified blobs below a prefix until stopped
+    Watch(WatchArgs),
+    /// Upload the blobs created since the last run, read from the account change feed
+    ChangeFeed(ChangeFeedArgs),
+    /// Run the copy and sync jobs of a config file on their cron schedule until stopped
+    Schedule(ScheduleArgs),
+    /// Copy the rows of a CSV or JSON manifest and write a report of each row
+    Batch(BatchArgs),
+    /// Routing rules of the event and batch destinations
+    Route(RouteArgs),
+}
+
+#[derive(Args)]
+struct CopyArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    #[command(flatten)]
+    destination: DestinationArgs,
+    /// Share point column (internal name) which receives the copied blob version id or snapshot
+    #[arg(long)]
+    version_field: Option<String>,
+    /// Start rehydration (to Hot) of an archived source blob with this priority: Standard or High
+    #[arg(long, value_parser = parse_rehydrate_priority)]
+    rehydrate: Option<RehydratePriority>,
+    /// Wait until the rehydrated source blob is online, then copy it
+    #[arg(long, requires = "rehydrate")]
+    wait_rehydration: bool,
+    /// Poll interval in seconds while waiting for rehydration
+    #[arg(long, default_value_t = DEFAULT_REHYDRATE_POLL_SECS)]
+    rehydrate_poll_secs: u64,
+    /// Protection of the source blob against overwrites during copy: etag, lease or none
+    #[arg(long, default_value = "etag")]
+    consistency: SourceConsistency,
+    /// Compare the uploaded file length with the blob size before any post-copy action
     #[arg(long)]
-    storage_account: String,
-    /// Container name in storage account for copy file to share point online
+    verify: bool,
+    /// Action on the source blob after a successful copy:
+    /// delete, move:<container>[/<prefix>], tier:<Hot|Cool|Archive> or metadata:<name>=<value>
+    #[arg(long)]
+    post_action: Option<PostCopyAction>,
+    #[command(flatten)]
+    filter: FilterArgs,
+    #[command(flatten)]
+    plan: PlanArgs,
+    #[command(flatten)]
+    credential: CredentialArgs,
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Args)]
+struct SyncArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    /// Container name in storage account
     #[arg(long)]
     container_name: String,
-    /// Blob name or File name for copy file to share point online
+    /// Blob name prefix, the rest of the blob name is the path below --spo-path ex. reports/
+    #[arg(long, default_value = "")]
+    prefix: String,
+    #[command(flatten)]
+    destination: DestinationArgs,
+    /// Recycle Share Point files whose source blob was deleted (only files uploaded by a previous sync)
     #[arg(long)]
-    blob_name: String,
-    /// Share point domain ex. [share_point_domain].sharepoint.com
+    delete: bool,
+    /// State of the previous runs, used to skip unchanged blobs
+    #[arg(long, default_value = DEFAULT_SYNC_STATE_FILE)]
+    state_file: PathBuf,
+    /// Protection of the source blob against overwrites during copy: etag, lease or none
+    #[arg(long, default_value = "etag")]
+    consistency: SourceConsistency,
+    /// Compare the uploaded file length with the blob size
+    #[arg(long)]
+    verify: bool,
+    #[command(flatten)]
+    filter: FilterArgs,
+    #[command(flatten)]
+    plan: PlanArgs,
+    #[command(flatten)]
+    credential: CredentialArgs,
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    /// Container name in storage account
+    #[arg(long)]
+    container_name: String,
+    /// Blob name prefix, the rest of the blob name is the path below --spo-path ex. inbox/
+    #[arg(long, default_value = "")]
+    prefix: String,
+    #[command(flatten)]
+    destination: DestinationArgs,
+    /// Seconds between two listings of the container
+    #[arg(long, default_value_t = DEFAULT_WATCH_POLL_SECS)]
+    poll_secs: u64,
+    /// Blobs modified less than this many seconds ago are picked up by a later poll
+    #[arg(long, default_value_t = DEFAULT_WATCH_SETTLE_SECS)]
+    settle_secs: u64,
+    /// Last copied blob, a restart resumes from it
+    #[arg(long, default_value = DEFAULT_WATCH_CHECKPOINT_FILE)]
+    checkpoint_file: PathBuf,
+    /// Without checkpoint, only copy blobs modified after this time (RFC 3339), default all blobs
+    #[arg(long)]
+    since: Option<String>,
+    /// Protection of the source blob against overwrites during copy: etag, lease or none
+    #[arg(long, default_value = "etag")]
+    consistency: SourceConsistency,
+    /// Compare the uploaded file length with the blob size
+    #[arg(long)]
+    verify: bool,
+    #[command(flatten)]
+    filter: FilterArgs,
+    #[command(flatten)]
+    credential: CredentialArgs,
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Args)]
+struct ChangeFeedArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    /// Blobs to copy as container[/prefix], can be repeated,
+    /// the rest of the blob name is the path below --spo-path
+    #[arg(long, required = true)]
+    filter: Vec<ChangeFeedFilter>,
+    #[command(flatten)]
+    destination: DestinationArgs,
+    /// Position in the change feed, written after each batch, a restart resumes from it
+    #[arg(long, default_value = DEFAULT_CHANGE_FEED_CURSOR_FILE)]
+    cursor_file: PathBuf,
+    /// Without cursor, start at the change feed segment (hour) of this time (RFC 3339), default the beginning
+    #[arg(long)]
+    start_time: Option<String>,
+    /// Protection of the source blob against overwrites during copy: etag, lease or none
+    #[arg(long, default_value = "etag")]
+    consistency: SourceConsistency,
+    /// Compare the uploaded file length with the blob size
+    #[arg(long)]
+    verify: bool,
+    #[command(flatten)]
+    blob_filter: FilterArgs,
+    #[command(flatten)]
+    credential: CredentialArgs,
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Args)]
+struct ScheduleArgs {
+    /// Job definitions, JSON file or blob url (https://[account].blob.core.windows.net/[container]/[blob][?sas])
+    #[arg(long)]
+    config: String,
+    /// Last run status of each job, JSON file or blob url
+    #[arg(long, default_value = DEFAULT_JOB_STATUS_FILE)]
+    status: String,
+    /// Folder of the sync state files of the sync jobs
+    #[arg(long, default_value = ".")]
+    state_dir: PathBuf,
+    /// Run the due jobs once and exit, for an external scheduler
+    #[arg(long)]
+    once: bool,
+    #[command(flatten)]
+    credential: CredentialArgs,
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Args)]
+struct BatchArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    /// Manifest of the copies, .csv or .json file or blob url
+    /// (columns account, container, blob_name, share_point_domain, share_point_site, share_point_path, target_name, metadata)
+    #[arg(long)]
+    manifest: String,
+    /// Report of each row in the manifest format, file or blob url, default [manifest].report.[csv|json]
     #[arg(long)]
-    spo_domain: String,
-    /// Share point domain ex. [share_point_domain].sharepoint.com/sites/[share_point_site]
+    report: Option<String>,
+    /// Share point domain of the rows without share_point_domain
     #[arg(long)]
-    spo_site: String,
+    spo_domain: Option<String>,
+    /// Routing rules (.toml or .json file or blob url) of the rows without share_point_site
+    /// and share_point_path (default: SPO_ROUTE_RULES)
+    #[arg(long)]
+    rules: Option<String>,
+    /// Copies running at the same time
+    #[arg(long, default_value_t = DEFAULT_BATCH_CONCURRENCY)]
+    concurrency: usize,
+    /// Protection of the source blob against overwrites during copy: etag, lease or none
+    #[arg(long, default_value = "etag")]
+    consistency: SourceConsistency,
+    /// Compare the uploaded file length with the blob size
+    #[arg(long)]
+    verify: bool,
+    #[command(flatten)]
+    credential: CredentialArgs,
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+// Blobs of a listing (prefix, tag query or change feed) which are copied
+#[derive(Args)]
+struct FilterArgs {
+    /// Copy only the blobs matching a glob pattern (below the prefix, * also matches /) ex. *.pdf, can be repeated
+    #[arg(long)]
+    include: Vec<String>,
+    /// Never copy the blobs matching a glob pattern ex. **/tmp/**, can be repeated
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Minimum blob size in bytes
+    #[arg(long)]
+    min_size: Option<u64>,
+    /// Maximum blob size in bytes
+    #[arg(long)]
+    max_size: Option<u64>,
+    /// Only blobs modified after this time (RFC 3339)
+    #[arg(long)]
+    modified_after: Option<String>,
+    /// Only blobs modified before this time (RFC 3339)
+    #[arg(long)]
+    modified_before: Option<String>,
+    /// Only blobs with this content type ex. application/pdf or image/*, can be repeated
+    #[arg(long)]
+    content_type: Vec<String>,
+}
+
+impl FilterArgs {
+    fn to_spec(&self) -> BlobFilterSpec {
+        BlobFilterSpec {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            min_size: self.min_size,
+            max_size: self.max_size,
+            modified_after: self.modified_after.clone(),
+            modified_before: self.modified_before.clone(),
+            content_types: self.content_type.clone(),
+        }
+    }
+
+    fn to_blob_filter(&self) -> Result<BlobFilter, Box<dyn Error>> {
+        Ok(self.to_spec().to_blob_filter()?)
+    }
+}
+
+#[derive(Args)]
+struct RouteArgs {
+    #[command(subcommand)]
+    command: RouteCommand,
+}
+
+#[derive(Subcommand)]
+enum RouteCommand {
+    /// Show the rule matching a blob and its Share Point destination
+    Test(RouteTestArgs),
+}
+
+#[derive(Args)]
+struct RouteTestArgs {
+    /// Blob url https://[account].blob.core.windows.net/[container]/[blob] or [container]/[blob name]
+    blob: String,
+    /// Storage account of a blob given as [container]/[blob name]
+    #[arg(long)]
+    storage_account: Option<String>,
+    /// Routing rules, .toml or .json file or blob url (default: SPO_ROUTE_RULES)
+    #[arg(long)]
+    rules: Option<String>,
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+//
+//  Rules of --rules or SPO_ROUTE_RULES, no rules when neither is set
+//
+async fn load_rules(
+    rules: &Option<JobLocation>,
+    http_config: &HttpConfig,
+) -> Result<RouteRules, Box<dyn Error>> {
+    let http_client = http_config.build_client()?;
+    Ok(match rules {
+        Some(rules) => load_route_rules(rules, &http_client).await?,
+        None => load_env_route_rules(&http_client)
+            .await?
+            .unwrap_or_default(),
+    })
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PlanFormat {
+    Table,
+    Json,
+}
+
+#[derive(Args)]
+struct PlanArgs {
+    /// Authenticate, list and check the destination, then show what would be uploaded without uploading
+    #[arg(long)]
+    dry_run: bool,
+    /// Output of --dry-run
+    #[arg(long, value_enum, default_value = "table", requires = "dry_run")]
+    plan_format: PlanFormat,
+}
+
+impl PlanArgs {
+    fn print(&self, plan: &CopyPlan) -> Result<(), Box<dyn Error>> {
+        match self.plan_format {
+            PlanFormat::Table => println!("{}", plan.to_table()),
+            PlanFormat::Json => println!("{}", serde_json::to_string_pretty(plan)?),
+        }
+        Ok(())
+    }
+}
+
+fn parse_rehydrate_priority(value: &str) -> Result<RehydratePriority, String> {
+    value
+        .parse()
+        .map_err(|_| String::from("expect Standard or High"))
+}
+
+// Required by clap, Option only because the copy arguments are also parsed (empty) when a command is given
+#[derive(Args)]
+struct DestinationArgs {
+    /// Share point domain ex. [share_point_domain].sharepoint.com
+    #[arg(long, required_unless_present_any = ["destination_url", "team"])]
+    spo_domain: Option<String>,
+    /// Share point site ex. [share_point_domain].sharepoint.com/sites/[share_point_site],
+    /// or a web path: / for the root site, /teams/[site], /sites/[site]/[subsite]
+    #[arg(long, required_unless_present_any = ["destination_url", "onedrive_user", "team"])]
+    spo_site: Option<String>,
     /// Share point domain ex. [share_point_domain].sharepoint.com/sites/[share_point_site]/_api/web/GetFileByServerRelativeUrl('[spo_path]')
+    /// With --onedrive-user or --team, the folder below Documents or the channel folder (created when missing)
+    #[arg(long, required_unless_present_any = ["destination_url", "onedrive_user", "team"])]
+    spo_path: Option<String>,
+    /// Folder url copied from the browser instead of --spo-domain, --spo-site and --spo-path
+    /// ex. https://[share_point_domain].sharepoint.com/sites/[share_point_site]/Shared%20Documents/Reports
+    #[arg(long, conflicts_with_all = ["spo_domain", "spo_site", "spo_path"])]
+    destination_url: Option<String>,
+    /// OneDrive of the user principal name ex. john.doe@contoso.com, on [spo_domain]-my.sharepoint.com
+    #[arg(long, requires = "spo_domain", conflicts_with_all = ["spo_site", "destination_url"])]
+    onedrive_user: Option<String>,
+    /// Team id or name, the files go to the folder of --channel (default: General), on its own site for
+    /// private and shared channels
+    #[arg(
+        long,
+        conflicts_with_all = ["spo_domain", "spo_site", "destination_url", "onedrive_user"]
+    )]
+    team: Option<String>,
+    /// Channel id or name of --team
+    #[arg(long, requires = "team")]
+    channel: Option<String>,
+}
+
+impl DestinationArgs {
+    //
+    //  (share_point_domain, share_point_site, share_point_path), a destination url, a OneDrive
+    //  or a Teams channel is resolved online
+    //
+    async fn into_parts(
+        self,
+        tenant_id: &String,
+        client_id: &String,
+        client_secret: &Secret,
+        http_config: &HttpConfig,
+    ) -> Result<(String, String, String), SPOError> {
+        if let Some(destination_url) = &self.destination_url {
+            let folder_url = resolve_destination_url(
+                tenant_id,
+                client_id,
+                client_secret,
+                destination_url,
+                http_config,
+            )
+            .await?;
+            info!(
+                "Destination site {} folder {}",
+                folder_url.share_point_site, folder_url.share_point_path
+            );
+            return Ok((
+                folder_url.share_point_domain,
+                folder_url.share_point_site,
+                folder_url.share_point_path,
+            ));
+        }
+        if let Some(team) = &self.team {
+            let folder_url = resolve_channel_folder(
+                tenant_id,
+                client_id,
+                client_secret,
+                team,
+                &self.channel.unwrap_or(String::from(DEFAULT_CHANNEL)),
+                &self.spo_path.unwrap_or_default(),
+                http_config,
+            )
+            .await?;
+            info!(
+                "Destination channel site {} folder {}",
+                folder_url.share_point_site, folder_url.share_point_path
+            );
+            return Ok((
+                folder_url.share_point_domain,
+                folder_url.share_point_site,
+                folder_url.share_point_path,
+            ));
+        }
+        if let Some(onedrive_user) = &self.onedrive_user {
+            let onedrive = resolve_onedrive(
+                tenant_id,
+                client_id,
+                client_secret,
+                &self.spo_domain.unwrap_or_default(),
+                onedrive_user,
+                &self.spo_path.unwrap_or_default(),
+                &http_config.build_client()?,
+            )
+            .await?;
+            info!(
+                "Destination OneDrive {} folder {}",
+                onedrive.share_point_site, onedrive.share_point_path
+            );
+            return Ok((
+                onedrive.share_point_domain,
+                onedrive.share_point_site,
+                onedrive.share_point_path,
+            ));
+        }
+        Ok((
+            self.spo_domain.unwrap_or_default(),
+            self.spo_site.unwrap_or_default(),
+            self.spo_path.unwrap_or_default(),
+        ))
+    }
+}
+
+#[derive(Args)]
+struct StorageArgs {
+    /// Storage account for copy file to share point online, optional with --source-url or --connection-string
+    #[arg(long)]
+    storage_account: Option<String>,
+    /// Storage account key (default: AZURE_STORAGE_KEY), accepts Key Vault reference
+    #[arg(long, conflicts_with = "connection_string")]
+    account_key: Option<String>,
+    /// Storage connection string, UseDevelopmentStorage=true for Azurite (default: AZURE_STORAGE_CONNECTION_STRING),
+    /// accepts Key Vault reference
+    #[arg(long)]
+    connection_string: Option<String>,
+    /// Customer-provided key (base64 AES-256) of the source blob (default: AZURE_STORAGE_ENCRYPTION_KEY),
+    /// accepts Key Vault reference
+    #[arg(long, conflicts_with = "encryption_scope")]
+    encryption_key: Option<String>,
+    /// Base64 SHA256 of the customer-provided key (default: AZURE_STORAGE_ENCRYPTION_KEY_SHA256)
     #[arg(long)]
-    spo_path: String,
+    encryption_key_sha256: Option<String>,
+    /// Encryption scope the source blob is expected to use
+    #[arg(long)]
+    encryption_scope: Option<String>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    pretty_env_logger::init();
+impl StorageArgs {
+    async fn to_blob_source(
+        &self,
+        resolver: &KeyVaultResolver,
+        container_name: &Option<String>,
+        blob_name: &Option<String>,
+        source_url: &Option<String>,
+    ) -> Result<BlobSource, Box<dyn Error>> {
+        let account_key = self
+            .account_key
+            .clone()
+            .or(std::env::var("AZURE_STORAGE_KEY").ok());
+        let connection_string = self
+            .connection_string
+            .clone()
+            .or(std::env::var("AZURE_STORAGE_CONNECTION_STRING").ok());
+        // explicit source url / account key wins over the connection string from environment
+        let connection_string = if source_url.is_some() || self.account_key.is_some() {
+            self.connection_string.clone()
+        } else {
+            connection_string
+        };
+        let encryption_key = self
+            .encryption_key
+            .clone()
+            .or(std::env::var("AZURE_STORAGE_ENCRYPTION_KEY").ok());
+        let encryption_key_sha256 = self
+            .encryption_key_sha256
+            .clone()
+            .or(std::env::var("AZURE_STORAGE_ENCRYPTION_KEY_SHA256").ok());
+        let encryption = BlobEncryption::new(
+            resolver.resolve_option(&encryption_key).await?,
+            encryption_key_sha256,
+            self.encryption_scope.clone(),
+        )?;
+        Ok(resolve_blob_source(
+            &self.storage_account,
+            container_name,
+            blob_name,
+            resolver.resolve_option(source_url).await?,
+            resolver.resolve_option(&account_key).await?,
+            resolver.resolve_option(&connection_string).await?,
+        )?
+        .set_encryption(encryption))
+    }
+}
 
-    let cli = Cli::parse();
+#[derive(Args)]
+struct SourceArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    /// Container name in storage account for copy file to share point online,
+    /// optional with --tag-query to search the whole account
+    #[arg(long, required_unless_present_any = ["source_url", "tag_query"])]
+    container_name: Option<String>,
+    /// Blob name or File name for copy file to share point online
+    #[arg(long, required_unless_present_any = ["source_url", "tag_query"])]
+    blob_name: Option<String>,
+    /// Copy every blob matching this blob index tag query instead of a single blob
+    /// ex. "status='approved' AND dept='finance'"
+    #[arg(long, conflicts_with_all = ["blob_name", "source_url", "version_id", "snapshot", "as_of"])]
+    tag_query: Option<String>,
+    /// Blob index tag set to the copy time on each blob copied by --tag-query ex. spo_copied
+    #[arg(long, requires = "tag_query")]
+    stamp_tag: Option<String>,
+    /// Full blob SAS url instead of storage account / container / blob name, accepts Key Vault reference
+    #[arg(long, conflicts_with_all = ["storage_account", "container_name", "blob_name", "account_key", "connection_string"])]
+    source_url: Option<String>,
+    /// Copy this blob version id instead of the current version
+    #[arg(long, conflicts_with_all = ["snapshot", "as_of"])]
+    version_id: Option<String>,
+    /// Copy this blob snapshot (timestamp) instead of the current version
+    #[arg(long, conflicts_with = "as_of")]
+    snapshot: Option<String>,
+    /// Copy the version which was current at this time (RFC 3339) ex. 2023-12-31T23:59:59Z
+    #[arg(long)]
+    as_of: Option<String>,
+}
+
+impl SourceArgs {
+    async fn to_blob_source(
+        &self,
+        resolver: &KeyVaultResolver,
+    ) -> Result<BlobSource, Box<dyn Error>> {
+        let version =
+            BlobVersionSelector::from_options(&self.version_id, &self.snapshot, &self.as_of)?;
+        Ok(self
+            .storage
+            .to_blob_source(
+                resolver,
+                &self.container_name,
+                &self.blob_name,
+                &self.source_url,
+            )
+            .await?
+            .set_version(version))
+    }
+}
+
+#[derive(Args)]
+struct CredentialArgs {
+    /// Tenant id of App Registration (default: AZURE_TENANT_ID), accepts Key Vault reference
+    #[arg(long)]
+    tenant_id: Option<String>,
+    /// Client id of App Registration (default: AZURE_CLIENT_ID), accepts Key Vault reference
+    #[arg(long)]
+    client_id: Option<String>,
+    /// Client secret of App Registration (default: AZURE_CLIENT_SECRET), accepts Key Vault reference
+    /// ex. kv://[vault]/[secret] or @Microsoft.KeyVault(SecretUri=...)
+    #[arg(long)]
+    client_secret: Option<String>,
+}
+
+impl CredentialArgs {
+    //
+    //  Resolve (tenant_id, client_id, client_secret), Key Vault references are resolved with the ambient identity
+    //
+    async fn resolve(
+        &self,
+        resolver: &KeyVaultResolver,
+    ) -> Result<(String, String, Secret), Box<dyn Error>> {
+        let tenant_id = resolve_arg(resolver, &self.tenant_id, "AZURE_TENANT_ID").await?;
+        let client_id = resolve_arg(resolver, &self.client_id, "AZURE_CLIENT_ID").await?;
+        let client_secret =
+            resolve_arg(resolver, &self.client_secret, "AZURE_CLIENT_SECRET").await?;
+        Ok((
+            tenant_id.expose().to_owned(),
+            client_id.expose().to_owned(),
+            client_secret,
+        ))
+    }
+}
+
+async fn resolve_arg(
+    resolver: &KeyVaultResolver,
+    arg: &Option<String>,
+    env_key: &str,
+) -> Result<Secret, Box<dyn Error>> {
+    let value = match arg {
+        Some(v) => v.to_owned(),
+        None => std::env::var(env_key).map_err(|_| format!("{} is required", env_key))?,
+    };
+    Ok(resolver.resolve(&value).await?)
+}
+
+#[derive(Args)]
+struct HttpArgs {
+    /// Proxy for Storage Account and Share Point Online traffic ex. http://proxy:8080 (default: SPO_PROXY_URL or HTTPS_PROXY)
+    #[arg(long)]
+    proxy_url: Option<String>,
+    /// Proxy basic authentication user name (default: SPO_PROXY_USERNAME)
+    #[arg(long, requires = "proxy_password")]
+    proxy_username: Option<String>,
+    /// Proxy basic authentication password (default: SPO_PROXY_PASSWORD)
+    #[arg(long, requires = "proxy_username")]
+    proxy_password: Option<Secret>,
+    /// Comma separated hosts which bypass the proxy (default: SPO_NO_PROXY or NO_PROXY)
+    #[arg(long)]
+    no_proxy: Option<String>,
+    /// Extra trusted CA certificates (PEM bundle), can be repeated (default: SPO_CA_CERTIFICATES)
+    #[arg(long)]
+    ca_cert: Vec<String>,
+    /// Cloud of the tenant: public, usgov, usgovhigh, dod, china or custom (default: SPO_CLOUD)
+    #[arg(long)]
+    cloud: Option<CloudEnvironment>,
+}
+
+impl HttpArgs {
+    fn to_http_config(&self) -> HttpConfig {
+        let mut http_config = HttpConfig::from_env();
+        if let Some(proxy_url) = &self.proxy_url {
+            http_config.set_proxy_url(proxy_url);
+        }
+        if let (Some(username), Some(password)) = (&self.proxy_username, &self.proxy_password) {
+            http_config.set_proxy_credentials(username, password);
+        }
+        if let Some(no_proxy) = &self.no_proxy {
+            http_config.set_no_proxy(no_proxy);
+        }
+        for ca_cert in &self.ca_cert {
+            http_config.add_ca_certificate(ca_cert);
+        }
+        http_config
+    }
+    //
+    //  Share Point, token and storage hosts of the process, SPO_CLOUD_* settings override them
+    //
+    fn init_cloud(&self) -> Result<(), SPOError> {
+        let endpoints = match self.cloud {
+            Some(environment) => CloudEndpoints::from_settings(environment, |key| {
+                std::env::var(key).ok().filter(|v| !v.trim().is_empty())
+            })?,
+            None => CloudEndpoints::from_env()?,
+        };
+        init_cloud(endpoints);
+        Ok(())
+    }
+}
+
+//
+//  Http configuration, Key Vault resolver and Share Point credentials shared by all commands
+//
+async fn resolve_common(
+    credential: &CredentialArgs,
+    http: &HttpArgs,
+) -> Result<(HttpConfig, KeyVaultResolver, String, String, Secret), Box<dyn Error>> {
+    http.init_cloud()?;
+    let http_config = http.to_http_config();
 
     // Common parameters for uses authentication for Storage Account , Share Point Online
     // Client Secret got from App Registration in Azure Active Directory
-    let tenant_id = std::env::var("AZURE_TENANT_ID").unwrap();
-    let client_id = std::env::var("AZURE_CLIENT_ID").unwrap();
-    let client_secret = std::env::var("AZURE_CLIENT_SECRET").unwrap();
+    let resolver = KeyVaultResolver::new(&http_config.build_client()?);
+    // the blobs are read with the same application, see BlobSource::set_application. It is never
+    // written to the AZURE_* variables, they are inherited by child processes (Azure CLI credential)
+    let (tenant_id, client_id, client_secret) = credential.resolve(&resolver).await?;
+    Ok((http_config, resolver, tenant_id, client_id, client_secret))
+}
+
+async fn copy(args: CopyArgs) -> Result<(), Box<dyn Error>> {
+    check_stamp_tag(&args.source.stamp_tag, &args.post_action)?;
+    let (http_config, resolver, tenant_id, client_id, client_secret) =
+        resolve_common(&args.credential, &args.http).await?;
 
     // Parameters for blob storage
-    let account = cli.storage_account;
-    let container = cli.container_name;
-    let blob_name = cli.blob_name;
+    let source = args
+        .source
+        .to_blob_source(&resolver)
+        .await?
+        .set_application(&tenant_id, &client_id, &client_secret);
 
     // Parameters for share point online
-    let share_point_domain = cli.spo_domain;
-    let share_point_site = cli.spo_site;
-    let share_point_path = cli.spo_path;
+    let (share_point_domain, share_point_site, share_point_path) = args
+        .destination
+        .into_parts(&tenant_id, &client_id, &client_secret, &http_config)
+        .await?;
+
+    if args.source.tag_query.is_none() && args.filter.to_spec() != BlobFilterSpec::default() {
+        return Err("blob filters apply to --tag-query".into());
+    }
+    let filter = args.filter.to_blob_filter()?;
+    let options = CopyOptions {
+        version_field: args.version_field,
+        rehydrate_priority: args.rehydrate,
+        rehydrate_wait_secs: args.wait_rehydration.then_some(args.rehydrate_poll_secs),
+        consistency: args.consistency,
+        verify: args.verify,
+        post_action: args.post_action,
+        target_name: None,
+        metadata: vec![],
+    };
+
+    if args.plan.dry_run {
+        if args.source.tag_query.is_some() {
+            return Err("--dry-run is not supported with --tag-query".into());
+        }
+        let plan = plan_copy_file_to_spo(
+            &tenant_id,
+            &client_id,
+            &client_secret,
+            &share_point_domain,
+            &share_point_site,
+            &share_point_path,
+            &source,
+            &http_config,
+            &options,
+        )
+        .await?;
+        return args.plan.print(&plan);
+    }
 
     let sp = SpinnerBuilder::new("Copy file to SPO".into()).start();
     let start = SystemTime::now();
 
-    let res = do_copy_file_to_spo(
+    if let Some(tag_query) = &args.source.tag_query {
+        let res = do_copy_tagged_blobs_to_spo(
+            &tenant_id,
+            &client_id,
+            &client_secret,
+            &share_point_domain,
+            &share_point_site,
+            &share_point_path,
+            &source,
+            tag_query,
+            &args.source.stamp_tag,
+            &filter,
+            &http_config,
+            &options,
+            Some(show_status),
+            Some(&sp),
+        )
+        .await;
+        match res {
+            Ok(summary) => {
+                for r in &summary.copied {
+                    info!("Copy file to SPO complete : {:?}", r);
+                }
+                for f in &summary.failed {
+                    error!(
+                        "Copy file to SPO error : {}/{} {}",
+                        f.container, f.blob_name, f.error
+                    );
+                }
+                info!(
+                    "Copied {} blobs, {} failed",
+                    summary.copied.len(),
+                    summary.failed.len()
+                );
+            }
+            Err(e) => {
+                error!("Find blobs by tags error : {}", e);
+            }
+        }
+    } else {
+        let res = do_copy_file_to_spo(
+            &tenant_id,
+            &client_id,
+            &client_secret,
+            &share_point_domain,
+            &share_point_site,
+            &share_point_path,
+            &source,
+            &http_config,
+            &options,
+            Some(show_status),
+            Some(&sp),
+        )
+        .await;
+        match res {
+            Ok(r) => {
+                info!("Copy file to SPO complete : {:?}", r);
+            }
+            Err(e) => {
+                error!("Copy file to SPO error : {}", e);
+            }
+        }
+    }
+
+    let diff = SystemTime::now().duration_since(start).unwrap();
+    info!("Executed complete : {:?} secs", diff.as_secs());
+
+    Ok(())
+}
+
+async fn sync(args: SyncArgs) -> Result<(), Box<dyn Error>> {
+    let (http_config, resolver, tenant_id, client_id, client_secret) =
+        resolve_common(&args.credential, &args.http).await?;
+
+    let source = args
+        .storage
+        .to_blob_source(&resolver, &Some(args.container_name), &None, &None)
+        .await?
+        .set_application(&tenant_id, &client_id, &client_secret);
+    let options = CopyOptions {
+        consistency: args.consistency,
+        verify: args.verify,
+        ..Default::default()
+    };
+    let sync_options = SyncOptions {
+        prefix: args.prefix,
+        delete: args.delete,
+        state_file: args.state_file,
+        filter: args.filter.to_blob_filter()?,
+    };
+    let (share_point_domain, share_point_site, share_point_path) = args
+        .destination
+        .into_parts(&tenant_id, &client_id, &client_secret, &http_config)
+        .await?;
+
+    if args.plan.dry_run {
+        let plan = plan_sync_prefix_to_spo(
+            &tenant_id,
+            &client_id,
+            &client_secret,
+            &share_point_domain,
+            &share_point_site,
+            &share_point_path,
+            &source,
+            &http_config,
+            &sync_options,
+        )
+        .await?;
+        return args.plan.print(&plan);
+    }
+
+    let sp = SpinnerBuilder::new("Sync blobs to SPO".into()).start();
+    let start = SystemTime::now();
+
+    let res = do_sync_prefix_to_spo(
+        &tenant_id,
+        &client_id,
+        &client_secret,
+        &share_point_domain,
+        &share_point_site,
+        &share_point_path,
+        &source,
+        &http_config,
+        &options,
+        &sync_options,
+        Some(show_status),
+        Some(&sp),
+    )
+    .await;
+    sp.close();
+    match res {
+        Ok(summary) => {
+            for path in &summary.added {
+                println!("added   {}", path);
+            }
+            for path in &summary.updated {
+                println!("updated {}", path);
+            }
+            for path in &summary.deleted {
+                println!("deleted {}", path);
+            }
+            for f in &summary.failed {
+                println!("failed  {} : {}", f.blob_name, f.error);
+            }
+            println!(
+                "{} added, {} updated, {} deleted, {} skipped, {} excluded, {} failed",
+                summary.added.len(),
+                summary.updated.len(),
+                summary.deleted.len(),
+                summary.skipped,
+                summary.excluded,
+                summary.failed.len()
+            );
+        }
+        Err(e) => {
+            error!("Sync blobs to SPO error : {}", e);
+        }
+    }
+
+    let diff = SystemTime::now().duration_since(start).unwrap();
+    info!("Executed complete : {:?} secs", diff.as_secs());
+
+    Ok(())
+}
+
+async fn watch(args: WatchArgs) -> Result<(), Box<dyn Error>> {
+    let (http_config, resolver, tenant_id, client_id, client_secret) =
+        resolve_common(&args.credential, &args.http).await?;
+
+    let source = args
+        .storage
+        .to_blob_source(&resolver, &Some(args.container_name), &None, &None)
+        .await?
+        .set_application(&tenant_id, &client_id, &client_secret);
+    let options = CopyOptions {
+        consistency: args.consistency,
+        verify: args.verify,
+        ..Default::default()
+    };
+    let since = match &args.since {
+        Some(since) => Some(parse_datetime(since)?),
+        None => None,
+    };
+    let watch_options = WatchOptions {
+        prefix: args.prefix,
+        poll_interval: Duration::from_secs(args.poll_secs),
+        settle: Duration::from_secs(args.settle_secs),
+        checkpoint_file: args.checkpoint_file,
+        since,
+        filter: args.filter.to_blob_filter()?,
+    };
+    let (share_point_domain, share_point_site, share_point_path) = args
+        .destination
+        .into_parts(&tenant_id, &client_id, &client_secret, &http_config)
+        .await?;
+
+    let sp = SpinnerBuilder::new("Watch blobs".into()).start();
+    do_watch_prefix_to_spo(
+        &tenant_id,
+        &client_id,
+        &client_secret,
+        &share_point_domain,
+        &share_point_site,
+        &share_point_path,
+        &source,
+        &http_config,
+        &options,
+        &watch_options,
+        Some(show_status),
+        Some(&sp),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn change_feed(args: ChangeFeedArgs) -> Result<(), Box<dyn Error>> {
+    let (http_config, resolver, tenant_id, client_id, client_secret) =
+        resolve_common(&args.credential, &args.http).await?;
+
+    let source = args
+        .storage
+        .to_blob_source(&resolver, &None, &None, &None)
+        .await?
+        .set_application(&tenant_id, &client_id, &client_secret);
+    let options = CopyOptions {
+        consistency: args.consistency,
+        verify: args.verify,
+        ..Default::default()
+    };
+    let start_time = match &args.start_time {
+        Some(start_time) => Some(parse_datetime(start_time)?),
+        None => None,
+    };
+    let (share_point_domain, share_point_site, share_point_path) = args
+        .destination
+        .into_parts(&tenant_id, &client_id, &client_secret, &http_config)
+        .await?;
+
+    let sp = SpinnerBuilder::new("Copy change feed to SPO".into()).start();
+    let start = SystemTime::now();
+
+    let res = do_copy_change_feed_to_spo(
         &tenant_id,
         &client_id,
         &client_secret,
         &share_point_domain,
         &share_point_site,
         &share_point_path,
-        &account,
-        &container,
-        &blob_name,
+        &source,
+        &args.filter,
+        &args.blob_filter.to_blob_filter()?,
+        &args.cursor_file,
+        &start_time,
+        &http_config,
+        &options,
         Some(show_status),
         Some(&sp),
     )
     .await;
     match res {
-        Ok(_) => {
-            info!("Copy file to SPO complete");
+        Ok(summary) => {
+            for r in &summary.copied {
+                info!("Copy file to SPO complete : {:?}", r);
+            }
+            for f in &summary.failed {
+                error!(
+                    "Copy file to SPO error : {}/{} {}",
+                    f.container, f.blob_name, f.error
+                );
+            }
+            info!(
+                "Copied {} blobs, {} failed",
+                summary.copied.len(),
+                summary.failed.len()
+            );
         }
         Err(e) => {
-            error!("Copy file to SPO error : {}", e);
+            error!("Read change feed error : {}", e);
         }
     }
 
@@ -131,3 +1126,200 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+async fn schedule(args: ScheduleArgs) -> Result<(), Box<dyn Error>> {
+    let (http_config, _, tenant_id, client_id, client_secret) =
+        resolve_common(&args.credential, &args.http).await?;
+
+    let config_location =
+        JobLocation::parse(&args.config)?.set_application(&tenant_id, &client_id, &client_secret);
+    let status_location =
+        JobLocation::parse(&args.status)?.set_application(&tenant_id, &client_id, &client_secret);
+    if !args.once {
+        do_schedule_jobs(
+            &tenant_id,
+            &client_id,
+            &client_secret,
+            &config_location,
+            &status_location,
+            &args.state_dir,
+            &http_config,
+        )
+        .await?;
+        return Ok(());
+    }
+    let (config, status, _) = do_run_scheduled_jobs(
+        &tenant_id,
+        &client_id,
+        &client_secret,
+        &config_location,
+        &status_location,
+        &args.state_dir,
+        &http_config,
+    )
+    .await?;
+    for job in &config.jobs {
+        match status.jobs.get(&job.name) {
+            Some(s) if s.succeeded => println!("{:<24} ok     {}", job.name, s.last_run),
+            Some(s) => println!(
+                "{:<24} failed {} {}",
+                job.name,
+                s.last_run,
+                s.error.clone().unwrap_or_default()
+            ),
+            None => println!("{:<24} never run", job.name),
+        }
+    }
+    Ok(())
+}
+
+async fn batch(args: BatchArgs) -> Result<(), Box<dyn Error>> {
+    let (http_config, resolver, tenant_id, client_id, client_secret) =
+        resolve_common(&args.credential, &args.http).await?;
+
+    let source = args
+        .storage
+        .to_blob_source(&resolver, &None, &None, &None)
+        .await?
+        .set_application(&tenant_id, &client_id, &client_secret);
+    let options = CopyOptions {
+        consistency: args.consistency,
+        verify: args.verify,
+        ..Default::default()
+    };
+    let manifest =
+        JobLocation::parse(&args.manifest)?.set_application(&tenant_id, &client_id, &client_secret);
+    let report = match &args.report {
+        Some(report) => {
+            JobLocation::parse(report)?.set_application(&tenant_id, &client_id, &client_secret)
+        }
+        // local report next to a local manifest, in the current folder for a blob
+        None => {
+            let name = manifest.name();
+            let (stem, extension) = name.rsplit_once('.').unwrap_or((&name, ""));
+            let report = format!("{}.report.{}", stem, extension);
+            JobLocation::File(match &manifest {
+                JobLocation::File(_) => PathBuf::from(report),
+                JobLocation::Blob(_) => {
+                    PathBuf::from(report.rsplit('/').next().unwrap_or_default())
+                }
+            })
+        }
+    };
+
+    let rules = match &args.rules {
+        Some(rules) => {
+            Some(JobLocation::parse(rules)?.set_application(&tenant_id, &client_id, &client_secret))
+        }
+        None => None,
+    };
+    let rules = load_rules(&rules, &http_config).await?;
+
+    let sp = SpinnerBuilder::new("Copy manifest to SPO".into()).start();
+    let start = SystemTime::now();
+
+    let res = do_batch_copy_to_spo(
+        &tenant_id,
+        &client_id,
+        &client_secret,
+        &args.spo_domain,
+        &source,
+        &manifest,
+        &Some(report.clone()),
+        &rules,
+        args.concurrency,
+        &http_config,
+        &options,
+    )
+    .await;
+    sp.close();
+    match res {
+        Ok(summary) => {
+            for r in summary.rows.iter().filter(|r| r.error.is_some()) {
+                println!(
+                    "{}/{} : {} {}",
+                    r.row.container,
+                    r.row.blob_name,
+                    r.error_class.clone().unwrap_or_default(),
+                    r.error.clone().unwrap_or_default()
+                );
+            }
+            println!(
+                "{} copied, {} failed, {} invalid, report {}",
+                summary.copied,
+                summary.failed,
+                summary.invalid,
+                report.name()
+            );
+        }
+        Err(e) => {
+            error!("Batch copy to SPO error : {}", e);
+        }
+    }
+
+    let diff = SystemTime::now().duration_since(start).unwrap();
+    info!("Executed complete : {:?} secs", diff.as_secs());
+
+    Ok(())
+}
+
+async fn route_test(args: RouteTestArgs) -> Result<(), Box<dyn Error>> {
+    args.http.init_cloud()?;
+    let http_config = args.http.to_http_config();
+    let rules = match &args.rules {
+        Some(rules) => Some(JobLocation::parse(rules)?),
+        None => None,
+    };
+    let rules = load_rules(&rules, &http_config).await?;
+    if rules.is_empty() {
+        return Err("no routing rules, set --rules or SPO_ROUTE_RULES".into());
+    }
+    let (account, container, blob_name) = match JobLocation::parse(&args.blob)? {
+        JobLocation::Blob(source) => (source.account, source.container, source.blob_name),
+        JobLocation::File(_) => match args.blob.split_once('/') {
+            Some((container, blob_name)) => (
+                args.storage_account.unwrap_or_default(),
+                container.to_string(),
+                blob_name.to_string(),
+            ),
+            None => return Err("expect a blob url or [container]/[blob name]".into()),
+        },
+    };
+    match rules.resolve(&account, &container, &blob_name)? {
+        Some(destination) => {
+            println!("Rule     : {}", destination.rule);
+            println!(
+                "Domain   : {}",
+                destination.share_point_domain.clone().unwrap_or_default()
+            );
+            println!("Site     : {}", destination.share_point_site);
+            println!("Library  : {}", destination.library);
+            println!("Folder   : {}", destination.share_point_path());
+            println!("File     : {}", destination.file_name);
+            for (field, value) in &destination.metadata {
+                println!("Metadata : {}={}", field, value);
+            }
+            Ok(())
+        }
+        None => Err(format!("no rule matches {}/{}/{}", account, container, blob_name).into()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    init_logger();
+
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Command::Sync(args)) => sync(args).await,
+        Some(Command::Watch(args)) => watch(args).await,
+        Some(Command::ChangeFeed(args)) => change_feed(args).await,
+        Some(Command::Schedule(args)) => schedule(args).await,
+        Some(Command::Batch(args)) => batch(args).await,
+        Some(Command::Route(RouteArgs {
+            command: RouteCommand::Test(args),
+        })) => route_test(args).await,
+        None => copy(cli.copy).await,
+    }
+}
@@ -1,28 +1,125 @@
+use std::collections::BTreeMap;
 use std::net::Ipv4Addr;
+use std::sync::OnceLock;
 
+use azure_storage_blobs::prelude::RehydratePriority;
 use log::debug;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use warp::reject::Reject;
 use warp::{Filter, Rejection};
 
-use crate::blob::blob2spo::do_copy_file_to_spo;
-use crate::spo::spo_engine::SPOError;
-
-mod blob;
-mod spo;
+use sharepoint_uploader::blob::blob2spo::{do_copy_file_to_spo, CopyOptions};
+use sharepoint_uploader::blob::blob_consistency::SourceConsistency;
+use sharepoint_uploader::blob::blob_encryption::BlobEncryption;
+use sharepoint_uploader::blob::blob_filter::BlobFilterSpec;
+use sharepoint_uploader::blob::blob_post_action::PostCopyAction;
+use sharepoint_uploader::blob::blob_query::{check_stamp_tag, do_copy_tagged_blobs_to_spo};
+use sharepoint_uploader::blob::blob_source::{
+    resolve_blob_source, BlobSource, BlobVersionSelector,
+};
+use sharepoint_uploader::common::cloud::{init_cloud, CloudEndpoints};
+use sharepoint_uploader::common::http_client::HttpConfig;
+use sharepoint_uploader::common::key_vault::{allowed_vaults_from_env, KeyVaultResolver};
+use sharepoint_uploader::common::logging::init_logger;
+use sharepoint_uploader::common::secret::Secret;
+use sharepoint_uploader::event::event_grid::{
+    parse_event_grid_payload, BlobCreatedEvent, EventGridPayload,
+};
+use sharepoint_uploader::event::event_route::{
+    do_copy_blob_created_to_spo, event_routes_to_rules, load_event_routes,
+};
+use sharepoint_uploader::job::job_batch::{
+    check_manifest_rows, checked_rows_report, copy_manifest_row, format_report, read_manifest,
+    BatchRowResult, BatchRowStatus, ManifestRow, RowFolder,
+};
+use sharepoint_uploader::job::job_config::JobLocation;
+use sharepoint_uploader::job::job_schedule::do_run_scheduled_jobs;
+use sharepoint_uploader::route::route_rules::{load_env_route_rules, load_route_rules};
+use sharepoint_uploader::spo::spo_engine::{SPOError, SPOErrorKind};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct UploadFileToSPORequest {
     tenant_id: String,
     client_id: String,
-    client_secret: String,
+    client_secret: Secret,
     share_point_domain: String,
     share_point_site: String,
     share_point_path: String,
-    account: String,
-    container: String,
-    blob_name: String,
+    #[serde(default)]
+    account: Option<String>,
+    #[serde(default)]
+    container: Option<String>,
+    #[serde(default)]
+    blob_name: Option<String>,
+    /// Full blob SAS url instead of account / container / blob_name
+    #[serde(default)]
+    source_sas_url: Option<Secret>,
+    #[serde(default)]
+    account_key: Option<Secret>,
+    /// UseDevelopmentStorage=true for Azurite
+    #[serde(default)]
+    connection_string: Option<Secret>,
+    #[serde(default)]
+    version_id: Option<String>,
+    #[serde(default)]
+    snapshot: Option<String>,
+    /// Copy the version which was current at this time (RFC 3339)
+    #[serde(default)]
+    as_of: Option<String>,
+    /// Share point column (internal name) which receives the copied blob version
+    #[serde(default)]
+    version_field: Option<String>,
+    /// Customer-provided key (base64 AES-256) of the source blob
+    #[serde(default)]
+    encryption_key: Option<Secret>,
+    #[serde(default)]
+    encryption_key_sha256: Option<String>,
+    /// Encryption scope the source blob is expected to use
+    #[serde(default)]
+    encryption_scope: Option<String>,
+    /// Start rehydration of an archived source blob: Standard or High
+    #[serde(default)]
+    rehydrate_priority: Option<RehydratePriority>,
+    /// Protection of the source blob against overwrites during copy: etag (default), lease or none
+    #[serde(default)]
+    consistency: SourceConsistency,
+    /// Compare the uploaded file length with the blob size before the post action
+    #[serde(default)]
+    verify: bool,
+    /// delete, move:<container>[/<prefix>], tier:<Hot|Cool|Archive> or metadata:<name>=<value>
+    #[serde(default)]
+    post_action: Option<PostCopyAction>,
+    /// Copy every blob matching this blob index tag query, container is optional
+    #[serde(default)]
+    tag_query: Option<String>,
+    /// Blob index tag set to the copy time on each blob copied by tag_query
+    #[serde(default)]
+    stamp_tag: Option<String>,
+    /// Blobs of tag_query which are copied: include, exclude, min_size, max_size,
+    /// modified_after, modified_before, content_types
+    #[serde(default, flatten)]
+    filter: BlobFilterSpec,
+}
+
+//
+//  Manifest, report and rules are blob urls, blobs are read with the function identity
+//
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BatchCopyRequest {
+    manifest: String,
+    /// Written with the rows queued, or the invalid rows
+    #[serde(default)]
+    report: Option<String>,
+    /// Storage account of the rows without account
+    #[serde(default)]
+    account: Option<String>,
+    /// Share point domain of the rows without share_point_domain
+    #[serde(default)]
+    share_point_domain: Option<String>,
+    /// Routing rules (.toml or .json blob url) of the rows without site and path,
+    /// default SPO_ROUTE_RULES
+    #[serde(default)]
+    rules: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,26 +140,739 @@ async fn copy_file_blob_to_spo(
 ) -> Result<impl warp::Reply, warp::Rejection> {
     debug!("Request: {:#?}", req);
 
-    std::env::set_var("AZURE_TENANT_ID", &req.tenant_id);
-    std::env::set_var("AZURE_CLIENT_ID", &req.client_id);
-    std::env::set_var("AZURE_CLIENT_SECRET", &req.client_secret);
+    copy_request(&req)
+        .await
+        .map(|r| warp::reply::json(&r))
+        .map_err(|e| warp::reject::custom(UploadFileToSPOReject::new(e)))
+}
 
-    do_copy_file_to_spo(
-        &req.tenant_id,
-        &req.client_id,
-        &req.client_secret,
-        &req.share_point_domain,
-        &req.share_point_site,
-        &req.share_point_path,
+//
+//  Copy of an HTTP or queue request, the reply is the copy result or the tag query summary
+//
+async fn copy_request(req: &UploadFileToSPORequest) -> Result<serde_json::Value, SPOError> {
+    check_stamp_tag(&req.stamp_tag, &req.post_action)?;
+    let http_config = HttpConfig::from_env();
+    // Key Vault references are resolved with the function's managed identity
+    let (tenant_id, client_id, client_secret, mut source) =
+        resolve_credentials(req, &http_config).await?;
+    // without SAS, key or connection string the blobs are read with the application of the request,
+    // requests run concurrently so it is never set in the AZURE_* variables
+    source.set_application(&tenant_id, &client_id, &client_secret);
+
+    // the function never waits for rehydration, callers retry on 409
+    let options = CopyOptions {
+        version_field: req.version_field.clone(),
+        rehydrate_priority: req.rehydrate_priority,
+        rehydrate_wait_secs: None,
+        consistency: req.consistency,
+        verify: req.verify,
+        post_action: req.post_action.clone(),
+        target_name: None,
+        metadata: vec![],
+    };
+    match &req.tag_query {
+        Some(tag_query) => do_copy_tagged_blobs_to_spo(
+            &tenant_id,
+            &client_id,
+            &client_secret,
+            &req.share_point_domain,
+            &req.share_point_site,
+            &req.share_point_path,
+            &source,
+            tag_query,
+            &req.stamp_tag,
+            &req.filter.to_blob_filter()?,
+            &http_config,
+            &options,
+            None,
+            None,
+        )
+        .await
+        .map(|r| serde_json::json!(r)),
+        None => do_copy_file_to_spo(
+            &tenant_id,
+            &client_id,
+            &client_secret,
+            &req.share_point_domain,
+            &req.share_point_site,
+            &req.share_point_path,
+            &source,
+            &http_config,
+            &options,
+            None,
+            None,
+        )
+        .await
+        .map(|r| serde_json::json!(r)),
+    }
+}
+
+//
+//  Key Vault references of the request only name the vaults of the SPO_KEY_VAULT_NAMES setting,
+//  a caller can't have the managed identity token sent elsewhere or read the other vaults
+//
+async fn resolve_credentials(
+    req: &UploadFileToSPORequest,
+    http_config: &HttpConfig,
+) -> Result<(String, String, Secret, BlobSource), SPOError> {
+    let resolver = KeyVaultResolver::with_managed_identity(&http_config.build_client()?)
+        .set_allowed_vaults(&allowed_vaults_from_env());
+    let tenant_id = resolver.resolve(&req.tenant_id).await?;
+    let client_id = resolver.resolve(&req.client_id).await?;
+    let client_secret = resolver.resolve(req.client_secret.expose()).await?;
+    let source = resolve_blob_source(
         &req.account,
         &req.container,
         &req.blob_name,
-        None,
-        None,
+        resolve_secret_option(&resolver, &req.source_sas_url).await?,
+        resolve_secret_option(&resolver, &req.account_key).await?,
+        resolve_secret_option(&resolver, &req.connection_string).await?,
+    )?
+    .set_version(BlobVersionSelector::from_options(
+        &req.version_id,
+        &req.snapshot,
+        &req.as_of,
+    )?)
+    .set_encryption(BlobEncryption::new(
+        resolve_secret_option(&resolver, &req.encryption_key).await?,
+        req.encryption_key_sha256.clone(),
+        req.encryption_scope.clone(),
+    )?);
+    Ok((
+        tenant_id.expose().to_owned(),
+        client_id.expose().to_owned(),
+        client_secret,
+        source,
+    ))
+}
+
+async fn resolve_secret_option(
+    resolver: &KeyVaultResolver,
+    value: &Option<Secret>,
+) -> Result<Option<Secret>, SPOError> {
+    resolver
+        .resolve_option(&value.as_ref().map(|v| v.expose().to_owned()))
+        .await
+}
+
+/// Trigger, response and queue output bindings of the batch
+const BATCH_REQUEST_BINDING: &str = "req";
+const BATCH_RESPONSE_BINDING: &str = "res";
+const BATCH_QUEUE_BINDING: &str = "rows";
+
+//
+//  Check the rows of a manifest and write each row to the copy requests queue (202), the queue
+//  trigger copies them with its retries and poison queue. Nothing is queued when a row is invalid.
+//  The request only reaches blobs, never the files of the function
+//
+async fn queue_batch_rows(
+    invocation: InvocationRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let req = invocation
+        .data
+        .get(BATCH_REQUEST_BINDING)
+        .cloned()
+        .unwrap_or_default();
+    let mut response = InvocationResponse::default();
+    let (status, body) = match check_batch_request(&http_request_body(&req)).await {
+        Ok((status, body, messages)) => {
+            if !messages.is_empty() {
+                response.outputs.insert(
+                    String::from(BATCH_QUEUE_BINDING),
+                    serde_json::Value::Array(messages),
+                );
+            }
+            (status, body)
+        }
+        Err(e) => {
+            response.logs.push(format!("Batch error: {}", e));
+            (500, serde_json::json!({ "error": e }))
+        }
+    };
+    response.outputs.insert(
+        String::from(BATCH_RESPONSE_BINDING),
+        serde_json::json!({
+            "statusCode": status,
+            "headers": { "Content-Type": "application/json" },
+            "body": body.to_string()
+        }),
+    );
+    Ok(warp::reply::json(&response))
+}
+
+//
+//  Status and body of the reply, with the queue messages of the rows when they are all valid
+//
+async fn check_batch_request(
+    body: &serde_json::Value,
+) -> Result<(u16, serde_json::Value, Vec<serde_json::Value>), SPOError> {
+    let req: BatchCopyRequest = serde_json::from_value(body.clone())
+        .map_err(|e| SPOError::new(&format!("Invalid batch request: {}", e)))?;
+    debug!("Batch request: {:#?}", req);
+    let manifest = JobLocation::parse_blob_url(&req.manifest)?;
+    let report = match &req.report {
+        Some(report) => Some(JobLocation::parse_blob_url(report)?),
+        None => None,
+    };
+    let http_client = HttpConfig::from_env().build_client()?;
+    let rules = match &req.rules {
+        Some(rules) => load_route_rules(&JobLocation::parse_blob_url(rules)?, &http_client).await?,
+        None => load_env_route_rules(&http_client)
+            .await?
+            .unwrap_or_default(),
+    };
+    let (mut rows, format) = read_manifest(&manifest, &http_client).await?;
+    // the queue trigger has no defaults, each row carries its account and domain
+    for row in rows.iter_mut() {
+        row.account = row.account.take().or(req.account.clone());
+        row.share_point_domain = row
+            .share_point_domain
+            .take()
+            .or(req.share_point_domain.clone());
+    }
+    let (folders, errors) = check_manifest_rows(
+        &mut rows,
+        &req.account.clone().unwrap_or_default(),
+        &req.share_point_domain,
+        &rules,
+    );
+    let invalid = errors.iter().filter(|e| !e.is_empty()).count();
+    let status = match invalid {
+        0 => BatchRowStatus::Queued,
+        _ => BatchRowStatus::Skipped,
+    };
+    let report_rows = checked_rows_report(&rows, &errors, status);
+    if let Some(report) = &report {
+        report
+            .write(&http_client, format_report(&report_rows, format)?)
+            .await?;
+    }
+    if invalid > 0 {
+        let body = serde_json::json!({
+            "manifest": req.manifest,
+            "queued": 0,
+            "invalid": invalid,
+            "rows": report_rows,
+        });
+        return Ok((400, body, vec![]));
+    }
+    let messages: Vec<serde_json::Value> = rows
+        .into_iter()
+        .zip(folders)
+        .map(|(row, folder)| {
+            serde_json::json!({ BATCH_ROW_MESSAGE_KEY: BatchRowMessage { row, folder } })
+        })
+        .collect();
+    let body = serde_json::json!({
+        "manifest": req.manifest,
+        "queued": messages.len(),
+    });
+    Ok((202, body, messages))
+}
+
+//
+//  The body of an HTTP trigger arrives as a JSON string, or already parsed
+//
+fn http_request_body(req: &serde_json::Value) -> serde_json::Value {
+    match &req["Body"] {
+        serde_json::Value::String(s) => serde_json::from_str(s).unwrap_or_default(),
+        body => body.clone(),
+    }
+}
+
+/// Trigger and response bindings of the Event Grid webhook
+const EVENT_GRID_REQUEST_BINDING: &str = "req";
+const EVENT_GRID_RESPONSE_BINDING: &str = "res";
+/// Queue output of the Event Grid webhook, the queue of the queue trigger
+const EVENT_GRID_QUEUE_BINDING: &str = "events";
+/// Comma separated origins allowed by the CloudEvents handshake, Event Grid when not set
+const EVENT_GRID_ORIGINS_KEY: &str = "SPO_EVENT_GRID_ORIGINS";
+const DEFAULT_EVENT_GRID_ORIGIN: &str = "eventgrid.azure.net";
+
+fn allowed_event_grid_origins() -> Vec<String> {
+    let origins: Vec<String> = std::env::var(EVENT_GRID_ORIGINS_KEY)
+        .unwrap_or_default()
+        .split(',')
+        .map(|o| o.trim().to_lowercase())
+        .filter(|o| !o.is_empty())
+        .collect();
+    if origins.is_empty() {
+        vec![String::from(DEFAULT_EVENT_GRID_ORIGIN)]
+    } else {
+        origins
+    }
+}
+
+//
+//  Event Grid delivery: subscription validation handshakes, the BlobCreated events are written
+//  to the copy requests queue and copied by the queue trigger. The webhook answers at once,
+//  Event Grid redelivers the whole batch when the answer is late or an error
+//
+async fn accept_event_grid_delivery(
+    invocation: InvocationRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let req = invocation
+        .data
+        .get(EVENT_GRID_REQUEST_BINDING)
+        .cloned()
+        .unwrap_or_default();
+    let mut response = InvocationResponse::default();
+    // CloudEvents abuse protection handshake, only the allowed origins may deliver events
+    if req["Method"].as_str() == Some("OPTIONS") {
+        let origin = req["Headers"]["WebHook-Request-Origin"][0]
+            .as_str()
+            .unwrap_or_default();
+        let reply = if allowed_event_grid_origins().contains(&origin.to_lowercase()) {
+            serde_json::json!({
+                "statusCode": 200,
+                "headers": { "WebHook-Allowed-Origin": origin }
+            })
+        } else {
+            response
+                .logs
+                .push(format!("Event delivery origin '{}' is not allowed", origin));
+            serde_json::json!({ "statusCode": 403 })
+        };
+        response
+            .outputs
+            .insert(String::from(EVENT_GRID_RESPONSE_BINDING), reply);
+        return Ok(warp::reply::json(&response));
+    }
+    let (status, body) = match parse_event_grid_payload(&http_request_body(&req)) {
+        Ok(EventGridPayload::Validation { validation_code }) => {
+            debug!("Event Grid subscription validation");
+            (
+                200,
+                serde_json::json!({ "validationResponse": validation_code }),
+            )
+        }
+        Ok(EventGridPayload::BlobCreated(events)) => {
+            debug!("BlobCreated events: {:#?}", events);
+            let messages: Vec<serde_json::Value> = events
+                .iter()
+                .map(|e| serde_json::json!({ BLOB_CREATED_MESSAGE_KEY: e }))
+                .collect();
+            let queued = messages.len();
+            response.outputs.insert(
+                String::from(EVENT_GRID_QUEUE_BINDING),
+                serde_json::Value::Array(messages),
+            );
+            (200, serde_json::json!({ "queued": queued }))
+        }
+        // a redelivery can't fix the payload
+        Err(e) => {
+            response
+                .logs
+                .push(format!("Invalid Event Grid delivery: {}", e));
+            (400, serde_json::json!({ "error": e }))
+        }
+    };
+    response.outputs.insert(
+        String::from(EVENT_GRID_RESPONSE_BINDING),
+        serde_json::json!({
+            "statusCode": status,
+            "headers": { "Content-Type": "application/json" },
+            "body": body.to_string()
+        }),
+    );
+    Ok(warp::reply::json(&response))
+}
+
+//
+//  Copy a created blob to the Share Point destination of its route,
+//  the routing rules or the event routes when no rules are set
+//
+async fn copy_blob_created(event: &BlobCreatedEvent) -> Result<serde_json::Value, SPOError> {
+    let http_config = HttpConfig::from_env();
+    let rules = match load_env_route_rules(&http_config.build_client()?).await? {
+        Some(rules) => rules,
+        None => event_routes_to_rules(&load_event_routes()?)?,
+    };
+    let (tenant_id, client_id, client_secret) = resolve_env_credentials(&http_config).await?;
+    let options = CopyOptions {
+        version_field: None,
+        rehydrate_priority: None,
+        rehydrate_wait_secs: None,
+        consistency: SourceConsistency::default(),
+        verify: false,
+        post_action: None,
+        target_name: None,
+        metadata: vec![],
+    };
+    let summary = do_copy_blob_created_to_spo(
+        &tenant_id,
+        &client_id,
+        &client_secret,
+        &rules,
+        std::slice::from_ref(event),
+        &http_config,
+        &options,
     )
-    .await
-    .map(|r| warp::reply::json(&json!({})))
-    .map_err(|e| warp::reject::custom(UploadFileToSPOReject::new(e)))
+    .await?;
+    // the message is retried like a failed copy request
+    match summary.failed.into_iter().next() {
+        Some(failure) => Err(failure.error),
+        None => Ok(serde_json::json!(summary.copied)),
+    }
+}
+
+//
+//  Copy a manifest row of the batch, the blob is read with the function identity
+//
+async fn copy_batch_row(message: &BatchRowMessage) -> Result<serde_json::Value, SPOError> {
+    let http_config = HttpConfig::from_env();
+    let (tenant_id, client_id, client_secret) = resolve_env_credentials(&http_config).await?;
+    let source = BlobSource::new(
+        &message.row.account.clone().unwrap_or_default(),
+        &String::new(),
+        &String::new(),
+    );
+    let r = copy_manifest_row(
+        &tenant_id,
+        &client_id,
+        &client_secret,
+        &message.row.share_point_domain,
+        &source,
+        &message.row,
+        &message.folder,
+        &http_config,
+        &CopyOptions::default(),
+    )
+    .await?;
+    Ok(serde_json::json!(BatchRowResult {
+        row: message.row.clone(),
+        status: BatchRowStatus::Copied,
+        spo_file_url: Some(r.spo_file_url),
+        error_class: None,
+        error: None,
+    }))
+}
+
+//
+//  Share Point credentials of the function: SPO_* settings, AZURE_* as fallback
+//
+async fn resolve_env_credentials(
+    http_config: &HttpConfig,
+) -> Result<(String, String, Secret), SPOError> {
+    let env = |keys: &[&str]| {
+        keys.iter()
+            .filter_map(|k| std::env::var(k).ok())
+            .find(|v| !v.is_empty())
+            .ok_or_else(|| SPOError::new(&format!("Missing application setting {}", keys[0])))
+    };
+    let resolver = KeyVaultResolver::with_managed_identity(&http_config.build_client()?);
+    let tenant_id = resolver
+        .resolve(&env(&["SPO_TENANT_ID", "AZURE_TENANT_ID"])?)
+        .await?;
+    let client_id = resolver
+        .resolve(&env(&["SPO_CLIENT_ID", "AZURE_CLIENT_ID"])?)
+        .await?;
+    let client_secret = resolver
+        .resolve(&env(&["SPO_CLIENT_SECRET", "AZURE_CLIENT_SECRET"])?)
+        .await?;
+    Ok((
+        tenant_id.expose().to_owned(),
+        client_id.expose().to_owned(),
+        client_secret,
+    ))
+}
+
+//
+//  Custom handler invocation of a non HTTP trigger, Data holds the trigger and input bindings
+//
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InvocationRequest {
+    #[serde(default)]
+    data: BTreeMap<String, serde_json::Value>,
+    #[serde(default)]
+    metadata: BTreeMap<String, serde_json::Value>,
+}
+
+//
+//  Outputs are written to the output bindings, Logs to the function invocation log
+//
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct InvocationResponse {
+    outputs: BTreeMap<String, serde_json::Value>,
+    logs: Vec<String>,
+    return_value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum QueueCopyStatus {
+    Copied,
+    /// Failed on the last attempt or not a copy request, the message went to the poison queue
+    Poisoned,
+}
+
+//
+//  Message of the results queue, the request is not repeated as it carries the client secret
+//
+#[derive(Debug, Clone, Serialize)]
+struct QueueCopyResult {
+    message_id: Option<String>,
+    dequeue_count: u32,
+    status: QueueCopyStatus,
+    result: Option<serde_json::Value>,
+    error: Option<SPOError>,
+}
+
+//
+//  Message of the copy requests queue
+//
+#[derive(Debug, Clone)]
+enum QueueRequest {
+    Copy(Box<UploadFileToSPORequest>),
+    /// Written by the Event Grid trigger, copied to the destination of its route
+    BlobCreated(BlobCreatedEvent),
+    /// Written by the batch, a checked manifest row
+    BatchRow(Box<BatchRowMessage>),
+}
+
+//
+//  Manifest row of the batch with the folder of its route, the credentials come from the settings
+//
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BatchRowMessage {
+    row: ManifestRow,
+    #[serde(default)]
+    folder: RowFolder,
+}
+
+/// Key of the BlobCreated event in a queue message
+const BLOB_CREATED_MESSAGE_KEY: &str = "blob_created";
+/// Key of the manifest row in a queue message
+const BATCH_ROW_MESSAGE_KEY: &str = "batch_row";
+const QUEUE_MESSAGE_BINDING: &str = "msg";
+const RESULT_BINDING: &str = "result";
+const POISON_BINDING: &str = "poison";
+/// Attempts of a queue message before it is poisoned, at most host.json maxDequeueCount
+const QUEUE_MAX_DEQUEUE_KEY: &str = "SPO_QUEUE_MAX_DEQUEUE";
+/// maxDequeueCount of the host when host.json doesn't set it
+const DEFAULT_QUEUE_MAX_DEQUEUE: u32 = 5;
+const HOST_FILE: &str = "host.json";
+static QUEUE_MAX_DEQUEUE: OnceLock<u32> = OnceLock::new();
+
+fn host_max_dequeue_count(host: &serde_json::Value) -> u32 {
+    json_u32(&host["extensions"]["queues"]["maxDequeueCount"]).unwrap_or(DEFAULT_QUEUE_MAX_DEQUEUE)
+}
+
+//
+//  The host gives up a message after maxDequeueCount attempts, a higher setting would never
+//  poison the message. Not set, the message is poisoned on the last attempt of the host
+//
+fn queue_max_dequeue(setting: Option<String>, host_max: u32) -> Result<u32, SPOError> {
+    let Some(setting) = setting else {
+        return Ok(host_max);
+    };
+    match setting.trim().parse::<u32>() {
+        Ok(max) if (1..=host_max).contains(&max) => Ok(max),
+        _ => Err(SPOError::new(&format!(
+            "{} must be a number from 1 to the host.json maxDequeueCount {}, not '{}'",
+            QUEUE_MAX_DEQUEUE_KEY, host_max, setting
+        ))),
+    }
+}
+
+//
+//  Queue trigger: copy request as message, the result goes to the results queue.
+//  A failed copy fails the invocation so the message is retried,
+//  on the last attempt the message goes to the poison queue instead
+//
+async fn copy_queue_message_to_spo(
+    invocation: InvocationRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let message_id = invocation
+        .metadata
+        .get("Id")
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string());
+    let dequeue_count = invocation
+        .metadata
+        .get("DequeueCount")
+        .and_then(json_u32)
+        .unwrap_or(1);
+    let max_dequeue = *QUEUE_MAX_DEQUEUE
+        .get()
+        .unwrap_or(&DEFAULT_QUEUE_MAX_DEQUEUE);
+    let message = invocation
+        .data
+        .get(QUEUE_MESSAGE_BINDING)
+        .cloned()
+        .unwrap_or_default();
+    debug!(
+        "Queue message {:?}, dequeue count {}",
+        message_id, dequeue_count
+    );
+
+    let mut response = InvocationResponse::default();
+    // an invalid request never succeeds, it is poisoned on the first attempt
+    let (res, retry) = match parse_queue_message(&message) {
+        Ok(QueueRequest::Copy(req)) => (copy_request(&req).await, dequeue_count < max_dequeue),
+        Ok(QueueRequest::BlobCreated(event)) => {
+            (copy_blob_created(&event).await, dequeue_count < max_dequeue)
+        }
+        Ok(QueueRequest::BatchRow(message)) => {
+            (copy_batch_row(&message).await, dequeue_count < max_dequeue)
+        }
+        Err(e) => (Err(e), false),
+    };
+    let status = match res {
+        Ok(result) => {
+            response.outputs.insert(
+                String::from(RESULT_BINDING),
+                serde_json::json!(QueueCopyResult {
+                    message_id,
+                    dequeue_count,
+                    status: QueueCopyStatus::Copied,
+                    result: Some(result),
+                    error: None,
+                }),
+            );
+            warp::http::StatusCode::OK
+        }
+        Err(e) if retry => {
+            response.logs.push(format!(
+                "Copy failed, attempt {} of {}: {}",
+                dequeue_count, max_dequeue, e
+            ));
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR
+        }
+        Err(e) => {
+            response.logs.push(format!(
+                "Copy failed, message poisoned after {} attempts: {}",
+                dequeue_count, e
+            ));
+            response
+                .outputs
+                .insert(String::from(POISON_BINDING), message);
+            response.outputs.insert(
+                String::from(RESULT_BINDING),
+                serde_json::json!(QueueCopyResult {
+                    message_id,
+                    dequeue_count,
+                    status: QueueCopyStatus::Poisoned,
+                    result: None,
+                    error: Some(e),
+                }),
+            );
+            warp::http::StatusCode::OK
+        }
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
+
+/// Job definitions, JSON file or blob url
+const JOB_CONFIG_KEY: &str = "SPO_JOB_CONFIG";
+/// Last run status of the jobs, blob url (or file on a mounted share) kept between invocations
+const JOB_STATUS_KEY: &str = "SPO_JOB_STATUS";
+/// Folder of the sync state files, on a mounted share kept between invocations
+const JOB_STATE_DIR_KEY: &str = "SPO_JOB_STATE_DIR";
+
+//
+//  Timer trigger: run the configured jobs whose schedule is due, the invocation fails when a job failed.
+//  The status and the sync states must outlive the instance, the temporary folder doesn't
+//
+async fn run_scheduled_jobs(
+    invocation: InvocationRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("Timer: {:?}", invocation.data.get("timer"));
+    let http_config = HttpConfig::from_env();
+    let res = async {
+        let setting = |key: &str| {
+            std::env::var(key)
+                .ok()
+                .filter(|v| !v.is_empty())
+                .ok_or_else(|| SPOError::new(&format!("Missing application setting {}", key)))
+        };
+        let config_location = JobLocation::parse(&setting(JOB_CONFIG_KEY)?)?;
+        let status_location = JobLocation::parse(&setting(JOB_STATUS_KEY)?)?;
+        let state_dir = std::path::PathBuf::from(setting(JOB_STATE_DIR_KEY)?);
+        let (tenant_id, client_id, client_secret) = resolve_env_credentials(&http_config).await?;
+        do_run_scheduled_jobs(
+            &tenant_id,
+            &client_id,
+            &client_secret,
+            &config_location,
+            &status_location,
+            &state_dir,
+            &http_config,
+        )
+        .await
+    }
+    .await;
+
+    let mut response = InvocationResponse::default();
+    let status = match res {
+        Ok((_, status, names)) => {
+            let mut failed = false;
+            for name in &names {
+                if let Some(s) = status.jobs.get(name) {
+                    response.logs.push(format!(
+                        "Job {} run at {} {}",
+                        name,
+                        s.last_run,
+                        s.error.as_deref().unwrap_or("succeeded")
+                    ));
+                    failed |= !s.succeeded;
+                }
+            }
+            response.return_value = Some(serde_json::json!(status));
+            match failed {
+                true => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                false => warp::http::StatusCode::OK,
+            }
+        }
+        Err(e) => {
+            response.logs.push(format!("Scheduled jobs error: {}", e));
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
+
+//
+//  The queue message arrives as a JSON string, or already parsed. It is a copy request,
+//  a BlobCreated event written by the Event Grid trigger or a manifest row written by the batch
+//
+fn parse_queue_message(message: &serde_json::Value) -> Result<QueueRequest, SPOError> {
+    let message = match message {
+        serde_json::Value::String(s) => serde_json::from_str(s)
+            .map_err(|e| SPOError::new(&format!("Invalid copy request message: {}", e)))?,
+        _ => message.clone(),
+    };
+    if let Some(row) = message.get(BATCH_ROW_MESSAGE_KEY) {
+        return serde_json::from_value(row.clone())
+            .map(|r| QueueRequest::BatchRow(Box::new(r)))
+            .map_err(|e| SPOError::new(&format!("Invalid batch row message: {}", e)));
+    }
+    match message.get(BLOB_CREATED_MESSAGE_KEY) {
+        Some(event) => serde_json::from_value(event.clone())
+            .map(QueueRequest::BlobCreated)
+            .map_err(|e| SPOError::new(&format!("Invalid blob created message: {}", e))),
+        None => serde_json::from_value(message)
+            .map(|r| QueueRequest::Copy(Box::new(r)))
+            .map_err(|e| SPOError::new(&format!("Invalid copy request message: {}", e))),
+    }
+}
+
+//
+//  Metadata values are numbers or strings depending on the runtime version
+//
+fn json_u32(value: &serde_json::Value) -> Option<u32> {
+    match value {
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => value.as_u64().map(|v| v as u32),
+    }
 }
 
 fn json_body() -> impl Filter<Extract = (UploadFileToSPORequest,), Error = Rejection> + Clone {
@@ -82,10 +892,13 @@ fn log_body() -> impl Filter<Extract = (UploadFileToSPORequest,), Error = Reject
 async fn recover(err: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
     if let Some(e) = err.find::<UploadFileToSPOReject>() {
         let json = warp::reply::json(&e);
-        Ok(warp::reply::with_status(
-            json,
-            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-        ))
+        let status = match e.error.kind() {
+            SPOErrorKind::BlobArchived
+            | SPOErrorKind::BlobRehydrating
+            | SPOErrorKind::SourceChanged => warp::http::StatusCode::CONFLICT,
+            _ => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Ok(warp::reply::with_status(json, status))
     } else {
         Err(warp::reject::not_found())
     }
@@ -93,8 +906,19 @@ async fn recover(err: warp::Rejection) -> Result<impl warp::Reply, warp::Rejecti
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
+    init_logger();
     debug!("Start Azure Function");
+    init_cloud(CloudEndpoints::from_env().expect("Invalid cloud settings!"));
+    let host: serde_json::Value = std::fs::read_to_string(HOST_FILE)
+        .ok()
+        .and_then(|h| serde_json::from_str(&h).ok())
+        .unwrap_or_default();
+    let max_dequeue = queue_max_dequeue(
+        std::env::var(QUEUE_MAX_DEQUEUE_KEY).ok(),
+        host_max_dequeue_count(&host),
+    )
+    .expect("Invalid queue settings!");
+    QUEUE_MAX_DEQUEUE.get_or_init(|| max_dequeue);
 
     let blob2spo_endpoint = warp::post()
         .and(warp::path("api"))
@@ -105,13 +929,313 @@ async fn main() {
         .and_then(copy_file_blob_to_spo)
         .recover(recover);
 
+    // the queue output binding makes the host send the HTTP request as invocation
+    let batch_endpoint = warp::post()
+        .and(warp::path("HttpTriggerBatchCopy2SPO"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::json())
+        .and_then(queue_batch_rows);
+
+    // the queue output binding makes the host send the HTTP request as invocation
+    let event_grid_endpoint = warp::post()
+        .and(warp::path("EventGridCopyBlob2SPO"))
+        .and(warp::path::end())
+        // Event Grid delivers batches up to 1 MB, escaped in the invocation
+        .and(warp::body::content_length_limit(1024 * 1024 * 4))
+        .and(warp::body::json())
+        .and_then(accept_event_grid_delivery);
+
+    let queue_endpoint = warp::post()
+        .and(warp::path("QueueTriggerCopyBlob2SPO"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024 * 256))
+        .and(warp::body::json())
+        .and_then(copy_queue_message_to_spo);
+
+    let timer_endpoint = warp::post()
+        .and(warp::path("TimerTriggerSyncJobs"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and_then(run_scheduled_jobs);
+
     let port_key = "FUNCTIONS_CUSTOMHANDLER_PORT";
     let port: u16 = match std::env::var(port_key) {
         Ok(val) => val.parse().expect("Custom Handler port is not a number!"),
         Err(_) => 3000,
     };
 
-    warp::serve(blob2spo_endpoint)
-        .run((Ipv4Addr::LOCALHOST, port))
-        .await
+    warp::serve(
+        blob2spo_endpoint
+            .or(batch_endpoint)
+            .or(event_grid_endpoint)
+            .or(queue_endpoint)
+            .or(timer_endpoint),
+    )
+    .run((Ipv4Addr::LOCALHOST, port))
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn upload_request_does_not_leak_client_secret() {
+        let req: UploadFileToSPORequest = serde_json::from_value(json!({
+            "tenant_id": "tenant",
+            "client_id": "client",
+            "client_secret": "very-secret-value",
+            "share_point_domain": "contoso",
+            "share_point_site": "MVP",
+            "share_point_path": "/sites/MVP/Shared Documents",
+            "account": "account",
+            "container": "container",
+            "blob_name": "file.txt"
+        }))
+        .unwrap();
+
+        assert_eq!(req.client_secret.expose(), "very-secret-value");
+        assert!(!format!("{:#?}", req).contains("very-secret-value"));
+        assert!(!serde_json::to_string(&req)
+            .unwrap()
+            .contains("very-secret-value"));
+    }
+
+    #[tokio::test]
+    async fn request_references_only_name_listed_vaults() {
+        let req: UploadFileToSPORequest = serde_json::from_value(json!({
+            "tenant_id": "tenant",
+            "client_id": "client",
+            "client_secret": "@Microsoft.KeyVault(SecretUri=https://attacker.example.com/secrets/x)",
+            "share_point_domain": "contoso",
+            "share_point_site": "MVP",
+            "share_point_path": "/sites/MVP/Shared Documents",
+            "account": "account",
+            "container": "container",
+            "blob_name": "file.txt"
+        }))
+        .unwrap();
+        let error = resolve_credentials(&req, &HttpConfig::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Invalid Key Vault reference"));
+
+        let req = UploadFileToSPORequest {
+            client_secret: Secret::new(&String::from("kv://other-vault/spo-secret")),
+            ..req
+        };
+        let error = resolve_credentials(&req, &HttpConfig::default())
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Key Vault other-vault is not listed in SPO_KEY_VAULT_NAMES"));
+    }
+
+    async fn deliver_event_grid(req: serde_json::Value) -> serde_json::Value {
+        let res = warp::test::request()
+            .method("POST")
+            .json(&json!({ "Data": { "req": req }, "Metadata": {} }))
+            .reply(&warp::body::json().and_then(accept_event_grid_delivery))
+            .await;
+        assert_eq!(res.status(), 200);
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn event_grid_validation_handshakes() {
+        let body = deliver_event_grid(json!({
+            "Method": "POST",
+            "Body": json!([{
+                "id": "1",
+                "eventType": "Microsoft.EventGrid.SubscriptionValidationEvent",
+                "data": { "validationCode": "code" }
+            }])
+            .to_string()
+        }))
+        .await;
+        assert_eq!(body["Outputs"]["res"]["statusCode"], json!(200));
+        assert_eq!(
+            body["Outputs"]["res"]["body"],
+            json!(r#"{"validationResponse":"code"}"#)
+        );
+
+        let body = deliver_event_grid(json!({
+            "Method": "OPTIONS",
+            "Headers": { "WebHook-Request-Origin": ["eventgrid.azure.net"] }
+        }))
+        .await;
+        assert_eq!(body["Outputs"]["res"]["statusCode"], json!(200));
+        assert_eq!(
+            body["Outputs"]["res"]["headers"]["WebHook-Allowed-Origin"],
+            json!("eventgrid.azure.net")
+        );
+
+        for headers in [
+            json!({ "WebHook-Request-Origin": ["eventemitter.example.com"] }),
+            json!({}),
+        ] {
+            let body = deliver_event_grid(json!({ "Method": "OPTIONS", "Headers": headers })).await;
+            assert_eq!(body["Outputs"]["res"]["statusCode"], json!(403));
+            assert!(body["Outputs"]["res"]["headers"]["WebHook-Allowed-Origin"].is_null());
+        }
+    }
+
+    #[tokio::test]
+    async fn blob_created_events_are_queued() {
+        let body = deliver_event_grid(json!({
+            "Method": "POST",
+            "Body": [{
+                "id": "1",
+                "eventType": "Microsoft.Storage.BlobCreated",
+                "topic": "/subscriptions/s/resourceGroups/rg/providers/Microsoft.Storage/storageAccounts/contosoblobs",
+                "subject": "/blobServices/default/containers/inbox/blobs/scans/a.pdf",
+                "data": { "contentLength": 10 }
+            }]
+        }))
+        .await;
+        assert_eq!(body["Outputs"]["res"]["statusCode"], json!(200));
+        assert_eq!(body["Outputs"]["res"]["body"], json!(r#"{"queued":1}"#));
+        let messages = body["Outputs"]["events"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        match parse_queue_message(&json!(messages[0].to_string())).unwrap() {
+            QueueRequest::BlobCreated(event) => {
+                assert_eq!(event.account, "contosoblobs");
+                assert_eq!(event.container, "inbox");
+                assert_eq!(event.blob_name, "scans/a.pdf");
+            }
+            other => panic!("expect a blob created message, got {:?}", other),
+        }
+
+        // Event Grid doesn't redeliver an invalid payload
+        let body = deliver_event_grid(json!({ "Method": "POST", "Body": "42" })).await;
+        assert_eq!(body["Outputs"]["res"]["statusCode"], json!(400));
+        assert!(body["Outputs"].get("events").is_none());
+    }
+
+    #[tokio::test]
+    async fn invalid_queue_message_is_poisoned() {
+        let res = warp::test::request()
+            .method("POST")
+            .json(&json!({
+                "Data": { "msg": "{\"container\": \"inbox\"}" },
+                "Metadata": { "Id": "42", "DequeueCount": "1" }
+            }))
+            .reply(&warp::body::json().and_then(copy_queue_message_to_spo))
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            body["Outputs"]["poison"],
+            json!("{\"container\": \"inbox\"}")
+        );
+        assert_eq!(body["Outputs"]["result"]["message_id"], json!("42"));
+        assert_eq!(body["Outputs"]["result"]["status"], json!("poisoned"));
+        assert_eq!(
+            host_max_dequeue_count(
+                &json!({ "extensions": { "queues": { "maxDequeueCount": 3 } } })
+            ),
+            3
+        );
+        assert_eq!(host_max_dequeue_count(&json!({})), 5);
+        assert_eq!(queue_max_dequeue(None, 3).unwrap(), 3);
+        assert_eq!(queue_max_dequeue(Some(String::from("2")), 3).unwrap(), 2);
+        for setting in ["4", "0", "x"] {
+            assert!(queue_max_dequeue(Some(String::from(setting)), 3).is_err());
+        }
+        assert!(parse_queue_message(&json!({
+            "tenant_id": "tenant",
+            "client_id": "client",
+            "client_secret": "secret",
+            "share_point_domain": "contoso",
+            "share_point_site": "MVP",
+            "share_point_path": "/sites/MVP/Shared Documents"
+        }))
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn scheduled_jobs_need_a_kept_status_and_state() {
+        std::env::set_var(JOB_CONFIG_KEY, "jobs.json");
+        let res = warp::test::request()
+            .method("POST")
+            .json(&json!({ "Data": { "timer": {} }, "Metadata": {} }))
+            .reply(&warp::body::json().and_then(run_scheduled_jobs))
+            .await;
+        assert_eq!(res.status(), 500);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(body["Logs"][0]
+            .as_str()
+            .unwrap()
+            .contains("Missing application setting SPO_JOB_STATUS"));
+    }
+
+    #[tokio::test]
+    async fn batch_reads_only_blobs() {
+        for (req, error) in [
+            (
+                json!({ "manifest": "/etc/passwd" }),
+                "/etc/passwd is not a blob url",
+            ),
+            (
+                json!({ "manifest": "https://xx.blob.core.windows.net/c/m.csv", "report": "report.csv" }),
+                "report.csv is not a blob url",
+            ),
+            (json!({ "report": "report.csv" }), "Invalid batch request"),
+        ] {
+            let res = warp::test::request()
+                .method("POST")
+                .json(&json!({
+                    "Data": { "req": { "Method": "POST", "Body": req.to_string() } },
+                    "Metadata": {}
+                }))
+                .reply(&warp::body::json().and_then(queue_batch_rows))
+                .await;
+            assert_eq!(res.status(), 200);
+            let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(body["Outputs"]["res"]["statusCode"], json!(500));
+            assert!(
+                body["Outputs"]["res"]["body"]
+                    .as_str()
+                    .unwrap()
+                    .contains(error),
+                "{:?}",
+                body
+            );
+            assert!(body["Outputs"].get("rows").is_none());
+        }
+    }
+
+    #[test]
+    fn batch_rows_are_copy_requests() {
+        let message = json!({
+            BATCH_ROW_MESSAGE_KEY: BatchRowMessage {
+                row: ManifestRow {
+                    account: Some(String::from("contosoblobs")),
+                    container: String::from("reports"),
+                    blob_name: String::from("2024/q1.pdf"),
+                    share_point_domain: Some(String::from("contoso")),
+                    share_point_site: String::from("Finance"),
+                    share_point_path: String::from("/sites/Finance/Shared Documents/2024"),
+                    ..Default::default()
+                },
+                folder: Some((String::from("Shared Documents"), String::from("2024"))),
+            }
+        });
+        match parse_queue_message(&json!(message.to_string())).unwrap() {
+            QueueRequest::BatchRow(parsed) => {
+                assert_eq!(parsed.row.blob_name, "2024/q1.pdf");
+                assert_eq!(parsed.row.account, Some(String::from("contosoblobs")));
+                assert_eq!(
+                    parsed.folder,
+                    Some((String::from("Shared Documents"), String::from("2024")))
+                );
+            }
+            request => panic!("not a batch row: {:?}", request),
+        }
+        assert!(parse_queue_message(&json!({ BATCH_ROW_MESSAGE_KEY: {} })).is_err());
+    }
 }
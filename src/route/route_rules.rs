@@ -0,0 +1,372 @@
+use std::collections::BTreeMap;
+
+use regex::Regex;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::job::job_config::JobLocation;
+use crate::spo::spo_engine::SPOError;
+use crate::spo::spo_path::join_path;
+
+/// Routing rules file (TOML or JSON) or blob url
+pub const ROUTE_RULES_KEY: &str = "SPO_ROUTE_RULES";
+
+//
+//  Share Point destination of the blobs matching the account, container and blob name patterns.
+//  Patterns are regular expressions matching the whole value, their capture groups can be
+//  used in the templates by name {year} or by number {1} (numbered across account, container
+//  and blob), with {account}, {container}, {blob_name} and {file_name}
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RouteRule {
+    /// Shown by route test, the rule number when not set
+    #[serde(default)]
+    pub name: String,
+    /// Any storage account when not set
+    #[serde(default)]
+    pub account: Option<String>,
+    pub container: String,
+    /// Any blob when not set
+    #[serde(default)]
+    pub blob: Option<String>,
+    /// Default share_point_domain of the rules when not set
+    #[serde(default)]
+    pub share_point_domain: Option<String>,
+    pub site: String,
+    /// Folder of the site where the files go, as --spo-path ex. Shared Documents or /sites/Finance/Invoices
+    pub library: String,
+    /// Folder below the library, created when missing ex. {year}/{month}
+    #[serde(default)]
+    pub folder: String,
+    /// File name in Share Point, {file_name} when not set
+    #[serde(default)]
+    pub file_name: Option<String>,
+    /// Share point columns (internal names) set on each file, a row of a manifest overrides them
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RouteConfig {
+    #[serde(default)]
+    pub share_point_domain: Option<String>,
+    pub rules: Vec<RouteRule>,
+}
+
+//
+//  Where a blob goes
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RouteDestination {
+    pub rule: String,
+    pub share_point_domain: Option<String>,
+    pub share_point_site: String,
+    pub library: String,
+    pub folder: String,
+    pub file_name: String,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl RouteDestination {
+    //
+    //  Share point folder of the file, library / folder
+    //
+    pub fn share_point_path(&self) -> String {
+        join_path(&self.library, &self.folder)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CompiledRule {
+    rule: RouteRule,
+    name: String,
+    account: Option<Regex>,
+    container: Regex,
+    blob: Option<Regex>,
+}
+
+//
+//  Rules in the order of the config, the first matching rule wins
+//
+#[derive(Debug, Clone, Default)]
+pub struct RouteRules {
+    share_point_domain: Option<String>,
+    rules: Vec<CompiledRule>,
+}
+
+fn compile_pattern(rule: &str, field: &str, pattern: &str) -> Result<Regex, SPOError> {
+    Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| {
+        SPOError::new(&format!(
+            "Rule {}: invalid {} pattern {}: {}",
+            rule, field, pattern, e
+        ))
+    })
+}
+
+//
+//  Replace {name} with its value, an unknown name is an error
+//
+fn render_template(template: &str, values: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed {{ in {}", template))?;
+        let name = &rest[start + 1..start + end];
+        match values.get(name) {
+            Some(value) => rendered.push_str(value),
+            None => return Err(format!("unknown variable {{{}}} in {}", name, template)),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+impl RouteRules {
+    pub fn new(config: &RouteConfig) -> Result<RouteRules, SPOError> {
+        let mut rules = vec![];
+        for (i, rule) in config.rules.iter().enumerate() {
+            let name = match rule.name.is_empty() {
+                true => format!("#{}", i + 1),
+                false => rule.name.to_owned(),
+            };
+            if rule.site.trim().is_empty() || rule.library.trim().is_empty() {
+                return Err(SPOError::new(&format!(
+                    "Rule {}: site and library are required",
+                    name
+                )));
+            }
+            rules.push(CompiledRule {
+                account: match &rule.account {
+                    Some(p) => Some(compile_pattern(&name, "account", p)?),
+                    None => None,
+                },
+                container: compile_pattern(&name, "container", &rule.container)?,
+                blob: match &rule.blob {
+                    Some(p) => Some(compile_pattern(&name, "blob", p)?),
+                    None => None,
+                },
+                rule: rule.clone(),
+                name,
+            });
+        }
+        Ok(RouteRules {
+            share_point_domain: config.share_point_domain.clone(),
+            rules,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    //
+    //  Destination of the first rule matching the blob, None when no rule matches
+    //
+    pub fn resolve(
+        &self,
+        account: &str,
+        container: &str,
+        blob_name: &str,
+    ) -> Result<Option<RouteDestination>, SPOError> {
+        let mut values: BTreeMap<String, String> = BTreeMap::new();
+        values.insert(String::from("account"), account.to_string());
+        values.insert(String::from("container"), container.to_string());
+        values.insert(String::from("blob_name"), blob_name.to_string());
+        values.insert(
+            String::from("file_name"),
+            blob_name.rsplit('/').next().unwrap_or_default().to_string(),
+        );
+        for compiled in &self.rules {
+            let mut values = values.clone();
+            let patterns = [
+                (compiled.account.as_ref(), account),
+                (Some(&compiled.container), container),
+                (compiled.blob.as_ref(), blob_name),
+            ];
+            let mut matched = true;
+            let mut group = 0;
+            for (regex, value) in patterns {
+                let regex = match regex {
+                    Some(r) => r,
+                    None => continue,
+                };
+                let captures = match regex.captures(value) {
+                    Some(c) => c,
+                    None => {
+                        matched = false;
+                        break;
+                    }
+                };
+                // groups are numbered across the account, container and blob patterns
+                for m in captures.iter().skip(1) {
+                    group += 1;
+                    values.insert(
+                        group.to_string(),
+                        m.map(|m| m.as_str().to_string()).unwrap_or_default(),
+                    );
+                }
+                // a group which did not participate is empty
+                for name in regex.capture_names().flatten() {
+                    values.insert(
+                        name.to_string(),
+                        captures
+                            .name(name)
+                            .map(|m| m.as_str().to_string())
+                            .unwrap_or_default(),
+                    );
+                }
+            }
+            if !matched {
+                continue;
+            }
+            let rule = &compiled.rule;
+            let render = |template: &str| {
+                render_template(template, &values)
+                    .map_err(|e| SPOError::new(&format!("Rule {}: {}", compiled.name, e)))
+            };
+            let file_name = render(rule.file_name.as_deref().unwrap_or("{file_name}"))?;
+            if file_name.is_empty() || file_name.contains('/') {
+                return Err(SPOError::new(&format!(
+                    "Rule {}: invalid file name {}",
+                    compiled.name, file_name
+                )));
+            }
+            let mut metadata = BTreeMap::new();
+            for (field, template) in &rule.metadata {
+                metadata.insert(field.to_owned(), render(template)?);
+            }
+            return Ok(Some(RouteDestination {
+                rule: compiled.name.to_owned(),
+                share_point_domain: rule
+                    .share_point_domain
+                    .clone()
+                    .or(self.share_point_domain.clone()),
+                share_point_site: render(&rule.site)?,
+                library: render(&rule.library)?,
+                folder: render(&rule.folder)?.trim_matches('/').to_string(),
+                file_name,
+                metadata,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+pub fn parse_route_config(content: &[u8], name: &str) -> Result<RouteConfig, SPOError> {
+    if name.to_lowercase().ends_with(".toml") {
+        let text = String::from_utf8_lossy(content);
+        return toml::from_str(&text)
+            .map_err(|e| SPOError::new(&format!("Invalid routing rules {}: {}", name, e)));
+    }
+    serde_json::from_slice(content)
+        .map_err(|e| SPOError::new(&format!("Invalid routing rules {}: {}", name, e)))
+}
+
+//
+//  Routing rules of a .toml or .json file or blob, a missing file is an error
+//
+pub async fn load_route_rules(
+    location: &JobLocation,
+    http_client: &Client,
+) -> Result<RouteRules, SPOError> {
+    let content = location
+        .read(http_client)
+        .await?
+        .ok_or_else(|| SPOError::new(&format!("Routing rules {} not found", location.name())))?;
+    RouteRules::new(&parse_route_config(&content, &location.name())?)
+}
+
+//
+//  Routing rules of SPO_ROUTE_RULES, None when not set
+//
+pub async fn load_env_route_rules(http_client: &Client) -> Result<Option<RouteRules>, SPOError> {
+    match std::env::var(ROUTE_RULES_KEY) {
+        Ok(value) => load_route_rules(&JobLocation::parse(&value)?, http_client)
+            .await
+            .map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+share_point_domain = "contoso"
+
+[[rules]]
+name = "invoices"
+container = "inbox|invoices"
+blob = 'invoices/(?P<year>\d{4})/(?P<month>\d{2})/.+\.pdf'
+site = "Finance"
+library = "Invoices"
+folder = "{year}/{month}"
+metadata = { Year = "{year}", Source = "{container}" }
+
+[[rules]]
+account = "contoso(dev|prod)"
+container = "team-(.+)"
+site = "Team-{2}"
+library = "Shared Documents"
+folder = "From {1}"
+file_name = "{1}-{file_name}"
+"#;
+
+    #[test]
+    fn first_matching_rule_with_captures() {
+        let rules =
+            RouteRules::new(&parse_route_config(RULES.as_bytes(), "rules.toml").unwrap()).unwrap();
+
+        let destination = rules
+            .resolve("contosoblobs", "inbox", "invoices/2024/03/a.pdf")
+            .unwrap()
+            .unwrap();
+        assert_eq!(destination.rule, "invoices");
+        assert_eq!(
+            destination.share_point_domain,
+            Some(String::from("contoso"))
+        );
+        assert_eq!(destination.share_point_site, "Finance");
+        assert_eq!(destination.share_point_path(), "Invoices/2024/03");
+        assert_eq!(destination.file_name, "a.pdf");
+        assert_eq!(destination.metadata["Year"], "2024");
+        assert_eq!(destination.metadata["Source"], "inbox");
+
+        // the container pattern matches the whole name
+        assert!(rules
+            .resolve("contosoblobs", "inbox-old", "invoices/2024/03/a.pdf")
+            .unwrap()
+            .is_none());
+
+        let destination = rules
+            .resolve("contosoprod", "team-sales", "q1/report.xlsx")
+            .unwrap()
+            .unwrap();
+        assert_eq!(destination.rule, "#2");
+        assert_eq!(destination.share_point_site, "Team-sales");
+        assert_eq!(destination.share_point_path(), "Shared Documents/From prod");
+        assert_eq!(destination.file_name, "prod-report.xlsx");
+
+        let config = RouteConfig {
+            rules: vec![RouteRule {
+                container: String::from("inbox"),
+                site: String::from("MVP"),
+                library: String::from("Shared Documents"),
+                folder: String::from("{dept}"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(RouteRules::new(&config)
+            .unwrap()
+            .resolve("a", "inbox", "b.txt")
+            .is_err());
+        let json = serde_json::to_vec(&config).unwrap();
+        assert_eq!(parse_route_config(&json, "rules.json").unwrap(), config);
+    }
+}
@@ -1,3 +1,7 @@
-mod spo_endpoint;
+pub mod spo_endpoint;
 pub mod spo_engine;
 pub mod spo_model;
+pub mod spo_onedrive;
+pub mod spo_path;
+pub mod spo_teams;
+pub mod spo_url;
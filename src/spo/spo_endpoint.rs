@@ -1,7 +1,30 @@
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::common::cloud::{cloud, CloudEndpoints};
+
+//
+//  Server relative path of a web, empty for the root site.
+//  A bare name is a site collection (Finance is /sites/Finance), a path starting with / is taken
+//  as is (/teams/Sales, /sites/Hub/Projects) and / is the root site
+//
+pub fn spo_web_path(site: &str) -> String {
+    let site = site.trim().trim_end_matches('/');
+    if site.is_empty() {
+        return String::new();
+    }
+    match site.starts_with('/') {
+        true => site.to_string(),
+        false => format!("/sites/{}", site),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SPOEndpoint {
-    share_point_domain: String,
+    /// [share_point_domain].[share point suffix of the cloud]
+    share_point_host: String,
     share_point_site: Option<String>,
+    /// WebFullUrl of _api/contextinfo, the actual web of the site once connected
+    web_url: Option<String>,
     path: Option<String>,
     file_name: Option<String>,
     offset: Option<u64>,
@@ -10,9 +33,14 @@ pub struct SPOEndpoint {
 
 impl SPOEndpoint {
     pub fn new(share_point_domain: &String) -> SPOEndpoint {
+        SPOEndpoint::with_cloud(share_point_domain, cloud())
+    }
+    #[allow(clippy::ptr_arg)]
+    pub fn with_cloud(share_point_domain: &String, cloud: &CloudEndpoints) -> SPOEndpoint {
         SPOEndpoint {
-            share_point_domain: share_point_domain.to_owned(),
+            share_point_host: cloud.share_point_host(share_point_domain),
             share_point_site: None,
+            web_url: None,
             path: None,
             file_name: None,
             offset: None,
@@ -21,6 +49,12 @@ impl SPOEndpoint {
     }
     pub fn set_site(&mut self, site: &String) -> SPOEndpoint {
         self.share_point_site = Some(site.to_owned());
+        self.web_url = None;
+        self.to_owned()
+    }
+    #[allow(clippy::ptr_arg)]
+    pub fn set_web_url(&mut self, web_url: &String) -> SPOEndpoint {
+        self.web_url = Some(web_url.trim_end_matches('/').to_owned());
         self.to_owned()
     }
     pub fn set_path(&mut self, path: &String) -> SPOEndpoint {
@@ -40,16 +74,26 @@ impl SPOEndpoint {
         self.to_owned()
     }
     pub fn to_spo_web_url(&self) -> String {
-        format!(
-            "https://{share_point_domain}.sharepoint.com/sites/{share_point_site}",
-            share_point_domain = self.share_point_domain,
-            share_point_site = self.share_point_site.clone().unwrap()
-        )
+        match &self.web_url {
+            Some(web_url) => web_url.to_owned(),
+            None => format!(
+                "{domain_url}{web_path}",
+                domain_url = self.to_spo_domain_url(),
+                web_path = spo_web_path(&self.share_point_site.clone().unwrap())
+            ),
+        }
+    }
+    pub fn to_spo_web_path(&self) -> String {
+        let web_url = self.to_spo_web_url();
+        match web_url.strip_prefix(&self.to_spo_domain_url()) {
+            Some(web_path) => web_path.to_string(),
+            None => spo_web_path(&self.share_point_site.clone().unwrap_or_default()),
+        }
     }
     pub fn to_spo_domain_url(&self) -> String {
         format!(
-            "https://{share_point_domain}.sharepoint.com",
-            share_point_domain = self.share_point_domain,
+            "https://{share_point_host}",
+            share_point_host = self.share_point_host,
         )
     }
     pub fn to_spo_digest_url(&self) -> String {
@@ -64,6 +108,57 @@ impl SPOEndpoint {
                 path = self.path.clone().unwrap(),
                 file_name = self.file_name.clone().unwrap())
     }
+    pub fn to_server_relative_file_url(&self) -> String {
+        format!(
+            "{path}/{file_name}",
+            path = self.path.clone().unwrap(),
+            file_name = self.file_name.clone().unwrap()
+        )
+    }
+    pub fn to_file_properties_endpoint(&self) -> String {
+        format!(
+            "{web_url}/_api/web/GetFileByServerRelativeUrl('{path}/{file_name}')",
+            web_url = self.to_spo_web_url(),
+            path = self.path.clone().unwrap(),
+            file_name = self.file_name.clone().unwrap()
+        )
+    }
+    pub fn to_folder_expand_endpoint(&self) -> String {
+        format!(
+            "{web_url}/_api/web/GetFolderByServerRelativeUrl('{path}')?$expand=Files,Folders",
+            web_url = self.to_spo_web_url(),
+            path = self.path.clone().unwrap()
+        )
+    }
+    pub fn to_personal_url_endpoint(&self, user_principal_name: &String) -> String {
+        let account_name = format!("i:0#.f|membership|{}", user_principal_name);
+        format!(
+            "{web_url}/_api/SP.UserProfiles.PeopleManager/GetPropertiesFor(accountName=@v)?@v='{account_name}'&$select=PersonalUrl",
+            web_url = self.to_spo_web_url(),
+            account_name = utf8_percent_encode(&account_name, NON_ALPHANUMERIC)
+        )
+    }
+    pub fn to_folder_add_endpoint(&self) -> String {
+        format!(
+            "{web_url}/_api/web/folders/add('{path}')",
+            web_url = self.to_spo_web_url(),
+            path = self.path.clone().unwrap()
+        )
+    }
+    pub fn to_file_recycle_endpoint(&self) -> String {
+        format!(
+            "{web_url}/_api/web/GetFileByServerRelativeUrl('{path}/{file_name}')/recycle()",
+            web_url = self.to_spo_web_url(),
+            path = self.path.clone().unwrap(),
+            file_name = self.file_name.clone().unwrap()
+        )
+    }
+    pub fn to_file_list_item_update_endpoint(&self) -> String {
+        format!("{web_url}/_api/web/GetFileByServerRelativeUrl('{path}/{file_name}')/ListItemAllFields/ValidateUpdateListItem()",
+                web_url = self.to_spo_web_url(),
+                path = self.path.clone().unwrap(),
+                file_name = self.file_name.clone().unwrap())
+    }
     pub fn to_file_start_upload_endpoint(&self) -> String {
         format!("{web_url}/_api/web/GetFileByServerRelativeUrl('{path}/{file_name}')/StartUpload(uploadId=guid'{uuid}')",
                 web_url = self.to_spo_web_url(),
@@ -78,7 +173,15 @@ impl SPOEndpoint {
                 path = self.path.clone().unwrap(),
                 file_name = self.file_name.clone().unwrap(),
                 uuid = self.uuid.clone().unwrap(),
-                offset = self.offset.clone().unwrap()
+                offset = self.offset.unwrap()
+        )
+    }
+    pub fn to_file_cancel_upload_endpoint(&self) -> String {
+        format!("{web_url}/_api/web/GetFileByServerRelativeUrl('{path}/{file_name}')/CancelUpload(uploadId=guid'{uuid}')",
+                web_url = self.to_spo_web_url(),
+                path = self.path.clone().unwrap(),
+                file_name = self.file_name.clone().unwrap(),
+                uuid = self.uuid.clone().unwrap()
         )
     }
     pub fn to_file_finish_upload_endpoint(&self) -> String {
@@ -87,7 +190,76 @@ impl SPOEndpoint {
                 path = self.path.clone().unwrap(),
                 file_name = self.file_name.clone().unwrap(),
                 uuid = self.uuid.clone().unwrap(),
-                offset = self.offset.clone().unwrap()
+                offset = self.offset.unwrap()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::cloud::CloudEnvironment;
+
+    fn web_url(site: &str) -> String {
+        SPOEndpoint::new(&String::from("contoso"))
+            .set_site(&String::from(site))
+            .to_spo_web_url()
+    }
+
+    #[test]
+    fn web_url_of_site_forms() {
+        assert_eq!(web_url("MVP"), "https://contoso.sharepoint.com/sites/MVP");
+        assert_eq!(
+            web_url("Hub/Sub"),
+            "https://contoso.sharepoint.com/sites/Hub/Sub"
+        );
+        assert_eq!(web_url(""), "https://contoso.sharepoint.com");
+        assert_eq!(web_url("/"), "https://contoso.sharepoint.com");
+        assert_eq!(
+            web_url("/teams/Sales"),
+            "https://contoso.sharepoint.com/teams/Sales"
+        );
+        assert_eq!(
+            web_url("/sites/Hub/Sub/"),
+            "https://contoso.sharepoint.com/sites/Hub/Sub"
+        );
+        assert_eq!(
+            SPOEndpoint::new(&String::from("contoso"))
+                .set_site(&String::from("/"))
+                .to_spo_digest_url(),
+            "https://contoso.sharepoint.com/_api/ContextInfo"
+        );
+    }
+
+    #[test]
+    fn web_url_from_context_info() {
+        let mut end_point = SPOEndpoint::new(&String::from("contoso"))
+            .set_site(&String::from("/sites/Hub/Sub/Shared Documents"))
+            .set_web_url(&String::from(
+                "https://contoso.sharepoint.com/sites/Hub/Sub/",
+            ))
+            .set_path(&String::from("/sites/Hub/Sub/Shared Documents"));
+        assert_eq!(end_point.to_spo_web_path(), "/sites/Hub/Sub");
+        assert_eq!(
+            end_point.to_folder_expand_endpoint(),
+            "https://contoso.sharepoint.com/sites/Hub/Sub/_api/web/GetFolderByServerRelativeUrl('/sites/Hub/Sub/Shared Documents')?$expand=Files,Folders"
+        );
+        // connecting to another site forgets the discovered web
+        end_point.set_site(&String::from(""));
+        assert_eq!(end_point.to_spo_web_path(), "");
+    }
+
+    #[test]
+    fn web_url_of_sovereign_cloud() {
+        let end_point = SPOEndpoint::with_cloud(
+            &String::from("contoso"),
+            &CloudEnvironment::USGovHigh.endpoints(),
         )
+        .set_site(&String::from("/teams/Sales"));
+        assert_eq!(
+            end_point.to_spo_web_url(),
+            "https://contoso.sharepoint.us/teams/Sales"
+        );
+        assert_eq!(end_point.to_spo_web_path(), "/teams/Sales");
     }
 }
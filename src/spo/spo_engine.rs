@@ -3,45 +3,101 @@ use std::fmt::{Display, Formatter};
 use log::{debug, error};
 use oauth2::http::HeaderMap;
 use reqwest::Client;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use uuid::Uuid;
 
+use crate::common::cloud::cloud;
+use crate::common::secret::Secret;
 use crate::spo::spo_endpoint::SPOEndpoint;
-use crate::spo::spo_model::{SPOContextInfoResponse, SPOErrorResponse, SPOTokenResponse};
+use crate::spo::spo_model::{
+    SPOContextInfoResponse, SPOErrorResponse, SPOFile, SPOFileResponse, SPOFolder,
+    SPOFolderResponse, SPOPersonPropertiesResponse, SPOTokenResponse,
+    SPOValidateUpdateListItemResponse,
+};
+
+//
+//  Error class, so callers can react on specific failures (ex. retry later)
+//
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SPOErrorKind {
+    #[default]
+    General,
+    /// Source blob is in the Archive tier and must be rehydrated first
+    BlobArchived,
+    /// Source blob rehydration is pending
+    BlobRehydrating,
+    /// Source blob was overwritten while it was copied
+    SourceChanged,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SPOError {
     message: String,
+    #[serde(default)]
+    kind: SPOErrorKind,
     spo_error: Option<SPOErrorResponse>,
+    /// Status of the failed Share Point request
+    #[serde(default)]
+    http_status: Option<u16>,
 }
 
 impl SPOError {
+    #[allow(clippy::ptr_arg)]
     pub fn new(message: &String) -> SPOError {
         SPOError {
             message: message.clone(),
+            kind: SPOErrorKind::General,
+            spo_error: None,
+            http_status: None,
+        }
+    }
+    #[allow(clippy::ptr_arg)]
+    pub fn with_kind(kind: SPOErrorKind, message: &String) -> SPOError {
+        SPOError {
+            message: message.clone(),
+            kind,
             spo_error: None,
+            http_status: None,
         }
     }
     pub fn set_spo_error(&mut self, spo_error: SPOErrorResponse) -> SPOError {
         self.spo_error = Some(spo_error);
         self.clone()
     }
+    pub fn set_http_status(&mut self, http_status: u16) -> SPOError {
+        self.http_status = Some(http_status);
+        self.clone()
+    }
+    pub fn kind(&self) -> SPOErrorKind {
+        self.kind
+    }
+    pub fn spo_error(&self) -> Option<&SPOErrorResponse> {
+        self.spo_error.as_ref()
+    }
+    pub fn http_status(&self) -> Option<u16> {
+        self.http_status
+    }
 }
 
+impl std::error::Error for SPOError {}
+
 impl Display for SPOError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "SPOError: {}", self.message)
     }
 }
 
+#[derive(Debug)]
 pub struct SPOEngine {
     tenant_id: String,
     client_id: String,
-    client_secret: String,
+    client_secret: Secret,
     share_point_domain: String,
     end_point: SPOEndpoint,
+    http_client: Client,
 
-    uuid: Option<String>,
     token: Option<SPOTokenResponse>,
     context_info: Option<SPOContextInfoResponse>,
 }
@@ -50,8 +106,9 @@ impl SPOEngine {
     pub fn new(
         tenant_id: &String,
         client_id: &String,
-        client_secret: &String,
+        client_secret: &Secret,
         share_point_domain: &String,
+        http_client: &Client,
     ) -> SPOEngine {
         SPOEngine {
             tenant_id: tenant_id.to_owned(),
@@ -59,7 +116,7 @@ impl SPOEngine {
             client_secret: client_secret.to_owned(),
             share_point_domain: share_point_domain.to_owned(),
             end_point: SPOEndpoint::new(share_point_domain),
-            uuid: None,
+            http_client: http_client.clone(),
             token: None,
             context_info: None,
         }
@@ -74,14 +131,29 @@ impl SPOEngine {
         file_name: &String,
         data: &[u8],
     ) -> Result<(), SPOError> {
-        let mut end_point = self
-            .end_point
-            .set_site(site)
-            .set_path(path)
-            .set_file_name(file_name);
+        self.connect(site).await?;
+        let end_point = self.end_point.set_path(path).set_file_name(file_name);
+        self.end_point = end_point.clone();
+
+        transfer_data_to_spo(
+            &self.http_client,
+            &self.end_point.to_file_one_time_upload_endpoint(),
+            &self.context_info.clone().unwrap(),
+            &self.token.clone().unwrap().access_token.unwrap(),
+            data,
+        )
+        .await
+        .map_err(|e| SPOError::new(&format!("to_file_one_time_upload_endpoint error: {:#?}", e)))
+    }
+    //
+    //  Connect , Get access token and request digest for the site
+    //
+    pub async fn connect(&mut self, site: &String) -> Result<(), SPOError> {
+        let end_point = self.end_point.set_site(site);
         self.end_point = end_point.clone();
 
         let token = get_spo_token(
+            &self.http_client,
             &self.tenant_id,
             &self.client_id,
             &self.client_secret,
@@ -98,6 +170,7 @@ impl SPOEngine {
             }
         }
         let context_info = get_spo_digest_value(
+            &self.http_client,
             &self.end_point.to_spo_digest_url(),
             &self.token.clone().unwrap().access_token.unwrap(),
         )
@@ -105,20 +178,17 @@ impl SPOEngine {
         match context_info {
             Ok(d) => {
                 debug!("context_info: {:#?}", d);
+                // the site may be any url below the web (ex. a library), use the actual web
+                if let Some(web_url) = &d.d.get_context_web_information.web_full_url {
+                    self.end_point = self.end_point.set_web_url(web_url);
+                }
                 self.context_info = Some(d);
             }
             Err(e) => {
                 return Err(SPOError::new(&format!("get_spo_digest_value error: {}", e)));
             }
         }
-        transfer_data_to_spo(
-            &self.end_point.to_file_one_time_upload_endpoint(),
-            &self.context_info.clone().unwrap(),
-            &self.token.clone().unwrap().access_token.unwrap(),
-            &data.to_vec(),
-        )
-        .await
-        .map_err(|e| SPOError::new(&format!("to_file_one_time_upload_endpoint error: {:#?}", e)))
+        Ok(())
     }
     //
     //  Upload Start , Start for upload multiple chunk to Share point online
@@ -131,7 +201,7 @@ impl SPOEngine {
         data: &[u8],
     ) -> Result<(), SPOError> {
         let uuid = Uuid::new_v4();
-        let mut end_point = self.end_point.set_uuid(&uuid.to_string());
+        let end_point = self.end_point.set_uuid(&uuid.to_string());
         self.end_point = end_point.clone();
 
         //save empty file first
@@ -150,10 +220,11 @@ impl SPOEngine {
         }
         //upload file
         transfer_data_to_spo(
+            &self.http_client,
             &self.end_point.to_file_start_upload_endpoint(),
             &self.context_info.clone().unwrap(),
             &self.token.clone().unwrap().access_token.unwrap(),
-            &data.to_vec(),
+            data,
         )
         .await
         .map_err(|e| SPOError::new(&format!("transfer_data_to_spo error: {:#?}", e)))
@@ -170,15 +241,150 @@ impl SPOEngine {
         self.end_point = end_point.clone();
 
         transfer_data_to_spo(
+            &self.http_client,
             &self.end_point.to_file_continue_upload_endpoint(),
             &self.context_info.clone().unwrap(),
             &self.token.clone().unwrap().access_token.unwrap(),
-            &data.to_vec(),
+            data,
+        )
+        .await
+        .map_err(|e| SPOError::new(&format!("transfer_data_to_spo error: {:#?}", e)))
+    }
+    //
+    //  Upload Cancel, Cancel the multiple chunk upload session, the uploaded chunks are discarded
+    //
+    pub async fn upload_cancel(&mut self) -> Result<(), SPOError> {
+        transfer_data_to_spo(
+            &self.http_client,
+            &self.end_point.to_file_cancel_upload_endpoint(),
+            &self.context_info.clone().unwrap(),
+            &self.token.clone().unwrap().access_token.unwrap(),
+            &[],
         )
         .await
         .map_err(|e| SPOError::new(&format!("transfer_data_to_spo error: {:#?}", e)))
     }
     //
+    //  Url of the connected web, ex. https://contoso.sharepoint.com/teams/Sales
+    //
+    pub fn web_url(&self) -> String {
+        self.end_point.to_spo_web_url()
+    }
+    //
+    //  Server relative path of the connected web, empty for the root site
+    //
+    pub fn web_path(&self) -> String {
+        self.end_point.to_spo_web_path()
+    }
+    //
+    //  Properties (length, last modified) of the file from the last upload
+    //
+    pub async fn get_file(&self) -> Result<SPOFile, SPOError> {
+        get_spo_resource::<SPOFileResponse>(
+            &self.http_client,
+            &self.end_point.to_file_properties_endpoint(),
+            &self.token.clone().unwrap().access_token.unwrap(),
+        )
+        .await
+        .map(|r| r.d)
+    }
+    //
+    //  Properties of a file in the connected site
+    //
+    pub async fn get_file_at(
+        &self,
+        path: &String,
+        file_name: &String,
+    ) -> Result<SPOFile, SPOError> {
+        let end_point = self
+            .end_point
+            .clone()
+            .set_path(path)
+            .set_file_name(file_name);
+        get_spo_resource::<SPOFileResponse>(
+            &self.http_client,
+            &end_point.to_file_properties_endpoint(),
+            &self.token.clone().unwrap().access_token.unwrap(),
+        )
+        .await
+        .map(|r| r.d)
+    }
+    //
+    //  Folder with its files and sub folders (one level) in the connected site
+    //
+    pub async fn get_folder(&self, path: &String) -> Result<SPOFolder, SPOError> {
+        let end_point = self.end_point.clone().set_path(path);
+        get_spo_resource::<SPOFolderResponse>(
+            &self.http_client,
+            &end_point.to_folder_expand_endpoint(),
+            &self.token.clone().unwrap().access_token.unwrap(),
+        )
+        .await
+        .map(|r| r.d)
+    }
+    //
+    //  OneDrive site url of a user from the user profile, None when it is not provisioned
+    //
+    pub async fn get_personal_url(
+        &self,
+        user_principal_name: &String,
+    ) -> Result<Option<String>, SPOError> {
+        get_spo_resource::<SPOPersonPropertiesResponse>(
+            &self.http_client,
+            &self.end_point.to_personal_url_endpoint(user_principal_name),
+            &self.token.clone().unwrap().access_token.unwrap(),
+        )
+        .await
+        .map(|r| r.d.personal_url.filter(|u| !u.is_empty()))
+    }
+    //
+    //  Create the folder in the connected site, nothing happens when it exists
+    //
+    pub async fn ensure_folder(&self, path: &String) -> Result<(), SPOError> {
+        let end_point = self.end_point.clone().set_path(path);
+        transfer_data_to_spo(
+            &self.http_client,
+            &end_point.to_folder_add_endpoint(),
+            &self.context_info.clone().unwrap(),
+            &self.token.clone().unwrap().access_token.unwrap(),
+            &[],
+        )
+        .await
+        .map_err(|e| SPOError::new(&format!("ensure_folder error: {}", e)))
+    }
+    //
+    //  Move a file of the connected site to the recycle bin
+    //
+    pub async fn recycle_file(&self, path: &String, file_name: &String) -> Result<(), SPOError> {
+        let end_point = self
+            .end_point
+            .clone()
+            .set_path(path)
+            .set_file_name(file_name);
+        transfer_data_to_spo(
+            &self.http_client,
+            &end_point.to_file_recycle_endpoint(),
+            &self.context_info.clone().unwrap(),
+            &self.token.clone().unwrap().access_token.unwrap(),
+            &[],
+        )
+        .await
+        .map_err(|e| SPOError::new(&format!("recycle_file error: {}", e)))
+    }
+    //
+    //  Set list item fields (internal names) of the file from the last upload
+    //
+    pub async fn set_file_metadata(&mut self, fields: &[(String, String)]) -> Result<(), SPOError> {
+        update_spo_list_item(
+            &self.http_client,
+            &self.end_point.to_file_list_item_update_endpoint(),
+            &self.context_info.clone().unwrap(),
+            &self.token.clone().unwrap().access_token.unwrap(),
+            fields,
+        )
+        .await
+    }
+    //
     //  Upload  Finish,  Finish for upload multiple chunk to Share point online
     //
     pub async fn upload_finish(&mut self, data: &[u8], file_offset: &u64) -> Result<(), SPOError> {
@@ -186,40 +392,40 @@ impl SPOEngine {
         self.end_point = end_point.clone();
 
         transfer_data_to_spo(
+            &self.http_client,
             &self.end_point.to_file_finish_upload_endpoint(),
             &self.context_info.clone().unwrap(),
             &self.token.clone().unwrap().access_token.unwrap(),
-            &data.to_vec(),
+            data,
         )
         .await
         .map_err(|e| SPOError::new(&format!("transfer_data_to_spo error: {:#?}", e)))
     }
 }
 
+#[allow(clippy::ptr_arg)]
 async fn get_spo_token(
+    http_client: &Client,
     tenant_id: &String,
     client_id: &String,
-    client_secret: &String,
+    client_secret: &Secret,
     share_point_domain: &String,
 ) -> Result<SPOTokenResponse, reqwest::Error> {
     //https://accounts.accesscontrol.windows.net/5612aad0-a1b7-4391-87a7-389e38e63b73/tokens/OAuth/2
-    let url = format!(
-        "https://accounts.accesscontrol.windows.net/{tenant_id}/tokens/OAuth/2",
-        tenant_id = tenant_id,
-    );
+    let url = cloud().acs_token_url(tenant_id);
     let body = format!(
-        r#"grant_type=client_credentials&client_id={client_id}@{tenant_id}&client_secret={client_secret}&resource=00000003-0000-0ff1-ce00-000000000000/{share_point_domain}.sharepoint.com@{tenant_id}"#,
+        r#"grant_type=client_credentials&client_id={client_id}@{tenant_id}&client_secret={client_secret}&resource={resource}"#,
         client_id = client_id,
         tenant_id = tenant_id,
-        client_secret = client_secret,
-        share_point_domain = share_point_domain
+        client_secret = client_secret.expose(),
+        resource = cloud().share_point_resource(share_point_domain, tenant_id)
     );
     let mut headers = HeaderMap::new();
     headers.append(
         "Content-Type",
         "application/x-www-form-urlencoded".parse().unwrap(),
     );
-    let res_post = Client::new()
+    http_client
         .post(url)
         .headers(headers)
         .body(body)
@@ -227,14 +433,13 @@ async fn get_spo_token(
         .await?
         .json::<SPOTokenResponse>()
         .await
-        .map_err(|e| e);
-    res_post
 }
 
 async fn transfer_data_to_spo(
+    http_client: &Client,
     spo_save_endpoint: &String,
     digest: &SPOContextInfoResponse,
-    spo_access_token: &String,
+    spo_access_token: &Secret,
     data: &[u8],
 ) -> Result<(), SPOError> {
     debug!("transfer_data_to_spo with url : {:?}", spo_save_endpoint);
@@ -242,7 +447,9 @@ async fn transfer_data_to_spo(
     let mut headers = HeaderMap::new();
     headers.append(
         "Authorization",
-        format!("Bearer {}", spo_access_token).parse().unwrap(),
+        format!("Bearer {}", spo_access_token.expose())
+            .parse()
+            .unwrap(),
     );
     headers.append(
         "Content-Type",
@@ -259,7 +466,7 @@ async fn transfer_data_to_spo(
             .parse()
             .unwrap(),
     );
-    let res = Client::new()
+    let res = http_client
         .post(spo_save_endpoint)
         .headers(headers.clone())
         .body(data.to_owned())
@@ -293,16 +500,145 @@ async fn transfer_data_to_spo(
     Ok(())
 }
 
+async fn get_spo_resource<T: DeserializeOwned>(
+    http_client: &Client,
+    spo_resource_endpoint: &String,
+    spo_access_token: &Secret,
+) -> Result<T, SPOError> {
+    debug!("get_spo_resource with url : {:?}", spo_resource_endpoint);
+
+    let mut headers = HeaderMap::new();
+    headers.append(
+        "Authorization",
+        format!("Bearer {}", spo_access_token.expose())
+            .parse()
+            .unwrap(),
+    );
+    headers.append("Accept", "application/json;odata=verbose".parse().unwrap());
+    let r = http_client
+        .get(spo_resource_endpoint)
+        .headers(headers)
+        .send()
+        .await
+        .map_err(|e| SPOError::new(&format!("Error Get Resource : {:#?}", e)))?;
+    if !r.status().is_success() {
+        let status = r.status().as_u16();
+        return match r.json::<SPOErrorResponse>().await {
+            Ok(rj) => Err(SPOError::new(&format!(
+                "Error Get Resource : {:#?}",
+                rj.error.message.value
+            ))
+            .set_spo_error(rj)
+            .set_http_status(status)),
+            Err(e) => {
+                Err(SPOError::new(&format!("Error Get Resource : {:#?}", e))
+                    .set_http_status(status))
+            }
+        };
+    }
+    r.json::<T>()
+        .await
+        .map_err(|e| SPOError::new(&format!("Error Get Resource : {:#?}", e)))
+}
+
+async fn update_spo_list_item(
+    http_client: &Client,
+    spo_update_endpoint: &String,
+    digest: &SPOContextInfoResponse,
+    spo_access_token: &Secret,
+    fields: &[(String, String)],
+) -> Result<(), SPOError> {
+    debug!("update_spo_list_item with url : {:?}", spo_update_endpoint);
+
+    let form_values: Vec<serde_json::Value> = fields
+        .iter()
+        .map(|(name, value)| {
+            json!({
+                "__metadata": { "type": "SP.ListItemFormUpdateValue" },
+                "FieldName": name,
+                "FieldValue": value,
+            })
+        })
+        .collect();
+    let body = json!({ "formValues": form_values, "bNewDocumentUpdate": true });
+
+    let mut headers = HeaderMap::new();
+    headers.append(
+        "Authorization",
+        format!("Bearer {}", spo_access_token.expose())
+            .parse()
+            .unwrap(),
+    );
+    headers.append(
+        "Content-Type",
+        "application/json;odata=verbose".parse().unwrap(),
+    );
+    headers.append("Accept", "application/json;odata=verbose".parse().unwrap());
+    headers.append(
+        "X-RequestDigest",
+        digest
+            .d
+            .get_context_web_information
+            .form_digest_value
+            .parse()
+            .unwrap(),
+    );
+    let r = http_client
+        .post(spo_update_endpoint)
+        .headers(headers)
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| SPOError::new(&format!("Error Update Metadata : {:#?}", e)))?;
+    if !r.status().is_success() {
+        return match r.json::<SPOErrorResponse>().await {
+            Ok(rj) => Err(SPOError::new(&format!(
+                "Error Update Metadata : {:#?}",
+                rj.error.message.value
+            ))
+            .set_spo_error(rj)),
+            Err(e) => Err(SPOError::new(&format!("Error Update Metadata : {:#?}", e))),
+        };
+    }
+    let rj = r
+        .json::<SPOValidateUpdateListItemResponse>()
+        .await
+        .map_err(|e| SPOError::new(&format!("Error Update Metadata : {:#?}", e)))?;
+    let failed: Vec<String> =
+        rj.d.validate_update_list_item
+            .results
+            .iter()
+            .filter(|v| v.has_exception)
+            .map(|v| {
+                format!(
+                    "{}: {}",
+                    v.field_name,
+                    v.error_message.clone().unwrap_or_default()
+                )
+            })
+            .collect();
+    if !failed.is_empty() {
+        return Err(SPOError::new(&format!(
+            "Error Update Metadata : {}",
+            failed.join(", ")
+        )));
+    }
+    Ok(())
+}
+
 async fn get_spo_digest_value(
+    http_client: &Client,
     spo_digest_endpoint: &String,
-    spo_access_token: &String,
+    spo_access_token: &Secret,
 ) -> Result<SPOContextInfoResponse, SPOError> {
     debug!("spo_digest_endpoint: {:?}", spo_digest_endpoint);
 
     let mut headers = HeaderMap::new();
     headers.append(
         "Authorization",
-        format!("Bearer {}", spo_access_token).parse().unwrap(),
+        format!("Bearer {}", spo_access_token.expose())
+            .parse()
+            .unwrap(),
     );
     headers.append("Accept", "application/json;odata=verbose".parse().unwrap());
     headers.append("Content-Length", "0".parse().unwrap());
@@ -311,7 +647,7 @@ async fn get_spo_digest_value(
         "application/json;odata=verbose".parse().unwrap(),
     );
 
-    let res = Client::new()
+    let res = http_client
         .post(spo_digest_endpoint)
         .headers(headers.clone())
         .send()
@@ -322,48 +658,244 @@ async fn get_spo_digest_value(
                 debug!("Success Get Digest Value");
                 let res_json = r.json::<SPOContextInfoResponse>().await;
                 match res_json {
-                    Ok(rj) => {
-                        return Ok(rj);
-                    }
+                    Ok(rj) => Ok(rj),
                     Err(e) => {
                         error!("Error Get Digest Value : {:#?}", e);
-                        return Err(SPOError::new(&format!("Error Get Digest Value : {:#?}", e)));
+                        Err(SPOError::new(&format!("Error Get Digest Value : {:#?}", e)))
                     }
-                };
+                }
             } else {
                 let res_json = r.json::<SPOErrorResponse>().await;
                 match res_json {
-                    Ok(rj) => {
-                        return Err(SPOError::new(&format!(
-                            "Error Get Digest Value : {:#?}",
-                            rj.error.message.value
-                        ))
-                        .set_spo_error(rj));
-                    }
+                    Ok(rj) => Err(SPOError::new(&format!(
+                        "Error Get Digest Value : {:#?}",
+                        rj.error.message.value
+                    ))
+                    .set_spo_error(rj)),
                     Err(e) => {
                         error!("Parse Error Get Digest Value failed : {:#?}", e);
-                        return Err(SPOError::new(&format!("Error Get Digest Value : {:#?}", e)));
+                        Err(SPOError::new(&format!("Error Get Digest Value : {:#?}", e)))
                     }
-                };
-                /*
-               let text_err = r.text().await;
-                match text_err {
-                     Ok(te) => {
-                          return Err(SPOError::new(&format!(
-                            "Error Get Digest Value : {:#?}",
-                            te
-                          )));
-                     }
-                     Err(e) => {
-                          error!("Parse Error Get Digest Value failed : {:#?}", e);
-                          return Err(SPOError::new(&format!("Error Get Digest Value : {:#?}", e)));
-                     }
-                };*/
+                }
             }
         }
         Err(e) => {
             //panic!("url : {}\n{}", spo_digest_endpoint, e);
-            return Err(SPOError::new(&format!("Error Get Digest Value : {:#?}", e)));
+            Err(SPOError::new(&format!("Error Get Digest Value : {:#?}", e)))
         }
-    };
+    }
+}
+
+//
+//  Share Point Online stand-in for tests, keeps the uploaded files and folders of the
+//  sites in memory. Served with http_client::fixture::serve_https together with the ACS token
+//
+#[cfg(test)]
+pub(crate) mod fixture {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::{Arc, Mutex};
+
+    use percent_encoding::percent_decode_str;
+    use serde_json::{json, Value};
+    use warp::filters::BoxedFilter;
+    use warp::host::Authority;
+    use warp::http::{Method, Response};
+    use warp::hyper::body::Bytes;
+    use warp::Filter;
+
+    #[derive(Default)]
+    pub struct SharePointStandIn {
+        /// server relative url and content
+        pub files: Mutex<BTreeMap<String, Vec<u8>>>,
+        /// server relative urls of created folders
+        pub folders: Mutex<BTreeSet<String>>,
+        /// method and decoded path of every Share Point request
+        pub requests: Mutex<Vec<String>>,
+    }
+
+    //
+    //  Argument of the first call of the path, ex. /sites/A/Docs of GetFolderByServerRelativeUrl('/sites/A/Docs')
+    //
+    fn argument(path: &str, call: &str) -> Option<String> {
+        let start = path.find(&format!("{}('", call))? + call.len() + 2;
+        let end = path[start..].find("')")? + start;
+        Some(path[start..end].to_string())
+    }
+
+    fn json_response(status: u16, body: Value) -> Response<Vec<u8>> {
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(body.to_string().into_bytes())
+            .unwrap()
+    }
+
+    fn not_found(path: &str) -> Response<Vec<u8>> {
+        json_response(
+            404,
+            json!({ "error": {
+                "code": "-2130575338, System.IO.FileNotFoundException",
+                "message": { "lang": "en-US", "value": format!("File Not Found: {}", path) }
+            }}),
+        )
+    }
+
+    fn file_json(url: &str, content: &[u8]) -> Value {
+        json!({
+            "Name": url.rsplit('/').next(),
+            "ServerRelativeUrl": url,
+            "Length": content.len().to_string(),
+            "TimeLastModified": "2024-01-01T10:00:00Z",
+        })
+    }
+
+    impl SharePointStandIn {
+        pub fn file(&self, url: &str) -> Option<Vec<u8>> {
+            self.files.lock().unwrap().get(url).cloned()
+        }
+        fn folder_exists(&self, url: &str) -> bool {
+            let prefix = format!("{}/", url);
+            self.folders.lock().unwrap().contains(url)
+                || self
+                    .files
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .any(|f| f.starts_with(&prefix))
+        }
+        fn folder_json(&self, url: &str) -> Value {
+            let prefix = format!("{}/", url);
+            let child = |p: &String| {
+                p.strip_prefix(&prefix)
+                    .is_some_and(|name| !name.is_empty() && !name.contains('/'))
+            };
+            let files: Vec<Value> = self
+                .files
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(p, _)| child(p))
+                .map(|(p, content)| file_json(p, content))
+                .collect();
+            let folders: Vec<Value> = self
+                .folders
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|p| child(p))
+                .map(|p| json!({ "Name": p.rsplit('/').next(), "ServerRelativeUrl": p }))
+                .collect();
+            json!({ "d": {
+                "Name": url.rsplit('/').next(),
+                "ServerRelativeUrl": url,
+                "Files": { "results": files },
+                "Folders": { "results": folders },
+            }})
+        }
+        fn respond(
+            &self,
+            method: &Method,
+            host: &str,
+            path: &str,
+            body: Bytes,
+        ) -> Response<Vec<u8>> {
+            let path = percent_decode_str(path).decode_utf8_lossy().to_string();
+            if path.ends_with("/tokens/OAuth/2") {
+                return json_response(
+                    200,
+                    json!({ "token_type": "Bearer", "access_token": "stand-in-token" }),
+                );
+            }
+            self.requests
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", method, path));
+            let Some((web_path, api)) = path.split_once("/_api/") else {
+                return not_found(&path);
+            };
+            if api == "ContextInfo" {
+                return json_response(
+                    200,
+                    json!({ "d": { "GetContextWebInformation": {
+                        "FormDigestValue": "stand-in-digest",
+                        "WebFullUrl": format!("https://{}{}", host, web_path),
+                    }}}),
+                );
+            }
+            let mut files = self.files.lock().unwrap();
+            if let Some(folder) = argument(api, "web/GetFolderByServerRelativeUrl") {
+                let add = api
+                    .split_once("/Files/add(url='")
+                    .and_then(|(_, a)| a.split_once('\''));
+                if let Some((name, _)) = add {
+                    files.insert(format!("{}/{}", folder, name), body.to_vec());
+                    return json_response(200, json!({}));
+                }
+                drop(files);
+                return match self.folder_exists(&folder) {
+                    true => json_response(200, self.folder_json(&folder)),
+                    false => not_found(&folder),
+                };
+            }
+            if let Some(folder) = argument(api, "web/folders/add") {
+                self.folders.lock().unwrap().insert(folder);
+                return json_response(200, json!({}));
+            }
+            let Some(file) = argument(api, "web/GetFileByServerRelativeUrl") else {
+                return not_found(&path);
+            };
+            if !files.contains_key(&file) {
+                return not_found(&file);
+            }
+            if api.contains("/StartUpload(") {
+                files.insert(file, body.to_vec());
+            } else if api.contains("/ContinueUpload(") || api.contains("/FinishUpload(") {
+                files.get_mut(&file).unwrap().extend(body.to_vec());
+            } else if api.ends_with("/recycle()") {
+                files.remove(&file);
+            } else if api.ends_with("/ValidateUpdateListItem()") {
+                return json_response(
+                    200,
+                    json!({ "d": { "ValidateUpdateListItem": { "results": [] }}}),
+                );
+            } else if *method == Method::GET {
+                return json_response(200, json!({ "d": file_json(&file, &files[&file]) }));
+            }
+            json_response(200, json!({}))
+        }
+    }
+
+    pub fn route(stand_in: Arc<SharePointStandIn>) -> BoxedFilter<(Response<Vec<u8>>,)> {
+        warp::method()
+            .and(warp::host::optional())
+            .and(warp::path::full())
+            .and(warp::body::bytes())
+            .map(
+                move |method: Method,
+                      host: Option<Authority>,
+                      path: warp::path::FullPath,
+                      body: Bytes| {
+                    let host = host.map(|h| h.to_string()).unwrap_or_default();
+                    stand_in.respond(&method, &host, path.as_str(), body)
+                },
+            )
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engine_debug_does_not_leak_client_secret() {
+        let engine = SPOEngine::new(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("very-secret-value")),
+            &String::from("contoso"),
+            &Client::new(),
+        );
+        assert!(!format!("{:#?}", engine).contains("very-secret-value"));
+    }
 }
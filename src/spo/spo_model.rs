@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::common::secret::Secret;
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SPOErrorResponse {
@@ -74,6 +76,88 @@ pub struct Metadata2 {
     pub type_field: String,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SPOValidateUpdateListItemResponse {
+    pub d: ValidateUpdateListItemD,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidateUpdateListItemD {
+    #[serde(rename = "ValidateUpdateListItem")]
+    pub validate_update_list_item: ValidateUpdateListItemResults,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidateUpdateListItemResults {
+    pub results: Vec<ListItemFormUpdateValue>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListItemFormUpdateValue {
+    #[serde(rename = "ErrorMessage")]
+    pub error_message: Option<String>,
+    #[serde(rename = "FieldName")]
+    pub field_name: String,
+    #[serde(rename = "FieldValue")]
+    pub field_value: Option<String>,
+    #[serde(rename = "HasException")]
+    pub has_exception: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SPOFileResponse {
+    pub d: SPOFile,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SPOFile {
+    #[serde(rename = "Name")]
+    pub name: Option<String>,
+    #[serde(rename = "ServerRelativeUrl")]
+    pub server_relative_url: Option<String>,
+    /// Edm.Int64 is returned as a string
+    #[serde(rename = "Length")]
+    pub length: String,
+    #[serde(rename = "TimeLastModified")]
+    pub time_last_modified: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SPOFolderResponse {
+    pub d: SPOFolder,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SPOFolder {
+    #[serde(rename = "Name")]
+    pub name: Option<String>,
+    #[serde(rename = "ServerRelativeUrl")]
+    pub server_relative_url: String,
+    /// Only with $expand=Files
+    #[serde(rename = "Files")]
+    pub files: Option<SPOResults<SPOFile>>,
+    /// Only with $expand=Folders
+    #[serde(rename = "Folders")]
+    pub folders: Option<SPOResults<SPOFolder>>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SPOPersonPropertiesResponse {
+    pub d: SPOPersonProperties,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SPOPersonProperties {
+    /// OneDrive site ex. https://contoso-my.sharepoint.com/personal/john_contoso_com/, empty when not provisioned
+    #[serde(rename = "PersonalUrl")]
+    pub personal_url: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SPOResults<T> {
+    pub results: Vec<T>,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SPOTokenResponse {
@@ -87,5 +171,5 @@ pub struct SPOTokenResponse {
     pub expires_on: Option<String>,
     pub resource: Option<String>,
     #[serde(rename = "access_token")]
-    pub access_token: Option<String>,
+    pub access_token: Option<Secret>,
 }
@@ -0,0 +1,221 @@
+use std::collections::BTreeSet;
+
+use log::debug;
+use reqwest::Client;
+
+use crate::common::secret::Secret;
+use crate::spo::spo_engine::{SPOEngine, SPOError};
+use crate::spo::spo_path::{ensure_spo_folders, join_path};
+
+/// Url name of the OneDrive document library, whatever the language of the site
+pub const ONEDRIVE_LIBRARY: &str = "Documents";
+
+//
+//  Personal sites live on the [share_point_domain]-my host
+//
+pub fn onedrive_domain(share_point_domain: &str) -> String {
+    match share_point_domain.ends_with("-my") {
+        true => share_point_domain.to_string(),
+        false => format!("{}-my", share_point_domain),
+    }
+}
+
+//
+//  Personal site as OneDrive names it when it is provisioned, the user principal name
+//  in lower case with . and @ (and other punctuation) replaced by _
+//  ex. John.Doe@contoso.com is /personal/john_doe_contoso_com
+//
+pub fn personal_site_path(user_principal_name: &str) -> String {
+    let name: String = user_principal_name
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| match c.is_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    format!("/personal/{}", name)
+}
+
+//
+//  OneDrive folder of a user as the --spo-domain, --spo-site and --spo-path arguments
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct OneDriveDestination {
+    /// [share_point_domain]-my
+    pub share_point_domain: String,
+    /// ex. /personal/john_doe_contoso_com
+    pub share_point_site: String,
+    /// ex. /personal/john_doe_contoso_com/Documents/Reports
+    pub share_point_path: String,
+}
+
+//
+//  Find the personal site of the user from its profile (the name differs when an account
+//  was renamed or recreated), else from the user principal name when the profile is missing
+//  or not readable (403 / 404). The drive must be provisioned,
+//  the folder below Documents is created when missing
+//
+#[allow(clippy::ptr_arg)]
+pub async fn resolve_onedrive(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    share_point_domain: &String,
+    user_principal_name: &String,
+    folder: &str,
+    http_client: &Client,
+) -> Result<OneDriveDestination, SPOError> {
+    let onedrive_domain = onedrive_domain(share_point_domain);
+    let mut spo_engine = SPOEngine::new(
+        tenant_id,
+        client_id,
+        client_secret,
+        &onedrive_domain,
+        http_client,
+    );
+    spo_engine.connect(&String::from("/")).await?;
+    let domain_url = spo_engine.web_url();
+    let share_point_site = match spo_engine.get_personal_url(user_principal_name).await {
+        Ok(Some(personal_url)) => personal_url
+            .strip_prefix(&domain_url)
+            .unwrap_or(&personal_url)
+            .trim_end_matches('/')
+            .to_string(),
+        Ok(None) => {
+            return Err(SPOError::new(&format!(
+                "OneDrive of {} is not provisioned",
+                user_principal_name
+            )))
+        }
+        // no user profile or no access to the profiles
+        Err(e) if matches!(e.http_status(), Some(403) | Some(404)) => {
+            debug!(
+                "User profile of {} not readable, use its user principal name: {}",
+                user_principal_name, e
+            );
+            personal_site_path(user_principal_name)
+        }
+        Err(e) => return Err(e),
+    };
+    debug!(
+        "OneDrive of {} is {}",
+        user_principal_name, share_point_site
+    );
+
+    spo_engine.connect(&share_point_site).await.map_err(|e| {
+        SPOError::new(&format!(
+            "OneDrive {} of {} not found: {}",
+            share_point_site, user_principal_name, e
+        ))
+    })?;
+    let library = join_path(&share_point_site, ONEDRIVE_LIBRARY);
+    spo_engine.get_folder(&library).await.map_err(|e| {
+        SPOError::new(&format!(
+            "OneDrive {} of {} not found: {}",
+            library, user_principal_name, e
+        ))
+    })?;
+    let folder = folder.trim_matches('/');
+    ensure_spo_folders(&spo_engine, &library, folder, &mut BTreeSet::new()).await?;
+    Ok(OneDriveDestination {
+        share_point_domain: onedrive_domain,
+        share_point_site,
+        share_point_path: join_path(&library, folder),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+    use warp::http::Response;
+    use warp::Filter;
+
+    use super::*;
+    use crate::common::http_client::fixture::serve_https;
+    use crate::spo::spo_engine::fixture::{route, SharePointStandIn};
+
+    #[test]
+    fn personal_site_of_user() {
+        assert_eq!(onedrive_domain("contoso"), "contoso-my");
+        assert_eq!(onedrive_domain("contoso-my"), "contoso-my");
+        assert_eq!(
+            personal_site_path("John.Doe@Contoso.com"),
+            "/personal/john_doe_contoso_com"
+        );
+        assert_eq!(
+            personal_site_path("ann-marie.o'neil@sub.contoso.com"),
+            "/personal/ann-marie_o_neil_sub_contoso_com"
+        );
+    }
+
+    async fn resolve(http_client: &Client) -> Result<OneDriveDestination, SPOError> {
+        resolve_onedrive(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+            &String::from("contoso"),
+            &String::from("John.Doe@contoso.com"),
+            "Reports",
+            http_client,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn personal_site_of_unreadable_profile() {
+        let share_point = Arc::new(SharePointStandIn::default());
+        share_point
+            .folders
+            .lock()
+            .unwrap()
+            .insert(String::from("/personal/john_doe_contoso_com/Documents"));
+        // status of the user profile request
+        let profile_status = Arc::new(Mutex::new(404));
+        let status = profile_status.clone();
+        let profile = warp::path::full()
+            .and_then(move |path: warp::path::FullPath| {
+                let status = *status.lock().unwrap();
+                async move {
+                    match path.as_str().contains("SP.UserProfiles.PeopleManager") {
+                        true => Ok(Response::builder()
+                            .status(status)
+                            .header("content-type", "application/json")
+                            .body(
+                                json!({ "error": {
+                                    "code": "-1, Microsoft.SharePoint.SPException",
+                                    "message": { "lang": "en-US", "value": "profile error" }
+                                }})
+                                .to_string()
+                                .into_bytes(),
+                            )
+                            .unwrap()),
+                        false => Err(warp::reject::not_found()),
+                    }
+                }
+            })
+            .or(route(share_point.clone()))
+            .boxed();
+        let http_client = serve_https(profile).await.build_client().unwrap();
+
+        for status in [404, 403] {
+            *profile_status.lock().unwrap() = status;
+            let onedrive = resolve(&http_client).await.unwrap();
+            assert_eq!(onedrive.share_point_domain, "contoso-my");
+            assert_eq!(onedrive.share_point_site, "/personal/john_doe_contoso_com");
+            assert_eq!(
+                onedrive.share_point_path,
+                "/personal/john_doe_contoso_com/Documents/Reports"
+            );
+        }
+
+        // other errors are not hidden by a guessed site
+        *profile_status.lock().unwrap() = 500;
+        let error = resolve(&http_client).await.unwrap_err();
+        assert_eq!(error.http_status(), Some(500));
+        assert!(error.to_string().contains("profile error"));
+    }
+}
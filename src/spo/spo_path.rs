@@ -0,0 +1,38 @@
+use std::collections::BTreeSet;
+
+use crate::spo::spo_engine::{SPOEngine, SPOError};
+
+//
+//  Join a Share Point folder and a name, either may be empty
+//
+pub(crate) fn join_path(parent: &str, name: &str) -> String {
+    match (parent.is_empty(), name.is_empty()) {
+        (_, true) => parent.to_string(),
+        (true, false) => name.to_string(),
+        (false, false) => format!("{}/{}", parent.trim_end_matches('/'), name),
+    }
+}
+
+//
+//  Create every missing folder of the relative folder path, parent first
+//
+#[allow(clippy::ptr_arg)]
+pub(crate) async fn ensure_spo_folders(
+    spo_engine: &SPOEngine,
+    share_point_path: &String,
+    relative_folder: &str,
+    folders: &mut BTreeSet<String>,
+) -> Result<(), SPOError> {
+    let mut relative_path = String::new();
+    for name in relative_folder.split('/').filter(|n| !n.is_empty()) {
+        relative_path = join_path(&relative_path, name);
+        if folders.contains(&relative_path) {
+            continue;
+        }
+        spo_engine
+            .ensure_folder(&join_path(share_point_path, &relative_path))
+            .await?;
+        folders.insert(relative_path.clone());
+    }
+    Ok(())
+}
@@ -0,0 +1,428 @@
+use std::collections::BTreeSet;
+
+use log::debug;
+use oauth2::http::HeaderMap;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::common::cloud::cloud;
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::spo::spo_engine::{SPOEngine, SPOError};
+use crate::spo::spo_path::{ensure_spo_folders, join_path};
+use crate::spo::spo_url::{resolve_destination_url, SPOFolderUrl};
+
+/// Channel of every team
+pub const DEFAULT_CHANNEL: &str = "General";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GraphTokenResponse {
+    access_token: Secret,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GraphList<T> {
+    value: Vec<T>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphGroup {
+    id: String,
+    display_name: Option<String>,
+    #[serde(default)]
+    resource_provisioning_options: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphChannel {
+    id: String,
+    display_name: String,
+    /// standard, private or shared
+    membership_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphDriveItem {
+    name: Option<String>,
+    /// ex. https://contoso.sharepoint.com/sites/Sales-Private/Shared%20Documents/Private
+    web_url: String,
+}
+
+//
+//  Team id (a group id) or display name
+//
+fn is_team_id(team: &str) -> bool {
+    Uuid::parse_str(team.trim()).is_ok()
+}
+
+//
+//  Channel id ex. 19:4a95f7d8db4c4e7fae857bcebe0623e6@thread.tacv2 or display name
+//
+fn is_channel_id(channel: &str) -> bool {
+    channel.starts_with("19:") && channel.contains("@thread")
+}
+
+//
+//  The channel of the name, the name is not case sensitive and must not be ambiguous
+//
+fn find_channel<'a>(
+    channels: &'a [GraphChannel],
+    name: &str,
+) -> Result<&'a GraphChannel, SPOError> {
+    let matches: Vec<&GraphChannel> = channels
+        .iter()
+        .filter(|c| c.display_name.eq_ignore_ascii_case(name.trim()))
+        .collect();
+    match matches.as_slice() {
+        [channel] => Ok(channel),
+        [] => Err(SPOError::new(&format!(
+            "Channel {} not found, channels: {}",
+            name,
+            channels
+                .iter()
+                .map(|c| c.display_name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+        _ => Err(SPOError::new(&format!(
+            "Channel name {} is ambiguous, use its id",
+            name
+        ))),
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+async fn get_graph_token(
+    http_client: &Client,
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+) -> Result<Secret, SPOError> {
+    let res = http_client
+        .post(cloud().graph_token_url(tenant_id))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.expose().as_str()),
+            ("scope", cloud().graph_scope().as_str()),
+        ])
+        .send()
+        .await
+        .map_err(|e| SPOError::new(&format!("Graph token error: {}", e)))?;
+    if !res.status().is_success() {
+        return Err(SPOError::new(&format!(
+            "Graph token error: {} {}",
+            res.status(),
+            res.text().await.unwrap_or_default()
+        )));
+    }
+    res.json::<GraphTokenResponse>()
+        .await
+        .map(|r| r.access_token)
+        .map_err(|e| SPOError::new(&format!("Graph token response error: {}", e)))
+}
+
+async fn get_graph_resource<T: DeserializeOwned>(
+    http_client: &Client,
+    access_token: &Secret,
+    path: &String,
+) -> Result<T, SPOError> {
+    let url = format!("{}{}", cloud().graph_url(), path);
+    debug!("get_graph_resource with url : {:?}", url);
+
+    let mut headers = HeaderMap::new();
+    headers.append(
+        "Authorization",
+        format!("Bearer {}", access_token.expose()).parse().unwrap(),
+    );
+    headers.append("Accept", "application/json".parse().unwrap());
+    let res = http_client
+        .get(url)
+        .headers(headers)
+        .send()
+        .await
+        .map_err(|e| SPOError::new(&format!("Graph request error: {}", e)))?;
+    if !res.status().is_success() {
+        return Err(SPOError::new(&format!(
+            "Graph {} error: {} {}",
+            path,
+            res.status(),
+            res.text().await.unwrap_or_default()
+        )));
+    }
+    res.json::<T>()
+        .await
+        .map_err(|e| SPOError::new(&format!("Graph {} response error: {}", path, e)))
+}
+
+//
+//  Group id of the team, a name must match one team
+//
+async fn resolve_team_id(
+    http_client: &Client,
+    access_token: &Secret,
+    team: &String,
+) -> Result<String, SPOError> {
+    if is_team_id(team) {
+        return Ok(team.trim().to_string());
+    }
+    let filter = format!("displayName eq '{}'", team.trim().replace('\'', "''"));
+    let groups: GraphList<GraphGroup> = get_graph_resource(
+        http_client,
+        access_token,
+        &format!(
+            "/groups?$filter={}&$select=id,displayName,resourceProvisioningOptions",
+            utf8_percent_encode(&filter, NON_ALPHANUMERIC)
+        ),
+    )
+    .await?;
+    let teams: Vec<&GraphGroup> = groups
+        .value
+        .iter()
+        .filter(|g| g.resource_provisioning_options.iter().any(|o| o == "Team"))
+        .collect();
+    match teams.as_slice() {
+        [group] => {
+            debug!("Team {:?} is {}", group.display_name, group.id);
+            Ok(group.id.to_owned())
+        }
+        [] => Err(SPOError::new(&format!("Team {} not found", team))),
+        _ => Err(SPOError::new(&format!(
+            "Team name {} is ambiguous, use its id",
+            team
+        ))),
+    }
+}
+
+//
+//  Share Point folder of the channel files from Graph filesFolder. Private and shared
+//  channels have their own site, the web is found like a --destination-url.
+//  The folder below the channel folder is created when missing
+//
+pub async fn resolve_channel_folder(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    team: &String,
+    channel: &String,
+    folder: &str,
+    http_config: &HttpConfig,
+) -> Result<SPOFolderUrl, SPOError> {
+    let http_client = http_config.build_client()?;
+    let access_token = get_graph_token(&http_client, tenant_id, client_id, client_secret).await?;
+    let team_id = resolve_team_id(&http_client, &access_token, team).await?;
+    let channel_id = match is_channel_id(channel) {
+        true => channel.to_owned(),
+        false => {
+            let channels: GraphList<GraphChannel> = get_graph_resource(
+                &http_client,
+                &access_token,
+                &format!(
+                    "/teams/{}/channels?$select=id,displayName,membershipType",
+                    team_id
+                ),
+            )
+            .await?;
+            let found = find_channel(&channels.value, channel)?;
+            debug!(
+                "Channel {} is {} ({:?})",
+                found.display_name, found.id, found.membership_type
+            );
+            found.id.to_owned()
+        }
+    };
+    let files_folder: GraphDriveItem = get_graph_resource(
+        &http_client,
+        &access_token,
+        &format!(
+            "/teams/{}/channels/{}/filesFolder",
+            team_id,
+            utf8_percent_encode(&channel_id, NON_ALPHANUMERIC)
+        ),
+    )
+    .await?;
+    debug!(
+        "Files folder {:?} of channel {} is {}",
+        files_folder.name, channel, files_folder.web_url
+    );
+
+    let mut folder_url = resolve_destination_url(
+        tenant_id,
+        client_id,
+        client_secret,
+        &files_folder.web_url,
+        http_config,
+    )
+    .await?;
+    let folder = folder.trim_matches('/');
+    if !folder.is_empty() {
+        let mut spo_engine = SPOEngine::new(
+            tenant_id,
+            client_id,
+            client_secret,
+            &folder_url.share_point_domain,
+            &http_client,
+        );
+        spo_engine.connect(&folder_url.share_point_site).await?;
+        ensure_spo_folders(
+            &spo_engine,
+            &folder_url.share_point_path,
+            folder,
+            &mut BTreeSet::new(),
+        )
+        .await?;
+        folder_url.share_point_path = join_path(&folder_url.share_point_path, folder);
+    }
+    Ok(folder_url)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use percent_encoding::percent_decode_str;
+    use serde_json::{json, Value};
+    use warp::host::Authority;
+    use warp::http::Response;
+    use warp::Filter;
+
+    use super::*;
+    use crate::common::http_client::fixture::serve_https;
+    use crate::spo::spo_engine::fixture::{route, SharePointStandIn};
+
+    const TEAM_ID: &str = "02bd9fd6-8f93-4758-87c3-1fb73740a315";
+
+    //
+    //  Graph of a team Sales with a private channel Budget, the files of the channel
+    //  are in the Sales-Budget site
+    //
+    fn graph_response(path: &str) -> Option<Value> {
+        let channels = format!("/v1.0/teams/{}/channels", TEAM_ID);
+        match path {
+            p if p.ends_with("/oauth2/v2.0/token") => {
+                Some(json!({ "token_type": "Bearer", "access_token": "graph-token" }))
+            }
+            "/v1.0/groups" => Some(json!({ "value": [
+                { "id": TEAM_ID, "displayName": "Sales", "resourceProvisioningOptions": ["Team"] },
+                // a group without a team, ignored
+                { "id": "7c1bd6e8-22c4-4a39-9e3a-04d8e9b4e5f1", "displayName": "Sales" }
+            ]})),
+            p if p == channels => Some(json!({ "value": [
+                { "id": "19:a@thread.tacv2", "displayName": "General", "membershipType": "standard" },
+                { "id": "19:b@thread.tacv2", "displayName": "Budget", "membershipType": "private" }
+            ]})),
+            p if p == format!("{}/19:b@thread.tacv2/filesFolder", channels) => Some(json!({
+                "name": "Budget",
+                "webUrl": "https://contoso.sharepoint.com/sites/Sales-Budget/Shared%20Documents/Budget"
+            })),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn team_and_channel_by_id_or_name() {
+        assert!(is_team_id("02bd9fd6-8f93-4758-87c3-1fb73740a315"));
+        assert!(!is_team_id("Sales"));
+        assert!(is_channel_id(
+            "19:4a95f7d8db4c4e7fae857bcebe0623e6@thread.tacv2"
+        ));
+        assert!(!is_channel_id("General"));
+
+        let channels: GraphList<GraphChannel> = serde_json::from_str(
+            r#"{"value": [
+                {"id": "19:a@thread.tacv2", "displayName": "General", "membershipType": "standard"},
+                {"id": "19:b@thread.tacv2", "displayName": "Budget", "membershipType": "private"},
+                {"id": "19:c@thread.skype", "displayName": "Partners", "membershipType": "shared"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            find_channel(&channels.value, "budget").unwrap().id,
+            "19:b@thread.tacv2"
+        );
+        assert!(find_channel(&channels.value, "Marketing").is_err());
+    }
+
+    #[tokio::test]
+    async fn private_channel_folder_in_its_own_site() {
+        let share_point = Arc::new(SharePointStandIn::default());
+        share_point
+            .folders
+            .lock()
+            .unwrap()
+            .insert(String::from("/sites/Sales-Budget/Shared Documents/Budget"));
+        // decoded path of every Graph request
+        let graph_requests = Arc::new(Mutex::new(Vec::<String>::new()));
+        let requests = graph_requests.clone();
+        let graph = warp::host::optional()
+            .and(warp::path::full())
+            .and_then(move |host: Option<Authority>, path: warp::path::FullPath| {
+                let requests = requests.clone();
+                async move {
+                    let host = host.map(|h| h.host().to_string()).unwrap_or_default();
+                    if host != "graph.microsoft.com" && host != "login.microsoftonline.com" {
+                        return Err(warp::reject::not_found());
+                    }
+                    let path = percent_decode_str(path.as_str())
+                        .decode_utf8_lossy()
+                        .to_string();
+                    requests.lock().unwrap().push(path.clone());
+                    let response = match graph_response(&path) {
+                        Some(body) => Response::builder()
+                            .header("content-type", "application/json")
+                            .body(body.to_string().into_bytes()),
+                        None => Response::builder().status(404).body(Vec::new()),
+                    };
+                    Ok(response.unwrap())
+                }
+            })
+            .or(route(share_point.clone()))
+            .boxed();
+        let http_config = serve_https(graph).await;
+
+        let folder_url = resolve_channel_folder(
+            &String::from("tenant"),
+            &String::from("client"),
+            &Secret::new(&String::from("secret")),
+            &String::from("Sales"),
+            &String::from("budget"),
+            "/Reports/",
+            &http_config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            folder_url,
+            SPOFolderUrl {
+                share_point_domain: String::from("contoso"),
+                share_point_site: String::from("/sites/Sales-Budget"),
+                share_point_path: String::from(
+                    "/sites/Sales-Budget/Shared Documents/Budget/Reports"
+                ),
+            }
+        );
+        assert!(share_point
+            .folders
+            .lock()
+            .unwrap()
+            .contains("/sites/Sales-Budget/Shared Documents/Budget/Reports"));
+        assert_eq!(
+            *graph_requests.lock().unwrap(),
+            vec![
+                String::from("/tenant/oauth2/v2.0/token"),
+                String::from("/v1.0/groups"),
+                format!("/v1.0/teams/{}/channels", TEAM_ID),
+                format!(
+                    "/v1.0/teams/{}/channels/19:b@thread.tacv2/filesFolder",
+                    TEAM_ID
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use log::debug;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::common::cloud::cloud;
+use crate::common::http_client::HttpConfig;
+use crate::common::secret::Secret;
+use crate::spo::spo_endpoint::SPOEndpoint;
+use crate::spo::spo_engine::{SPOEngine, SPOError};
+
+// Folder of a library view url ex. Shared Documents/Forms/AllItems.aspx?id=/sites/x/Shared Documents/Reports
+const FOLDER_QUERY_KEYS: [&str; 2] = ["id", "RootFolder"];
+
+//
+//  Share point folder of a browser url, split as the --spo-domain, --spo-site and --spo-path arguments
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct SPOFolderUrl {
+    pub share_point_domain: String,
+    pub share_point_site: String,
+    /// Server relative ex. /sites/Finance/Shared Documents/Reports
+    pub share_point_path: String,
+}
+
+impl SPOFolderUrl {
+    //
+    //  https://contoso.sharepoint.com/sites/Finance/Shared%20Documents/Reports,
+    //  the /:f:/r/ sharing form of the same url, or a library view with ?id=[folder].
+    //  The site is the /sites/ or /teams/ site collection or / for the root site,
+    //  see site_candidates for subsites
+    //
+    pub fn parse(value: &str) -> Result<SPOFolderUrl, SPOError> {
+        let invalid = |reason: &str| {
+            SPOError::new(&format!(
+                "Invalid Share Point folder url {}: {}",
+                value, reason
+            ))
+        };
+        let url = Url::parse(value.trim()).map_err(|e| invalid(&e.to_string()))?;
+        let share_point_domain = url
+            .host_str()
+            .and_then(|h| cloud().share_point_domain(h))
+            .ok_or_else(|| {
+                invalid(&format!(
+                    "expect https://{}",
+                    cloud().share_point_host("[domain]")
+                ))
+            })?
+            .to_string();
+
+        let folder_query = url
+            .query_pairs()
+            .find(|(k, _)| FOLDER_QUERY_KEYS.iter().any(|q| k.eq_ignore_ascii_case(q)))
+            .map(|(_, v)| v.to_string());
+        let segments: Vec<String> = match folder_query {
+            Some(folder) => folder
+                .split('/')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect(),
+            None => {
+                let mut segments: Vec<String> = url
+                    .path_segments()
+                    .map(|s| {
+                        s.filter(|s| !s.is_empty())
+                            .map(|s| percent_decode_str(s).decode_utf8_lossy().to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                // sharing link /:f:/r/[path], other sharing forms hold a token instead of the path
+                if segments.first().is_some_and(|s| s.starts_with(':')) {
+                    match segments.get(1).map(|s| s.as_str()) {
+                        Some("r") => {
+                            segments.drain(..2);
+                        }
+                        _ => {
+                            return Err(invalid(
+                                "sharing links without the folder path are not supported, copy the url of the folder",
+                            ))
+                        }
+                    }
+                }
+                segments
+            }
+        };
+        if segments.last().is_some_and(|s| s.ends_with(".aspx")) {
+            return Err(invalid("page url without the folder (id=)"));
+        }
+        if segments.is_empty() {
+            return Err(invalid("expect [site]/[library]/[folder] in the url"));
+        }
+        // /sites/[site] and /teams/[site] site collections, the root site otherwise
+        let collection = match segments[0].to_lowercase().as_str() {
+            "sites" | "teams" if segments.len() >= 3 => 2,
+            "sites" | "teams" => {
+                return Err(invalid(
+                    "expect /sites/[site]/[library]/[folder] in the url",
+                ))
+            }
+            _ => 0,
+        };
+        Ok(SPOFolderUrl {
+            share_point_domain,
+            share_point_site: format!("/{}", segments[..collection].join("/")),
+            share_point_path: format!("/{}", segments.join("/")),
+        })
+    }
+
+    //
+    //  Webs which may hold the folder as --spo-site paths, the site collection first then its
+    //  subsites ex. /sites/Finance, /sites/Finance/Projects for /sites/Finance/Projects/Shared Documents
+    //
+    pub fn site_candidates(&self) -> Vec<String> {
+        let segments: Vec<&str> = self
+            .share_point_path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect();
+        let collection = self
+            .share_point_site
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .count();
+        // the last segment is at least the library
+        (collection..segments.len())
+            .map(|n| format!("/{}", segments[..n].join("/")))
+            .collect()
+    }
+
+    pub fn to_end_point(&self) -> SPOEndpoint {
+        SPOEndpoint::new(&self.share_point_domain)
+            .set_site(&self.share_point_site)
+            .set_path(&self.share_point_path)
+    }
+}
+
+//
+//  Parse the url and find the web of the folder with GetFolderByServerRelativeUrl,
+//  the folder must exist. The site is the web of _api/contextinfo
+//
+#[allow(clippy::ptr_arg)]
+pub async fn resolve_destination_url(
+    tenant_id: &String,
+    client_id: &String,
+    client_secret: &Secret,
+    value: &String,
+    http_config: &HttpConfig,
+) -> Result<SPOFolderUrl, SPOError> {
+    let mut folder_url = SPOFolderUrl::parse(value)?;
+    let http_client = http_config.build_client()?;
+    let mut last_error: Option<SPOError> = None;
+    for site in folder_url.site_candidates() {
+        let mut spo_engine = SPOEngine::new(
+            tenant_id,
+            client_id,
+            client_secret,
+            &folder_url.share_point_domain,
+            &http_client,
+        );
+        let folder = match spo_engine.connect(&site).await {
+            Ok(_) => spo_engine.get_folder(&folder_url.share_point_path).await,
+            Err(e) => Err(e),
+        };
+        match folder {
+            Ok(folder) => {
+                debug!("Folder {} in site {}", folder.server_relative_url, site);
+                folder_url.share_point_site = match spo_engine.web_path() {
+                    web_path if web_path.is_empty() => String::from("/"),
+                    web_path => web_path,
+                };
+                folder_url.share_point_path = folder.server_relative_url;
+                return Ok(folder_url);
+            }
+            Err(e) => {
+                debug!("Folder not found in site {}: {}", site, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(SPOError::new(&format!(
+        "Folder {} not found: {}",
+        folder_url.share_point_path,
+        last_error.map(|e| e.to_string()).unwrap_or_default()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_browser_folder_urls() {
+        let expected = SPOFolderUrl {
+            share_point_domain: String::from("contoso"),
+            share_point_site: String::from("/sites/Finance"),
+            share_point_path: String::from("/sites/Finance/Shared Documents/Reports"),
+        };
+        for url in [
+            "https://contoso.sharepoint.com/sites/Finance/Shared%20Documents/Reports",
+            "https://contoso.sharepoint.com/sites/Finance/Shared%20Documents/Reports/",
+            "https://contoso.sharepoint.com/:f:/r/sites/Finance/Shared%20Documents/Reports?csf=1&web=1&e=Ab12Cd",
+            "https://contoso.sharepoint.com/sites/Finance/Shared%20Documents/Forms/AllItems.aspx?id=%2Fsites%2FFinance%2FShared%20Documents%2FReports&viewid=6e2a",
+        ] {
+            assert_eq!(SPOFolderUrl::parse(url).unwrap(), expected, "{}", url);
+        }
+        assert_eq!(
+            expected.site_candidates(),
+            vec!["/sites/Finance", "/sites/Finance/Shared Documents"]
+        );
+
+        let root = SPOFolderUrl::parse(
+            "https://contoso.sharepoint.com/Shared%20Documents/Forms/AllItems.aspx?RootFolder=%2FShared%20Documents%2FReports",
+        )
+        .unwrap();
+        assert_eq!(root.share_point_site, "/");
+        assert_eq!(root.share_point_path, "/Shared Documents/Reports");
+        assert_eq!(root.site_candidates(), vec!["/", "/Shared Documents"]);
+
+        let team = SPOFolderUrl::parse(
+            "https://contoso.sharepoint.com/teams/Sales/Projects/Shared%20Documents/General",
+        )
+        .unwrap();
+        assert_eq!(team.share_point_site, "/teams/Sales");
+        assert_eq!(
+            team.site_candidates(),
+            vec![
+                "/teams/Sales",
+                "/teams/Sales/Projects",
+                "/teams/Sales/Projects/Shared Documents"
+            ]
+        );
+
+        for url in [
+            "https://contoso.sharepoint.com/:f:/s/Finance/EkZ1x2y3",
+            "https://contoso.sharepoint.com/sites/Finance/Shared%20Documents/Forms/AllItems.aspx",
+            "https://contoso.sharepoint.com/sites/Finance",
+            "https://contoso.sharepoint.com/teams/Sales",
+            "https://contoso.sharepoint.com/",
+            "https://contoso.example.com/sites/Finance/Shared%20Documents",
+            "contoso/Finance",
+        ] {
+            assert!(SPOFolderUrl::parse(url).is_err(), "{}", url);
+        }
+    }
+}